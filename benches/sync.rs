@@ -0,0 +1,98 @@
+//! Criterion benchmarks for parsing and syncing, run with `cargo bench`. Everything goes through a
+//! mock runner, so neither sops nor the directive commands need to be installed.
+//!
+//! Expected complexity for a file of n lines with k directives: `parse_commands` is O(n),
+//! a single `parse_decrypted_value` lookup is O(n), and processing a file is O(k * n) since every
+//! directive looks up its current value in the decrypted content.
+
+use std::hint::black_box;
+use std::io::{self, Write};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use sops_shell::parser::{parse_commands, ParseOptions};
+use sops_shell::runner::{CommandOutput, CommandRunner, Runner};
+use sops_shell::sync::{parse_decrypted_value, process_file, RunState, SyncOptions};
+
+const SIZES: &[usize] = &[10, 100, 1000];
+
+/// Serves the generated plaintext to sops decrypts and answers `echo` directives with their argument
+struct MockRunner {
+    decrypted: String,
+}
+
+impl CommandRunner for MockRunner {
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+        let stdout = match (program, args) {
+            ("sops", [.., "--decrypt", _]) => self.decrypted.clone().into_bytes(),
+            ("sh", ["-c", command]) => command.trim_start_matches("echo ").as_bytes().to_vec(),
+            _ => Vec::new(),
+        };
+        Ok(CommandOutput { code: Some(0), stdout, stderr: Vec::new() })
+    }
+}
+
+/// A yaml file with `secrets` directives, half of them already in sync
+fn secrets_yaml(secrets: usize) -> String {
+    (0..secrets)
+        .map(|i| {
+            let stored = if i % 2 == 0 { format!("value_{}", i) } else { "stale".to_string() };
+            format!("# shell: echo value_{}\nkey_{}: {}\n", i, i, stored)
+        })
+        .collect()
+}
+
+fn options(content: &str) -> SyncOptions {
+    SyncOptions {
+        runner: Runner::new(MockRunner { decrypted: content.to_string() }),
+        summary_only: true,
+        ..Default::default()
+    }
+}
+
+fn temp_file(content: &str) -> tempfile::NamedTempFile {
+    let mut file = tempfile::Builder::new().suffix(".yaml").tempfile().expect("Should create temp file");
+    file.write_all(content.as_bytes()).expect("Should write temp file");
+    file
+}
+
+fn bench_parse_commands(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_commands");
+    let parse_options = ParseOptions::default();
+    for &size in SIZES {
+        let content = secrets_yaml(size);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &content, |b, content| {
+            b.iter(|| parse_commands(black_box(content), &parse_options).expect("Should parse"));
+        });
+    }
+    group.finish();
+}
+
+fn bench_parse_decrypted_value(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_decrypted_value");
+    for &size in SIZES {
+        let content = secrets_yaml(size);
+        // The last key, so the whole content is scanned
+        let last_key = format!("key_{}", size - 1);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &content, |b, content| {
+            b.iter(|| parse_decrypted_value(black_box(content), &last_key, None));
+        });
+    }
+    group.finish();
+}
+
+fn bench_process_file(c: &mut Criterion) {
+    let mut group = c.benchmark_group("process_file");
+    for &size in SIZES {
+        let content = secrets_yaml(size);
+        let file = temp_file(&content);
+        let options = options(&content);
+        // The mock runner never changes the file, so every run performs the same updates
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter(|| process_file(file.path(), false, &options, &mut RunState::default()).expect("Should process file"));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse_commands, bench_parse_decrypted_value, bench_process_file);
+criterion_main!(benches);
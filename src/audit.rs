@@ -0,0 +1,90 @@
+//! Change trail for `--audit-log`: a line per value written, never the value itself
+
+use anyhow::{Context, Result};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::plan::{mask_in, MASK};
+use crate::state::format_timestamp;
+
+/// Stands in for the command of every entry with `AuditLog::redact_commands`
+const REDACTED: &str = "[redacted]";
+
+/// Appends a line per value written to `path`
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    pub path: PathBuf,
+    /// Leave directive commands out of the log, e.g. when they name internal systems
+    pub redact_commands: bool,
+}
+
+/// A value written to a file, as passed to `AuditLog::append`
+#[derive(Debug)]
+pub struct AuditEntry<'a> {
+    pub file: &'a Path,
+    pub key: &'a str,
+    pub command: Option<&'a str>,
+    /// `None` when the key had no value yet
+    pub old_value: Option<&'a str>,
+    pub new_value: &'a str,
+}
+
+impl AuditLog {
+    /// Checks the log can be appended to, so a run fails before writing anything it couldn't log
+    pub fn ensure_writable(&self) -> Result<()> {
+        self.open().map(|_| ())
+    }
+
+    /// Appends `entry` as a single line. The file is opened for each entry rather than held open,
+    /// so a log moved away by logrotate is recreated instead of written to under its old name
+    pub fn append(&self, entry: &AuditEntry, time: SystemTime) -> Result<()> {
+        let mut file = self.open()?;
+        let line = self.render(entry, time);
+        file.write_all(format!("{}\n", line).as_bytes())
+            .with_context(|| format!("Failed to append to audit log {}", self.path.display()))
+    }
+
+    /// e.g. `2024-05-01T09:30:00Z update file="secrets.yaml" key="api_key" command="vault read secret/api"
+    /// old=********(12) new=********(32)`, values masked down to their length. Strings are quoted
+    /// like JSON, so a newline in a command can't start a line of its own
+    pub fn render(&self, entry: &AuditEntry, time: SystemTime) -> String {
+        let timestamp = format_timestamp(time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs());
+        let masked = |value: &str| format!("{}({})", MASK, value.chars().count());
+        let command = match entry.command {
+            Some(_) if self.redact_commands => REDACTED.to_string(),
+            // A value can be part of its own command, e.g. `echo <secret>`
+            Some(command) => {
+                let command = entry.old_value.map_or_else(|| command.to_string(), |old| mask_in(command, old));
+                quote(&mask_in(&command, entry.new_value))
+            }
+            None => "-".to_string(),
+        };
+
+        format!(
+            "{} update file={} key={} command={} old={} new={}",
+            timestamp,
+            quote(&entry.file.display().to_string()),
+            quote(entry.key),
+            command,
+            entry.old_value.map_or_else(|| "-".to_string(), masked),
+            masked(entry.new_value),
+        )
+    }
+
+    fn open(&self) -> Result<std::fs::File> {
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(&self.path).with_context(|| format!("Failed to open audit log {}", self.path.display()))
+    }
+}
+
+fn quote(text: &str) -> String {
+    serde_json::Value::from(text).to_string()
+}
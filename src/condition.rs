@@ -0,0 +1,71 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+
+/// Guard of a `when=` directive option, checked against environment variables: `$VAR` (set and
+/// non-empty), `$VAR == value` or `$VAR != value`. Values may be quoted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    variable: String,
+    test: Test,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Test {
+    Present,
+    Equals(String),
+    NotEquals(String),
+}
+
+impl Condition {
+    pub fn parse(raw: &str) -> Result<Self> {
+        let raw = raw.trim();
+        let (variable, test) = if let Some((variable, value)) = raw.split_once("==") {
+            (variable, Test::Equals(unquote(value)))
+        } else if let Some((variable, value)) = raw.split_once("!=") {
+            (variable, Test::NotEquals(unquote(value)))
+        } else {
+            (raw, Test::Present)
+        };
+
+        let variable = variable
+            .trim()
+            .strip_prefix('$')
+            .filter(|name| !name.is_empty() && name.chars().all(|ch| ch.is_ascii_alphanumeric() || ch == '_'))
+            .ok_or_else(|| anyhow!("Invalid when condition '{}', expected '$VAR', '$VAR == value' or '$VAR != value'", raw))?;
+
+        Ok(Condition { variable: variable.to_string(), test })
+    }
+
+    /// Evaluates the condition against the process environment
+    pub fn holds(&self) -> bool {
+        self.holds_with(|name| std::env::var(name).ok())
+    }
+
+    pub fn holds_with(&self, lookup: impl Fn(&str) -> Option<String>) -> bool {
+        let value = lookup(&self.variable);
+        match &self.test {
+            Test::Present => value.is_some_and(|value| !value.is_empty()),
+            Test::Equals(expected) => value.as_deref() == Some(expected.as_str()),
+            Test::NotEquals(expected) => value.as_deref() != Some(expected.as_str()),
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.test {
+            Test::Present => write!(f, "${}", self.variable),
+            Test::Equals(value) => write!(f, "${} == {}", self.variable, value),
+            Test::NotEquals(value) => write!(f, "${} != {}", self.variable, value),
+        }
+    }
+}
+
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    ['"', '\'']
+        .iter()
+        .find_map(|quote| value.strip_prefix(*quote)?.strip_suffix(*quote))
+        .unwrap_or(value)
+        .to_string()
+}
@@ -0,0 +1,71 @@
+use serde_json::{json, Map};
+
+use crate::output::{OutputFormat, Printer};
+
+/// Where the effective value of a setting came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettingSource {
+    /// Fixed in sops-shell, not configurable
+    BuiltIn,
+    Default,
+    Flag,
+    Env,
+    /// Set by the profile selected with `--profile`
+    Profile,
+}
+
+impl SettingSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SettingSource::BuiltIn => "built-in",
+            SettingSource::Default => "default",
+            SettingSource::Flag => "flag",
+            SettingSource::Env => "env",
+            SettingSource::Profile => "profile",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Setting {
+    pub name: &'static str,
+    pub value: String,
+    pub source: SettingSource,
+}
+
+/// Settings that can't be changed, but are useful to know when debugging a run
+pub fn built_in_settings() -> Vec<Setting> {
+    [("sops binary", "sops (from PATH)"), ("shell", "sh -c"), ("directive keyword", "shell")]
+        .into_iter()
+        .map(|(name, value)| Setting {
+            name,
+            value: value.to_string(),
+            source: SettingSource::BuiltIn,
+        })
+        .collect()
+}
+
+pub fn print_settings(settings: &[Setting], format: OutputFormat) {
+    let out = Printer::new(format);
+    let width = settings.iter().map(|setting| setting.name.len()).max().unwrap_or(0);
+
+    out.text("Effective configuration:");
+    for setting in settings {
+        out.text(format_args!(
+            "  {:width$}  {}  ({})",
+            setting.name,
+            setting.value,
+            setting.source.as_str(),
+            width = width
+        ));
+    }
+
+    let settings: Map<String, serde_json::Value> = settings
+        .iter()
+        .map(|setting| {
+            let entry = json!({ "value": setting.value, "source": setting.source.as_str() });
+            (setting.name.to_string(), entry)
+        })
+        .collect();
+    out.event(json!({ "event": "config", "settings": settings }));
+}
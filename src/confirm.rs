@@ -0,0 +1,71 @@
+//! Per-command approval for `--paranoid` runs, for files whose commands aren't trusted
+
+use anyhow::{Context, Result};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::{Arc, Mutex};
+
+/// Asks before each directive command is run. Shared by clones, so answering "all" once
+/// approves the rest of the run
+#[derive(Clone)]
+pub struct Confirmer(Arc<Mutex<Prompt>>);
+
+struct Prompt {
+    input: Box<dyn BufRead + Send>,
+    /// Answered "all", or approved up front with `--yes`
+    approve_all: bool,
+}
+
+impl Confirmer {
+    /// Reads answers from `input`. With `approve_all`, nothing is read and every command is approved
+    pub fn new(input: impl BufRead + Send + 'static, approve_all: bool) -> Self {
+        Confirmer(Arc::new(Mutex::new(Prompt { input: Box::new(input), approve_all })))
+    }
+
+    /// Reads answers from stdin. Prompts go to stderr, which keeps `--format json` output parseable
+    pub fn stdin(approve_all: bool) -> Self {
+        Confirmer::new(BufReader::new(io::stdin()), approve_all)
+    }
+
+    /// Whether the commands of `key` may run, asking with y/N/all. Anything but an explicit yes,
+    /// including running out of input, declines
+    pub fn confirm(&self, key: &str, setup: Option<&str>, command: &str) -> Result<bool> {
+        let mut prompt = self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if prompt.approve_all {
+            return Ok(true);
+        }
+
+        let mut stderr = io::stderr();
+        if let Some(setup) = setup {
+            let _ = writeln!(stderr, "  Setup for {}: {}", key, setup);
+        }
+        let _ = writeln!(stderr, "  Command for {}: {}", key, command);
+        loop {
+            let _ = write!(stderr, "  Run it? [y/N/all] ");
+            let _ = stderr.flush();
+
+            let mut answer = String::new();
+            if prompt.input.read_line(&mut answer).context("Failed to read the answer")? == 0 {
+                let _ = writeln!(stderr);
+                return Ok(false);
+            }
+            match answer.trim().to_lowercase().as_str() {
+                "y" | "yes" => return Ok(true),
+                "" | "n" | "no" => return Ok(false),
+                "a" | "all" => {
+                    prompt.approve_all = true;
+                    return Ok(true);
+                }
+                _ => {
+                    let _ = writeln!(stderr, "  Please answer y, n or all");
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Confirmer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Confirmer")
+    }
+}
@@ -0,0 +1,45 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+/// Writes `value` in plaintext to `dir/<key>`, readable by the owner only. `dir` is created,
+/// likewise owner-only, when missing
+pub fn emit_value(dir: &Path, key: &str, value: &str) -> Result<()> {
+    if key.is_empty() || key == "." || key == ".." || key.contains(['/', '\\']) {
+        return Err(anyhow!("Key '{}' can't be used as a file name", key));
+    }
+
+    create_private_dir(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let path = dir.join(key);
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&path).with_context(|| format!("Failed to create {}", path.display()))?;
+
+    // The mode above only applies to new files
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("Failed to restrict permissions of {}", path.display()))?;
+    }
+
+    file.write_all(value.as_bytes()).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(dir)
+}
@@ -0,0 +1,97 @@
+use std::path::Path;
+
+/// sops file formats that sops-shell knows how to read keys and values from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FileFormat {
+    Yaml,
+    Json,
+    Env,
+    Ini,
+}
+
+impl FileFormat {
+    /// Detects the format from the file extension, the same way sops does
+    pub fn from_path(filepath: &Path) -> Option<Self> {
+        match filepath.extension().and_then(|ext| ext.to_str())? {
+            "yaml" | "yml" => Some(FileFormat::Yaml),
+            "json" => Some(FileFormat::Json),
+            "env" => Some(FileFormat::Env),
+            "ini" => Some(FileFormat::Ini),
+            _ => None,
+        }
+    }
+
+    /// A forced format wins over detection
+    pub fn resolve(filepath: &Path, forced: Option<Self>) -> Option<Self> {
+        forced.or_else(|| Self::from_path(filepath))
+    }
+
+    /// Name of the format for sops' `--input-type`/`--output-type`
+    pub fn sops_type(self) -> &'static str {
+        match self {
+            FileFormat::Yaml => "yaml",
+            FileFormat::Json => "json",
+            FileFormat::Env => "dotenv",
+            FileFormat::Ini => "ini",
+        }
+    }
+
+    /// Characters separating a key from its value
+    pub fn key_separators(format: Option<Self>) -> &'static [char] {
+        match format {
+            Some(FileFormat::Yaml) | Some(FileFormat::Json) => &[':'],
+            Some(FileFormat::Env) | Some(FileFormat::Ini) => &['='],
+            None => &['=', ':'],
+        }
+    }
+
+    /// Whether keys of the format are conventionally case-insensitive, e.g. `api_key` and `API_KEY`
+    pub fn case_insensitive_keys(format: Option<Self>) -> bool {
+        matches!(format, Some(FileFormat::Env) | Some(FileFormat::Ini))
+    }
+
+    /// Whether a key defined more than once has its last value, the way env loaders read dotenv files.
+    /// Elsewhere the first definition is read, e.g. a top-level YAML key and not a nested one after it
+    pub fn last_definition_wins(format: Option<Self>) -> bool {
+        format == Some(FileFormat::Env)
+    }
+
+    /// Strips a trailing comment from a value as written after its key, e.g. `bar # note` in
+    /// `FOO=bar # note`. A comment marker only counts after whitespace and outside a quoted value,
+    /// so `pass#word` and `"a # b"` stay whole. JSON has no comments
+    pub fn strip_inline_comment(value: &str, format: Option<Self>) -> &str {
+        let markers: &[char] = match format {
+            Some(FileFormat::Json) => return value,
+            Some(FileFormat::Ini) => &['#', ';'],
+            _ => &['#'],
+        };
+        let is_comment = |rest: &str| rest.is_empty() || rest.starts_with(markers);
+
+        let trimmed = value.trim_start();
+        if let Some(quote) = trimmed.chars().next().filter(|ch| matches!(ch, '"' | '\'')) {
+            if let Some(end) = trimmed[1..].find(quote) {
+                let (quoted, rest) = trimmed.split_at(end + 2);
+                if is_comment(rest.trim_start()) {
+                    return quoted;
+                }
+            }
+        }
+
+        let mut previous = None;
+        for (idx, ch) in value.char_indices() {
+            if markers.contains(&ch) && previous.is_some_and(char::is_whitespace) {
+                return value[..idx].trim_end();
+            }
+            previous = Some(ch);
+        }
+        value
+    }
+
+    /// Strips the shell `export ` prefix dotenv files allow in front of a key
+    pub fn strip_export(line: &str, format: Option<Self>) -> &str {
+        match format {
+            Some(FileFormat::Env) | None => line.strip_prefix("export ").map_or(line, str::trim_start),
+            _ => line,
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::runner::CommandRunner;
+
+/// Fails when git reports uncommitted changes to `filepath`. Files outside a git repository,
+/// never committed, or without git installed, are treated as clean
+pub fn ensure_clean(runner: &dyn CommandRunner, filepath: &Path) -> Result<()> {
+    let dir = match filepath.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let Some(name) = filepath.file_name() else {
+        return Ok(());
+    };
+
+    let dir = dir.to_string_lossy();
+    let name = name.to_string_lossy();
+    // An untracked file has no committed version for a sync to clobber
+    let args = ["-C", &dir, "status", "--porcelain", "--untracked-files=no", "--", &name];
+    let Ok(output) = runner.run("git", &args) else {
+        return Ok(());
+    };
+
+    // Most likely not a repository, which is nothing to protect
+    if !output.success() {
+        return Ok(());
+    }
+
+    let status = String::from_utf8_lossy(&output.stdout);
+    if status.trim().is_empty() {
+        return Ok(());
+    }
+
+    Err(anyhow!(
+        "{} has uncommitted changes ({}), commit or stash them first (--require-clean-git)",
+        filepath.display(),
+        status.trim()
+    ))
+}
+
+/// Files changed since `reference` according to `git diff --name-only`, committed or not, as
+/// absolute paths. `None` outside a git repository, or without git installed
+pub fn changed_since(runner: &dyn CommandRunner, reference: &str) -> Result<Option<HashSet<PathBuf>>> {
+    // Would be taken for an option of git diff
+    if reference.starts_with('-') {
+        return Err(anyhow!("Invalid git reference '{}'", reference));
+    }
+
+    let Ok(output) = runner.run("git", &["rev-parse", "--show-toplevel"]) else {
+        return Ok(None);
+    };
+    if !output.success() {
+        return Ok(None);
+    }
+    let toplevel = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+    let toplevel = std::fs::canonicalize(&toplevel).unwrap_or(toplevel);
+
+    // NUL separated, so unusual names aren't quoted
+    let output = runner.run("git", &["diff", "--name-only", "-z", reference, "--"]).context("Failed to run git diff")?;
+    if !output.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow!("git diff against '{}' failed: {}", reference, stderr.trim()));
+    }
+
+    let names = String::from_utf8_lossy(&output.stdout);
+    Ok(Some(names.split('\0').filter(|name| !name.is_empty()).map(|name| toplevel.join(name)).collect()))
+}
+
+/// The `files` among `changed`, in their order. Paths are compared once resolved, so any
+/// spelling of a file matches
+pub fn filter_changed(files: Vec<PathBuf>, changed: &HashSet<PathBuf>) -> Vec<PathBuf> {
+    files
+        .into_iter()
+        .filter(|file| std::fs::canonicalize(file).is_ok_and(|canonical| changed.contains(&canonical)))
+        .collect()
+}
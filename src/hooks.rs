@@ -0,0 +1,20 @@
+use anyhow::{anyhow, Context, Result};
+use std::process::{Command, Stdio};
+
+/// Runs a user supplied hook through `sh -c` with the given extra environment variables
+pub fn run_hook(command: &str, envs: &[(&str, String)]) -> Result<()> {
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(envs.iter().map(|(name, value)| (name, value)))
+        // Keep stdout free for sops-shell's own (possibly machine readable) output
+        .stdout(Stdio::from(std::io::stderr()))
+        .status()
+        .with_context(|| format!("Failed to execute hook '{}'", command))?;
+
+    if !status.success() {
+        return Err(anyhow!("Hook '{}' exited with {}", command, status));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,92 @@
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+
+/// A small subset of jq paths: `.field`, `.["quoted field"]`, `[index]` and chains of
+/// these such as `.data.uris[0].uri`. A lone `.` selects the whole document
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonPath {
+    raw: String,
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Field(String),
+    Index(usize),
+}
+
+impl JsonPath {
+    pub fn parse(raw: &str) -> Result<Self> {
+        let raw = raw.trim();
+        if !raw.starts_with('.') && !raw.starts_with('[') {
+            return Err(anyhow!("JSON path '{}' must start with '.' or '['", raw));
+        }
+
+        let chars: Vec<char> = raw.chars().collect();
+        let mut segments = Vec::new();
+        let mut i = 0;
+
+        while i < chars.len() {
+            match chars[i] {
+                '.' => {
+                    i += 1;
+                    let start = i;
+                    while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '-') {
+                        i += 1;
+                    }
+                    if i > start {
+                        segments.push(Segment::Field(chars[start..i].iter().collect()));
+                    } else if i < chars.len() && chars[i] != '[' {
+                        return Err(anyhow!("Unexpected '{}' in JSON path '{}'", chars[i], raw));
+                    }
+                }
+                '[' => {
+                    let end = chars[i..]
+                        .iter()
+                        .position(|&c| c == ']')
+                        .map(|offset| i + offset)
+                        .ok_or_else(|| anyhow!("Unclosed '[' in JSON path '{}'", raw))?;
+                    let inner: String = chars[i + 1..end].iter().collect();
+                    let inner = inner.trim();
+                    let segment = if let Some(field) = inner.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+                        Segment::Field(field.to_string())
+                    } else {
+                        Segment::Index(
+                            inner
+                                .parse()
+                                .with_context(|| format!("Invalid index '{}' in JSON path '{}'", inner, raw))?,
+                        )
+                    };
+                    segments.push(segment);
+                    i = end + 1;
+                }
+                c => return Err(anyhow!("Unexpected '{}' in JSON path '{}'", c, raw)),
+            }
+        }
+
+        Ok(JsonPath {
+            raw: raw.to_string(),
+            segments,
+        })
+    }
+
+    /// Parses `output` as JSON and returns the selected value. Strings are returned without
+    /// quotes like `jq -r` does, anything else as compact JSON
+    pub fn extract(&self, output: &str) -> Result<String> {
+        let document: Value = serde_json::from_str(output).context("Command output is not valid JSON")?;
+
+        let mut current = &document;
+        for segment in &self.segments {
+            let next = match segment {
+                Segment::Field(field) => current.get(field.as_str()),
+                Segment::Index(index) => current.get(*index),
+            };
+            current = next.ok_or_else(|| anyhow!("JSON path '{}' not found in command output", self.raw))?;
+        }
+
+        Ok(match current {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        })
+    }
+}
@@ -0,0 +1,52 @@
+//! `keyring://<service>/<account>` directives, read from a secret store such as the OS keyring
+//! instead of running a command. No store is bundled: library users plug one in through
+//! `SyncOptions::keyring`, e.g. a thin wrapper around the `keyring` crate
+
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::sync::Arc;
+
+pub const SCHEME: &str = "keyring://";
+
+/// A secret store that `keyring://` directives read from
+pub trait Keyring: Send + Sync {
+    /// The secret stored for `account` under `service`, `None` if there is none
+    fn get(&self, service: &str, account: &str) -> Result<Option<String>>;
+}
+
+/// Shared handle to a `Keyring`
+#[derive(Clone)]
+pub struct KeyringStore(Arc<dyn Keyring>);
+
+impl KeyringStore {
+    pub fn new(keyring: impl Keyring + 'static) -> Self {
+        KeyringStore(Arc::new(keyring))
+    }
+}
+
+impl fmt::Debug for KeyringStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KeyringStore")
+    }
+}
+
+/// Service and account of a `keyring://` directive command, `None` for any other command
+pub fn parse_reference(command: &str) -> Option<Result<(&str, &str)>> {
+    let reference = command.trim().strip_prefix(SCHEME)?;
+    Some(match reference.split_once('/') {
+        Some((service, account)) if !service.is_empty() && !account.is_empty() => Ok((service, account)),
+        _ => Err(anyhow!("Invalid keyring reference '{}', expected keyring://<service>/<account>", command.trim())),
+    })
+}
+
+/// Reads the secret `command` refers to from `store`, `None` if `command` isn't a `keyring://` reference
+pub fn read_reference(store: Option<&KeyringStore>, command: &str) -> Option<Result<String>> {
+    let reference = parse_reference(command)?;
+    Some(reference.and_then(|(service, account)| {
+        let store = store.ok_or_else(|| anyhow!("No keyring configured to read {}{}/{}", SCHEME, service, account))?;
+        store
+            .0
+            .get(service, account)?
+            .ok_or_else(|| anyhow!("No secret for account '{}' of service '{}' in the keyring", account, service))
+    }))
+}
@@ -0,0 +1,40 @@
+pub mod audit;
+pub mod condition;
+pub mod confirm;
+pub mod config;
+pub mod emit;
+pub mod format;
+pub mod git;
+pub mod hash;
+pub mod hooks;
+pub mod json_path;
+#[cfg(feature = "keyring")]
+pub mod keyring;
+pub mod lint;
+pub mod list;
+pub mod lock;
+pub mod metrics;
+pub mod output;
+pub mod parser;
+pub mod paths;
+pub mod plaintext;
+pub mod plan;
+pub mod policy;
+pub mod profile;
+pub mod reencrypt;
+pub mod render;
+pub mod report;
+pub mod runner;
+pub mod sops;
+pub mod sops_config;
+pub mod state;
+pub mod sync;
+pub mod table;
+pub mod template;
+#[cfg(test)]
+mod testing;
+pub mod validate;
+pub mod value_check;
+
+pub use parser::{parse_directives, CommandMapping, DirectiveIssue, ParsedDirective};
+pub use sync::{apply_updates, compute_drift, pending_updates};
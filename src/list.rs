@@ -0,0 +1,106 @@
+//! The `list` subcommand: each file's directives as parsed, without running any command
+
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::fs;
+use std::path::Path;
+
+use crate::parser::{parse_directives, ParseOptions, ParsedDirective};
+use crate::runner::CommandRunner;
+use crate::sops::{sops_decrypt, SopsOptions};
+use crate::sync::has_comment_lines;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ListFormat {
+    /// A line per directive
+    #[default]
+    Text,
+    /// A single JSON array with an object per file, e.g. for editor plugins
+    Json,
+}
+
+/// The directives of `filepath`. Files without comment lines can't have any and aren't decrypted.
+/// When decryption fails, only the directives in comments left in plaintext are found
+pub fn list_file(
+    runner: &dyn CommandRunner,
+    filepath: &Path,
+    options: &ParseOptions,
+    sops: &SopsOptions,
+) -> Result<Vec<ParsedDirective>> {
+    if !has_comment_lines(filepath)? {
+        return Ok(Vec::new());
+    }
+
+    let content = match sops_decrypt(runner, filepath, sops) {
+        Ok(decrypted) => decrypted,
+        Err(e) => {
+            eprintln!(
+                "Could not decrypt {} ({}), listing visible directives only",
+                filepath.display(),
+                e.to_string().trim()
+            );
+            fs::read_to_string(filepath)?
+        }
+    };
+    parse_directives(&content, options)
+}
+
+/// e.g. `{"line": 3, "key": "api_key", "command": "vault read ...", "status": "resolved"}`. Unresolved
+/// directives have a null `key`, the directive line as `command` and the `reason`
+pub fn directive_json(directive: &ParsedDirective) -> Value {
+    match directive {
+        ParsedDirective::Resolved(mapping) => json!({
+            "line": mapping.line,
+            "key": mapping.key,
+            "command": mapping.command,
+            "status": "resolved",
+        }),
+        ParsedDirective::Unresolved(issue) => json!({
+            "line": issue.line,
+            "key": null,
+            "command": issue.snippet,
+            "status": "unresolved",
+            "reason": issue.message,
+        }),
+    }
+}
+
+pub fn list_files(
+    runner: &dyn CommandRunner,
+    files: &[impl AsRef<Path>],
+    options: &ParseOptions,
+    sops: &SopsOptions,
+    format: ListFormat,
+) -> Result<()> {
+    let mut listed = Vec::new();
+    for file in files {
+        let file = file.as_ref();
+        let directives = list_file(runner, file, options, sops)?;
+
+        match format {
+            ListFormat::Text => {
+                println!("{}:", file.display());
+                if directives.is_empty() {
+                    println!("  No directives");
+                }
+                for directive in &directives {
+                    match directive {
+                        ParsedDirective::Resolved(mapping) => {
+                            println!("  Line {}: {} <- {}", mapping.line, mapping.key, mapping.command)
+                        }
+                        ParsedDirective::Unresolved(issue) => println!("  {} (unresolved)", issue),
+                    }
+                }
+            }
+            ListFormat::Json => listed.push(json!({
+                "file": file.display().to_string(),
+                "directives": directives.iter().map(directive_json).collect::<Vec<_>>(),
+            })),
+        }
+    }
+
+    if format == ListFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&listed)?);
+    }
+    Ok(())
+}
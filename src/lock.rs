@@ -0,0 +1,54 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File, TryLockError};
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Takes an advisory lock on `filepath` itself (flock on unix, LockFileEx on windows), released
+/// when the returned file is dropped. Without `wait`, `None` means another process holds it
+pub fn lock_file(filepath: &Path, wait: bool) -> Result<Option<File>> {
+    let file = File::open(filepath).with_context(|| format!("Failed to open {} for locking", filepath.display()))?;
+
+    if wait {
+        file.lock().with_context(|| format!("Failed to lock {}", filepath.display()))?;
+        return Ok(Some(file));
+    }
+
+    match file.try_lock() {
+        Ok(()) => Ok(Some(file)),
+        Err(TryLockError::WouldBlock) => Ok(None),
+        Err(TryLockError::Error(e)) => Err(e).with_context(|| format!("Failed to lock {}", filepath.display())),
+    }
+}
+
+/// A file's modification time, size and content hash, to notice it being changed by someone else
+/// between decrypting it and writing to it. The lock is advisory, so this also catches writers
+/// that don't take it, e.g. an editor or a directive command rewriting the file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileFingerprint {
+    modified: Option<SystemTime>,
+    len: u64,
+    hash: u64,
+}
+
+impl FileFingerprint {
+    pub fn read(filepath: &Path) -> Result<Self> {
+        let content = fs::read(filepath).with_context(|| format!("Failed to read {}", filepath.display()))?;
+        let modified = fs::metadata(filepath).and_then(|metadata| metadata.modified()).ok();
+        let mut hasher = DefaultHasher::new();
+        content.hash(&mut hasher);
+        Ok(FileFingerprint { modified, len: content.len() as u64, hash: hasher.finish() })
+    }
+
+    /// Fails if `filepath` no longer matches this fingerprint
+    pub fn ensure_unchanged(&self, filepath: &Path) -> Result<()> {
+        if FileFingerprint::read(filepath)? != *self {
+            return Err(anyhow!(
+                "file modified during processing, not writing to {} to avoid overwriting those changes",
+                filepath.display()
+            ));
+        }
+        Ok(())
+    }
+}
@@ -1,12 +1,31 @@
-use anyhow::{anyhow, Result};
-use clap::{Parser, Subcommand};
+use anyhow::{anyhow, Context, Result};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use regex::Regex;
+use std::collections::HashSet;
+use std::io::IsTerminal;
 use std::path::PathBuf;
+use std::time::Duration;
 
-mod parser;
-mod sops;
-mod sync;
-
-use sync::{check_files, sync_files};
+use sops_shell::audit::AuditLog;
+use sops_shell::confirm::Confirmer;
+use sops_shell::config::{built_in_settings, print_settings, Setting, SettingSource};
+use sops_shell::format::FileFormat;
+use sops_shell::git::{changed_since, filter_changed};
+use sops_shell::lint::lint_files;
+use sops_shell::list::{list_files, ListFormat};
+use sops_shell::output::OutputFormat;
+use sops_shell::parser::{validate_assign_op, CommandSettings, DirectivePosition, KeyGlob, ParseOptions};
+use sops_shell::paths::expand_path;
+use sops_shell::policy::CommandPolicy;
+use sops_shell::profile::{Profile, DEFAULT_CONFIG_FILE};
+use sops_shell::reencrypt::reencrypt_files;
+use sops_shell::report::ReportTarget;
+use sops_shell::runner::{CommandEnv, Runner, SystemRunner, DEFAULT_MAX_OUTPUT_BYTES};
+use sops_shell::sops::{LineEnding, RetryHook, SopsOptions};
+use sops_shell::sops_config::discover_files;
+use sops_shell::sync::{check_files, create_from_template, sync_files, CompareMode, SyncOptions, TimeoutExceeded, DEFAULT_STREAM_ABOVE_BYTES};
+use sops_shell::validate::validate_files;
 
 #[derive(Parser)]
 #[command(name = "sops-shell")]
@@ -15,41 +34,823 @@ use sync::{check_files, sync_files};
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    #[arg(long, global = true, help = "Process files in sorted path order instead of the order given")]
+    sort_files: bool,
+    #[arg(
+        long,
+        global = true,
+        help = "Never write anything: sync only reports what it would update, like check (also set by SOPS_SHELL_DRY_RUN=1)"
+    )]
+    dry_run: bool,
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = ".sops.yaml",
+        help = "Also process every file matching a path_regex of the creation rules in PATH [default: .sops.yaml]"
+    )]
+    from_sops_config: Option<PathBuf>,
+    #[arg(
+        long,
+        global = true,
+        value_name = "REF",
+        help = "Only process files that git diff --name-only REF lists as changed, e.g. in CI against the base branch"
+    )]
+    since_commit: Option<String>,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     Sync {
-        #[arg(required = true, help = "SOPS encrypted files to sync")]
+        #[arg(help = "SOPS encrypted files to sync")]
         files: Vec<PathBuf>,
+        #[command(flatten)]
+        run: RunArgs,
+        #[arg(long, requires = "template", help = "Create files that don't exist yet from --template, encrypted with sops' creation rules")]
+        create: bool,
+        #[arg(long, value_name = "PATH", requires = "create", help = "Plaintext file of keys and their directives used by --create")]
+        template: Option<PathBuf>,
+        #[arg(long, conflicts_with = "create", help = "Report out of sync secrets like a sync would, but never update them")]
+        detect_only: bool,
+        #[arg(long, conflicts_with = "detect_only", help = "Rewrite each file through sops after syncing so its layout is consistent, values are never changed")]
+        normalize: bool,
+        #[arg(
+            long,
+            value_name = "FILE",
+            conflicts_with_all = ["detect_only", "output_suffix"],
+            help = "Also write updated values to FILE, in its own format, e.g. a .env kept next to a .yaml (repeatable)"
+        )]
+        also_write: Vec<PathBuf>,
+        #[arg(
+            long,
+            conflicts_with_all = ["detect_only", "output_suffix", "also_write", "normalize"],
+            help = "List out of sync secrets and open each file with any in sops' editor to update them by hand"
+        )]
+        interactive_edit: bool,
     },
     Check {
-        #[arg(required = true, help = "SOPS encrypted files to check")]
+        #[arg(help = "SOPS encrypted files to check")]
+        files: Vec<PathBuf>,
+        #[command(flatten)]
+        run: RunArgs,
+        #[arg(long, help = "Describe what a sync would do to each key in a sentence instead of status lines")]
+        explain: bool,
+        #[arg(long, help = "Exit like grep: 0 when everything is in sync, 1 when something is out of sync, 2 on errors")]
+        exit_code: bool,
+        #[arg(long, value_name = "PATH", help = "Write the updates a sync would apply to PATH as JSON, with values masked")]
+        plan_out: Option<PathBuf>,
+        #[arg(long, help = "Print each file as a sync would leave it, with every value masked")]
+        render: bool,
+    },
+    /// Print the settings a sync or check with the same flags would use, and where each came from
+    Config {
+        #[command(flatten)]
+        run: RunArgs,
+    },
+    /// Run every directive's command and report whether it succeeded, without comparing or writing values
+    #[command(name = "validate-commands")]
+    Validate {
+        #[arg(help = "SOPS encrypted files whose commands to run")]
+        files: Vec<PathBuf>,
+        #[command(flatten)]
+        run: RunArgs,
+    },
+    /// Rotate each file's data key with `sops --rotate --in-place`, without changing any value
+    Reencrypt {
+        #[arg(help = "SOPS encrypted files whose data key to rotate")]
         files: Vec<PathBuf>,
+        #[command(flatten)]
+        run: RunArgs,
+        #[arg(long, help = "Sync values from their directives first, then rotate the data key of the synced files")]
+        sync: bool,
     },
+    Lint {
+        #[arg(help = "SOPS encrypted files to lint")]
+        files: Vec<PathBuf>,
+        #[arg(long, value_enum, default_value_t = DirectivePosition::Before, help = "Whether directives are written before or after their key")]
+        directive_position: DirectivePosition,
+        #[arg(
+            long = "assign-op",
+            value_name = "OP",
+            value_parser = validate_assign_op,
+            help = "Also accept OP between a key and its value, e.g. ':=' (repeatable)"
+        )]
+        assign_ops: Vec<String>,
+        #[command(flatten)]
+        sops: SopsArgs,
+    },
+    /// Print each file's directives and the keys they resolve to, without running any command
+    List {
+        #[arg(help = "SOPS encrypted files whose directives to list")]
+        files: Vec<PathBuf>,
+        #[arg(long, value_enum, default_value_t = ListFormat::Text, help = "Output format")]
+        format: ListFormat,
+        #[arg(long, value_name = "NAME", help = "Use directives tagged with this environment, e.g. '# shell[prod]: ...'")]
+        env_profile: Option<String>,
+        #[arg(long, value_enum, default_value_t = DirectivePosition::Before, help = "Whether directives are written before or after their key")]
+        directive_position: DirectivePosition,
+        #[arg(
+            long = "assign-op",
+            value_name = "OP",
+            value_parser = validate_assign_op,
+            help = "Also accept OP between a key and its value, e.g. ':=' (repeatable)"
+        )]
+        assign_ops: Vec<String>,
+        #[command(flatten)]
+        sops: SopsArgs,
+    },
+}
+
+#[derive(Args)]
+struct RunArgs {
+    #[arg(long, value_name = "NAME", help = "Apply the settings of this profile from --config-file, flags given still win")]
+    profile: Option<String>,
+    #[arg(long, value_name = "PATH", default_value = DEFAULT_CONFIG_FILE, help = "JSON file --profile looks up profiles in")]
+    config_file: PathBuf,
+    #[arg(long, value_enum, default_value_t = CompareMode::Exact, help = "How stored values are compared with command output")]
+    compare: CompareMode,
+    #[arg(long, value_name = "NAME", help = "Use directives tagged with this environment, e.g. '# shell[prod]: ...'")]
+    env_profile: Option<String>,
+    #[arg(long, value_enum, default_value_t = DirectivePosition::Before, help = "Whether directives are written before or after their key")]
+    directive_position: DirectivePosition,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text, help = "Output format")]
+    format: OutputFormat,
+    #[command(flatten)]
+    sops: SopsArgs,
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        help = "Command to run before processing, with SOPS_SHELL_FILES listing the files; a non-zero exit aborts the run"
+    )]
+    pre_sync: Option<String>,
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        help = "Command to run after each file with updates is synced, with SOPS_SHELL_FILE and SOPS_SHELL_UPDATES set (never run by check)"
+    )]
+    post_sync: Option<String>,
+    #[arg(long, value_name = "N", help = "Stop after executing N shell commands across all files")]
+    max_commands: Option<usize>,
+    #[arg(long = "allow-binary", value_name = "BINARY", help = "Only allow commands invoking these binaries (repeatable)")]
+    allow_binaries: Vec<String>,
+    #[arg(long = "deny-binary", value_name = "BINARY", help = "Refuse commands invoking these binaries (repeatable)")]
+    deny_binaries: Vec<String>,
+    #[arg(long, help = "Refuse commands that reference the file being synced, and duplicated env keys, instead of warning")]
+    strict: bool,
+    #[arg(long, value_name = "SUFFIX", help = "Write updates to a copy named <file><SUFFIX> instead of in place")]
+    output_suffix: Option<String>,
+    #[arg(long, help = "Only print the final summary, errors are included there as a count")]
+    summary_only: bool,
+    #[arg(
+        long,
+        help = "Skip keys that already have a value and whose command is unchanged since the last run (assumes deterministic commands)"
+    )]
+    only_changed_commands: bool,
+    #[arg(
+        long,
+        value_name = "PATH",
+        default_value = ".sops-shell-state.json",
+        help = "Where --only-changed-commands records the command of each key"
+    )]
+    state_file: PathBuf,
+    #[arg(long, help = "Show when a sync last updated each key, as recorded in the state file by --only-changed-commands")]
+    show_last_synced: bool,
+    #[arg(long, help = "Update the target of symlinked files instead of the link itself")]
+    follow_symlinks: bool,
+    #[arg(long, help = "Refuse to update files with uncommitted changes in git")]
+    require_clean_git: bool,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Stop the run once SECONDS have passed, after the command or write in progress, and exit with 124"
+    )]
+    timeout_total: Option<u64>,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Stop processing a file once SECONDS have passed on it, after the command or write in progress, and move on"
+    )]
+    timeout_per_file: Option<u64>,
+    #[arg(long, help = "Fail the run if any directive can't be matched to a key, instead of warning")]
+    require_all_directives: bool,
+    #[arg(long, help = "Abort the run if a file decrypts to empty content, which usually means sops is misconfigured")]
+    abort_on_empty_file: bool,
+    #[arg(long, help = "List keys that no directive covers, to audit which secrets are managed")]
+    report_orphans: bool,
+    #[arg(
+        long,
+        value_enum,
+        value_name = "WHEN",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "auto",
+        help = "Show each file's keys as a Key | Status | Command | Duration table, only on a terminal unless --table=always"
+    )]
+    table: Option<TableMode>,
+    #[arg(long = "skip-key", value_name = "KEY", help = "Don't run the directive of this key (repeatable)")]
+    skip_keys: Vec<String>,
+    #[arg(
+        long,
+        value_name = "PATTERN",
+        value_parser = Regex::new,
+        help = "Only run the directives of keys matching this regex, e.g. '^db_' (combined with --skip-key, both must allow a key)"
+    )]
+    name_regex: Option<Regex>,
+    #[arg(
+        long = "exclude-key",
+        value_name = "PATTERN",
+        value_parser = |pattern: &str| KeyGlob::new(pattern),
+        help = "Don't run the directives of keys matching this glob, e.g. 'legacy_*' (repeatable, wins over --name-regex)"
+    )]
+    exclude_keys: Vec<KeyGlob>,
+    #[arg(
+        long = "assign-op",
+        value_name = "OP",
+        value_parser = validate_assign_op,
+        help = "Also accept OP between a key and its value, e.g. ':=' (repeatable)"
+    )]
+    assign_ops: Vec<String>,
+    #[arg(long, value_name = "SECONDS", help = "Fail commands running longer than this, overriding the file's shell-defaults")]
+    command_timeout: Option<u64>,
+    #[arg(long, value_name = "N", help = "Retry failing commands N times, overriding the file's shell-defaults")]
+    command_retries: Option<u32>,
+    #[arg(long, help = "Retry commands with empty output like failing ones, for commands with retries, and fail them if it stays empty")]
+    retry_on_empty: bool,
+    #[arg(long, value_enum, help = "Convert the newlines of multi-line values written to this line ending [default: keep as is]")]
+    line_ending: Option<LineEnding>,
+    #[arg(long, help = "Match keys case-insensitively in env and ini files, e.g. api_key against API_KEY")]
+    ignore_key_case: bool,
+    #[arg(long, help = "Keep leading and trailing whitespace of command output, e.g. a trailing newline, instead of trimming it")]
+    no_trim: bool,
+    #[arg(long, help = "Don't lock files while syncing them, e.g. on filesystems without lock support")]
+    no_lock: bool,
+    #[arg(long, conflicts_with = "no_lock", help = "Wait for files locked by another run instead of skipping them")]
+    lock_wait: bool,
+    #[arg(long, value_name = "DIR", help = "Also write each computed value in plaintext to DIR/<KEY>, readable by the owner only")]
+    emit_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "MODE",
+        value_parser = parse_mode,
+        help = "Set written files to this octal mode, e.g. 600, instead of keeping the original file's permissions"
+    )]
+    chmod: Option<u32>,
+    #[arg(long, value_name = "PATH", help = "Write run totals to PATH in the Prometheus textfile collector format")]
+    metrics_file: Option<PathBuf>,
+    #[arg(long, value_name = "FORMAT:PATH", help = "Write the outcome of every key to PATH, e.g. junit:report.xml for CI test dashboards")]
+    report: Option<ReportTarget>,
+    #[arg(long, value_name = "PATH", help = "Append a line per value written to PATH, with the values masked")]
+    audit_log: Option<PathBuf>,
+    #[arg(long, requires = "audit_log", help = "Leave directive commands out of the --audit-log lines")]
+    audit_redact_commands: bool,
+    #[arg(
+        long = "env",
+        value_name = "NAME=VALUE",
+        value_parser = parse_env_var,
+        help = "Set an environment variable for directive commands (repeatable)"
+    )]
+    env_vars: Vec<(String, String)>,
+    #[arg(
+        long,
+        value_name = "PREFIX",
+        allow_hyphen_values = true,
+        help = "Write PREFIX in front of every directive command, e.g. 'aws-vault exec prod --' (joined with a space, unquoted)"
+    )]
+    command_prefix: Option<String>,
+    #[arg(
+        long,
+        value_name = "SUFFIX",
+        allow_hyphen_values = true,
+        help = "Write SUFFIX after every directive command, e.g. '2>/dev/null' (joined with a space, unquoted)"
+    )]
+    command_suffix: Option<String>,
+    #[arg(long, help = "Run directive commands with only the --env variables and a minimal PATH instead of inheriting the environment")]
+    no_inherit_env: bool,
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "Run directive commands in a clean environment like --no-inherit-env, but forward NAME from this one (repeatable)"
+    )]
+    pass_env: Vec<String>,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = DEFAULT_MAX_OUTPUT_BYTES,
+        help = "Fail directive commands whose stdout or stderr exceeds BYTES, stopping them early (0 disables)"
+    )]
+    max_output_bytes: usize,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = DEFAULT_STREAM_ABOVE_BYTES,
+        help = "Decrypt files larger than BYTES a line at a time, keeping only the values being synced in memory (0 disables)"
+    )]
+    stream_above_bytes: u64,
+    #[arg(
+        long,
+        visible_alias = "confirm-each",
+        help = "Show each directive's command and ask y/N/all before running it, e.g. for files from an untrusted source"
+    )]
+    paranoid: bool,
+    #[arg(long, requires = "paranoid", help = "Approve every command of --paranoid without asking")]
+    yes: bool,
+}
+
+/// When `--table` replaces the per-key lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum TableMode {
+    /// Only when stdout is a terminal, so piped output keeps its usual lines
+    Auto,
+    Always,
+}
+
+#[derive(Args)]
+struct SopsArgs {
+    #[arg(
+        long = "sops-arg",
+        value_name = "ARG",
+        allow_hyphen_values = true,
+        help = "Extra argument passed verbatim to every sops invocation (repeatable)"
+    )]
+    sops_args: Vec<String>,
+    #[arg(long, value_enum, value_name = "FORMAT", help = "Treat every file as this format instead of detecting it from the extension")]
+    format_as: Option<FileFormat>,
+    #[arg(long, value_name = "N", default_value_t = 0, help = "Retry sops decrypt/set up to N times on throttling or timeout errors")]
+    sops_retries: u32,
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = 500,
+        help = "Delay before the first sops retry in milliseconds, doubled on each further attempt"
+    )]
+    sops_retry_delay_ms: u64,
+    #[arg(long, help = "Show sops prompts, e.g. for MFA or a hardware key touch, instead of capturing its stderr")]
+    interactive: bool,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 0,
+        help = "Fail sops invocations that take longer than this, as sops is likely waiting on a prompt (0, the default, never times out)"
+    )]
+    sops_prompt_timeout: u64,
+}
+
+impl SopsArgs {
+    fn into_options(self) -> SopsOptions {
+        SopsOptions {
+            extra_args: self.sops_args,
+            format_as: self.format_as,
+            retries: self.sops_retries,
+            retry_delay: Duration::from_millis(self.sops_retry_delay_ms),
+            interactive: self.interactive,
+            prompt_timeout: (self.sops_prompt_timeout > 0).then(|| Duration::from_secs(self.sops_prompt_timeout)),
+            line_ending: None,
+            cache: None,
+            on_retry: Some(RetryHook::new(|notice| eprintln!("{}", notice))),
+        }
+    }
+}
+
+impl RunArgs {
+    fn load_profile(&self) -> Result<Option<Profile>> {
+        self.profile.as_ref().map(|name| Profile::load(&self.config_file, name)).transpose()
+    }
+
+    fn into_options(self) -> Result<SyncOptions> {
+        let profile = self.load_profile()?;
+        Ok(self.into_options_with(profile.as_ref()))
+    }
+
+    /// Flags given win over `profile`: its env profile only applies without `--env-profile`, and its
+    /// sops arguments and variables go ahead of `--sops-arg` and `--env`
+    fn into_options_with(mut self, profile: Option<&Profile>) -> SyncOptions {
+        if let Some(profile) = profile {
+            self.env_profile = self.env_profile.or_else(|| profile.env_profile.clone());
+            self.sops.sops_args = profile.sops_args().into_iter().chain(self.sops.sops_args).collect();
+            self.env_vars = profile.env.iter().cloned().chain(self.env_vars).collect();
+        }
+        SyncOptions {
+            compare_mode: self.compare,
+            parse: ParseOptions {
+                env_profile: self.env_profile,
+                directive_position: self.directive_position,
+                format: self.sops.format_as,
+                skip_keys: self.skip_keys,
+                name_regex: self.name_regex,
+                exclude_keys: self.exclude_keys,
+                assign_ops: self.assign_ops,
+                command_settings: CommandSettings {
+                    timeout: self.command_timeout.map(Duration::from_secs),
+                    retries: self.command_retries,
+                },
+            },
+            format: self.format,
+            sops: SopsOptions { line_ending: self.line_ending, ..self.sops.into_options() },
+            pre_sync: self.pre_sync,
+            post_sync: self.post_sync,
+            max_commands: self.max_commands,
+            policy: CommandPolicy {
+                allow: self.allow_binaries,
+                deny: self.deny_binaries,
+            },
+            strict: self.strict,
+            output_suffix: self.output_suffix,
+            summary_only: self.summary_only,
+            runner: Runner::default(),
+            last_synced: self.show_last_synced.then(|| self.state_file.clone()),
+            command_state: self.only_changed_commands.then_some(self.state_file),
+            explain: false,
+            render: false,
+            plan_out: None,
+            follow_symlinks: self.follow_symlinks,
+            require_clean_git: self.require_clean_git,
+            timeout_total: self.timeout_total.map(Duration::from_secs),
+            timeout_per_file: self.timeout_per_file.map(Duration::from_secs),
+            retry_on_empty: self.retry_on_empty,
+            require_all_directives: self.require_all_directives,
+            abort_on_empty_file: self.abort_on_empty_file,
+            report_orphans: self.report_orphans,
+            table: match self.table {
+                Some(TableMode::Always) => true,
+                Some(TableMode::Auto) => std::io::stdout().is_terminal(),
+                None => false,
+            },
+            ignore_key_case: self.ignore_key_case,
+            no_trim: self.no_trim,
+            stream_above: (self.stream_above_bytes > 0).then_some(self.stream_above_bytes),
+            no_lock: self.no_lock,
+            lock_wait: self.lock_wait,
+            emit_dir: self.emit_dir,
+            chmod: self.chmod,
+            detect_only: false,
+            normalize: false,
+            also_write: Vec::new(),
+            interactive_edit: false,
+            metrics_file: self.metrics_file,
+            report: self.report,
+            audit_log: self.audit_log.map(|path| AuditLog {
+                path,
+                redact_commands: self.audit_redact_commands,
+            }),
+            #[cfg(feature = "keyring")]
+            keyring: None,
+            command_env: CommandEnv {
+                clear: self.no_inherit_env || !self.pass_env.is_empty(),
+                pass: self.pass_env,
+                vars: self.env_vars,
+                max_output: (self.max_output_bytes > 0).then_some(self.max_output_bytes),
+                prefix: self.command_prefix,
+                suffix: self.command_suffix,
+            },
+            confirm: self.paranoid.then(|| Confirmer::stdin(self.yes)),
+        }
+    }
+}
+
+/// Expands `~` and variables in path arguments, for callers that don't go through a shell, which
+/// would have expanded them already
+fn expand_paths(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    paths.iter().map(|path| expand_path(path)).collect()
+}
+
+fn ensure_files_exist(files: &[PathBuf]) -> Result<()> {
+    for file in files {
+        if !file.exists() {
+            return Err(anyhow!("File not found: {}", file.display()));
+        }
+    }
+    Ok(())
 }
 
+/// How the files of a subcommand are picked, from the global flags
+struct FileSelection {
+    sort: bool,
+    /// Found through `--from-sops-config`, processed after the files given
+    discovered: Vec<PathBuf>,
+    /// Changed files in the repository, from `--since-commit`
+    changed: Option<(String, HashSet<PathBuf>)>,
+}
+
+impl FileSelection {
+    fn resolve(&self, files: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+        self.resolve_expanded(expand_paths(&files)?)
+    }
+
+    /// `resolve` for files that went through `expand_paths` already, so they aren't expanded twice
+    fn resolve_expanded(&self, files: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+        // Not required by clap, which can't see a global flag given before the subcommand
+        if files.is_empty() && self.discovered.is_empty() {
+            return Err(anyhow!("No files given, pass them as arguments or use --from-sops-config"));
+        }
+        let files = resolve_files(files.into_iter().chain(self.discovered.iter().cloned()).collect(), self.sort)?;
+        let Some((reference, changed)) = &self.changed else {
+            return Ok(files);
+        };
+
+        let given = files.len();
+        let files = filter_changed(files, changed);
+        // On stderr, so it can't end up in `--format json` output
+        if files.len() < given {
+            eprintln!("Skipping {} file(s) unchanged since {}", given - files.len(), reference);
+        }
+        Ok(files)
+    }
+}
+
+/// Checks that every file exists and drops repeated ones, including different spellings of the
+/// same path like `./secrets.yaml` and `secrets.yaml`, so no file is processed, or written, twice
+fn resolve_files(files: Vec<PathBuf>, sort: bool) -> Result<Vec<PathBuf>> {
+    ensure_files_exist(&files)?;
+
+    let given = files.len();
+    let mut seen = HashSet::new();
+    let mut resolved = Vec::new();
+    for file in files {
+        let canonical = std::fs::canonicalize(&file).with_context(|| format!("Failed to resolve {}", file.display()))?;
+        if seen.insert(canonical.clone()) {
+            resolved.push((canonical, file));
+        }
+    }
+
+    // On stderr, so it can't end up in `--format json` output
+    if resolved.len() < given {
+        eprintln!("Skipping {} duplicate file argument(s)", given - resolved.len());
+    }
+    if sort {
+        resolved.sort();
+    }
+    Ok(resolved.into_iter().map(|(_, file)| file).collect())
+}
+
+// Octal like chmod(1), with or without a leading `0` or `0o`
+fn parse_mode(raw: &str) -> Result<u32, String> {
+    let digits = raw.strip_prefix("0o").unwrap_or(raw);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("'{}' isn't an octal file mode like 600", raw)),
+    }
+}
+
+fn parse_env_var(raw: &str) -> Result<(String, String), String> {
+    match raw.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err(format!("'{}' isn't of the form NAME=VALUE", raw)),
+    }
+}
+
+// Same as timeout(1), so CI can tell a cut-off run from a failed one
+const TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// Forces dry runs when set to a true value, whatever the flags, e.g. as a safe mode in shared CI images
+const DRY_RUN_ENV: &str = "SOPS_SHELL_DRY_RUN";
+
+// `1`, `true`, `yes` or `on`, in any case. Anything else, e.g. `0`, leaves dry runs to `--dry-run`
+fn dry_run_from_env() -> bool {
+    std::env::var(DRY_RUN_ENV).is_ok_and(|value| matches!(value.trim().to_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+}
+
+// `check --exit-code` follows grep: 0 = in sync, 1 = out of sync, 2 = error
+const DRIFT_EXIT_CODE: i32 = 1;
+const ERROR_EXIT_CODE: i32 = 2;
+
 fn main() -> Result<()> {
-    let cli = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches)?;
+    let grep_exit_codes = matches
+        .subcommand_matches("check")
+        .is_some_and(|check| check.get_flag("exit_code"));
+
+    // Either one enables it, so the variable can't be overridden from the command line
+    let dry_run = cli.dry_run || dry_run_from_env();
+    let discovered = match &cli.from_sops_config {
+        Some(config) => {
+            let files = discover_files(config)?;
+            if files.is_empty() {
+                return Err(anyhow!("No files match the creation rules of {}", config.display()));
+            }
+            files
+        }
+        None => Vec::new(),
+    };
+    let changed = match &cli.since_commit {
+        Some(reference) => {
+            let changed = changed_since(&SystemRunner, reference)?;
+            if changed.is_none() {
+                eprintln!(
+                    "Warning: Not in a git repository, ignoring --since-commit {} and processing every file",
+                    reference
+                );
+            }
+            changed.map(|changed| (reference.clone(), changed))
+        }
+        None => None,
+    };
+    let selection = FileSelection { sort: cli.sort_files, discovered, changed };
+    match run(cli.command, &selection, dry_run, &matches) {
+        Ok(0) => Ok(()),
+        Ok(code) => std::process::exit(code),
+        Err(e) if e.is::<TimeoutExceeded>() => {
+            eprintln!("Error: {}", e);
+            std::process::exit(TIMEOUT_EXIT_CODE);
+        }
+        Err(e) if grep_exit_codes => {
+            eprintln!("Error: {:#}", e);
+            std::process::exit(ERROR_EXIT_CODE);
+        }
+        Err(e) => Err(e),
+    }
+}
 
-    match cli.command {
-        Commands::Sync { files } => {
-            for file in &files {
-                if !file.exists() {
-                    return Err(anyhow!("File not found: {}", file.display()));
+/// Runs the subcommand and returns the process exit code
+fn run(command: Commands, selection: &FileSelection, dry_run: bool, matches: &ArgMatches) -> Result<i32> {
+    match command {
+        Commands::Sync { files, run, create: _, template, detect_only, normalize, also_write, interactive_edit } => {
+            // Expanded before the files are created or checked for, not only once they're resolved
+            let mut files = expand_paths(&files)?;
+            let also_write = expand_paths(&also_write)?;
+            let template = template.as_deref().map(expand_path).transpose()?;
+            ensure_files_exist(&also_write)?;
+            let options = SyncOptions { detect_only, normalize, also_write, interactive_edit, ..run.into_options()? };
+            if let Some(template) = &template {
+                if dry_run {
+                    // On stderr, so it can't end up in `--format json` output
+                    for file in files.iter().filter(|file| !file.exists()) {
+                        eprintln!("Not creating {} from {} (dry run)", file.display(), template.display());
+                    }
+                    files.retain(|file| file.exists());
+                } else {
+                    for file in files.iter().filter(|file| !file.exists()) {
+                        create_from_template(file, template, &options)?;
+                    }
                 }
             }
-            sync_files(&files)?
+            let files = selection.resolve_expanded(files)?;
+            if dry_run {
+                check_files(&files, &options)?;
+            } else {
+                sync_files(&files, &options)?;
+            }
+        },
+        Commands::Check { files, run, explain, exit_code, plan_out, render } => {
+            let files = selection.resolve(files)?;
+            let summary = check_files(&files, &SyncOptions { explain, plan_out, render, ..run.into_options()? })?;
+            if exit_code && summary.errors > 0 {
+                return Ok(ERROR_EXIT_CODE);
+            }
+            if exit_code && summary.updates > 0 {
+                return Ok(DRIFT_EXIT_CODE);
+            }
+        },
+        Commands::Config { run } => {
+            let matches = matches.subcommand_matches("config").expect("config subcommand was parsed");
+            let profile = run.load_profile()?;
+            let options = run.into_options_with(profile.as_ref());
+            print_settings(&effective_settings(&options, profile.as_ref(), dry_run, matches), options.format)
         },
-        Commands::Check { files } => {
-            for file in &files {
-                if !file.exists() {
-                    return Err(anyhow!("File not found: {}", file.display()));
+        Commands::Validate { files, run } => {
+            let files = selection.resolve(files)?;
+            validate_files(&files, &run.into_options()?)?
+        },
+        Commands::Reencrypt { files, run, sync } => {
+            let options = run.into_options()?;
+            let files = selection.resolve(files)?;
+            if dry_run {
+                if sync {
+                    check_files(&files, &options)?;
+                }
+                // On stderr, so it can't end up in `--format json` output
+                for file in &files {
+                    eprintln!("Not rotating the data key of {} (dry run)", file.display());
                 }
+                return Ok(0);
+            }
+            if sync {
+                sync_files(&files, &options)?;
             }
-            check_files(&files)?
+            reencrypt_files(&*options.runner, &files, &options.sops)?
+        },
+        Commands::Lint { files, directive_position, assign_ops, sops } => {
+            let files = selection.resolve(files)?;
+            lint_files(&files, directive_position, &assign_ops, &sops.into_options())?
+        },
+        Commands::List { files, format, env_profile, directive_position, assign_ops, sops } => {
+            let files = selection.resolve(files)?;
+            let options = ParseOptions { env_profile, directive_position, assign_ops, format: sops.format_as, ..Default::default() };
+            list_files(&SystemRunner, &files, &options, &sops.into_options(), format)?
         },
     }
 
-    Ok(())
+    Ok(0)
+}
+
+fn effective_settings(options: &SyncOptions, profile: Option<&Profile>, dry_run: bool, matches: &ArgMatches) -> Vec<Setting> {
+    fn name(value: &impl ValueEnum) -> String {
+        value.to_possible_value().map_or_else(String::new, |value| value.get_name().to_string())
+    }
+    fn optional(value: Option<impl ToString>, unset: &str) -> String {
+        value.map_or_else(|| unset.to_string(), |value| value.to_string())
+    }
+    fn list(values: &[String]) -> String {
+        if values.is_empty() { "(none)".to_string() } else { values.join(", ") }
+    }
+
+    let sops = &options.sops;
+    let command = &options.parse.command_settings;
+    let settings = [
+        ("profile", "profile", optional(profile.map(|profile| &profile.name), "(none)")),
+        ("config file", "config_file", optional(matches.get_one::<PathBuf>("config_file").map(|path| path.display()), "")),
+        ("compare", "compare", name(&options.compare_mode)),
+        ("env profile", "env_profile", optional(options.parse.env_profile.as_ref(), "(none)")),
+        ("directive position", "directive_position", name(&options.parse.directive_position)),
+        ("format", "format", name(&options.format)),
+        ("sops args", "sops_args", list(&sops.extra_args)),
+        ("format as", "format_as", optional(sops.format_as.map(|format| name(&format)), "(from extension)")),
+        ("sops retries", "sops_retries", sops.retries.to_string()),
+        ("sops retry delay", "sops_retry_delay_ms", format!("{}ms", sops.retry_delay.as_millis())),
+        ("interactive", "interactive", sops.interactive.to_string()),
+        ("sops prompt timeout", "sops_prompt_timeout", optional(sops.prompt_timeout.map(|timeout| format!("{}s", timeout.as_secs())), "(none)")),
+        ("pre-sync hook", "pre_sync", optional(options.pre_sync.as_ref(), "(none)")),
+        ("post-sync hook", "post_sync", optional(options.post_sync.as_ref(), "(none)")),
+        ("max commands", "max_commands", optional(options.max_commands, "unlimited")),
+        ("allowed binaries", "allow_binaries", list(&options.policy.allow)),
+        ("denied binaries", "deny_binaries", list(&options.policy.deny)),
+        ("strict", "strict", options.strict.to_string()),
+        ("output suffix", "output_suffix", optional(options.output_suffix.as_ref(), "(in place)")),
+        ("summary only", "summary_only", options.summary_only.to_string()),
+        ("only changed commands", "only_changed_commands", options.command_state.is_some().to_string()),
+        ("show last synced", "show_last_synced", options.last_synced.is_some().to_string()),
+        ("state file", "state_file", optional(matches.get_one::<PathBuf>("state_file").map(|path| path.display()), "")),
+        ("follow symlinks", "follow_symlinks", options.follow_symlinks.to_string()),
+        ("require clean git", "require_clean_git", options.require_clean_git.to_string()),
+        ("timeout total", "timeout_total", optional(options.timeout_total.map(|timeout| format!("{}s", timeout.as_secs())), "(none)")),
+        (
+            "timeout per file",
+            "timeout_per_file",
+            optional(options.timeout_per_file.map(|timeout| format!("{}s", timeout.as_secs())), "(none)"),
+        ),
+        ("require all directives", "require_all_directives", options.require_all_directives.to_string()),
+        ("abort on empty file", "abort_on_empty_file", options.abort_on_empty_file.to_string()),
+        ("report orphans", "report_orphans", options.report_orphans.to_string()),
+        ("table", "table", options.table.to_string()),
+        ("skipped keys", "skip_keys", list(&options.parse.skip_keys)),
+        ("name regex", "name_regex", optional(options.parse.name_regex.as_ref(), "(none)")),
+        (
+            "excluded keys",
+            "exclude_keys",
+            list(&options.parse.exclude_keys.iter().map(|glob| glob.to_string()).collect::<Vec<_>>()),
+        ),
+        ("assignment operators", "assign_ops", list(&options.parse.assign_ops)),
+        ("command timeout", "command_timeout", optional(command.timeout.map(|timeout| format!("{}s", timeout.as_secs())), "(from file)")),
+        ("command retries", "command_retries", optional(command.retries, "(from file)")),
+        ("retry on empty", "retry_on_empty", options.retry_on_empty.to_string()),
+        ("line ending", "line_ending", optional(sops.line_ending.map(|ending| name(&ending)), "(as is)")),
+        ("ignore key case", "ignore_key_case", options.ignore_key_case.to_string()),
+        ("no trim", "no_trim", options.no_trim.to_string()),
+        ("stream above bytes", "stream_above_bytes", optional(options.stream_above, "(never)")),
+        ("no lock", "no_lock", options.no_lock.to_string()),
+        ("lock wait", "lock_wait", options.lock_wait.to_string()),
+        ("emit dir", "emit_dir", optional(options.emit_dir.as_ref().map(|dir| dir.display()), "(none)")),
+        ("chmod", "chmod", optional(options.chmod.map(|mode| format!("{:o}", mode)), "(keep original)")),
+        ("metrics file", "metrics_file", optional(options.metrics_file.as_ref().map(|path| path.display()), "(none)")),
+        ("report", "report", optional(options.report.as_ref().map(|report| report.path.display()), "(none)")),
+        ("audit log", "audit_log", optional(options.audit_log.as_ref().map(|log| log.path.display()), "(none)")),
+        (
+            "audit redact commands",
+            "audit_redact_commands",
+            options.audit_log.as_ref().is_some_and(|log| log.redact_commands).to_string(),
+        ),
+        // Only names, values may well be secrets
+        ("command env", "env_vars", list(&options.command_env.vars.iter().map(|(name, _)| name.clone()).collect::<Vec<_>>())),
+        ("command prefix", "command_prefix", optional(options.command_env.prefix.as_ref(), "(none)")),
+        ("command suffix", "command_suffix", optional(options.command_env.suffix.as_ref(), "(none)")),
+        ("no inherit env", "no_inherit_env", options.command_env.clear.to_string()),
+        ("passed env", "pass_env", list(&options.command_env.pass)),
+        ("max output bytes", "max_output_bytes", optional(options.command_env.max_output, "(unlimited)")),
+        ("paranoid", "paranoid", options.confirm.is_some().to_string()),
+    ];
+
+    let mut effective = built_in_settings();
+    effective.extend(settings.into_iter().map(|(name, id, value)| Setting {
+        name,
+        value,
+        source: match matches.value_source(id) {
+            Some(ValueSource::CommandLine) => SettingSource::Flag,
+            Some(ValueSource::EnvVariable) => SettingSource::Env,
+            _ if profile.is_some_and(|profile| profile.settings().contains(&id)) => SettingSource::Profile,
+            _ => SettingSource::Default,
+        },
+    }));
+    // The variable is read by sops-shell rather than by clap, so clap can't tell where it came from
+    effective.push(Setting {
+        name: "dry run",
+        value: dry_run.to_string(),
+        source: match matches.value_source("dry_run") {
+            Some(ValueSource::CommandLine) => SettingSource::Flag,
+            _ if dry_run => SettingSource::Env,
+            _ => SettingSource::Default,
+        },
+    });
+    effective
 }
@@ -0,0 +1,51 @@
+use anyhow::{Context, Result};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Totals of a run, written in the Prometheus text format for node_exporter's textfile collector
+#[derive(Debug, Default)]
+pub struct RunMetrics {
+    pub dry_run: bool,
+    pub files: usize,
+    pub secrets_checked: usize,
+    /// Secrets found out of sync, which a sync has also updated
+    pub out_of_sync: usize,
+    pub command_failures: usize,
+    pub errors: usize,
+    pub duration: Duration,
+}
+
+impl RunMetrics {
+    pub fn render(&self, timestamp: SystemTime) -> String {
+        let mode = if self.dry_run { "check" } else { "sync" };
+        let timestamp = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let metrics: [(&str, &str, &str, String); 7] = [
+            ("files", "gauge", "Files processed by the last run", self.files.to_string()),
+            ("secrets_checked", "gauge", "Secrets checked by the last run", self.secrets_checked.to_string()),
+            ("secrets_out_of_sync", "gauge", "Secrets found out of sync by the last run", self.out_of_sync.to_string()),
+            ("command_failures", "gauge", "Directive commands that failed in the last run", self.command_failures.to_string()),
+            ("errors", "gauge", "Errors of any kind in the last run", self.errors.to_string()),
+            ("last_run_duration_seconds", "gauge", "Duration of the last run", format!("{:.3}", self.duration.as_secs_f64())),
+            ("last_run_timestamp_seconds", "gauge", "Unix time the last run finished", timestamp.to_string()),
+        ];
+
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(out, "# HELP sops_shell_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE sops_shell_{} {}", name, kind);
+            let _ = writeln!(out, "sops_shell_{}{{mode=\"{}\"}} {}", name, mode, value);
+        }
+        out
+    }
+
+    /// Writes through a temporary file renamed into place, so the collector never reads a partial file
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        fs::write(&temporary, self.render(SystemTime::now()))
+            .with_context(|| format!("Failed to write metrics file {}", path.display()))?;
+        fs::rename(&temporary, path).with_context(|| format!("Failed to write metrics file {}", path.display()))
+    }
+}
@@ -0,0 +1,50 @@
+use serde_json::Value;
+use std::fmt::Display;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable progress output
+    #[default]
+    Text,
+    /// One JSON object per line, emitted as each key and file is processed
+    #[value(alias = "json")]
+    Jsonl,
+}
+
+pub struct Printer {
+    format: OutputFormat,
+    summary_only: bool,
+}
+
+impl Printer {
+    pub fn new(format: OutputFormat) -> Self {
+        Self {
+            format,
+            summary_only: false,
+        }
+    }
+
+    /// Suppresses all text output except for `summary` lines
+    pub fn summary_only(mut self, summary_only: bool) -> Self {
+        self.summary_only = summary_only;
+        self
+    }
+
+    pub fn text(&self, line: impl Display) {
+        if self.format == OutputFormat::Text && !self.summary_only {
+            println!("{}", line);
+        }
+    }
+
+    pub fn summary(&self, line: impl Display) {
+        if self.format == OutputFormat::Text {
+            println!("{}", line);
+        }
+    }
+
+    pub fn event(&self, event: Value) {
+        if self.format == OutputFormat::Jsonl {
+            println!("{}", event);
+        }
+    }
+}
@@ -1,68 +1,996 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use regex::Regex;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::condition::Condition;
+use crate::format::FileFormat;
+use crate::json_path::JsonPath;
+use crate::template::Template;
+use crate::value_check::ValueCheck;
 
 #[derive(Debug, Clone)]
 pub struct CommandMapping {
+    /// 1-based line number of the directive
+    pub line: usize,
     pub key: String,
     pub command: String,
+    /// Run before `command` with its output ignored, e.g. to log in. Set by block directives
+    pub setup: Option<String>,
+    pub skip_if: Option<Regex>,
+    pub value_from: ValueSource,
+    /// Which output stream the value is read from
+    pub capture: Capture,
+    /// Field to extract from JSON command output, e.g. `.SecretString`
+    pub json_path: Option<JsonPath>,
+    /// Regex applied to the output, after `json_path`, whose first capture group is the value
+    pub extract: Option<Regex>,
+    /// Write the value with a single trailing newline, e.g. for PEM keys, and compare it exactly
+    pub keep_trailing_newline: bool,
+    /// Detect drift by comparing against a salted hash kept in a companion entry
+    pub compare_hash: bool,
+    /// Companion key kept set to the SHA-256 of the value, for systems storing a secret with its checksum
+    pub also_sha256: Option<String>,
+    /// Program deciding whether the stored and new values match by its exit status, instead of
+    /// comparing them byte for byte
+    pub compare_cmd: Option<String>,
+    /// Length and charset the value has to meet, or the key fails instead of being written
+    pub checks: ValueCheck,
+    /// Run the command as this user, through sudo or su
+    pub user: Option<String>,
+    /// Only run the command when this holds
+    pub when: Option<Condition>,
+    /// Part of a `shell-map` directive, whose command runs once for all of its keys
+    pub shared: bool,
+    /// Set by `shell-template` directives, whose `command` is the template as written
+    pub template: Option<Template>,
+    /// Set by `shell-file` directives, whose `command` is the path of a script, relative to the
+    /// secret file's directory, followed by its arguments
+    pub script: bool,
+    /// Timeout and retries, resolved from the directive, the command line and the file's `shell-defaults`
+    pub settings: CommandSettings,
+}
+
+impl CommandMapping {
+    /// Every command the directive runs, in order: its setup, then its command, or for a template
+    /// the command of each placeholder, and last its `compare-cmd`
+    pub fn commands(&self) -> Vec<&str> {
+        let value_commands: Vec<&str> = match &self.template {
+            Some(template) => template.commands().collect(),
+            None => vec![self.command.as_str()],
+        };
+        let setup = self.setup.iter().map(String::as_str);
+        setup.chain(value_commands).chain(self.compare_cmd.as_deref()).collect()
+    }
+
+    /// A directive running `command` for `key`, set up by `options`. Other kinds of directives
+    /// override the fields that set them apart
+    fn from_options(key: &str, command: String, line: usize, options: DirectiveOptions) -> Self {
+        CommandMapping {
+            line,
+            key: key.to_string(),
+            command,
+            setup: None,
+            skip_if: options.skip_if,
+            value_from: options.value_from,
+            capture: options.capture,
+            json_path: options.json_path,
+            extract: options.extract,
+            keep_trailing_newline: options.keep_trailing_newline,
+            compare_hash: options.compare_hash,
+            also_sha256: options.also_sha256,
+            compare_cmd: options.compare_cmd,
+            checks: options.checks,
+            user: options.user,
+            when: options.when,
+            shared: false,
+            template: None,
+            script: false,
+            settings: options.settings,
+        }
+    }
+}
+
+/// How a directive's command is run. Unset fields fall back to the next, less specific source
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommandSettings {
+    pub timeout: Option<Duration>,
+    /// Extra attempts after the command fails
+    pub retries: Option<u32>,
+}
+
+impl CommandSettings {
+    /// Fills fields unset here from `fallback`
+    pub fn or(self, fallback: CommandSettings) -> CommandSettings {
+        CommandSettings {
+            timeout: self.timeout.or(fallback.timeout),
+            retries: self.retries.or(fallback.retries),
+        }
+    }
+
+    // Shared by directive options and `shell-defaults`. Returns false for other option names
+    fn apply(&mut self, name: &str, value: &str) -> Result<bool> {
+        match name {
+            "timeout" => {
+                let seconds: u64 = value.parse().map_err(|_| anyhow!("Invalid timeout '{}', expected seconds", value))?;
+                self.timeout = Some(Duration::from_secs(seconds));
+            }
+            "retries" => {
+                let retries = value.parse().map_err(|_| anyhow!("Invalid retries '{}', expected a number", value))?;
+                self.retries = Some(retries);
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+/// Where the secret value is read from once a directive's command has run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ValueSource {
+    #[default]
+    Stdout,
+    /// The exit code itself is the value, so a non-zero exit isn't a failure
+    ExitCode,
+}
+
+/// Output stream(s) of a directive's command that make up the value
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Capture {
+    #[default]
+    Stdout,
+    Stderr,
+    /// stdout followed by stderr, for tools that split their output between the two
+    Both,
+}
+
+#[derive(Debug, Default)]
+struct DirectiveOptions {
+    skip_if: Option<Regex>,
+    value_from: ValueSource,
+    capture: Capture,
+    json_path: Option<JsonPath>,
+    extract: Option<Regex>,
+    keep_trailing_newline: bool,
+    compare_hash: bool,
+    also_sha256: Option<String>,
+    compare_cmd: Option<String>,
+    checks: ValueCheck,
+    user: Option<String>,
+    when: Option<Condition>,
+    settings: CommandSettings,
 }
 
-pub fn parse_commands(decrypted_content: &str) -> Result<Vec<CommandMapping>> {
+/// Whether a directive refers to the key below or above it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DirectivePosition {
+    /// The directive is written on the line(s) before its key
+    #[default]
+    Before,
+    /// The directive is written on the line(s) after its key
+    After,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Selects directives tagged with this environment, e.g. `# shell[prod]: ...`
+    pub env_profile: Option<String>,
+    pub directive_position: DirectivePosition,
+    /// Restricts key detection to the separator of this format, instead of accepting both `:` and `=`
+    pub format: Option<FileFormat>,
+    /// Keys whose directives are left out, as if they were annotated with `# shell-skip`
+    pub skip_keys: Vec<String>,
+    /// Only keys matching this are kept, minus any in `skip_keys`
+    pub name_regex: Option<Regex>,
+    /// Keys matching any of these are left out like `skip_keys`, whatever `name_regex` keeps
+    pub exclude_keys: Vec<KeyGlob>,
+    /// Operators separating a key from its value besides the format's own, e.g. `:=`, see `validate_assign_op`
+    pub assign_ops: Vec<String>,
+    /// Set on the command line, these win over a file's `shell-defaults` but not over directive options
+    pub command_settings: CommandSettings,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectiveIssue {
+    pub line: usize,
+    pub message: String,
+    /// The directive line as written, so it can be found without counting lines
+    pub snippet: String,
+}
+
+impl DirectiveIssue {
+    fn new(line_idx: usize, raw_line: &str, message: String) -> Self {
+        DirectiveIssue {
+            line: line_idx + 1,
+            message,
+            snippet: raw_line.trim().to_string(),
+        }
+    }
+}
+
+impl fmt::Display for DirectiveIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Line {}: {}: `{}`", self.line, self.message, self.snippet)
+    }
+}
+
+#[derive(Debug)]
+pub struct ParsedCommands {
+    pub mappings: Vec<CommandMapping>,
+    /// Directives that couldn't be resolved to a key
+    pub issues: Vec<DirectiveIssue>,
+}
+
+/// A directive found by `parse_directives`, resolved to the key it applies to or not
+#[derive(Debug)]
+pub enum ParsedDirective {
+    Resolved(Box<CommandMapping>),
+    /// Malformed, or not followed by a key, with the reason
+    Unresolved(DirectiveIssue),
+}
+
+impl ParsedDirective {
+    /// 1-based line number of the directive
+    pub fn line(&self) -> usize {
+        match self {
+            ParsedDirective::Resolved(mapping) => mapping.line,
+            ParsedDirective::Unresolved(issue) => issue.line,
+        }
+    }
+}
+
+// The only directive option written without a value
+const KEEP_TRAILING_NEWLINE: &str = "keep-trailing-newline";
+
+// `# shell[<env>](<options>): <command>`, where the env tag and options are optional
+const DIRECTIVE_PATTERN: &str = r"^\s*[#;]\s*shell(?:\[([^\]]*)\])?(?:\((.*?)\))?:\s*(.*)$";
+
+// `# shell-map: <command>`, followed by comment lines of `<key>=<json path>` pairs
+const MAP_DIRECTIVE_PATTERN: &str = r"^\s*[#;]\s*shell-map:\s*(.*)$";
+
+// `# shell-template: <text with {{cmd:<command>}} placeholders>`
+const TEMPLATE_PATTERN: &str = r"^\s*[#;]\s*shell-template:\s*(.*)$";
+
+// `# shell-file: <script path> [arguments]`
+const SCRIPT_PATTERN: &str = r"^\s*[#;]\s*shell-file:\s*(.*)$";
+
+// `# shell-defaults: timeout=30 retries=2`, applying to every directive in the file
+const DEFAULTS_PATTERN: &str = r"^\s*[#;]\s*shell-defaults:\s*(.*)$";
+
+// Written on the line right before or after a directive to disable it
+const SKIP_ANNOTATION_PATTERN: &str = r"^\s*[#;]\s*shell-skip\s*$";
+
+// `# setup: <command>` and `# value: <command>` lines following a `# shell:` directive without
+// a command of its own, which form a block directive
+const BLOCK_LINE_PATTERN: &str = r"^\s*[#;]\s*(setup|value):\s*(.*)$";
+
+// `# shell-include: <path>`, pulling in the directives of another, unencrypted, file
+const INCLUDE_PATTERN: &str = r"^\s*[#;]\s*shell-include:\s*(.*)$";
+
+pub fn parse_commands(decrypted_content: &str, options: &ParseOptions) -> Result<ParsedCommands> {
     let lines: Vec<&str> = decrypted_content.split('\n').collect();
-    let mut mappings = Vec::new();
+    let mut issues = Vec::new();
+    // Each entry records whether its directive was tagged for the selected profile
+    let mut mappings: Vec<(CommandMapping, bool)> = Vec::new();
 
-    let shell_comment_regex = Regex::new(r"^\s*[#;]\s*shell:\s*(.+)$")?;
+    let shell_comment_regex = Regex::new(DIRECTIVE_PATTERN)?;
+    let map_regex = Regex::new(MAP_DIRECTIVE_PATTERN)?;
+    let template_regex = Regex::new(TEMPLATE_PATTERN)?;
+    let script_regex = Regex::new(SCRIPT_PATTERN)?;
+    let skip_regex = Regex::new(SKIP_ANNOTATION_PATTERN)?;
+    let file_defaults = parse_file_defaults(&lines)?;
+    let fallback_settings = options.command_settings.or(file_defaults);
 
     for (i, line) in lines.iter().enumerate() {
         let stripped = line.trim();
 
-        if let Some(captures) = shell_comment_regex.captures(stripped) {
-            let command = captures.get(1).ok_or_else(|| anyhow!("Failed to capture command"))?.as_str().trim();
+        // A `# shell-skip` annotation disables the directive right below it
+        let annotated = i.checked_sub(1).is_some_and(|idx| skip_regex.is_match(lines[idx]));
+        if annotated {
+            continue;
+        }
+
+        if let Some(captures) = map_regex.captures(stripped) {
+            let command = captures.get(1).map_or("", |command| command.as_str().trim());
             if command.is_empty() {
                 continue;
             }
 
-            if let Some(key) = find_next_key(&lines, i + 1) {
-                mappings.push(CommandMapping {
-                    key: key.to_string(),
-                    command: command.to_string(),
-                });
+            let fields = parse_map_fields(&lines, i)
+                .with_context(|| format!("Invalid directive on line {}: `{}`", i + 1, stripped))?;
+            if fields.is_empty() {
+                issues.push(DirectiveIssue::new(i, line, map_without_fields_message()));
             }
+            for (key, json_path) in fields {
+                let mapping = CommandMapping {
+                    json_path: Some(json_path),
+                    shared: true,
+                    settings: fallback_settings,
+                    ..CommandMapping::from_options(&key, command.to_string(), i + 1, DirectiveOptions::default())
+                };
+                mappings.push((mapping, false));
+            }
+            continue;
         }
+
+        if let Some(captures) = script_regex.captures(stripped) {
+            let reference = captures.get(1).map_or("", |reference| reference.as_str().trim());
+            if reference.is_empty() {
+                continue;
+            }
+
+            if let Some(key) = find_next_key(&lines, i, options.directive_position, options.format, &options.assign_ops) {
+                let mapping = CommandMapping {
+                    script: true,
+                    settings: fallback_settings,
+                    ..CommandMapping::from_options(key, reference.to_string(), i + 1, DirectiveOptions::default())
+                };
+                mappings.push((mapping, false));
+            } else if let Some(message) = unresolved_key_issue(&lines, i, options.directive_position) {
+                issues.push(DirectiveIssue::new(i, line, message));
+            }
+            continue;
+        }
+
+        if let Some(captures) = template_regex.captures(stripped) {
+            let raw = captures.get(1).map_or("", |raw| raw.as_str().trim());
+            if raw.is_empty() {
+                continue;
+            }
+
+            let template = Template::parse(raw)
+                .with_context(|| format!("Invalid directive on line {}: `{}`", i + 1, stripped))?;
+            if let Some(key) = find_next_key(&lines, i, options.directive_position, options.format, &options.assign_ops) {
+                let command = template.to_string();
+                let mapping = CommandMapping {
+                    template: Some(template),
+                    settings: fallback_settings,
+                    ..CommandMapping::from_options(key, command, i + 1, DirectiveOptions::default())
+                };
+                mappings.push((mapping, false));
+            } else if let Some(message) = unresolved_key_issue(&lines, i, options.directive_position) {
+                issues.push(DirectiveIssue::new(i, line, message));
+            }
+            continue;
+        }
+
+        if let Some(captures) = shell_comment_regex.captures(stripped) {
+            let command = captures.get(3).ok_or_else(|| anyhow!("Failed to capture command"))?.as_str().trim();
+            let (command, setup) = if command.is_empty() {
+                match parse_block(&lines, i).with_context(|| format!("Invalid directive on line {}: `{}`", i + 1, stripped))? {
+                    Some(block) => block,
+                    None => continue,
+                }
+            } else {
+                (command.to_string(), None)
+            };
+
+            let tagged = match captures.get(1).map(|tag| tag.as_str().trim()) {
+                Some(tag) if Some(tag) != options.env_profile.as_deref() => continue,
+                Some(_) => true,
+                None => false,
+            };
+
+            let directive_options = match captures.get(2) {
+                Some(raw) => parse_directive_options(raw.as_str())
+                    .with_context(|| format!("Invalid directive on line {}: `{}`", i + 1, stripped))?,
+                None => DirectiveOptions::default(),
+            };
+
+            if let Some(key) = find_next_key(&lines, i, options.directive_position, options.format, &options.assign_ops) {
+                if directive_options.also_sha256.as_deref() == Some(key) {
+                    let context = format!("Invalid directive on line {}: `{}`", i + 1, stripped);
+                    return Err(anyhow!("also-set-sha256 can't name the directive's own key '{}'", key).context(context));
+                }
+                let settings = directive_options.settings.or(fallback_settings);
+                let mapping = CommandMapping {
+                    setup,
+                    settings,
+                    ..CommandMapping::from_options(key, command, i + 1, directive_options)
+                };
+                mappings.push((mapping, tagged));
+            } else if let Some(message) = unresolved_key_issue(&lines, i, options.directive_position) {
+                issues.push(DirectiveIssue::new(i, line, message));
+            }
+        }
+    }
+
+    // A directive tagged for the selected profile overrides untagged ones for the same key
+    let overridden: Vec<String> = mappings
+        .iter()
+        .filter(|(_, tagged)| *tagged)
+        .map(|(mapping, _)| mapping.key.clone())
+        .collect();
+
+    let mappings = mappings
+        .into_iter()
+        .filter(|(mapping, tagged)| *tagged || !overridden.contains(&mapping.key))
+        .filter(|(mapping, _)| !options.skip_keys.contains(&mapping.key))
+        .filter(|(mapping, _)| options.name_regex.as_ref().is_none_or(|regex| regex.is_match(&mapping.key)))
+        .filter(|(mapping, _)| !options.exclude_keys.iter().any(|glob| glob.is_match(&mapping.key)))
+        .map(|(mapping, _)| mapping)
+        .collect();
+
+    Ok(ParsedCommands { mappings, issues })
+}
+
+/// Every directive in `text` in line order, e.g. for editor integrations. Unlike `parse_commands`,
+/// a malformed directive doesn't fail the whole text but is returned as unresolved along with
+/// the reason. A `shell-map` directive is returned once for each of its keys
+pub fn parse_directives(text: &str, options: &ParseOptions) -> Result<Vec<ParsedDirective>> {
+    let rejected = lint_directives_with_ops(text, options.directive_position, &options.assign_ops)?;
+    let rejected_lines: HashSet<usize> = rejected.iter().map(|issue| issue.line).collect();
+
+    // Blanking rejected directives keeps the line numbers of the rest, which then parse as usual
+    let remaining: Vec<&str> = text
+        .split('\n')
+        .enumerate()
+        .map(|(i, line)| if rejected_lines.contains(&(i + 1)) { "" } else { line })
+        .collect();
+    let parsed = parse_commands(&remaining.join("\n"), options)?;
+
+    let mut directives: Vec<ParsedDirective> = rejected.into_iter().map(ParsedDirective::Unresolved).collect();
+    directives.extend(parsed.issues.into_iter().map(ParsedDirective::Unresolved));
+    directives.extend(parsed.mappings.into_iter().map(|mapping| ParsedDirective::Resolved(Box::new(mapping))));
+    directives.sort_by_key(ParsedDirective::line);
+    Ok(directives)
+}
+
+/// `parse_commands`, plus the directives of files pulled in with `shell-include`. Includes are
+/// resolved relative to the including file and may include further files, but not in a cycle.
+/// Directives of the including file win over included ones for the same key
+pub fn parse_commands_with_includes(content: &str, filepath: &Path, options: &ParseOptions) -> Result<ParsedCommands> {
+    let mut chain = vec![filepath.canonicalize().unwrap_or_else(|_| filepath.to_path_buf())];
+    parse_with_includes(content, filepath, options, &mut chain)
+}
+
+// `chain` holds the files currently being included, outermost first
+fn parse_with_includes(
+    content: &str,
+    filepath: &Path,
+    options: &ParseOptions,
+    chain: &mut Vec<PathBuf>,
+) -> Result<ParsedCommands> {
+    let mut parsed = parse_commands(content, options)?;
+    let include_regex = Regex::new(INCLUDE_PATTERN)?;
+    let dir = filepath.parent().unwrap_or(Path::new(""));
+
+    for (i, line) in content.split('\n').enumerate() {
+        let Some(captures) = include_regex.captures(line) else {
+            continue;
+        };
+        let target = captures.get(1).map_or("", |target| target.as_str().trim());
+        if target.is_empty() {
+            return Err(anyhow!("shell-include on line {} has no path", i + 1));
+        }
+
+        let included = dir.join(target);
+        let canonical = included
+            .canonicalize()
+            .with_context(|| format!("Failed to resolve shell-include {} on line {}", included.display(), i + 1))?;
+        if let Some(start) = chain.iter().position(|path| *path == canonical) {
+            let cycle: Vec<String> = chain[start..].iter().chain([&canonical]).map(|path| path.display().to_string()).collect();
+            return Err(anyhow!("shell-include cycle: {}", cycle.join(" -> ")));
+        }
+
+        let included_content =
+            fs::read_to_string(&included).with_context(|| format!("Failed to read {}", included.display()))?;
+        // Keys in the included file are written in its own format, e.g. `key = ""` in TOML
+        let included_options = ParseOptions { format: FileFormat::from_path(&included), ..options.clone() };
+        chain.push(canonical);
+        let nested = parse_with_includes(&included_content, &included, &included_options, chain)
+            .with_context(|| format!("Invalid included file {}", included.display()))?;
+        chain.pop();
+
+        for mapping in nested.mappings {
+            if !parsed.mappings.iter().any(|existing| existing.key == mapping.key) {
+                parsed.mappings.push(mapping);
+            }
+        }
+        parsed.issues.extend(nested.issues.into_iter().map(|issue| DirectiveIssue {
+            message: format!("{} (in {})", issue.message, included.display()),
+            ..issue
+        }));
     }
 
-    Ok(mappings)
+    Ok(parsed)
 }
 
-fn find_next_key<'a>(lines: &'a [&'a str], start_idx: usize) -> Option<&'a str> {
-    // Check the immediate next line(s) - if they're all comments, skip this mapping
-    let mut first_non_empty_idx = None;
-    for (offset, line) in lines.iter().skip(start_idx).enumerate() {
+/// Reports directives that `parse_commands` would reject or silently ignore
+pub fn lint_directives(content: &str, position: DirectivePosition) -> Result<Vec<DirectiveIssue>> {
+    lint_directives_with_ops(content, position, &[])
+}
+
+/// `lint_directives` for files whose keys may also be followed by one of `assign_ops`
+pub fn lint_directives_with_ops(
+    content: &str,
+    position: DirectivePosition,
+    assign_ops: &[String],
+) -> Result<Vec<DirectiveIssue>> {
+    let lines: Vec<&str> = content.split('\n').collect();
+    let mut issues = Vec::new();
+
+    // Anything that looks like it was meant to be a directive, well-formed or not
+    let candidate_regex = Regex::new(r"^\s*[#;]\s*shell\s*[:(\[]")?;
+    let shell_comment_regex = Regex::new(DIRECTIVE_PATTERN)?;
+    let map_regex = Regex::new(MAP_DIRECTIVE_PATTERN)?;
+    let template_regex = Regex::new(TEMPLATE_PATTERN)?;
+    let script_regex = Regex::new(SCRIPT_PATTERN)?;
+    let defaults_regex = Regex::new(DEFAULTS_PATTERN)?;
+
+    for (i, line) in lines.iter().enumerate() {
         let stripped = line.trim();
+        let mut report = |message: String| issues.push(DirectiveIssue::new(i, line, message));
+
+        if let Some(captures) = defaults_regex.captures(stripped) {
+            if let Err(e) = parse_defaults_line(captures.get(1).map_or("", |raw| raw.as_str())) {
+                report(format!("{:#}", e));
+            }
+            continue;
+        }
+
+        if let Some(captures) = map_regex.captures(stripped) {
+            if captures.get(1).is_none_or(|command| command.as_str().trim().is_empty()) {
+                report("Directive has no command".to_string());
+                continue;
+            }
+            match parse_map_fields(&lines, i) {
+                Ok(fields) if fields.is_empty() => report(map_without_fields_message()),
+                Ok(_) => {}
+                Err(e) => report(format!("{:#}", e)),
+            }
+            continue;
+        }
+
+        if let Some(captures) = template_regex.captures(stripped) {
+            let raw = captures.get(1).map_or("", |raw| raw.as_str().trim());
+            if raw.is_empty() {
+                report("Directive has no template".to_string());
+            } else if let Err(e) = Template::parse(raw) {
+                report(format!("{:#}", e));
+            } else if find_next_key(&lines, i, position, None, assign_ops).is_none() {
+                let message = unresolved_key_issue(&lines, i, position)
+                    .unwrap_or_else(|| unresolved_key_message(position));
+                report(message);
+            }
+            continue;
+        }
+
+        if let Some(captures) = script_regex.captures(stripped) {
+            if captures.get(1).is_none_or(|reference| reference.as_str().trim().is_empty()) {
+                report("Directive has no script".to_string());
+            } else if find_next_key(&lines, i, position, None, assign_ops).is_none() {
+                let message = unresolved_key_issue(&lines, i, position)
+                    .unwrap_or_else(|| unresolved_key_message(position));
+                report(message);
+            }
+            continue;
+        }
+
+        if !candidate_regex.is_match(stripped) {
+            continue;
+        }
+
+        let Some(captures) = shell_comment_regex.captures(stripped) else {
+            report("Malformed directive, expected '# shell[<env>](<options>): <command>'".to_string());
+            continue;
+        };
+
+        if captures.get(1).is_some_and(|tag| tag.as_str().trim().is_empty()) {
+            report("Directive has an empty environment tag".to_string());
+            continue;
+        }
+
+        if let Some(raw) = captures.get(2) {
+            if let Err(e) = parse_directive_options(raw.as_str()) {
+                report(format!("{:#}", e));
+                continue;
+            }
+        }
+
+        if captures.get(3).is_none_or(|command| command.as_str().trim().is_empty()) {
+            match parse_block(&lines, i) {
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    report("Directive has no command".to_string());
+                    continue;
+                }
+                Err(e) => {
+                    report(format!("{:#}", e));
+                    continue;
+                }
+            }
+        }
+
+        if find_next_key(&lines, i, position, None, assign_ops).is_none() {
+            let message = unresolved_key_issue(&lines, i, position)
+                .unwrap_or_else(|| unresolved_key_message(position));
+            report(message);
+        }
+    }
+
+    Ok(issues)
+}
+
+fn parse_directive_options(raw: &str) -> Result<DirectiveOptions> {
+    let mut options = DirectiveOptions::default();
+
+    for option in split_directive_options(raw) {
+        let option = option.trim();
+        if option.is_empty() {
+            continue;
+        }
+
+        if option == KEEP_TRAILING_NEWLINE {
+            options.keep_trailing_newline = true;
+            continue;
+        }
+        let (name, value) = option
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected 'name=value' in directive option '{}'", option))?;
+
+        match name.trim() {
+            "skip-if" => {
+                let regex = Regex::new(value.trim())
+                    .with_context(|| format!("Invalid skip-if regex '{}'", value.trim()))?;
+                options.skip_if = Some(regex);
+            }
+            "value-from" => {
+                options.value_from = match value.trim() {
+                    "stdout" => ValueSource::Stdout,
+                    "exit-code" => ValueSource::ExitCode,
+                    other => return Err(anyhow!("Unknown value-from '{}', expected 'stdout' or 'exit-code'", other)),
+                };
+            }
+            "capture" => {
+                options.capture = match value.trim() {
+                    "stdout" => Capture::Stdout,
+                    "stderr" => Capture::Stderr,
+                    "both" => Capture::Both,
+                    other => return Err(anyhow!("Unknown capture '{}', expected 'stdout', 'stderr' or 'both'", other)),
+                };
+            }
+            "compare" => {
+                options.compare_hash = match value.trim() {
+                    "value" => false,
+                    "hash" => true,
+                    other => return Err(anyhow!("Unknown compare '{}', expected 'value' or 'hash'", other)),
+                };
+            }
+            "also-set-sha256" => {
+                let key = value.trim();
+                if key.is_empty() || key.contains(|ch: char| ch.is_whitespace() || matches!(ch, '"' | '\'' | '[' | ']')) {
+                    return Err(anyhow!("Invalid also-set-sha256 key '{}'", key));
+                }
+                options.also_sha256 = Some(key.to_string());
+            }
+            "compare-cmd" => {
+                let program = value.trim();
+                if program.is_empty() || program.contains(char::is_whitespace) {
+                    return Err(anyhow!("Invalid compare-cmd '{}', expected the path of a single program", program));
+                }
+                options.compare_cmd = Some(program.to_string());
+            }
+            "user" => {
+                let user = value.trim();
+                if user.is_empty() || user.starts_with('-') || user.contains(char::is_whitespace) {
+                    return Err(anyhow!("Invalid user '{}'", user));
+                }
+                options.user = Some(user.to_string());
+            }
+            "when" => {
+                options.when = Some(Condition::parse(value)?);
+            }
+            "json-path" => {
+                options.json_path = Some(JsonPath::parse(value)?);
+            }
+            "extract" => {
+                let regex = Regex::new(value.trim())
+                    .with_context(|| format!("Invalid extract regex '{}'", value.trim()))?;
+                if regex.captures_len() < 2 {
+                    return Err(anyhow!("extract regex '{}' has no capture group for the value", value.trim()));
+                }
+                options.extract = Some(regex);
+            }
+            other if options.checks.apply(other, value)? => {}
+            other if options.settings.apply(other, value.trim())? => {}
+            other => return Err(anyhow!("Unknown directive option '{}'", other)),
+        }
+    }
+
+    if options.compare_hash && options.compare_cmd.is_some() {
+        return Err(anyhow!("compare=hash and compare-cmd can't be combined, only a hash of the value is stored"));
+    }
+
+    Ok(options)
+}
+
+fn parse_defaults_line(raw: &str) -> Result<CommandSettings> {
+    let mut settings = CommandSettings::default();
+
+    for option in raw.split_whitespace() {
+        let (name, value) = option
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected 'name=value' in shell-defaults option '{}'", option))?;
+        if !settings.apply(name, value)? {
+            return Err(anyhow!("Unknown shell-defaults option '{}', expected 'timeout' or 'retries'", name));
+        }
+    }
+
+    Ok(settings)
+}
+
+// Later `shell-defaults` lines override earlier ones field by field
+fn parse_file_defaults(lines: &[&str]) -> Result<CommandSettings> {
+    let defaults_regex = Regex::new(DEFAULTS_PATTERN)?;
+    let mut defaults = CommandSettings::default();
+
+    for (i, line) in lines.iter().enumerate() {
+        if let Some(captures) = defaults_regex.captures(line.trim()) {
+            let raw = captures.get(1).map_or("", |raw| raw.as_str());
+            let settings = parse_defaults_line(raw)
+                .with_context(|| format!("Invalid directive on line {}: `{}`", i + 1, line.trim()))?;
+            defaults = settings.or(defaults);
+        }
+    }
+
+    Ok(defaults)
+}
+
+// The comment lines right after a `shell-map` directive, e.g. `# username=.data.user password=.data.pass`.
+// A comment line that doesn't consist of `<key>=<path>` pairs ends the block
+fn parse_map_fields(lines: &[&str], directive_idx: usize) -> Result<Vec<(String, JsonPath)>> {
+    let mut fields = Vec::new();
+
+    for line in &lines[directive_idx + 1..] {
+        let Some(comment) = line.trim().strip_prefix(['#', ';']) else {
+            break;
+        };
+        let pairs: Vec<(&str, &str)> = comment
+            .split_whitespace()
+            .filter_map(|pair| match split_quoted(pair) {
+                Some((key, rest)) => Some((key, rest.strip_prefix('=')?)),
+                None => pair.split_once('='),
+            })
+            .filter(|(key, path)| !key.is_empty() && path.starts_with(['.', '[']))
+            .collect();
+        if pairs.is_empty() || pairs.len() != comment.split_whitespace().count() {
+            break;
+        }
+
+        for (key, path) in pairs {
+            fields.push((key.to_string(), JsonPath::parse(path)?));
+        }
+    }
+
+    Ok(fields)
+}
+
+// The value command and optional setup command of the block starting after `directive_idx`,
+// or `None` when no block lines follow
+fn parse_block(lines: &[&str], directive_idx: usize) -> Result<Option<(String, Option<String>)>> {
+    let block_regex = Regex::new(BLOCK_LINE_PATTERN)?;
+    let mut setup = None;
+    let mut value = None;
+
+    for line in &lines[directive_idx + 1..] {
+        let Some(captures) = block_regex.captures(line) else {
+            break;
+        };
+        let command = captures[2].trim();
+        if command.is_empty() {
+            return Err(anyhow!("'{}:' line has no command", &captures[1]));
+        }
+        let slot = if &captures[1] == "setup" { &mut setup } else { &mut value };
+        if slot.replace(command.to_string()).is_some() {
+            return Err(anyhow!("Block directive has more than one '{}:' line", &captures[1]));
+        }
+    }
+
+    match (value, setup) {
+        (Some(value), setup) => Ok(Some((value, setup))),
+        (None, Some(_)) => Err(anyhow!("Block directive has a 'setup:' line but no 'value:' line")),
+        (None, None) => Ok(None),
+    }
+}
+
+fn map_without_fields_message() -> String {
+    "shell-map directive isn't followed by '# <key>=<json path>' mappings".to_string()
+}
+
+// Options are separated by commas, or by whitespace like in `shell-defaults`, but only split on
+// one that starts a new `name=` pair or flag so values like `\d{1,3}` or `$ENV == prod` stay intact
+fn split_directive_options(raw: &str) -> Vec<&str> {
+    let next_option_regex = Regex::new(r"^\s*(?:[A-Za-z_-]+=|keep-trailing-newline(?:[\s,]|$))").expect("valid regex");
+    let mut parts = Vec::new();
+    let mut start = 0;
+
+    for (idx, ch) in raw.char_indices() {
+        let next = idx + ch.len_utf8();
+        if (ch == ',' || ch.is_whitespace()) && next_option_regex.is_match(&raw[next..]) {
+            parts.push(&raw[start..idx]);
+            start = next;
+        }
+    }
+    parts.push(&raw[start..]);
+
+    parts
+}
+
+// Closest line to the directive, in the direction of its key, that isn't blank or another directive
+fn adjacent_content_line<'a>(
+    lines: &'a [&'a str],
+    directive_idx: usize,
+    position: DirectivePosition,
+) -> Option<(usize, &'a str)> {
+    let directive_regex = Regex::new(DIRECTIVE_PATTERN).ok()?;
+    let skip_regex = Regex::new(SKIP_ANNOTATION_PATTERN).ok()?;
+    let block_regex = Regex::new(BLOCK_LINE_PATTERN).ok()?;
+    let candidates: Box<dyn Iterator<Item = usize>> = match position {
+        DirectivePosition::Before => Box::new(directive_idx + 1..lines.len()),
+        DirectivePosition::After => Box::new((0..directive_idx).rev()),
+    };
+
+    for idx in candidates {
+        let stripped = lines[idx].trim();
 
         // Skip empty lines to find the first actual content line
         if stripped.is_empty() {
             continue;
         }
 
-        // If the first non-empty line is a comment, skip this mapping
-        if stripped.starts_with('#') || stripped.starts_with(';') {
-            return None;
+        // Stacked directives (e.g. one per environment) share the same key
+        if directive_regex.is_match(stripped) || skip_regex.is_match(stripped) || block_regex.is_match(stripped) {
+            continue;
         }
 
-        // Otherwise, this is the line we want to check for a key
-        first_non_empty_idx = Some(start_idx + offset);
-        break;
+        return Some((idx, stripped));
     }
 
-    // If we found a non-empty, non-commented line, check if it's a key
-    if let Some(idx) = first_non_empty_idx {
-        let stripped = lines.get(idx)?.trim();
-        let key_regex = Regex::new(r"^\s*([^:=\s]+)\s*[:=]").ok()?;
-        if let Some(captures) = key_regex.captures(stripped) {
-            return Some(captures.get(1)?.as_str());
-        }
+    None
+}
+
+fn find_next_key<'a>(
+    lines: &'a [&'a str],
+    directive_idx: usize,
+    position: DirectivePosition,
+    format: Option<FileFormat>,
+    assign_ops: &[String],
+) -> Option<&'a str> {
+    let (_, stripped) = adjacent_content_line(lines, directive_idx, position)?;
+
+    // If the first non-empty line is a comment, skip this mapping
+    if stripped.starts_with('#') || stripped.starts_with(';') {
+        return None;
+    }
+
+    // Otherwise, this is the line we want to check for a key
+    line_key(stripped, format, assign_ops)
+}
+
+/// The key `line` assigns a value to, if it's a `key: value` or `key=value` line of `format`
+pub fn line_key<'a>(line: &'a str, format: Option<FileFormat>, assign_ops: &[String]) -> Option<&'a str> {
+    let stripped = FileFormat::strip_export(line.trim(), format);
+    if stripped.starts_with(['"', '\'']) {
+        let (key, rest) = split_quoted(stripped)?;
+        return strip_assignment(rest, format, assign_ops).is_some().then_some(key);
+    }
+
+    // Lazily, so the key ends at the first operator, e.g. `a` in `a <- b <- c`
+    let separators: String = FileFormat::key_separators(format).iter().collect();
+    let operators: String = sorted_assign_ops(assign_ops).iter().map(|op| format!("{}|", regex::escape(op))).collect();
+    let key_regex = Regex::new(&format!(r"^\s*([^{0}\s]+?)\s*(?:{1}[{0}])", separators, operators)).ok()?;
+    if let Some(captures) = key_regex.captures(stripped) {
+        return Some(captures.get(1)?.as_str());
     }
 
     None
+}
+
+/// Checks an operator given for `ParseOptions::assign_ops`. Only punctuation is allowed, as a word
+/// character would split keys, and quotes, comment markers and brackets would be mistaken for
+/// quoted keys, comments and ini sections
+pub fn validate_assign_op(op: &str) -> Result<String> {
+    if op.is_empty() || op.chars().count() > 4 {
+        return Err(anyhow!("expected 1 to 4 characters, e.g. ':='"));
+    }
+    let disallowed = |ch: &char| !ch.is_ascii_punctuation() || matches!(ch, '"' | '\'' | '#' | ';' | '[' | ']');
+    if let Some(ch) = op.chars().find(disallowed) {
+        return Err(anyhow!(
+            "'{}' isn't allowed in an assignment operator, only punctuation other than quotes, '#', ';' and brackets",
+            ch
+        ));
+    }
+    Ok(op.to_string())
+}
+
+/// A key pattern for `ParseOptions::exclude_keys`, where `*` matches any run of characters and `?`
+/// a single one. Unlike `name_regex`, it has to match the whole key
+#[derive(Debug, Clone)]
+pub struct KeyGlob {
+    pattern: String,
+    regex: Regex,
+}
+
+impl KeyGlob {
+    pub fn new(pattern: &str) -> Result<Self> {
+        if pattern.is_empty() {
+            return Err(anyhow!("expected a key or a pattern like 'db_*'"));
+        }
+        let translated: String = pattern
+            .chars()
+            .map(|ch| match ch {
+                '*' => ".*".to_string(),
+                '?' => ".".to_string(),
+                _ => regex::escape(&ch.to_string()),
+            })
+            .collect();
+        let regex = Regex::new(&format!("^{}$", translated))?;
+        Ok(KeyGlob { pattern: pattern.to_string(), regex })
+    }
+
+    pub fn is_match(&self, key: &str) -> bool {
+        self.regex.is_match(key)
+    }
+}
+
+impl fmt::Display for KeyGlob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+// Longest first, so `:=` is tried before a `:` that is a prefix of it
+fn sorted_assign_ops(assign_ops: &[String]) -> Vec<&str> {
+    let mut sorted: Vec<&str> = assign_ops.iter().map(String::as_str).collect();
+    sorted.sort_by_key(|op| std::cmp::Reverse(op.len()));
+    sorted
+}
+
+/// What follows the operator after a key, given what follows the key, or `None` if it isn't
+/// an assignment. `assign_ops` are tried before the format's own separators
+pub(crate) fn strip_assignment<'a>(rest: &'a str, format: Option<FileFormat>, assign_ops: &[String]) -> Option<&'a str> {
+    let rest = rest.trim_start();
+    sorted_assign_ops(assign_ops)
+        .into_iter()
+        .find_map(|op| rest.strip_prefix(op))
+        .or_else(|| FileFormat::key_separators(format).iter().find_map(|separator| rest.strip_prefix(*separator)))
+}
+
+/// Splits a leading `"quoted"` or `'quoted'` name, e.g. a YAML key containing `:`, into its
+/// unquoted text and whatever follows the closing quote
+pub(crate) fn split_quoted(text: &str) -> Option<(&str, &str)> {
+    let quote = text.chars().next().filter(|ch| matches!(ch, '"' | '\''))?;
+    let inner = &text[1..];
+    let end = inner.find(quote)?;
+    Some((&inner[..end], &inner[end + 1..]))
+}
+
+fn unresolved_key_message(position: DirectivePosition) -> String {
+    match position {
+        DirectivePosition::Before => "Directive is not followed by a key".to_string(),
+        DirectivePosition::After => "Directive is not preceded by a key".to_string(),
+    }
+}
+
+// Explains why `find_next_key` came up empty. Returns `None` when the directive is
+// next to a commented-out key, which is the intended way of disabling it
+fn unresolved_key_issue(lines: &[&str], directive_idx: usize, position: DirectivePosition) -> Option<String> {
+    let relation = match position {
+        DirectivePosition::Before => "followed",
+        DirectivePosition::After => "preceded",
+    };
+
+    match adjacent_content_line(lines, directive_idx, position) {
+        Some((_, stripped)) if stripped.starts_with('#') || stripped.starts_with(';') => None,
+        Some((idx, stripped)) if stripped == "-" || stripped.starts_with("- ") => Some(format!(
+            "Directive is {} by a sequence item on line {}, which can't be targeted by a key",
+            relation,
+            idx + 1
+        )),
+        _ => Some(unresolved_key_message(position)),
+    }
 }
\ No newline at end of file
@@ -0,0 +1,60 @@
+//! Expansion of `~` and environment variables in file arguments, for callers that don't go
+//! through a shell, e.g. a CI step passing `$HOME/secrets/app.yaml` as a single argument
+
+use anyhow::{anyhow, Result};
+use std::path::{Path, PathBuf};
+
+/// `path` with a leading `~` or `~/` replaced by `$HOME`, and `$VAR` and `${VAR}` by the variable's
+/// value. `~user` and a `$` not followed by a variable name are kept as written, while a variable
+/// that isn't set is an error rather than an empty string
+pub fn expand_path(path: &Path) -> Result<PathBuf> {
+    expand_path_with(path, |name| std::env::var(name).ok())
+}
+
+/// `expand_path`, looking variables up with `lookup`
+pub fn expand_path_with(path: &Path, lookup: impl Fn(&str) -> Option<String>) -> Result<PathBuf> {
+    // Not valid UTF-8, so not something a variable or `~` was written in
+    let Some(raw) = path.to_str() else {
+        return Ok(path.to_path_buf());
+    };
+    let variable = |name: &str| {
+        lookup(name).ok_or_else(|| anyhow!("Environment variable {} in file argument '{}' isn't set", name, raw))
+    };
+
+    let mut expanded = String::new();
+    let mut rest = raw;
+    if rest == "~" || rest.starts_with("~/") {
+        expanded.push_str(&variable("HOME")?);
+        rest = &rest[1..];
+    }
+
+    while let Some(idx) = rest.find('$') {
+        expanded.push_str(&rest[..idx]);
+        let after = &rest[idx + 1..];
+        let (name, consumed) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => return Err(anyhow!("Unterminated '${{' in file argument '{}'", raw)),
+            },
+            None => {
+                let end = after.find(|ch: char| !(ch.is_ascii_alphanumeric() || ch == '_')).unwrap_or(after.len());
+                (&after[..end], end)
+            }
+        };
+        if !is_variable_name(name) {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+        expanded.push_str(&variable(name)?);
+        rest = &after[consumed..];
+    }
+    expanded.push_str(rest);
+
+    Ok(PathBuf::from(expanded))
+}
+
+fn is_variable_name(name: &str) -> bool {
+    name.chars().next().is_some_and(|ch| ch.is_ascii_alphabetic() || ch == '_')
+        && name.chars().all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+}
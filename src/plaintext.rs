@@ -0,0 +1,106 @@
+//! Finds secrets that sit in plaintext in a sops file, e.g. added by hand after it was encrypted
+
+use anyhow::Result;
+use regex::Regex;
+use std::fmt;
+
+// sops' own default when a file sets none of the rules
+const DEFAULT_UNENCRYPTED_SUFFIX: &str = "_unencrypted";
+
+// In every store sops writes, e.g. `unencrypted_suffix: x` in yaml, `"unencrypted_suffix": "x"` in json
+// and `sops_unencrypted_suffix=x` in env files
+const RULE_PATTERN: &str =
+    r#"(?m)^\s*"?(?:sops_)?(unencrypted_suffix|encrypted_suffix|unencrypted_regex|encrypted_regex)"?\s*[:=]\s*["']?([^"'\r\n]*?)["']?\s*,?\s*$"#;
+
+/// Which keys sops encrypts, from the file's `sops` metadata. Only top-level keys are considered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncryptionRule {
+    UnencryptedSuffix(String),
+    EncryptedSuffix(String),
+    UnencryptedRegex(String),
+    EncryptedRegex(String),
+}
+
+impl EncryptionRule {
+    /// The rule recorded in an encrypted file's metadata, or sops' default when none is
+    pub fn from_metadata(raw: &str) -> Result<Self> {
+        let Some(captures) = Regex::new(RULE_PATTERN)?.captures(raw) else {
+            return Ok(EncryptionRule::UnencryptedSuffix(DEFAULT_UNENCRYPTED_SUFFIX.to_string()));
+        };
+        let value = captures[2].to_string();
+        Ok(match &captures[1] {
+            "unencrypted_suffix" => EncryptionRule::UnencryptedSuffix(value),
+            "encrypted_suffix" => EncryptionRule::EncryptedSuffix(value),
+            "unencrypted_regex" => EncryptionRule::UnencryptedRegex(value),
+            _ => EncryptionRule::EncryptedRegex(value),
+        })
+    }
+
+    /// Whether sops encrypts the value of `key`. An invalid regex is assumed to encrypt everything,
+    /// which errs on the side of flagging
+    pub fn encrypts(&self, key: &str) -> bool {
+        let matches = |pattern: &str| Regex::new(pattern).map_or(true, |regex| regex.is_match(key));
+        match self {
+            EncryptionRule::UnencryptedSuffix(suffix) => !key.ends_with(suffix.as_str()),
+            EncryptionRule::EncryptedSuffix(suffix) => key.ends_with(suffix.as_str()),
+            EncryptionRule::UnencryptedRegex(pattern) => !matches(pattern),
+            EncryptionRule::EncryptedRegex(pattern) => matches(pattern),
+        }
+    }
+}
+
+impl fmt::Display for EncryptionRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncryptionRule::UnencryptedSuffix(value) => write!(f, "unencrypted_suffix '{}'", value),
+            EncryptionRule::EncryptedSuffix(value) => write!(f, "encrypted_suffix '{}'", value),
+            EncryptionRule::UnencryptedRegex(value) => write!(f, "unencrypted_regex '{}'", value),
+            EncryptionRule::EncryptedRegex(value) => write!(f, "encrypted_regex '{}'", value),
+        }
+    }
+}
+
+/// A key whose value is stored unencrypted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlaintextValue {
+    pub key: String,
+    /// The rule leaving the key unencrypted on purpose, `None` if sops would have encrypted it,
+    /// i.e. the value was leaked into the file by something other than sops
+    pub exempted_by: Option<EncryptionRule>,
+}
+
+impl fmt::Display for PlaintextValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.exempted_by {
+            None => write!(f, "{} is stored in plaintext although sops would encrypt it, it was likely added without sops", self.key),
+            Some(rule) => write!(f, "{} is stored in plaintext, the file's {} leaves it unencrypted", self.key, rule),
+        }
+    }
+}
+
+/// Whether `raw` is a file sops has encrypted, as opposed to e.g. a plaintext draft
+pub fn is_encrypted(raw: &str) -> bool {
+    raw.contains("ENC[")
+}
+
+/// Which of `keys` have a non-empty value that isn't an `ENC[...]` blob in the encrypted file
+/// content `raw`. `raw_value` looks up a key's value as written in the file
+pub fn plaintext_values<'a>(
+    raw: &str,
+    keys: impl IntoIterator<Item = &'a str>,
+    raw_value: impl Fn(&str) -> Option<String>,
+) -> Result<Vec<PlaintextValue>> {
+    if !is_encrypted(raw) {
+        return Ok(Vec::new());
+    }
+
+    let rule = EncryptionRule::from_metadata(raw)?;
+    Ok(keys
+        .into_iter()
+        .filter(|key| raw_value(key).is_some_and(|value| !value.is_empty() && !value.starts_with("ENC[")))
+        .map(|key| PlaintextValue {
+            key: key.to_string(),
+            exempted_by: (!rule.encrypts(key)).then(|| rule.clone()),
+        })
+        .collect())
+}
@@ -0,0 +1,70 @@
+use anyhow::{Context, Result};
+use serde_json::json;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Updates a dry run would apply, written for review with values masked
+#[derive(Debug, Default)]
+pub struct Plan {
+    path: PathBuf,
+    updates: Vec<PlannedUpdate>,
+}
+
+#[derive(Debug)]
+struct PlannedUpdate {
+    file: String,
+    key: String,
+    value_length: usize,
+    replaces_value: bool,
+}
+
+impl Plan {
+    pub fn new(path: &Path) -> Self {
+        Plan {
+            path: path.to_path_buf(),
+            updates: Vec::new(),
+        }
+    }
+
+    /// `replaces_value` tells an update of an existing value from filling in a missing one
+    pub fn record(&mut self, file: &Path, key: &str, value: &str, replaces_value: bool) {
+        self.updates.push(PlannedUpdate {
+            file: file.display().to_string(),
+            key: key.to_string(),
+            value_length: value.chars().count(),
+            replaces_value,
+        });
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let updates: Vec<_> = self
+            .updates
+            .iter()
+            .map(|update| {
+                json!({
+                    "file": update.file,
+                    "key": update.key,
+                    "action": if update.replaces_value { "update" } else { "add" },
+                    "value": MASK,
+                    "value_length": update.value_length,
+                })
+            })
+            .collect();
+        let content = serde_json::to_string_pretty(&json!({ "updates": updates }))?;
+        fs::write(&self.path, content + "\n")
+            .with_context(|| format!("Failed to write plan file {}", self.path.display()))
+    }
+}
+
+// Stands in for every value, so the plan reveals nothing about a secret beyond its length
+pub(crate) const MASK: &str = "********";
+
+/// `text` with every occurrence of `value` masked, e.g. a secret in its own `echo` command.
+/// Shorter values, e.g. exit codes, would mask unrelated parts of the text and are left alone
+pub(crate) fn mask_in(text: &str, value: &str) -> String {
+    let value = value.trim();
+    if value.chars().count() < 4 {
+        return text.to_string();
+    }
+    text.replace(value, MASK)
+}
@@ -0,0 +1,145 @@
+use anyhow::{anyhow, Result};
+use std::path::Path;
+
+/// Restricts which binaries directive commands may invoke
+#[derive(Debug, Clone, Default)]
+pub struct CommandPolicy {
+    /// When non-empty, only these binaries may be invoked
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+impl CommandPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Checks the binary of every simple command in a pipeline or command list, so
+    /// `rbw get x | jq -r .uri` needs both `rbw` and `jq` to be allowed. Binaries are matched
+    /// by file name, so `/usr/bin/curl` is `curl`, and both a wrapper like `env` or `sudo` and
+    /// the binary it runs are checked
+    pub fn check(&self, command: &str) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+
+        let listed = |list: &[String], binary: &str| list.iter().any(|entry| binary_name(entry) == binary_name(binary));
+        for binary in command_binaries(command) {
+            if listed(&self.deny, &binary) {
+                return Err(anyhow!("'{}' is denied", binary));
+            }
+            if !self.allow.is_empty() && !listed(&self.allow, &binary) {
+                return Err(anyhow!("'{}' is not in the allowed binaries", binary));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Cheap check for a command that would read or rewrite the file being synced, either by
+/// mentioning its path or by running sops against a file with the same name
+pub fn references_file(command: &str, filepath: &Path) -> bool {
+    let given = filepath.to_string_lossy();
+    if !given.is_empty() && command.contains(given.as_ref()) {
+        return true;
+    }
+
+    if let Ok(canonical) = filepath.canonicalize() {
+        if command.contains(canonical.to_string_lossy().as_ref()) {
+            return true;
+        }
+    }
+
+    let runs_sops = command_binaries(command).iter().any(|binary| binary_name(binary) == "sops");
+    let file_name = filepath.file_name().map(|name| name.to_string_lossy());
+    runs_sops && file_name.is_some_and(|name| command.contains(name.as_ref()))
+}
+
+/// Commands that run the command given after their own options
+const WRAPPERS: &[&str] = &["env", "command", "exec", "sudo"];
+
+/// Options of `WRAPPERS` that take a value as the next word, e.g. `sudo -u root`
+const WRAPPER_OPTIONS_WITH_VALUE: &[&str] = &["-u", "-g", "-C", "-D", "-h", "-p", "-r", "-t", "-U", "-S"];
+
+// The file name of `binary`, e.g. `curl` for `/usr/bin/curl` and `./curl`
+fn binary_name(binary: &str) -> &str {
+    Path::new(binary).file_name().and_then(|name| name.to_str()).unwrap_or(binary)
+}
+
+// Splits on unquoted `|`, `;`, `&` and newlines and returns the first word of each
+// segment, skipping leading `NAME=value` assignments. For a wrapper like `env` or `sudo`,
+// the binary it runs, i.e. the first word after its options, is returned too. Command
+// substitutions aren't inspected, so this is a guard rail rather than a sandbox
+fn command_binaries(command: &str) -> Vec<String> {
+    let mut binaries = Vec::new();
+    let mut words: Vec<String> = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote: Option<char> = None;
+
+    let mut finish_segment = |words: &mut Vec<String>| {
+        let mut idx = 0;
+        while let Some(word) = words.get(idx) {
+            idx += 1;
+            if is_assignment(word) {
+                continue;
+            }
+            binaries.push(word.clone());
+            if !WRAPPERS.contains(&binary_name(word)) {
+                break;
+            }
+            // Skip the wrapper's options, up to `--` or the first other word
+            while let Some(option) = words.get(idx).filter(|w| w.starts_with('-')) {
+                idx += if WRAPPER_OPTIONS_WITH_VALUE.contains(&option.as_str()) { 2 } else { 1 };
+                if option == "--" {
+                    break;
+                }
+            }
+        }
+        words.clear();
+    };
+
+    for ch in command.chars() {
+        match quote {
+            Some(q) if ch == q => quote = None,
+            Some(_) => word.push(ch),
+            None => match ch {
+                '\'' | '"' => {
+                    quote = Some(ch);
+                    in_word = true;
+                }
+                '|' | ';' | '&' | '\n' => {
+                    if in_word {
+                        words.push(std::mem::take(&mut word));
+                        in_word = false;
+                    }
+                    finish_segment(&mut words);
+                }
+                c if c.is_whitespace() => {
+                    if in_word {
+                        words.push(std::mem::take(&mut word));
+                        in_word = false;
+                    }
+                }
+                c => {
+                    word.push(c);
+                    in_word = true;
+                }
+            },
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    finish_segment(&mut words);
+
+    binaries
+}
+
+fn is_assignment(word: &str) -> bool {
+    match word.split_once('=') {
+        Some((name, _)) => !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        None => false,
+    }
+}
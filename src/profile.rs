@@ -0,0 +1,120 @@
+//! Named bundles of settings for `--profile`, e.g. one per environment, read from a JSON config file
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::{Map, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where `--profile` looks up profiles unless `--config-file` says otherwise
+pub const DEFAULT_CONFIG_FILE: &str = ".sops-shell.json";
+
+/// Fields a profile may set, anything else in it is an error so a typo doesn't go unnoticed
+const FIELDS: [&str; 4] = ["env", "sops_config", "env_profile", "sops_args"];
+
+/// A profile of the config file, e.g.
+/// `{"profiles": {"prod": {"env": {"VAULT_ADDR": "https://vault.prod"}, "sops_config": "prod/.sops.yaml"}}}`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    pub name: String,
+    /// Set for directive commands, ahead of `--env` so a flag for the same variable wins
+    pub env: Vec<(String, String)>,
+    /// Passed to sops as `--config`, relative to the config file's directory
+    pub sops_config: Option<PathBuf>,
+    /// Directive environment to use when `--env-profile` isn't given
+    pub env_profile: Option<String>,
+    /// Passed to sops ahead of any `--sops-arg`
+    pub sops_args: Vec<String>,
+}
+
+impl Profile {
+    /// The profile `name` of the config file at `path`
+    pub fn load(path: &Path, name: &str) -> Result<Profile> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {} for profile '{}'", path.display(), name))?;
+        let mut profile =
+            Profile::parse(&content, name).with_context(|| format!("Invalid config file {}", path.display()))?;
+        if let Some(config) = profile.sops_config.take() {
+            let dir = path.parent().unwrap_or(Path::new(""));
+            profile.sops_config = Some(if config.is_absolute() { config } else { dir.join(config) });
+        }
+        Ok(profile)
+    }
+
+    /// The profile `name` of a config file's `content`, with paths as written
+    pub fn parse(content: &str, name: &str) -> Result<Profile> {
+        let config: Value = serde_json::from_str(content)?;
+        let profiles = config
+            .get("profiles")
+            .and_then(Value::as_object)
+            .ok_or_else(|| anyhow!("Expected a \"profiles\" object"))?;
+        let Some(fields) = profiles.get(name) else {
+            let known: Vec<&str> = profiles.keys().map(String::as_str).collect();
+            return Err(anyhow!("No profile '{}', known profiles are: {}", name, known.join(", ")));
+        };
+        let fields = fields.as_object().ok_or_else(|| anyhow!("Profile '{}' isn't an object", name))?;
+        if let Some(unknown) = fields.keys().find(|field| !FIELDS.contains(&field.as_str())) {
+            return Err(anyhow!(
+                "Unknown field '{}' in profile '{}', expected one of: {}",
+                unknown,
+                name,
+                FIELDS.join(", ")
+            ));
+        }
+
+        let invalid = |field: &str, expected: &str| anyhow!("'{}' of profile '{}' must be {}", field, name, expected);
+        let string = |field: &str| -> Result<Option<String>> {
+            match fields.get(field) {
+                None => Ok(None),
+                Some(Value::String(value)) => Ok(Some(value.clone())),
+                Some(_) => Err(invalid(field, "a string")),
+            }
+        };
+        let env = match fields.get("env") {
+            None => Vec::new(),
+            Some(Value::Object(vars)) => string_map(vars).ok_or_else(|| invalid("env", "an object of strings"))?,
+            Some(_) => return Err(invalid("env", "an object of strings")),
+        };
+        let sops_args = match fields.get("sops_args") {
+            None => Vec::new(),
+            Some(Value::Array(args)) => args
+                .iter()
+                .map(|arg| arg.as_str().map(str::to_string))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| invalid("sops_args", "an array of strings"))?,
+            Some(_) => return Err(invalid("sops_args", "an array of strings")),
+        };
+
+        Ok(Profile {
+            name: name.to_string(),
+            env,
+            sops_config: string("sops_config")?.map(PathBuf::from),
+            env_profile: string("env_profile")?,
+            sops_args,
+        })
+    }
+
+    /// Arguments for every sops invocation, `--config` first
+    pub fn sops_args(&self) -> Vec<String> {
+        let config = self.sops_config.iter().flat_map(|config| ["--config".to_string(), config.display().to_string()]);
+        config.chain(self.sops_args.iter().cloned()).collect()
+    }
+
+    /// Settings the profile changes, named as in the output of the `config` subcommand
+    pub fn settings(&self) -> Vec<&'static str> {
+        let mut settings = Vec::new();
+        if !self.env.is_empty() {
+            settings.push("env_vars");
+        }
+        if self.sops_config.is_some() || !self.sops_args.is_empty() {
+            settings.push("sops_args");
+        }
+        if self.env_profile.is_some() {
+            settings.push("env_profile");
+        }
+        settings
+    }
+}
+
+fn string_map(vars: &Map<String, Value>) -> Option<Vec<(String, String)>> {
+    vars.iter().map(|(name, value)| Some((name.clone(), value.as_str()?.to_string()))).collect()
+}
@@ -0,0 +1,35 @@
+//! Data key rotation for the `reencrypt` subcommand, which leaves values to `sync`
+
+use anyhow::{anyhow, Result};
+use std::path::Path;
+
+use crate::runner::CommandRunner;
+use crate::sops::{sops_rotate, SopsOptions};
+
+/// Rotates the data key of each file with `sops --rotate --in-place`. A file that fails doesn't
+/// stop the others, but fails the run once all were tried
+pub fn reencrypt_files(runner: &dyn CommandRunner, files: &[impl AsRef<Path>], sops: &SopsOptions) -> Result<()> {
+    let mut failed = 0;
+
+    for file in files {
+        let file = file.as_ref();
+        println!("\nRe-encrypting {}...", file.display());
+        match sops_rotate(runner, file, sops) {
+            Ok(()) => println!("  Rotated data key"),
+            Err(e) => {
+                println!("  Error: Failed to rotate data key: {}", e.to_string().trim());
+                failed += 1;
+            }
+        }
+    }
+
+    println!("\n{}", "=".repeat(60));
+    println!("Summary:");
+    println!("  Files re-encrypted: {}", files.len() - failed);
+    if failed > 0 {
+        println!("  Errors: {}", failed);
+        return Err(anyhow!("Failed to re-encrypt {} file(s)", failed));
+    }
+
+    Ok(())
+}
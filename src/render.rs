@@ -0,0 +1,136 @@
+//! `check --render`: a file as a sync would leave it, with every value masked
+
+use crate::format::FileFormat;
+use crate::parser::{line_key, split_quoted, strip_assignment};
+use crate::plan::{mask_in, MASK};
+use crate::sync::SyncOptions;
+
+/// `decrypted` with `updates` applied and every value masked, updated ones marked `(updated)`
+/// and keys the file doesn't have yet appended as `(added)`. Comments and directives are kept,
+/// minus any value that appears in them. Values spanning several lines, i.e. YAML block scalars,
+/// are masked line by line, or replaced by a single masked line when updated
+pub fn render_updated(decrypted: &str, updates: &[(String, String)], options: &SyncOptions) -> String {
+    let (format, assign_ops) = (options.parse.format, &options.parse.assign_ops);
+    let ignore_case = options.ignore_key_case && FileFormat::case_insensitive_keys(format);
+    let update_of = |key: &str| {
+        let matches = |update: &str| if ignore_case { update.eq_ignore_ascii_case(key) } else { update == key };
+        updates.iter().position(|(update, _)| matches(update))
+    };
+
+    // Every value is masked in comments too, e.g. the `echo` command of a directive
+    let mut secrets: Vec<&str> = decrypted
+        .lines()
+        .filter_map(|line| split_entry(line, format, assign_ops))
+        .map(|(_, value)| value.trim())
+        .collect();
+    secrets.extend(updates.iter().map(|(_, value)| value.as_str()));
+
+    let mut applied = vec![false; updates.len()];
+    let mut lines: Vec<String> = Vec::new();
+    // Indentation of the key of the block scalar being masked, and whether it was updated
+    let mut block: Option<(usize, bool)> = None;
+    for line in decrypted.lines() {
+        let indent = line.len() - line.trim_start().len();
+        if let Some((block_indent, updated)) = block {
+            if line.trim().is_empty() || indent > block_indent {
+                if !updated && !line.trim().is_empty() {
+                    lines.push(format!("{}{}", &line[..indent], MASK));
+                }
+                continue;
+            }
+            block = None;
+        }
+
+        let trimmed = line.trim_start();
+        if trimmed.starts_with(['#', ';']) {
+            lines.push(secrets.iter().fold(line.to_string(), |line, secret| mask_in(&line, secret)));
+            continue;
+        }
+        let entry = line_key(line, format, assign_ops).zip(split_entry(line, format, assign_ops));
+        let Some((key, (head, value))) = entry else {
+            lines.push(line.to_string());
+            continue;
+        };
+
+        let update = update_of(key);
+        if let Some(idx) = update {
+            applied[idx] = true;
+        }
+        // The value without its comment is a slice of `value`, though not always from its start
+        let core = FileFormat::strip_inline_comment(value, format);
+        let start = core.as_ptr() as usize - value.as_ptr() as usize;
+        let comment = &value[start + core.len()..];
+        let lead = &value[..start + core.len() - core.trim_start().len()];
+        let core = core.trim();
+        let keeps_structure = core.is_empty() || (format == Some(FileFormat::Json) && core.starts_with(['{', '[']));
+        if update.is_none() && keeps_structure {
+            lines.push(line.to_string());
+            continue;
+        }
+        if matches!(format, Some(FileFormat::Yaml) | None) && is_block_indicator(core) {
+            block = Some((indent, update.is_some()));
+            if update.is_none() {
+                lines.push(line.to_string());
+                continue;
+            }
+        }
+
+        let mask = if update.is_some() { format!("{} (updated)", MASK) } else { MASK.to_string() };
+        let comma = if core.ends_with(',') { "," } else { "" };
+        let quote = if core.starts_with('"') || format == Some(FileFormat::Json) { "\"" } else { "" };
+        let spaced = matches!(format, Some(FileFormat::Yaml) | Some(FileFormat::Json));
+        let lead = if lead.is_empty() && spaced { " " } else { lead };
+        lines.push(format!("{}{}{}{}{}{}{}", head, lead, quote, mask, quote, comma, comment));
+    }
+
+    let added: Vec<&str> =
+        updates.iter().zip(&applied).filter(|(_, applied)| !**applied).map(|((key, _), _)| key.as_str()).collect();
+    append_added(&mut lines, &added, format);
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+// `line` up to and including the separator after its key, and the value that follows
+fn split_entry<'a>(line: &'a str, format: Option<FileFormat>, assign_ops: &[String]) -> Option<(&'a str, &'a str)> {
+    let stripped = FileFormat::strip_export(line.trim(), format);
+    let rest = match split_quoted(stripped) {
+        Some((_, rest)) => rest,
+        None => &stripped[line_key(stripped, format, assign_ops)?.len()..],
+    };
+    let value = strip_assignment(rest, format, assign_ops)?;
+    let end = line.trim_end().len();
+    Some((&line[..end - value.len()], value))
+}
+
+// `|`, `>-`, `|+2` and the like, starting a YAML block scalar on the lines below
+fn is_block_indicator(value: &str) -> bool {
+    let mut chars = value.chars();
+    matches!(chars.next(), Some('|' | '>')) && chars.all(|ch| matches!(ch, '-' | '+' | '1'..='9'))
+}
+
+fn append_added(lines: &mut Vec<String>, keys: &[&str], format: Option<FileFormat>) {
+    let mask = format!("{} (added)", MASK);
+    if format != Some(FileFormat::Json) {
+        let separator = if matches!(format, Some(FileFormat::Env) | Some(FileFormat::Ini)) { "=" } else { ": " };
+        lines.extend(keys.iter().map(|key| format!("{}{}{}", key, separator, mask)));
+        return;
+    }
+
+    // Inside the top-level object, before its closing brace
+    let Some(close) = lines.iter().rposition(|line| line.trim() == "}") else {
+        return;
+    };
+    let mut entries: Vec<String> = keys.iter().map(|key| format!("  \"{}\": \"{}\"", key, mask)).collect();
+    if entries.is_empty() {
+        return;
+    }
+    if let Some(previous) = lines[..close].iter_mut().rev().find(|line| !line.trim().is_empty()) {
+        if !previous.trim_end().ends_with([',', '{']) {
+            previous.push(',');
+        }
+    }
+    let last = entries.len() - 1;
+    for entry in &mut entries[..last] {
+        entry.push(',');
+    }
+    lines.splice(close..close, entries);
+}
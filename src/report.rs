@@ -0,0 +1,163 @@
+use anyhow::{anyhow, Context, Result};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// JUnit XML, with a test suite per file and a test case per key
+    Junit,
+}
+
+/// Where a report is written and in which format, given as `<format>:<path>`, e.g. `junit:report.xml`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReportTarget {
+    pub format: ReportFormat,
+    pub path: PathBuf,
+}
+
+impl FromStr for ReportTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(raw: &str) -> Result<Self> {
+        let (format, path) = raw
+            .split_once(':')
+            .filter(|(_, path)| !path.is_empty())
+            .ok_or_else(|| anyhow!("expected <format>:<path>, e.g. junit:report.xml"))?;
+        let format = match format {
+            "junit" => ReportFormat::Junit,
+            other => return Err(anyhow!("unknown report format '{}', expected junit", other)),
+        };
+        Ok(ReportTarget { format, path: PathBuf::from(path) })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CaseResult {
+    Passed,
+    Failed(String),
+    Skipped(String),
+}
+
+#[derive(Debug)]
+struct TestCase {
+    key: String,
+    result: CaseResult,
+    duration: Duration,
+}
+
+/// Outcome of every key in a run, for CI test dashboards. Values are never included
+#[derive(Debug)]
+pub struct Report {
+    target: ReportTarget,
+    /// Test cases by file, in the order files were processed
+    suites: Vec<(String, Vec<TestCase>)>,
+}
+
+impl Report {
+    pub fn new(target: &ReportTarget) -> Self {
+        Report { target: target.clone(), suites: Vec::new() }
+    }
+
+    pub fn record(&mut self, file: &Path, key: &str, result: CaseResult, duration: Duration) {
+        let file = file.display().to_string();
+        let index = match self.suites.iter().position(|(name, _)| *name == file) {
+            Some(index) => index,
+            None => {
+                self.suites.push((file, Vec::new()));
+                self.suites.len() - 1
+            }
+        };
+        self.suites[index].1.push(TestCase { key: key.to_string(), result, duration });
+    }
+
+    /// Replaces the result recorded for `key`, e.g. once an out of sync key has been updated
+    pub fn resolve(&mut self, file: &Path, key: &str, result: CaseResult) {
+        let file = file.display().to_string();
+        let cases = self.suites.iter_mut().filter(|(name, _)| *name == file).flat_map(|(_, cases)| cases);
+        if let Some(case) = cases.filter(|case| case.key == key).last() {
+            case.result = result;
+        }
+    }
+
+    pub fn render(&self) -> String {
+        match self.target.format {
+            ReportFormat::Junit => self.render_junit(),
+        }
+    }
+
+    fn render_junit(&self) -> String {
+        let count = |cases: &[TestCase], matches: fn(&CaseResult) -> bool| cases.iter().filter(|case| matches(&case.result)).count();
+        let failed = |result: &CaseResult| matches!(result, CaseResult::Failed(_));
+        let skipped = |result: &CaseResult| matches!(result, CaseResult::Skipped(_));
+        let all: Vec<&TestCase> = self.suites.iter().flat_map(|(_, cases)| cases).collect();
+
+        let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        let _ = writeln!(
+            out,
+            "<testsuites name=\"sops-shell\" tests=\"{}\" failures=\"{}\" skipped=\"{}\">",
+            all.len(),
+            all.iter().filter(|case| failed(&case.result)).count(),
+            all.iter().filter(|case| skipped(&case.result)).count(),
+        );
+        for (file, cases) in &self.suites {
+            let time: Duration = cases.iter().map(|case| case.duration).sum();
+            let _ = writeln!(
+                out,
+                "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">",
+                escape(file),
+                cases.len(),
+                count(cases, failed),
+                count(cases, skipped),
+                time.as_secs_f64(),
+            );
+            for case in cases {
+                let _ = write!(
+                    out,
+                    "    <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\"",
+                    escape(file),
+                    escape(&case.key),
+                    case.duration.as_secs_f64(),
+                );
+                match &case.result {
+                    CaseResult::Passed => out.push_str("/>\n"),
+                    CaseResult::Failed(message) => {
+                        let _ = writeln!(out, ">\n      <failure message=\"{}\"/>\n    </testcase>", escape(message));
+                    }
+                    CaseResult::Skipped(message) => {
+                        let _ = writeln!(out, ">\n      <skipped message=\"{}\"/>\n    </testcase>", escape(message));
+                    }
+                }
+            }
+            out.push_str("  </testsuite>\n");
+        }
+        out.push_str("</testsuites>\n");
+        out
+    }
+
+    pub fn save(&self) -> Result<()> {
+        fs::write(&self.target.path, self.render())
+            .with_context(|| format!("Failed to write report {}", self.target.path.display()))
+    }
+}
+
+// For attribute values. Control characters other than tab and newline aren't allowed in XML 1.0 at all
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\n' => escaped.push_str("&#10;"),
+            '\t' => escaped.push_str("&#9;"),
+            c if c.is_control() => escaped.push('\u{FFFD}'),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
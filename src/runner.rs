@@ -0,0 +1,365 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// What a finished process left behind
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandOutput {
+    /// `None` when the process was terminated by a signal
+    pub code: Option<i32>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+impl CommandOutput {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+/// PATH given to commands run with a cleared environment, unless `vars` sets one
+pub const MINIMAL_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+/// Output limit of directive commands unless set otherwise, far beyond any secret
+pub const DEFAULT_MAX_OUTPUT_BYTES: usize = 4 * 1024 * 1024;
+
+/// Environment of directive commands, relative to sops-shell's own, and what they're wrapped in
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandEnv {
+    /// Start from an empty environment with only `MINIMAL_PATH` instead of inheriting sops-shell's
+    pub clear: bool,
+    /// With `clear`, variables still forwarded from sops-shell's environment, when set there
+    pub pass: Vec<String>,
+    /// Set on top of the inherited or cleared environment
+    pub vars: Vec<(String, String)>,
+    /// Kill commands whose stdout or stderr grows past this many bytes instead of reading the rest
+    pub max_output: Option<usize>,
+    /// Written in front of every command, e.g. `aws-vault exec prod --`
+    pub prefix: Option<String>,
+    /// Written after every command, e.g. `2>/dev/null`
+    pub suffix: Option<String>,
+}
+
+impl CommandEnv {
+    /// `command` between `prefix` and `suffix`, joined by spaces as they are, without any quoting.
+    /// The result is run by `sh -c` like any command, so a prefix such as `timeout 30` applies to
+    /// the first command of a pipeline or list only
+    pub fn wrap(&self, command: &str) -> String {
+        let parts = [self.prefix.as_deref(), Some(command), self.suffix.as_deref()];
+        parts.into_iter().flatten().collect::<Vec<_>>().join(" ")
+    }
+
+    fn apply(&self, command: &mut Command) {
+        if self.clear {
+            command.env_clear().env("PATH", MINIMAL_PATH);
+            for name in &self.pass {
+                if let Some(value) = std::env::var_os(name) {
+                    command.env(name, value);
+                }
+            }
+        }
+        command.envs(self.vars.iter().map(|(name, value)| (name, value)));
+    }
+}
+
+/// Spawns the external processes sops-shell depends on, i.e. sops itself and directive commands
+pub trait CommandRunner: Send + Sync {
+    /// Runs `program` to completion. An `Err` means it couldn't be started at all
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput>;
+
+    /// Like `run`, but fails with `io::ErrorKind::TimedOut` once `timeout` passes. Runners
+    /// that don't spawn real processes can rely on the default, which ignores the timeout
+    fn run_with_timeout(&self, program: &str, args: &[&str], timeout: Option<Duration>) -> io::Result<CommandOutput> {
+        let _ = timeout;
+        self.run(program, args)
+    }
+
+    /// Like `run_with_timeout`, but with `env` applied to the process' environment. Runners that
+    /// don't spawn real processes can rely on the default, which ignores it
+    fn run_with_env(
+        &self,
+        program: &str,
+        args: &[&str],
+        env: &CommandEnv,
+        timeout: Option<Duration>,
+    ) -> io::Result<CommandOutput> {
+        let _ = env;
+        self.run_with_timeout(program, args, timeout)
+    }
+
+    /// Like `run`, but leaves stderr attached to the terminal so prompts, e.g. for an MFA code
+    /// or a hardware key touch, reach the user. The returned `stderr` is empty
+    fn run_interactive(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+        self.run(program, args)
+    }
+
+    /// Like `run`, but with stdin, stdout and stderr all attached to the terminal, e.g. for sops'
+    /// editor. The returned `stdout` and `stderr` are empty
+    fn run_attached(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+        self.run(program, args)
+    }
+
+    /// Like `run_with_timeout`, or `run_interactive` when `interactive`, but passes stdout to `on_line`
+    /// a line at a time instead of collecting it, so the returned `stdout` is empty. The default
+    /// splits the output of those
+    fn run_lines(
+        &self,
+        program: &str,
+        args: &[&str],
+        timeout: Option<Duration>,
+        interactive: bool,
+        on_line: &mut dyn FnMut(&str),
+    ) -> io::Result<CommandOutput> {
+        let output = if interactive {
+            self.run_interactive(program, args)?
+        } else {
+            self.run_with_timeout(program, args, timeout)?
+        };
+        String::from_utf8_lossy(&output.stdout).lines().for_each(&mut *on_line);
+        Ok(CommandOutput { stdout: Vec::new(), ..output })
+    }
+}
+
+/// Runs commands as real child processes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+        let output = Command::new(program)
+            .args(args)
+            // Commands may prompt, e.g. for an MFA code
+            .stdin(Stdio::inherit())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()?;
+
+        Ok(CommandOutput {
+            code: output.status.code(),
+            stdout: output.stdout,
+            stderr: output.stderr,
+        })
+    }
+
+    fn run_with_timeout(&self, program: &str, args: &[&str], timeout: Option<Duration>) -> io::Result<CommandOutput> {
+        run_until(Command::new(program).args(args), timeout, None)
+    }
+
+    fn run_with_env(
+        &self,
+        program: &str,
+        args: &[&str],
+        env: &CommandEnv,
+        timeout: Option<Duration>,
+    ) -> io::Result<CommandOutput> {
+        let mut command = Command::new(program);
+        command.args(args);
+        env.apply(&mut command);
+        run_until(&mut command, timeout, env.max_output)
+    }
+
+    fn run_interactive(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+        let output = Command::new(program)
+            .args(args)
+            .stdin(Stdio::inherit())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .output()?;
+
+        Ok(CommandOutput {
+            code: output.status.code(),
+            stdout: output.stdout,
+            stderr: output.stderr,
+        })
+    }
+
+    fn run_attached(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+        let status = Command::new(program)
+            .args(args)
+            .stdin(Stdio::inherit())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .status()?;
+
+        Ok(CommandOutput { code: status.code(), ..Default::default() })
+    }
+
+    fn run_lines(
+        &self,
+        program: &str,
+        args: &[&str],
+        timeout: Option<Duration>,
+        interactive: bool,
+        on_line: &mut dyn FnMut(&str),
+    ) -> io::Result<CommandOutput> {
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::inherit())
+            .stdout(Stdio::piped())
+            .stderr(if interactive { Stdio::inherit() } else { Stdio::piped() })
+            .spawn()?;
+        let stderr = child.stderr.take().map(|pipe| read_in_background(pipe, None, Arc::default()));
+
+        // Read on another thread so the timeout is kept while waiting for a line. The channel is bounded,
+        // so a consumer slower than the process holds back the reading rather than buffering the output
+        let (sender, lines) = mpsc::sync_channel::<io::Result<String>>(64);
+        if let Some(stdout) = child.stdout.take() {
+            thread::spawn(move || {
+                for line in BufReader::new(stdout).lines() {
+                    let failed = line.is_err();
+                    if sender.send(line).is_err() || failed {
+                        break;
+                    }
+                }
+            });
+        }
+
+        let deadline = timeout.filter(|_| !interactive).map(|timeout| (Instant::now() + timeout, timeout));
+        let read = loop {
+            let line = match deadline {
+                Some((deadline, _)) => lines.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+                None => lines.recv().map_err(RecvTimeoutError::from),
+            };
+            match line {
+                Ok(Ok(line)) => on_line(&line),
+                Ok(Err(e)) => break Err(e),
+                Err(RecvTimeoutError::Disconnected) => break Ok(()),
+                Err(RecvTimeoutError::Timeout) => {
+                    let timeout = deadline.map(|(_, timeout)| timeout).unwrap_or_default();
+                    break Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("timed out after {}s", timeout.as_secs_f64()),
+                    ));
+                }
+            }
+        };
+
+        // Neither a timeout nor output that isn't text may leave the process blocked on the pipe
+        if read.is_err() {
+            let _ = child.kill();
+        }
+        let status = child.wait()?;
+        read?;
+
+        Ok(CommandOutput {
+            code: status.code(),
+            stdout: Vec::new(),
+            stderr: stderr.and_then(|reader| reader.join().ok()).unwrap_or_default(),
+        })
+    }
+}
+
+/// Runs `command` with stdout and stderr captured, killing it once `timeout` passes or either
+/// output grows past `max_output` bytes
+fn run_until(command: &mut Command, timeout: Option<Duration>, max_output: Option<usize>) -> io::Result<CommandOutput> {
+    // Commands may prompt, e.g. for an MFA code
+    command.stdin(Stdio::inherit()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    if timeout.is_none() && max_output.is_none() {
+        let output = command.output()?;
+        return Ok(CommandOutput {
+            code: output.status.code(),
+            stdout: output.stdout,
+            stderr: output.stderr,
+        });
+    }
+
+    let mut child = command.spawn()?;
+
+    // Drain both pipes while waiting so a chatty command can't block on a full pipe
+    let exceeded = Arc::new(AtomicBool::new(false));
+    let stdout = child.stdout.take().map(|pipe| read_in_background(pipe, max_output, Arc::clone(&exceeded)));
+    let stderr = child.stderr.take().map(|pipe| read_in_background(pipe, max_output, Arc::clone(&exceeded)));
+    let too_much_output = |max_output: Option<usize>| {
+        io::Error::new(io::ErrorKind::FileTooLarge, format!("output exceeded {} bytes", max_output.unwrap_or_default()))
+    };
+
+    let deadline = timeout.map(|timeout| (Instant::now() + timeout, timeout));
+    let status = loop {
+        if exceeded.load(Ordering::Relaxed) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(too_much_output(max_output));
+        }
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if let Some((deadline, timeout)) = deadline {
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("timed out after {}s", timeout.as_secs_f64()),
+                ));
+            }
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    let collect = |reader: Option<thread::JoinHandle<Vec<u8>>>| reader.and_then(|reader| reader.join().ok()).unwrap_or_default();
+    let (stdout, stderr) = (collect(stdout), collect(stderr));
+    // The limit may have been hit right before the command exited
+    if exceeded.load(Ordering::Relaxed) {
+        return Err(too_much_output(max_output));
+    }
+    Ok(CommandOutput {
+        code: status.code(),
+        stdout,
+        stderr,
+    })
+}
+
+/// Reads `pipe` to the end, or until it gives more than `limit` bytes, which sets `exceeded`
+fn read_in_background(
+    mut pipe: impl Read + Send + 'static,
+    limit: Option<usize>,
+    exceeded: Arc<AtomicBool>,
+) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        let _ = match limit {
+            Some(limit) => pipe.take(limit as u64 + 1).read_to_end(&mut buffer),
+            None => pipe.read_to_end(&mut buffer),
+        };
+        if let Some(limit) = limit.filter(|limit| buffer.len() > *limit) {
+            exceeded.store(true, Ordering::Relaxed);
+            buffer.truncate(limit);
+        }
+        buffer
+    })
+}
+
+/// Shared handle to a `CommandRunner`, defaulting to `SystemRunner`
+#[derive(Clone)]
+pub struct Runner(Arc<dyn CommandRunner>);
+
+impl Runner {
+    pub fn new(runner: impl CommandRunner + 'static) -> Self {
+        Runner(Arc::new(runner))
+    }
+}
+
+impl Default for Runner {
+    fn default() -> Self {
+        Runner::new(SystemRunner)
+    }
+}
+
+impl fmt::Debug for Runner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Runner")
+    }
+}
+
+impl std::ops::Deref for Runner {
+    type Target = dyn CommandRunner;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
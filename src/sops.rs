@@ -1,51 +1,406 @@
 use anyhow::{anyhow, Context, Result};
 use serde_json::{json, Value};
-use std::path::Path;
-use std::process::Command;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
 
-pub fn sops_decrypt(filepath: &Path) -> Result<String> {
-    run_sops_command(vec!["--decrypt", &filepath.to_string_lossy()])
+use crate::format::FileFormat;
+use crate::runner::{CommandOutput, CommandRunner};
+
+/// sops' exit status when its editor is closed without changing the file
+pub const SOPS_FILE_UNCHANGED: i32 = 200;
+
+#[derive(Debug, Clone, Default)]
+pub struct SopsOptions {
+    /// Passed verbatim to every sops invocation, ahead of the operation's own arguments
+    pub extra_args: Vec<String>,
+    /// Overrides sops' own detection of the input and output type
+    pub format_as: Option<FileFormat>,
+    /// How many times a decrypt or set is retried after a transient failure
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each one after it
+    pub retry_delay: Duration,
+    /// Show sops' prompts, e.g. for an MFA code or a hardware key touch, by leaving its stderr on the terminal
+    pub interactive: bool,
+    /// Give up on a non-interactive sops invocation after this long, as it's likely waiting on a prompt
+    pub prompt_timeout: Option<Duration>,
+    /// Newlines of values written with `sops_set`, kept as they are when unset
+    pub line_ending: Option<LineEnding>,
+    /// Plaintext of earlier decrypts for `sops_decrypt` to reuse, see `DecryptCache`. Without it,
+    /// every decrypt runs sops
+    pub cache: Option<Arc<DecryptCache>>,
+    /// Told about every retry, e.g. to report it as progress. Retries happen silently without it
+    pub on_retry: Option<RetryHook>,
+}
+
+/// A retry of a sops invocation after a transient failure, see `with_retries`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryNotice {
+    /// 1 for the first retry
+    pub attempt: u32,
+    pub retries: u32,
+    /// The failure that's being retried
+    pub error: String,
+}
+
+impl fmt::Display for RetryNotice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Retrying sops after transient failure (attempt {}/{}): {}",
+            self.attempt, self.retries, self.error
+        )
+    }
+}
+
+/// Callback for `SopsOptions::on_retry`
+#[derive(Clone)]
+pub struct RetryHook(Arc<dyn Fn(&RetryNotice) + Send + Sync>);
+
+impl RetryHook {
+    pub fn new(hook: impl Fn(&RetryNotice) + Send + Sync + 'static) -> Self {
+        RetryHook(Arc::new(hook))
+    }
+}
+
+impl fmt::Debug for RetryHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RetryHook")
+    }
+}
+
+/// Line ending of multi-line values, e.g. CRLF for consumers on Windows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+}
+
+impl LineEnding {
+    /// `value` with each of its newlines, `\n` or `\r\n`, turned into this line ending
+    pub fn apply(self, value: &str) -> String {
+        let lf = value.replace("\r\n", "\n");
+        match self {
+            LineEnding::Lf => lf,
+            LineEnding::Crlf => lf.replace('\n', "\r\n"),
+        }
+    }
+}
+
+impl SopsOptions {
+    fn type_args(&self) -> Vec<&str> {
+        match self.format_as {
+            Some(format) => vec!["--input-type", format.sops_type(), "--output-type", format.sops_type()],
+            None => Vec::new(),
+        }
+    }
+
+    /// These options with a new, empty cache, shared by their clones
+    pub fn with_cache(&self) -> SopsOptions {
+        SopsOptions { cache: Some(Arc::default()), ..self.clone() }
+    }
+
+    // Drops the cached plaintext of `filepath`, ahead of a sops invocation writing to it
+    fn invalidate_cached(&self, filepath: &Path) {
+        if let Some(cache) = &self.cache {
+            cache.invalidate(filepath);
+        }
+    }
+}
+
+/// Decrypts `filepath`, or returns its plaintext from an earlier decrypt through `options.cache` when
+/// the file hasn't changed since, e.g. when it's decrypted again to apply updates
+pub fn sops_decrypt(runner: &dyn CommandRunner, filepath: &Path, options: &SopsOptions) -> Result<String> {
+    if let Some(decrypted) = options.cache.as_ref().and_then(|cache| cache.get(filepath, options)) {
+        return Ok(decrypted);
+    }
+
+    let started = SystemTime::now();
+    let path = filepath.to_string_lossy();
+    let mut args = options.type_args();
+    args.extend(["--decrypt", &path]);
+    let decrypted = with_retries(options, || run_sops_command(runner, options, &args))?;
+    if let Some(cache) = &options.cache {
+        cache.insert(filepath, options, &decrypted, started);
+    }
+    Ok(decrypted)
+}
+
+/// Like `sops_decrypt`, but hands the plaintext to `on_line` a line at a time as sops produces it, for files
+/// too large to hold in memory. `on_line` folds the lines into an `S`, which a retry starts over from, as
+/// lines of a failed attempt have already been consumed. Never cached
+pub fn sops_decrypt_lines<S: Default>(
+    runner: &dyn CommandRunner,
+    filepath: &Path,
+    options: &SopsOptions,
+    mut on_line: impl FnMut(&mut S, &str),
+) -> Result<S> {
+    ensure_sops_available(runner)?;
+
+    let path = filepath.to_string_lossy();
+    let mut args: Vec<&str> = options.extra_args.iter().map(String::as_str).collect();
+    args.extend(options.type_args());
+    args.extend(["--decrypt", &path]);
+    with_retries(options, || {
+        let mut folded = S::default();
+        let output = runner.run_lines("sops", &args, options.prompt_timeout, options.interactive, &mut |line| {
+            on_line(&mut folded, line)
+        });
+        successful_output(output, options)?;
+        Ok(folded)
+    })
+}
+
+/// Plaintext of the files `sops_decrypt` decrypted, by path and the sops arguments that shape the
+/// output. Each run owns one, so plaintext isn't kept past the run or shared with another runner.
+/// An entry is only used while the file's modification time and size are as they were when it was
+/// decrypted, and writes through sops drop it. Files modified shortly before their decrypt aren't
+/// cached at all: on filesystems with coarse timestamps, a write right after could leave the
+/// modification time as it was
+#[derive(Debug, Default)]
+pub struct DecryptCache {
+    entries: Mutex<HashMap<(PathBuf, Vec<String>), CachedDecrypt>>,
+}
+
+#[derive(Debug)]
+struct CachedDecrypt {
+    modified: SystemTime,
+    len: u64,
+    plaintext: String,
+}
+
+/// How long before its decrypt a file must have last been modified for the plaintext to be cached
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+impl DecryptCache {
+    pub fn get(&self, filepath: &Path, options: &SopsOptions) -> Option<String> {
+        let metadata = std::fs::metadata(filepath).ok()?;
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let cached = entries.get(&Self::key(filepath, options))?;
+        let unchanged = metadata.modified().ok() == Some(cached.modified) && metadata.len() == cached.len;
+        unchanged.then(|| cached.plaintext.clone())
+    }
+
+    /// Keeps `plaintext` of `filepath` as decrypted by a sops invocation that started at `started`
+    pub fn insert(&self, filepath: &Path, options: &SopsOptions, plaintext: &str, started: SystemTime) {
+        let Ok(metadata) = std::fs::metadata(filepath) else {
+            return;
+        };
+        let Ok(modified) = metadata.modified() else {
+            return;
+        };
+        let settled = started.duration_since(modified).is_ok_and(|age| age >= RACY_WINDOW);
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let key = Self::key(filepath, options);
+        if settled {
+            let cached = CachedDecrypt { modified, len: metadata.len(), plaintext: plaintext.to_string() };
+            entries.insert(key, cached);
+        } else {
+            entries.remove(&key);
+        }
+    }
+
+    /// Drops every entry of `filepath`, ahead of a sops invocation writing to it
+    pub fn invalidate(&self, filepath: &Path) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.retain(|(path, _), _| path != filepath);
+    }
+
+    fn key(filepath: &Path, options: &SopsOptions) -> (PathBuf, Vec<String>) {
+        let mut args = options.extra_args.clone();
+        args.extend(options.type_args().into_iter().map(str::to_string));
+        (filepath.to_path_buf(), args)
+    }
+}
+
+pub fn sops_set(runner: &dyn CommandRunner, filepath: &Path, key: &str, value: &str, options: &SopsOptions) -> Result<()> {
+    let json_value = match options.line_ending {
+        Some(ending) => format_value_for_sops(&ending.apply(value))?,
+        None => format_value_for_sops(value)?,
+    };
+    let path = format!("{} {}", key_path(filepath, key, options), json_value);
+
+    options.invalidate_cached(filepath);
+    let filepath = filepath.to_string_lossy();
+    let mut args = options.type_args();
+    args.extend(["--set", &path, &filepath]);
+    with_retries(options, || run_sops_command(runner, options, &args))?;
+    Ok(())
+}
+
+/// The exact value of `key`, surrounding whitespace included, which the decrypted file may only
+/// have in another form, e.g. a YAML block scalar
+pub fn sops_extract(runner: &dyn CommandRunner, filepath: &Path, key: &str, options: &SopsOptions) -> Result<String> {
+    let path = key_path(filepath, key, options);
+    let filepath = filepath.to_string_lossy();
+    let mut args = options.type_args();
+    args.extend(["--decrypt", "--extract", &path, &filepath]);
+    with_retries(options, || run_sops_command(runner, options, &args))
+}
+
+/// Opens `filepath` in sops' editor for the user to change by hand. Returns whether the file was
+/// saved with changes, sops exits with `SOPS_FILE_UNCHANGED` when it wasn't
+pub fn sops_edit(runner: &dyn CommandRunner, filepath: &Path, options: &SopsOptions) -> Result<bool> {
+    ensure_sops_available(runner)?;
+
+    options.invalidate_cached(filepath);
+    let filepath = filepath.to_string_lossy();
+    let mut args: Vec<&str> = options.extra_args.iter().map(String::as_str).collect();
+    args.extend(options.type_args());
+    args.push(&filepath);
+    let output = runner.run_attached("sops", &args).context("Failed to execute sops command")?;
+    match output.code {
+        Some(0) => Ok(true),
+        Some(SOPS_FILE_UNCHANGED) => Ok(false),
+        Some(code) => Err(anyhow!("sops editor exited with status {}", code)),
+        None => Err(anyhow!("sops editor was terminated by a signal")),
+    }
 }
 
-pub fn sops_set(filepath: &Path, key: &str, value: &str) -> Result<()> {
-    let json_value = format_value_for_sops(value)?;
-    let path = if filepath.extension().and_then(|s| s.to_str()) == Some("ini") {
+// sops' tree path of a top-level `key`
+fn key_path(filepath: &Path, key: &str, options: &SopsOptions) -> String {
+    if FileFormat::resolve(filepath, options.format_as) == Some(FileFormat::Ini) {
         // For ini files, assume keys are in [config] section
-        &format!(r#"["config"]["{}"] {}"#, key, json_value)
+        format!(r#"["config"]["{}"]"#, key)
     } else {
         // For other formats, use direct key path
-        &format!(r#"["{}"] {}"#, key, json_value)
-    };
+        format!(r#"["{}"]"#, key)
+    }
+}
+
+/// Encrypts a plaintext file in place, so sops picks the keys from the creation rule matching its path
+pub fn sops_encrypt_in_place(runner: &dyn CommandRunner, filepath: &Path, options: &SopsOptions) -> Result<()> {
+    options.invalidate_cached(filepath);
+    let filepath = filepath.to_string_lossy();
+    let mut args = options.type_args();
+    args.extend(["--encrypt", "--in-place", &filepath]);
+    with_retries(options, || run_sops_command(runner, options, &args))?;
+    Ok(())
+}
 
-    run_sops_command(vec![
-        "--set",
-        path,
-        &filepath.to_string_lossy()
-    ])?;
+/// Re-encrypts `filepath` in place under a new data key, leaving its values as they are
+pub fn sops_rotate(runner: &dyn CommandRunner, filepath: &Path, options: &SopsOptions) -> Result<()> {
+    options.invalidate_cached(filepath);
+    let filepath = filepath.to_string_lossy();
+    let mut args = options.type_args();
+    args.extend(["--rotate", "--in-place", &filepath]);
+    with_retries(options, || run_sops_command(runner, options, &args))?;
     Ok(())
 }
 
-fn run_sops_command(args: Vec<&str>) -> Result<String> {
-    if Command::new("sops").arg("--version").output().is_err() {
+// Failures worth another attempt, e.g. KMS throttling. Anything else (bad credentials,
+// missing keys, a broken config) would fail the same way again
+const RETRYABLE_PATTERNS: &[&str] = &[
+    "throttl",
+    "rate exceeded",
+    "too many requests",
+    "timeout",
+    "timed out",
+    "temporarily unavailable",
+    "service unavailable",
+    "connection reset",
+];
+
+fn is_retryable(error: &anyhow::Error) -> bool {
+    let message = format!("{:#}", error).to_lowercase();
+    RETRYABLE_PATTERNS.iter().any(|pattern| message.contains(pattern))
+}
+
+/// Runs `operation`, retrying retryable failures with exponential backoff
+pub(crate) fn with_retries<T>(options: &SopsOptions, mut operation: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut delay = options.retry_delay;
+    let mut attempt = 0;
+
+    loop {
+        match operation() {
+            Err(e) if attempt < options.retries && is_retryable(&e) => {
+                attempt += 1;
+                if let Some(RetryHook(hook)) = &options.on_retry {
+                    hook(&RetryNotice { attempt, retries: options.retries, error: e.to_string().trim().to_string() });
+                }
+                thread::sleep(delay);
+                delay = delay.saturating_mul(2);
+            }
+            result => return result,
+        }
+    }
+}
+
+fn ensure_sops_available(runner: &dyn CommandRunner) -> Result<()> {
+    if runner.run("sops", &["--version"]).is_err() {
         return Err(anyhow!("SOPS command not found. Please install SOPS or ensure it's in PATH"));
     }
+    Ok(())
+}
 
-    let output = Command::new("sops")
-        .args(args)
-        .output()
-        .context("Failed to execute sops command")?;
+fn run_sops_command(runner: &dyn CommandRunner, options: &SopsOptions, args: &[&str]) -> Result<String> {
+    ensure_sops_available(runner)?;
 
-    if !output.status.success() {
+    let mut full_args: Vec<&str> = options.extra_args.iter().map(String::as_str).collect();
+    full_args.extend_from_slice(args);
+    let output = if options.interactive {
+        runner.run_interactive("sops", &full_args)
+    } else {
+        runner.run_with_timeout("sops", &full_args, options.prompt_timeout)
+    };
+    let output = successful_output(output, options)?;
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// The output of a sops run that finished successfully, with a hint at `--interactive` when it timed out
+fn successful_output(output: io::Result<CommandOutput>, options: &SopsOptions) -> Result<CommandOutput> {
+    let output = match output {
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+            let waited = options.prompt_timeout.unwrap_or_default().as_secs();
+            return Err(anyhow!(
+                "sops didn't finish within {}s, it may be waiting for an MFA code or a hardware key touch; \
+                 rerun with --interactive to see its prompts",
+                waited
+            ));
+        }
+        output => output.context("Failed to execute sops command")?,
+    };
+
+    if !output.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
         return Err(anyhow!("SOPS command failed: {}", stderr));
     }
 
-    Ok(String::from_utf8(output.stdout)?)
+    Ok(output)
 }
 
-fn format_value_for_sops(value: &str) -> Result<String> {
+pub(crate) fn format_value_for_sops(value: &str) -> Result<String> {
+    // JSON would be written without the whitespace, which untrimmed output means to keep
+    if value.trim() != value {
+        return Ok(json!(value).to_string());
+    }
     match serde_json::from_str::<Value>(value) {
-        Ok(parsed) => Ok(json!(parsed).to_string()),
+        Ok(parsed) => Ok(canonical_json(&parsed)),
         Err(_) => Ok(json!(value).to_string()),
     }
 }
+
+// Object keys are sorted explicitly rather than relying on serde_json's map ordering,
+// which changes as soon as any crate in the build enables `preserve_order`
+fn canonical_json(value: &Value) -> String {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<(&String, &Value)> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            let fields: Vec<String> = entries
+                .into_iter()
+                .map(|(key, value)| format!("{}:{}", json!(key), canonical_json(value)))
+                .collect();
+            format!("{{{}}}", fields.join(","))
+        }
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(canonical_json).collect();
+            format!("[{}]", items.join(","))
+        }
+        other => other.to_string(),
+    }
+}
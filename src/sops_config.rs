@@ -0,0 +1,108 @@
+//! File discovery from the `creation_rules` of a sops config, for `--from-sops-config`
+
+use anyhow::{anyhow, Context, Result};
+use regex::Regex;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const PATH_REGEX_PATTERN: &str = r"^\s*(?:-\s+)?path_regex:\s*(.*?)\s*$";
+
+/// The `path_regex` of every creation rule in `content`, a `.sops.yaml`. Rules without one, which
+/// sops applies to any file, are left out, since they'd match plaintext files just as well
+pub fn creation_rule_patterns(content: &str) -> Result<Vec<Regex>> {
+    let path_regex = Regex::new(PATH_REGEX_PATTERN)?;
+    let mut patterns = Vec::new();
+
+    for (i, line) in content.lines().enumerate() {
+        let Some(captures) = path_regex.captures(line) else {
+            continue;
+        };
+        let raw = scalar(&captures[1]);
+        if raw.is_empty() {
+            return Err(anyhow!("Empty path_regex on line {}", i + 1));
+        }
+        let pattern = Regex::new(&raw).with_context(|| format!("Invalid path_regex '{}' on line {}", raw, i + 1))?;
+        patterns.push(pattern);
+    }
+
+    Ok(patterns)
+}
+
+/// Files under the directory of `config` whose path relative to it, with `/` separators, matches
+/// one of its creation rules. Sorted, and listed once however many rules match them
+pub fn discover_files(config: &Path) -> Result<Vec<PathBuf>> {
+    let content = fs::read_to_string(config).with_context(|| format!("Failed to read {}", config.display()))?;
+    let patterns =
+        creation_rule_patterns(&content).with_context(|| format!("Invalid sops config {}", config.display()))?;
+    if patterns.is_empty() {
+        return Err(anyhow!("{} has no creation rule with a path_regex", config.display()));
+    }
+
+    let root = match config.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let mut files = Vec::new();
+    collect_matches(&root, &root, &patterns, config, &mut files)?;
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+fn collect_matches(root: &Path, dir: &Path, patterns: &[Regex], config: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let entries = fs::read_dir(dir).with_context(|| format!("Failed to list {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry.with_context(|| format!("Failed to list {}", dir.display()))?;
+        let path = entry.path();
+        // Not followed into symlinked directories, which could loop
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if entry.file_name() != ".git" {
+                collect_matches(root, &path, patterns, config, files)?;
+            }
+            continue;
+        }
+        if dir == root && Some(entry.file_name().as_os_str()) == config.file_name() {
+            continue;
+        }
+
+        let relative: Vec<String> = path
+            .strip_prefix(root)
+            .unwrap_or(&path)
+            .components()
+            .map(|part| part.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        if patterns.iter().any(|pattern| pattern.is_match(&relative.join("/"))) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+// A YAML scalar as written after a key, quoted or plain. Single quotes escape a quote by doubling it,
+// double quotes with a backslash. In a plain one, `#` only starts a comment after whitespace
+fn scalar(raw: &str) -> String {
+    if let Some(inner) = raw.strip_prefix('\'') {
+        let end = inner.rfind('\'').unwrap_or(inner.len());
+        return inner[..end].replace("''", "'");
+    }
+    if let Some(inner) = raw.strip_prefix('"') {
+        let end = inner.rfind('"').unwrap_or(inner.len());
+        let mut unescaped = String::new();
+        let mut chars = inner[..end].chars().peekable();
+        while let Some(ch) = chars.next() {
+            match (ch, chars.peek()) {
+                ('\\', Some(&next @ ('\\' | '"'))) => {
+                    unescaped.push(next);
+                    chars.next();
+                }
+                _ => unescaped.push(ch),
+            }
+        }
+        return unescaped;
+    }
+    match raw.find(" #") {
+        Some(idx) => raw[..idx].trim_end().to_string(),
+        None => raw.to_string(),
+    }
+}
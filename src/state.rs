@@ -0,0 +1,118 @@
+use anyhow::{Context, Result};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::hash::sha256_hex;
+
+/// Digests of the directive commands that last produced each stored value, and when a sync last
+/// updated each key, persisted between runs
+#[derive(Debug, Default)]
+pub struct CommandState {
+    path: PathBuf,
+    // file -> key -> command digest
+    files: BTreeMap<String, BTreeMap<String, String>>,
+    // file -> key -> seconds since the unix epoch
+    synced: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+impl CommandState {
+    /// Loads the state file, starting empty when it doesn't exist yet
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut state = CommandState {
+            path: path.to_path_buf(),
+            files: BTreeMap::new(),
+            synced: BTreeMap::new(),
+        };
+        if !path.exists() {
+            return Ok(state);
+        }
+
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read state file {}", path.display()))?;
+        let parsed: Value = serde_json::from_str(&content)
+            .with_context(|| format!("Invalid state file {}", path.display()))?;
+
+        let files = parsed.get("files").and_then(Value::as_object).into_iter().flatten();
+        for (file, keys) in files {
+            let keys = keys.as_object().into_iter().flatten();
+            let digests = keys
+                .filter_map(|(key, digest)| Some((key.clone(), digest.as_str()?.to_string())))
+                .collect();
+            state.files.insert(file.clone(), digests);
+        }
+
+        // Absent from state files written before update times were recorded
+        let synced = parsed.get("synced").and_then(Value::as_object).into_iter().flatten();
+        for (file, keys) in synced {
+            let keys = keys.as_object().into_iter().flatten();
+            let times = keys.filter_map(|(key, time)| Some((key.clone(), time.as_u64()?))).collect();
+            state.synced.insert(file.clone(), times);
+        }
+
+        Ok(state)
+    }
+
+    pub fn is_unchanged(&self, file: &Path, key: &str, command: &str) -> bool {
+        self.files
+            .get(&file.display().to_string())
+            .and_then(|keys| keys.get(key))
+            .is_some_and(|digest| *digest == command_digest(command))
+    }
+
+    pub fn record(&mut self, file: &Path, key: &str, command: &str) {
+        self.files
+            .entry(file.display().to_string())
+            .or_default()
+            .insert(key.to_string(), command_digest(command));
+    }
+
+    /// Records that a sync updated `key` at `time`
+    pub fn record_sync(&mut self, file: &Path, key: &str, time: SystemTime) {
+        let seconds = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        self.synced.entry(file.display().to_string()).or_default().insert(key.to_string(), seconds);
+    }
+
+    /// When a sync last updated each key of `file`, in seconds since the unix epoch
+    pub fn sync_times(&self, file: &Path) -> BTreeMap<String, u64> {
+        self.synced.get(&file.display().to_string()).cloned().unwrap_or_default()
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let files: Map<String, Value> = self
+            .files
+            .iter()
+            .map(|(file, keys)| (file.clone(), json!(keys)))
+            .collect();
+        let mut state = json!({ "files": files });
+        if !self.synced.is_empty() {
+            state["synced"] = json!(self.synced);
+        }
+        let content = serde_json::to_string_pretty(&state)?;
+        fs::write(&self.path, content + "\n")
+            .with_context(|| format!("Failed to write state file {}", self.path.display()))
+    }
+}
+
+fn command_digest(command: &str) -> String {
+    sha256_hex(command)
+}
+
+/// `seconds` since the unix epoch as an RFC 3339 UTC timestamp, e.g. `2024-05-01T09:30:00Z`
+pub fn format_timestamp(seconds: u64) -> String {
+    // Days to a civil date, from Howard Hinnant's `civil_from_days`
+    let days = (seconds / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    let time = seconds % 86_400;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, time / 3_600, time % 3_600 / 60, time % 60)
+}
@@ -1,383 +1,8417 @@
 use anyhow::{anyhow, Context, Result};
-use std::path::Path;
-use std::process::Command;
+use regex::Regex;
+use serde_json::{json, Value};
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::parser::parse_commands;
-use crate::sops::{sops_decrypt, sops_set};
+use crate::audit::{AuditEntry, AuditLog};
+use crate::condition::Condition;
+use crate::confirm::Confirmer;
+use crate::emit::emit_value;
+use crate::format::FileFormat;
+use crate::git;
+use crate::hash::{self, hash_key, salted_hash, sha256_hex};
+use crate::hooks::run_hook;
+#[cfg(feature = "keyring")]
+use crate::keyring::{self, KeyringStore};
+use crate::lock::{lock_file, FileFingerprint};
+use crate::metrics::RunMetrics;
+use crate::output::{OutputFormat, Printer};
+use crate::parser::{
+    line_key, parse_commands, parse_commands_with_includes, split_quoted, strip_assignment, Capture, CommandMapping,
+    ParseOptions, ValueSource,
+};
+use crate::plaintext::plaintext_values;
+use crate::plan::{mask_in, Plan};
+use crate::policy::{references_file, CommandPolicy};
+use crate::render::render_updated;
+use crate::report::{CaseResult, Report, ReportTarget};
+use crate::runner::{CommandEnv, CommandOutput, CommandRunner, Runner};
+use crate::sops::{
+    sops_decrypt, sops_decrypt_lines, sops_edit, sops_encrypt_in_place, sops_extract, sops_set, RetryHook, SopsOptions,
+};
+use crate::state::{format_timestamp, CommandState};
+use crate::table::Table;
+use crate::template::Template;
 
-fn print_file_error(operation: &str, error: &anyhow::Error) {
-    println!("  Error: Failed to {}: {}", operation, error);
+fn print_file_error(out: &Printer, filepath: &Path, operation: &str, error: &anyhow::Error) {
+    out.text(format_args!("  Error: Failed to {}: {}", operation, error));
+    out.event(json!({
+        "event": "file_error",
+        "file": filepath.display().to_string(),
+        "operation": operation,
+        "error": error.to_string(),
+    }));
 }
 
-fn print_command_error(error: &anyhow::Error) {
-    println!("    Error: Command failed");
+fn format_command_error(error: &anyhow::Error) -> String {
+    let mut lines = vec!["    Error: Command failed".to_string()];
     for msg in error.chain() {
-        println!("    {}", msg);
+        lines.push(format!("    {}", msg));
     }
+    lines.join("\n")
 }
 
-pub fn execute_command(command: &str) -> Result<String> {
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(command)
-        .envs(std::env::vars())
-        .stdin(std::process::Stdio::inherit())
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .output()
-        .context("Failed to execute command")?;
+pub fn execute_command(
+    runner: &dyn CommandRunner,
+    command: &str,
+    user: Option<&str>,
+    env: &CommandEnv,
+    value_from: ValueSource,
+    capture: Capture,
+    timeout: Option<Duration>,
+) -> Result<String> {
+    command_value(run_command(runner, command, user, env, timeout)?, value_from, capture, true)
+}
+
+/// Renders a `shell-template` directive, running the command of each placeholder with `execute_command`.
+/// The first command to fail fails the whole value
+pub fn execute_template(
+    runner: &dyn CommandRunner,
+    template: &Template,
+    user: Option<&str>,
+    env: &CommandEnv,
+    timeout: Option<Duration>,
+) -> Result<String> {
+    template.render(|command| {
+        execute_command(runner, command, user, env, ValueSource::Stdout, Capture::Stdout, timeout)
+            .with_context(|| format!("Template command `{}` failed", command))
+    })
+}
+
+/// The command producing the value of `mapping`, found in `filepath`. For a `shell-file` directive,
+/// that runs the script, resolved against the file's directory, with the arguments as written
+pub fn resolved_command(mapping: &CommandMapping, filepath: &Path) -> Result<String> {
+    if !mapping.script {
+        return Ok(mapping.command.clone());
+    }
+
+    let reference = mapping.command.trim();
+    let (script, args) = reference.split_once(char::is_whitespace).unwrap_or((reference, ""));
+    let path = filepath.parent().unwrap_or(Path::new("")).join(script);
+    // Absolute, so a script next to the file isn't looked up in PATH instead
+    let path = std::fs::canonicalize(&path).with_context(|| format!("Script {} doesn't exist", path.display()))?;
+    let metadata = std::fs::metadata(&path).with_context(|| format!("Failed to read script {}", path.display()))?;
+    if !metadata.is_file() {
+        return Err(anyhow!("Script {} isn't a file", path.display()));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o111 == 0 {
+            return Err(anyhow!("Script {} isn't executable, run `chmod +x` on it", path.display()));
+        }
+    }
+
+    // Quoted for the shell, which still splits and expands the arguments as written
+    let quoted = format!("'{}'", path.to_string_lossy().replace('\'', r"'\''"));
+    Ok(format!("{} {}", quoted, args.trim()).trim_end().to_string())
+}
+
+/// Runs `command` through `shell_invocation`, leaving its output to `command_value`
+pub fn run_command(
+    runner: &dyn CommandRunner,
+    command: &str,
+    user: Option<&str>,
+    env: &CommandEnv,
+    timeout: Option<Duration>,
+) -> Result<CommandOutput> {
+    let (program, args) = shell_invocation(runner, &env.wrap(command), user)?;
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let output = runner.run_with_env(program, &args, env, timeout);
+
+    // Runners that don't stop a command at `max_output` are checked once it's done
+    let exceeded = match (&output, env.max_output) {
+        (Err(error), _) => error.kind() == std::io::ErrorKind::FileTooLarge,
+        (Ok(output), Some(max_output)) => output.stdout.len() > max_output || output.stderr.len() > max_output,
+        (Ok(_), None) => false,
+    };
+    if exceeded {
+        return Err(anyhow!("Command output exceeded the limit of {} bytes", env.max_output.unwrap_or_default()));
+    }
+    output.context("Failed to execute command")
+}
 
-    if !output.status.success() {
+/// The value a directive takes from its command's `output`. Without `trim`, surrounding whitespace
+/// such as a trailing newline is kept, and `Capture::Both` joins stdout and stderr as they are
+pub fn command_value(output: CommandOutput, value_from: ValueSource, capture: Capture, trim: bool) -> Result<String> {
+    if value_from == ValueSource::ExitCode {
+        let code = output.code.ok_or_else(|| anyhow!("Command was terminated by a signal"))?;
+        return Ok(code.to_string());
+    }
+
+    if !output.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
         return Err(anyhow!("Command failed: {}", stderr.trim()));
     }
 
-    Ok(String::from_utf8(output.stdout)?.trim().to_string())
-}
+    let value = match capture {
+        Capture::Stdout => String::from_utf8(output.stdout)?,
+        Capture::Stderr => String::from_utf8(output.stderr)?,
+        Capture::Both => {
+            let stdout = String::from_utf8(output.stdout)?;
+            let stderr = String::from_utf8(output.stderr)?;
+            if !trim {
+                return Ok(stdout + &stderr);
+            }
+            format!("{}\n{}", stdout.trim(), stderr.trim())
+        }
+    };
+    Ok(if trim { value.trim().to_string() } else { value })
+}
+
+/// Whether `command` is a `keyring://` reference, which doesn't spawn anything, so binary
+/// policies don't apply to it
+#[cfg(feature = "keyring")]
+pub(crate) fn reads_keyring(command: &str) -> bool {
+    keyring::parse_reference(command).is_some()
+}
+
+#[cfg(not(feature = "keyring"))]
+pub(crate) fn reads_keyring(_command: &str) -> bool {
+    false
+}
+
+/// Why the commands of `mapping` may not run according to `SyncOptions::confirm`, `None` when they may
+pub(crate) fn declined(mapping: &CommandMapping, options: &SyncOptions) -> Option<anyhow::Error> {
+    let confirmer = options.confirm.as_ref()?;
+    match confirmer.confirm(&mapping.key, mapping.setup.as_deref(), &mapping.command) {
+        Ok(true) => None,
+        Ok(false) => Some(anyhow!("declined at the confirmation prompt")),
+        Err(e) => Some(e),
+    }
+}
+
+/// The first capture group of `regex` in `output`, for the `extract=` directive option. The error
+/// leaves the output out, as it may well contain the secret
+pub fn extract_capture(regex: &Regex, output: &str) -> Result<String> {
+    let captures = regex
+        .captures(output)
+        .ok_or_else(|| anyhow!("extract regex '{}' doesn't match the command output", regex))?;
+    captures
+        .get(1)
+        .map(|group| group.as_str().to_string())
+        .ok_or_else(|| anyhow!("extract regex '{}' matched without its first capture group", regex))
+}
+
+/// Program and arguments running `command` through `sh -c`, as `user` when given. Prefers
+/// non-interactive sudo and falls back to su
+pub fn shell_invocation(
+    runner: &dyn CommandRunner,
+    command: &str,
+    user: Option<&str>,
+) -> Result<(&'static str, Vec<String>)> {
+    let Some(user) = user else {
+        return Ok(("sh", vec!["-c".to_string(), command.to_string()]));
+    };
+
+    let available = |program: &str, args: &[&str]| runner.run(program, args).is_ok();
+    if available("sudo", &["-V"]) {
+        let args = ["-n", "-u", user, "--", "sh", "-c", command];
+        Ok(("sudo", args.iter().map(|arg| arg.to_string()).collect()))
+    } else if available("su", &["--version"]) {
+        let args = ["-s", "/bin/sh", "-c", command, user];
+        Ok(("su", args.iter().map(|arg| arg.to_string()).collect()))
+    } else {
+        Err(anyhow!("Running as user '{}' requires sudo or su, neither is available", user))
+    }
+}
+
+pub fn parse_decrypted_value(decrypted_content: &str, key: &str, format: Option<FileFormat>) -> Option<String> {
+    find_decrypted_entry(decrypted_content, key, format, false).map(|(_, value)| value)
+}
+
+/// Like `parse_decrypted_value`, but also returns the key as it's spelled in the file. With
+/// `ignore_case`, formats with case-insensitive keys match e.g. `api_key` against `API_KEY`
+pub fn find_decrypted_entry(
+    decrypted_content: &str,
+    key: &str,
+    format: Option<FileFormat>,
+    ignore_case: bool,
+) -> Option<(String, String)> {
+    find_decrypted_entry_with_ops(decrypted_content, key, format, ignore_case, &[])
+}
+
+/// `find_decrypted_entry` for files whose keys may also be followed by one of `assign_ops`
+pub fn find_decrypted_entry_with_ops(
+    decrypted_content: &str,
+    key: &str,
+    format: Option<FileFormat>,
+    ignore_case: bool,
+    assign_ops: &[String],
+) -> Option<(String, String)> {
+    let ignore_case = ignore_case && FileFormat::case_insensitive_keys(format);
+    let entry = |line| {
+        parse_value_line(line, key, format, ignore_case, assign_ops)
+            .map(|(stored_key, value)| (stored_key.to_string(), value))
+    };
+    if FileFormat::last_definition_wins(format) {
+        decrypted_content.lines().rev().find_map(entry)
+    } else {
+        decrypted_content.lines().find_map(entry)
+    }
+}
+
+/// How many times `key` is defined in `decrypted_content`, counted in formats where the last
+/// definition wins only, see `FileFormat::last_definition_wins`, as elsewhere the same key can
+/// legitimately appear more than once, e.g. nested under different YAML keys or ini sections
+pub fn key_definitions(
+    decrypted_content: &str,
+    key: &str,
+    format: Option<FileFormat>,
+    ignore_case: bool,
+    assign_ops: &[String],
+) -> usize {
+    if !FileFormat::last_definition_wins(format) {
+        return 1;
+    }
+    let ignore_case = ignore_case && FileFormat::case_insensitive_keys(format);
+    decrypted_content.lines().filter(|line| parse_value_line(line, key, format, ignore_case, assign_ops).is_some()).count()
+}
+
+/// Top-level keys defined in `decrypted_content`, sorted. Nested keys, i.e. indented in YAML or
+/// inside a JSON object, aren't included, and neither are comments or ini section headers
+pub fn decrypted_keys(decrypted_content: &str, format: Option<FileFormat>, assign_ops: &[String]) -> Vec<String> {
+    let mut keys = BTreeSet::new();
+    if format == Some(FileFormat::Json) {
+        if let Ok(Value::Object(map)) = serde_json::from_str::<Value>(decrypted_content) {
+            keys.extend(map.keys().cloned());
+        }
+        return keys.into_iter().collect();
+    }
+
+    // Env and ini files have no nesting, and their keys may well be indented
+    let nests = matches!(format, Some(FileFormat::Yaml) | None);
+    for line in decrypted_content.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with(['#', ';']) || (nests && line.starts_with(char::is_whitespace)) {
+            continue;
+        }
+        if let Some(key) = line_key(line, format, assign_ops) {
+            keys.insert(key.to_string());
+        }
+    }
+    keys.into_iter().collect()
+}
+
+// The key as written and the value if `line` defines `key`
+fn parse_value_line<'a>(
+    line: &'a str,
+    key: &str,
+    format: Option<FileFormat>,
+    ignore_case: bool,
+    assign_ops: &[String],
+) -> Option<(&'a str, String)> {
+    let trimmed = line.trim();
+    if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with(';') {
+        return None;
+    }
+
+    let trimmed = FileFormat::strip_export(trimmed, format);
+    let (stored_key, rest) = match split_quoted(trimmed) {
+        Some(quoted) => quoted,
+        None => trimmed.split_at_checked(key.len())?,
+    };
+    let matches = if ignore_case { stored_key.eq_ignore_ascii_case(key) } else { stored_key == key };
+    if !matches {
+        return None;
+    }
+
+    strip_assignment(rest.trim(), format, assign_ops).map(|value_part| {
+        let value = FileFormat::strip_inline_comment(value_part, format);
+        (stored_key, value.trim().trim_matches('"').to_string())
+    })
+}
+
+/// Size of encrypted files above which they're decrypted a line at a time unless set otherwise
+pub const DEFAULT_STREAM_ABOVE_BYTES: u64 = 8 * 1024 * 1024;
+
+/// Decrypts `filepath` a line at a time without holding its plaintext, for files over
+/// `SyncOptions::stream_above`. Only an outline of it is kept: comments and ini sections as they are,
+/// other lines cut after the key they define, or reduced to their indentation when they define none.
+/// Directives and their keys are found in it as in the plaintext, on the same lines
+fn stream_outline(filepath: &Path, options: &SyncOptions) -> Result<String> {
+    let (format, assign_ops) = (options.parse.format, &options.parse.assign_ops);
+    sops_decrypt_lines(&*options.runner, filepath, &options.sops, |outline: &mut String, line| {
+        outline.push_str(&outline_line(line, format, assign_ops));
+        outline.push('\n');
+    })
+}
+
+fn outline_line<'a>(line: &'a str, format: Option<FileFormat>, assign_ops: &[String]) -> Cow<'a, str> {
+    let trimmed = line.trim();
+    if trimmed.is_empty() || trimmed.starts_with(['#', ';', '[']) {
+        return Cow::Borrowed(line);
+    }
+    let indentation = &line[..line.len() - line.trim_start().len()];
+    // The key is a slice of `line`, and what follows it up to the value is kept, separator and all
+    let value = line_key(line, format, assign_ops).and_then(|key| {
+        let key_end = key.as_ptr() as usize - line.as_ptr() as usize + key.len();
+        let rest = &line[key_end..];
+        strip_assignment(rest.strip_prefix(['"', '\'']).unwrap_or(rest), format, assign_ops)
+    });
+    match value {
+        Some(value) => Cow::Borrowed(&line[..line.len() - value.len()]),
+        // Still a content line, so it isn't skipped over when looking for a directive's key
+        None => Cow::Owned(format!("{}-", indentation)),
+    }
+}
+
+/// `outline`, from `stream_outline`, with the lines defining any of `keys` decrypted again a line at a
+/// time and put back, so their values are looked up as in the plaintext
+fn stream_values(filepath: &Path, outline: &str, keys: &[String], options: &SyncOptions) -> Result<String> {
+    let (format, assign_ops) = (options.parse.format, &options.parse.assign_ops);
+    let ignore_case = options.ignore_key_case && FileFormat::case_insensitive_keys(format);
+    let defines = |line: &str| keys.iter().any(|key| parse_value_line(line, key, format, ignore_case, assign_ops).is_some());
+    let defining = sops_decrypt_lines(&*options.runner, filepath, &options.sops, |defining: &mut Vec<Option<String>>, line| {
+        defining.push(defines(line).then(|| line.to_string()));
+    })?;
+    if defining.len() != outline.lines().count() {
+        return Err(anyhow!("{} changed while it was being decrypted", filepath.display()));
+    }
+
+    Ok(outline
+        .lines()
+        .zip(defining)
+        .map(|(outlined, line)| line.unwrap_or_else(|| outlined.to_string()) + "\n")
+        .collect())
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum CompareMode {
+    /// Compare the value to be written with the stored value as is
+    #[default]
+    Exact,
+    /// Ignore surrounding whitespace of the stored value, e.g. a trailing newline sops keeps
+    TrimStored,
+    /// Trim the command output before it's compared and written, even with `--no-trim`
+    TrimOutput,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+    pub compare_mode: CompareMode,
+    pub format: OutputFormat,
+    pub parse: ParseOptions,
+    pub sops: SopsOptions,
+    pub pre_sync: Option<String>,
+    pub post_sync: Option<String>,
+    pub max_commands: Option<usize>,
+    pub policy: CommandPolicy,
+    /// Refuse commands that reference the file being synced, and keys defined more than once in an
+    /// env file, instead of only warning
+    pub strict: bool,
+    /// Write updates to `<file><suffix>` instead of the file itself
+    pub output_suffix: Option<String>,
+    /// Only print the final summary, errors are counted instead of shown
+    pub summary_only: bool,
+    /// Spawns sops and directive commands, replaceable to run the sync logic without them
+    pub runner: Runner,
+    /// State file recording each key's command, so keys whose command didn't change since the
+    /// last run and that already have a value are skipped
+    pub command_state: Option<PathBuf>,
+    /// Show when a sync last updated each key, as recorded in this state file
+    pub last_synced: Option<PathBuf>,
+    /// In dry runs, describe each key in a sentence instead of the terse status lines
+    pub explain: bool,
+    /// In dry runs, write the updates that would be applied to this file, with values masked
+    pub plan_out: Option<PathBuf>,
+    /// In dry runs, print each file as the sync would leave it, with values masked, see `render_updated`
+    pub render: bool,
+    /// Don't lock files while syncing them, see `lock::lock_file`
+    pub no_lock: bool,
+    /// Wait for a file locked by another run instead of skipping it
+    pub lock_wait: bool,
+    /// Also write each computed value, in plaintext, to a file named after its key in this directory
+    pub emit_dir: Option<PathBuf>,
+    /// Mode for written files, e.g. `0o600`, instead of keeping the original file's permissions (unix only)
+    pub chmod: Option<u32>,
+    /// In a sync, report out of sync secrets but never write them
+    pub detect_only: bool,
+    /// Write the run's totals here in the Prometheus text format
+    pub metrics_file: Option<PathBuf>,
+    /// Write the outcome of every key here, e.g. as JUnit XML for CI test dashboards
+    pub report: Option<ReportTarget>,
+    /// Append a line per value written here, with values masked
+    pub audit_log: Option<AuditLog>,
+    /// Environment directive commands run with
+    pub command_env: CommandEnv,
+    /// Ask before the commands of each directive are run, e.g. for a file from an untrusted source
+    pub confirm: Option<Confirmer>,
+    /// Store that `keyring://` directives are read from
+    #[cfg(feature = "keyring")]
+    pub keyring: Option<KeyringStore>,
+    /// After a sync, rewrite each file through sops so its layout is consistent, see `normalize_file`
+    pub normalize: bool,
+    /// Also write every updated value to these files, see `write_also`
+    pub also_write: Vec<PathBuf>,
+    /// Instead of writing out of sync secrets, list them and open the file in sops' editor to update by hand
+    pub interactive_edit: bool,
+    /// Update the target of a symlinked file instead of the link, which sops may replace with a regular file
+    pub follow_symlinks: bool,
+    /// Refuse to update files with uncommitted changes in git
+    pub require_clean_git: bool,
+    /// Stop the run once this much time has passed, after the command or write in progress
+    pub timeout_total: Option<Duration>,
+    /// Stop processing a file once this much time has passed on it, moving on to the next
+    pub timeout_per_file: Option<Duration>,
+    /// Retry commands whose output is empty like failed ones, for directives with retries. Still
+    /// empty after the last attempt, the key fails instead of being written empty
+    pub retry_on_empty: bool,
+    /// Fail instead of warning when a directive can't be matched to a key
+    pub require_all_directives: bool,
+    /// Abort the run when a file decrypts to nothing but whitespace, see `ensure_not_empty`
+    pub abort_on_empty_file: bool,
+    /// List the keys of each file that no directive covers, see `orphaned_keys`
+    pub report_orphans: bool,
+    /// Show each file's keys as a table once they're all checked, instead of lines as each one is, see `key_table`
+    pub table: bool,
+    /// Match keys case-insensitively in formats where keys are, see `FileFormat::case_insensitive_keys`
+    pub ignore_key_case: bool,
+    /// Keep the surrounding whitespace of command output, e.g. the trailing newline of a PEM key
+    pub no_trim: bool,
+    /// Decrypt files larger than this many bytes a line at a time instead of holding their whole plaintext,
+    /// see `stream_outline`
+    pub stream_above: Option<u64>,
+}
+
+impl SyncOptions {
+    fn printer(&self) -> Printer {
+        Printer::new(self.format).summary_only(self.summary_only)
+    }
+
+    /// The current value of `key` in the decrypted content
+    fn stored_value(&self, decrypted: &str, key: &str) -> Option<String> {
+        find_decrypted_entry_with_ops(decrypted, key, self.parse.format, self.ignore_key_case, &self.parse.assign_ops)
+            .map(|(_, value)| value)
+    }
+
+    /// Like `stored_value`, but for output kept untrimmed with `no_trim` or `keep-trailing-newline` that has
+    /// surrounding whitespace, the value is extracted with sops: decrypted content only has it in a form that
+    /// can't be read back line by line, e.g. a YAML block scalar. A key that can't be extracted counts as missing
+    fn exact_stored_value(
+        &self,
+        filepath: &Path,
+        decrypted: &str,
+        mapping: &CommandMapping,
+        value: &str,
+    ) -> Option<String> {
+        let key = &mapping.key;
+        if !(self.no_trim || mapping.keep_trailing_newline) || value.trim() == value {
+            return self.stored_value(decrypted, key);
+        }
+        sops_extract(&*self.runner, filepath, &self.stored_key(decrypted, key), &self.sops).ok()
+    }
+
+    /// How many times `key` is defined in the decrypted content, see `key_definitions`
+    fn key_definitions(&self, decrypted: &str, key: &str) -> usize {
+        key_definitions(decrypted, key, self.parse.format, self.ignore_key_case, &self.parse.assign_ops)
+    }
+
+    /// The spelling of `key` in the decrypted content, so an update replaces the existing entry
+    fn stored_key(&self, decrypted: &str, key: &str) -> String {
+        find_decrypted_entry_with_ops(decrypted, key, self.parse.format, self.ignore_key_case, &self.parse.assign_ops)
+            .map_or_else(|| key.to_string(), |(stored_key, _)| stored_key)
+    }
+
+    /// Whether `filepath` is decrypted a line at a time, see `stream_above`. JSON is only parsed whole, and
+    /// rendering a dry run shows the whole file
+    fn streams(&self, filepath: &Path, dry_run: bool) -> bool {
+        let Some(limit) = self.stream_above else {
+            return false;
+        };
+        if self.parse.format == Some(FileFormat::Json) || (dry_run && self.render) {
+            return false;
+        }
+        std::fs::metadata(filepath).is_ok_and(|metadata| metadata.len() > limit)
+    }
+
+    /// Resolves per-file settings, such as the file format when it wasn't forced for the whole run
+    pub fn for_file(&self, filepath: &Path) -> SyncOptions {
+        let mut options = self.clone();
+        options.parse.format = FileFormat::resolve(filepath, self.parse.format);
+        options
+    }
+}
+
+/// Bookkeeping shared by every file processed in a single invocation
+#[derive(Debug, Default)]
+pub struct RunState {
+    pub commands_executed: usize,
+    pub command_limit_reached: bool,
+    /// Files, commands, updates and hooks that failed
+    pub errors: usize,
+    /// Loaded from `SyncOptions::command_state`
+    pub command_state: Option<CommandState>,
+    /// Loaded from `SyncOptions::last_synced`, as of the start of the run
+    pub sync_times: Option<CommandState>,
+    /// Collected for `SyncOptions::plan_out`
+    pub plan: Option<Plan>,
+    /// Collected for `SyncOptions::report`
+    pub report: Option<Report>,
+    /// When `SyncOptions::timeout_total` runs out
+    pub deadline: Option<Instant>,
+    pub timed_out: bool,
+    /// When `SyncOptions::timeout_per_file` runs out for the file being processed
+    pub file_deadline: Option<Instant>,
+    pub file_timed_out: bool,
+    /// Files cut short by `SyncOptions::timeout_per_file`
+    pub files_timed_out: usize,
+    /// Directives that couldn't be matched to a key
+    pub unresolved_directives: usize,
+    /// Out of sync secrets left as is because of `SyncOptions::detect_only`
+    pub detected: usize,
+    /// Directive commands that ran and failed
+    pub command_failures: usize,
+    /// Keys whose value is stored unencrypted in the file itself
+    pub plaintext_values: usize,
+    /// Keys without a directive, counted for `SyncOptions::report_orphans`
+    pub orphans: usize,
+    /// Files left undecrypted, e.g. without comment lines or locked by another run
+    pub files_skipped: usize,
+    /// Files that couldn't be locked, decrypted, read or parsed
+    pub files_errored: usize,
+}
+
+impl RunState {
+    fn record_file_error(&mut self, filepath: &Path, operation: &str, error: &anyhow::Error) {
+        if let Some(report) = self.report.as_mut() {
+            report.record(filepath, operation, CaseResult::Failed(format!("{:#}", error)), Duration::ZERO);
+        }
+    }
+
+    fn command_budget_exhausted(&mut self, options: &SyncOptions) -> bool {
+        if options.max_commands.is_some_and(|max| self.commands_executed >= max) {
+            self.command_limit_reached = true;
+        }
+        self.command_limit_reached
+    }
+
+    fn deadline_passed(&mut self) -> bool {
+        if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            self.timed_out = true;
+        }
+        self.timed_out
+    }
+
+    /// Whether the run or the file being processed is out of time
+    fn out_of_time(&mut self) -> bool {
+        if self.file_deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            self.file_timed_out = true;
+        }
+        self.deadline_passed() || self.file_timed_out
+    }
+
+    fn timeout_reason(&self) -> &'static str {
+        if self.timed_out {
+            "Total timeout reached"
+        } else {
+            "File timeout reached"
+        }
+    }
+}
+
+/// The run was cut short by `SyncOptions::timeout_total`
+#[derive(Debug)]
+pub struct TimeoutExceeded {
+    pub timeout: Duration,
+}
+
+impl std::fmt::Display for TimeoutExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Total timeout of {}s exceeded, the run was stopped early", self.timeout.as_secs())
+    }
+}
+
+impl std::error::Error for TimeoutExceeded {}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SyncStatus {
+    InSync,
+    OutOfSync,
+    Skipped,
+}
+
+impl SyncStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SyncStatus::InSync => "in_sync",
+            SyncStatus::OutOfSync => "out_of_sync",
+            SyncStatus::Skipped => "skipped",
+        }
+    }
+}
+
+pub fn compare_value(
+    mapping: &CommandMapping,
+    value: &str,
+    current_value: Option<&str>,
+    compare_mode: CompareMode,
+) -> SyncStatus {
+    if let Some(skip_if) = &mapping.skip_if {
+        if skip_if.is_match(value) {
+            return SyncStatus::Skipped;
+        }
+    }
+
+    // Only the compared copies are trimmed, the value written back stays exact
+    let (value, current_value) = match compare_mode {
+        CompareMode::Exact => (value, current_value),
+        CompareMode::TrimStored => (value, current_value.map(str::trim)),
+        CompareMode::TrimOutput => (value.trim(), current_value),
+    };
+
+    if Some(value) != current_value {
+        SyncStatus::OutOfSync
+    } else {
+        SyncStatus::InSync
+    }
+}
+
+/// Like `compare_value`, but checks the output against the salted hash stored in the
+/// key's companion entry instead of the stored value itself
+pub fn compare_hashed(mapping: &CommandMapping, value: &str, stored_hash: Option<&str>) -> SyncStatus {
+    if let Some(skip_if) = &mapping.skip_if {
+        if skip_if.is_match(value) {
+            return SyncStatus::Skipped;
+        }
+    }
+
+    match stored_hash {
+        Some(stored) if hash::verify(value, stored) => SyncStatus::InSync,
+        _ => SyncStatus::OutOfSync,
+    }
+}
+
+/// Like `compare_value`, but leaves the decision to the directive's `compare-cmd`, run without a
+/// shell and given the stored and the new value as its two arguments. It exits with 0 when they
+/// match. A relative path with a `/`, e.g. `./eq.sh`, is resolved against the file's directory
+pub fn compare_with_command(
+    mapping: &CommandMapping,
+    program: &str,
+    value: &str,
+    current_value: Option<&str>,
+    filepath: &Path,
+    options: &SyncOptions,
+) -> Result<SyncStatus> {
+    if let Some(skip_if) = &mapping.skip_if {
+        if skip_if.is_match(value) {
+            return Ok(SyncStatus::Skipped);
+        }
+    }
+    let Some(current_value) = current_value else {
+        return Ok(SyncStatus::OutOfSync);
+    };
+
+    let program = match Path::new(program) {
+        path if path.is_relative() && program.contains('/') => filepath.parent().unwrap_or(Path::new("")).join(path),
+        path => path.to_path_buf(),
+    };
+    let program = program.to_string_lossy();
+    let output = options
+        .runner
+        .run_with_env(&program, &[current_value, value], &options.command_env, mapping.settings.timeout)
+        .with_context(|| format!("Failed to run compare-cmd {}", program))?;
+    match output.code {
+        Some(0) => Ok(SyncStatus::InSync),
+        Some(_) => Ok(SyncStatus::OutOfSync),
+        None => Err(anyhow!("compare-cmd {} was terminated by a signal", program)),
+    }
+}
+
+#[derive(Debug)]
+pub struct KeyStatus {
+    pub key: String,
+    pub command: String,
+    pub outcome: KeyOutcome,
+    pub warnings: Vec<String>,
+    pub duration: Duration,
+}
+
+#[derive(Debug)]
+pub enum KeyOutcome {
+    /// The command ran, `value` is its output. `hash` is the salted hash to store
+    /// alongside an out-of-sync value for `compare=hash` directives, and `checksum` the
+    /// companion key and SHA-256 of the value for `also-set-sha256` ones
+    Checked {
+        status: SyncStatus,
+        value: String,
+        hash: Option<String>,
+        checksum: Option<(String, String)>,
+    },
+    /// The command policy refused to run the command
+    Refused(anyhow::Error),
+    Failed(anyhow::Error),
+    /// The command is unchanged since the last run and a value is already stored, so it wasn't run
+    Unchanged,
+    /// The directive's `when` condition doesn't hold, so the command wasn't run
+    ConditionNotMet(Condition),
+}
+
+impl KeyOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyOutcome::Checked { status, .. } => status.as_str(),
+            KeyOutcome::Refused(_) => "refused",
+            KeyOutcome::Failed(_) => "error",
+            KeyOutcome::Unchanged => "unchanged",
+            KeyOutcome::ConditionNotMet(_) => "skipped",
+        }
+    }
+}
+
+/// Decrypts the file and reports which secrets are out of sync, without printing or writing anything
+pub fn compute_drift(filepath: &Path, options: &SyncOptions) -> Result<Vec<KeyStatus>> {
+    let options = options.for_file(filepath);
+    let decrypted = sops_decrypt(&*options.runner, filepath, &options.sops)?;
+    let parsed = parse_commands_with_includes(&decrypted, filepath, &options.parse)?;
+    let mut state = RunState::default();
+
+    Ok(drift_for_mappings(filepath, &decrypted, &parsed.mappings, &options, &mut state, |_| {}))
+}
+
+/// The writes a sync would apply for `statuses`, e.g. from `compute_drift`: each out of sync key and
+/// its new value, followed by the companion hash entry of `compare=hash` directives
+pub fn pending_updates(statuses: Vec<KeyStatus>) -> Vec<(String, String)> {
+    statuses
+        .into_iter()
+        .flat_map(|status| match status.outcome {
+            KeyOutcome::Checked { status: SyncStatus::OutOfSync, value, hash, checksum } => {
+                let companion = hash.map(|hash| (hash_key(&status.key), hash));
+                std::iter::once((status.key, value)).chain(companion).chain(checksum).collect()
+            }
+            _ => Vec::new(),
+        })
+        .collect()
+}
+
+/// Writes `updates`, e.g. from `pending_updates` once reviewed, to `filepath` the way a sync does: to
+/// the copy of `output_suffix` or the target of a followed symlink, under the key's spelling in the
+/// file, and keeping the file's permissions unless `chmod` is set. sops sets a single key per call,
+/// so there's one `sops --set` per update, and the file mustn't change in between. Stops at the
+/// first failed write. Returns the path written to
+pub fn apply_updates(filepath: &Path, updates: &[(String, String)], options: &SyncOptions) -> Result<PathBuf> {
+    let options = options.for_file(filepath);
+    let decrypted = sops_decrypt(&*options.runner, filepath, &options.sops)?;
+    let (target, sops) = prepare_write_target(filepath, &options)?;
+    let original_permissions = std::fs::metadata(&target).map(|metadata| metadata.permissions()).ok();
+
+    let mut fingerprint = FileFingerprint::read(&target)?;
+    for (key, value) in updates {
+        fingerprint.ensure_unchanged(&target)?;
+        let stored_key = options.stored_key(&decrypted, key);
+        sops_set(&*options.runner, &target, &stored_key, value, &sops)
+            .with_context(|| format!("Failed to update {} in {}", key, target.display()))?;
+        fingerprint = FileFingerprint::read(&target)?;
+    }
+
+    set_written_permissions(&target, original_permissions, options.chmod)
+        .with_context(|| format!("Couldn't set permissions of {}", target.display()))?;
+    Ok(target)
+}
+
+/// Runs the command of each mapping and compares it against the decrypted content. `on_key`
+/// is called as soon as each key is resolved. Stops early once the command budget is exhausted,
+/// so fewer statuses than mappings may be returned
+pub fn drift_for_mappings(
+    filepath: &Path,
+    decrypted: &str,
+    mappings: &[CommandMapping],
+    options: &SyncOptions,
+    state: &mut RunState,
+    mut on_key: impl FnMut(&KeyStatus),
+) -> Vec<KeyStatus> {
+    let mut statuses = Vec::new();
+    // Output of each `shell-map` command by directive line, so it runs once for all of its keys
+    let mut shared_outputs: HashMap<usize, Result<String, String>> = HashMap::new();
+
+    for mapping in mappings {
+        let unchanged = state
+            .command_state
+            .as_ref()
+            .is_some_and(|command_state| command_state.is_unchanged(filepath, &mapping.key, &mapping.command));
+        let has_value = options
+            .stored_value(decrypted, &mapping.key)
+            .is_some_and(|value| !value.is_empty());
+        if unchanged && has_value {
+            let status = KeyStatus {
+                key: mapping.key.clone(),
+                command: mapping.command.clone(),
+                outcome: KeyOutcome::Unchanged,
+                warnings: Vec::new(),
+                duration: Duration::ZERO,
+            };
+            on_key(&status);
+            statuses.push(status);
+            continue;
+        }
+
+        if let Some(condition) = mapping.when.as_ref().filter(|condition| !condition.holds()) {
+            let status = KeyStatus {
+                key: mapping.key.clone(),
+                command: mapping.command.clone(),
+                outcome: KeyOutcome::ConditionNotMet(condition.clone()),
+                warnings: Vec::new(),
+                duration: Duration::ZERO,
+            };
+            on_key(&status);
+            statuses.push(status);
+            continue;
+        }
+
+        if state.command_budget_exhausted(options) || state.out_of_time() {
+            break;
+        }
+
+        let started = Instant::now();
+        let mut warnings = Vec::new();
+        let self_reference = references_file(&mapping.command, filepath);
+        if self_reference && !options.strict {
+            warnings.push("Command references the file being synced, which may conflict with updating it".to_string());
+        }
+        // The last definition is read, but sops updates the first one it finds
+        let definitions = options.key_definitions(decrypted, &mapping.key);
+        if definitions > 1 && !options.strict {
+            warnings.push(format!(
+                "Key is defined {} times in the file, the last definition is read but an update may change another",
+                definitions
+            ));
+        }
+
+        let policy_check = mapping
+            .commands()
+            .into_iter()
+            .filter(|command| !reads_keyring(command))
+            .try_for_each(|command| options.policy.check(command));
+        // A `shell-map` command that already ran isn't asked about again for its other keys
+        let ran = mapping.shared && shared_outputs.contains_key(&mapping.line);
+        let outcome = if let Err(e) = policy_check {
+            KeyOutcome::Refused(e)
+        } else if self_reference && options.strict {
+            KeyOutcome::Refused(anyhow!("command references the file being synced (--strict)"))
+        } else if definitions > 1 && options.strict {
+            KeyOutcome::Refused(anyhow!("key is defined {} times in the file (--strict)", definitions))
+        } else if let Some(e) = (!ran).then(|| declined(mapping, options)).flatten() {
+            KeyOutcome::Refused(e)
+        } else {
+            let user = mapping.user.as_deref();
+            // Compared as it's written, so a key settles after one sync
+            let trim = !options.no_trim || options.compare_mode == CompareMode::TrimOutput;
+            let output = match shared_outputs.get(&mapping.line).filter(|_| mapping.shared) {
+                Some(cached) => cached.clone().map_err(|e| anyhow!(e)),
+                None => {
+                    state.commands_executed += mapping.commands().len();
+                    let run = || {
+                        let (timeout, env) = (mapping.settings.timeout, &options.command_env);
+                        // A failed setup, e.g. a login, leaves nothing for the value command to work with
+                        if let Some(setup) = &mapping.setup {
+                            let (value_from, capture) = (ValueSource::Stdout, Capture::Stdout);
+                            execute_command(&*options.runner, setup, user, env, value_from, capture, timeout)
+                                .context("Setup command failed")?;
+                        }
+                        if let Some(template) = &mapping.template {
+                            return execute_template(&*options.runner, template, user, env, timeout);
+                        }
+                        #[cfg(feature = "keyring")]
+                        if let Some(value) = keyring::read_reference(options.keyring.as_ref(), &mapping.command) {
+                            return value;
+                        }
+                        let command = resolved_command(mapping, filepath)?;
+                        let output = run_command(&*options.runner, &command, user, env, timeout)?;
+                        command_value(output, mapping.value_from, mapping.capture, trim)
+                    };
+                    let retries = mapping.settings.retries.unwrap_or(0);
+                    let retry_on_empty = options.retry_on_empty && retries > 0;
+                    let retryable = |output: &Result<String>| match output {
+                        Ok(value) => retry_on_empty && value.trim().is_empty(),
+                        Err(_) => true,
+                    };
+                    let mut output = run();
+                    for _ in 0..retries {
+                        // Every attempt counts against --max-commands, so retrying stops once it's used up
+                        if !retryable(&output) || state.command_budget_exhausted(options) {
+                            break;
+                        }
+                        state.commands_executed += mapping.commands().len();
+                        output = run();
+                    }
+                    if retry_on_empty && output.as_ref().is_ok_and(|value| value.trim().is_empty()) {
+                        output = Err(anyhow!("Command output was still empty after {} retries (--retry-on-empty)", retries));
+                    }
+                    if mapping.shared {
+                        let cached = output.as_ref().map(String::clone).map_err(|e| format!("{:#}", e));
+                        shared_outputs.insert(mapping.line, cached);
+                    }
+                    output
+                }
+            };
+            let result = output
+                .and_then(|output| match &mapping.json_path {
+                    Some(path) => path.extract(&output),
+                    None => Ok(output),
+                })
+                .and_then(|output| match &mapping.extract {
+                    Some(regex) => extract_capture(regex, &output),
+                    None => Ok(output),
+                })
+                .and_then(|value| {
+                    // A skip-if sentinel is never written, so it needn't look like the secret
+                    if mapping.skip_if.as_ref().is_some_and(|skip_if| skip_if.is_match(&value)) {
+                        return Ok(value);
+                    }
+                    mapping.checks.check(&value).map(|()| value)
+                })
+                .map(|value| if mapping.keep_trailing_newline { format!("{}\n", value.trim_end()) } else { value })
+                // Compared as it would be written, or a value written with CRLF would never be in sync
+                .map(|value| match options.sops.line_ending {
+                    Some(ending) => ending.apply(&value),
+                    None => value,
+                });
+            match result {
+                Ok(value) if mapping.compare_hash => {
+                    let stored_hash = options.stored_value(decrypted, &hash_key(&mapping.key));
+                    match compare_hashed(mapping, &value, stored_hash.as_deref()) {
+                        SyncStatus::OutOfSync => match salted_hash(&value) {
+                            Ok(hash) => {
+                                KeyOutcome::Checked { status: SyncStatus::OutOfSync, value, hash: Some(hash), checksum: None }
+                            }
+                            Err(e) => KeyOutcome::Failed(e),
+                        },
+                        status => KeyOutcome::Checked { status, value, hash: None, checksum: None },
+                    }
+                }
+                Ok(value) => {
+                    let current_value = options.exact_stored_value(filepath, decrypted, mapping, &value);
+                    let current_value = current_value.as_deref();
+                    let status = match &mapping.compare_cmd {
+                        Some(program) => compare_with_command(mapping, program, &value, current_value, filepath, options),
+                        None => Ok(compare_value(mapping, &value, current_value, options.compare_mode)),
+                    };
+                    match status {
+                        Ok(status) => KeyOutcome::Checked { status, value, hash: None, checksum: None },
+                        Err(e) => KeyOutcome::Failed(e),
+                    }
+                }
+                Err(e) => KeyOutcome::Failed(e),
+            }
+        };
+
+        // A missing or stale checksum is written along with the value, even one that's in sync
+        let outcome = match (outcome, &mapping.also_sha256) {
+            (KeyOutcome::Checked { status, value, hash, .. }, Some(companion)) => {
+                let digest = sha256_hex(&value);
+                let stale = options.stored_value(decrypted, companion).as_deref() != Some(digest.as_str());
+                let status = if status == SyncStatus::InSync && stale { SyncStatus::OutOfSync } else { status };
+                KeyOutcome::Checked { status, value, hash, checksum: Some((companion.clone(), digest)) }
+            }
+            (outcome, _) => outcome,
+        };
+
+        if let (Some(command_state), KeyOutcome::Checked { status: SyncStatus::InSync, .. }) =
+            (state.command_state.as_mut(), &outcome)
+        {
+            command_state.record(filepath, &mapping.key, &mapping.command);
+        }
+
+        let status = KeyStatus {
+            key: mapping.key.clone(),
+            command: mapping.command.clone(),
+            outcome,
+            warnings,
+            duration: started.elapsed(),
+        };
+        on_key(&status);
+        statuses.push(status);
+    }
+
+    statuses
+}
+
+/// Describes what happened, or would happen on a sync, to a key, e.g. "api_key will run `fetch`;
+/// current value present; output differs; will update."
+pub fn explain_key_status(status: &KeyStatus, current_value: Option<&str>) -> String {
+    let current = match current_value {
+        Some(value) if !value.is_empty() => "current value present",
+        _ => "no current value",
+    };
+    let (runs, result) = match &status.outcome {
+        KeyOutcome::Checked { status: SyncStatus::OutOfSync, .. } => ("will run", "output differs; will update".to_string()),
+        KeyOutcome::Checked { status: SyncStatus::InSync, .. } => ("will run", "output matches; nothing to do".to_string()),
+        KeyOutcome::Checked { status: SyncStatus::Skipped, .. } => {
+            ("will run", "output matches skip-if; nothing to do".to_string())
+        }
+        KeyOutcome::Refused(e) => ("would run", format!("command is refused ({}); will be left as is", e)),
+        KeyOutcome::Failed(e) => {
+            let error = format!("{:#}", e);
+            ("will run", format!("command fails ({}); will be left as is", error.trim()))
+        }
+        KeyOutcome::Unchanged => ("won't run", "command unchanged since last run; will be left as is".to_string()),
+        KeyOutcome::ConditionNotMet(condition) => {
+            ("won't run", format!("condition `{}` doesn't hold; will be left as is", condition))
+        }
+    };
+
+    let mut explanation = format!("{} {} `{}`; {}; {}.", status.key, runs, status.command, current, result);
+    for warning in &status.warnings {
+        explanation.push_str(&format!(" Note: {}.", warning));
+    }
+    explanation
+}
+
+/// `last_synced` is `Some` when update times are shown, holding the key's time if it has one
+fn print_key_status(
+    out: &Printer,
+    file: &str,
+    status: &KeyStatus,
+    explanation: Option<String>,
+    last_synced: Option<Option<u64>>,
+) {
+    if let Some(explanation) = explanation {
+        out.text(format_args!("  {}", explanation));
+    } else {
+        print_key_status_lines(out, status);
+    }
+    let last_synced = last_synced.map(|time| time.map(format_timestamp));
+    if let Some(time) = &last_synced {
+        out.text(format_args!("    Last synced: {}", time.as_deref().unwrap_or("never")));
+    }
+
+    let error = match &status.outcome {
+        KeyOutcome::Refused(e) | KeyOutcome::Failed(e) => Some(e.to_string()),
+        KeyOutcome::Checked { .. } | KeyOutcome::Unchanged | KeyOutcome::ConditionNotMet(_) => None,
+    };
+    let mut event = json!({
+        "event": "key",
+        "file": file,
+        "key": status.key,
+        "status": status.outcome.as_str(),
+        "error": error,
+        "warnings": status.warnings,
+        "duration_ms": status.duration.as_millis(),
+    });
+    if let Some(time) = last_synced {
+        event["last_synced"] = json!(time);
+    }
+    out.event(event);
+}
+
+/// One row per key for `SyncOptions::table`. A value that also appears in its key's command, e.g. as
+/// in `echo <secret>`, is masked there
+pub fn key_table(statuses: &[KeyStatus]) -> Table {
+    let mut table = Table::new(&["Key", "Status", "Command", "Duration"]);
+    for status in statuses {
+        let command = match &status.outcome {
+            KeyOutcome::Checked { value, .. } => mask_in(&status.command, value),
+            _ => status.command.clone(),
+        };
+        let duration = format!("{}ms", status.duration.as_millis());
+        table.push(vec![status.key.clone(), status.outcome.as_str().to_string(), command, duration]);
+    }
+    table
+}
+
+fn print_key_status_lines(out: &Printer, status: &KeyStatus) {
+    out.text(format_args!("  {}", status.key));
+    out.text(format_args!("    Command: {}", status.command));
+    for warning in &status.warnings {
+        out.text(format_args!("    Warning: {}", warning));
+    }
+
+    match &status.outcome {
+        KeyOutcome::Checked { status: SyncStatus::OutOfSync, .. } => out.text("    Status: OUT OF SYNC"),
+        KeyOutcome::Checked { status: SyncStatus::InSync, .. } => out.text("    Status: IN SYNC"),
+        KeyOutcome::Checked { status: SyncStatus::Skipped, .. } => {
+            out.text("    Status: IN SYNC (output matched skip-if)")
+        }
+        KeyOutcome::Refused(e) => out.text(format_args!("    Error: Command refused: {}", e)),
+        KeyOutcome::Failed(e) => out.text(format_command_error(e)),
+        KeyOutcome::Unchanged => out.text("    Status: SKIPPED (command unchanged since last run)"),
+        KeyOutcome::ConditionNotMet(condition) => {
+            out.text(format_args!("    Status: SKIPPED (condition `{}` doesn't hold)", condition))
+        }
+    }
+}
+
+/// Fails for decrypted content that is empty or whitespace only, which usually means sops is
+/// misconfigured rather than that the file is meant to be empty
+pub fn ensure_not_empty(filepath: &Path, decrypted: &str) -> Result<()> {
+    if decrypted.trim().is_empty() {
+        return Err(anyhow!(
+            "{} decrypted to empty content, check the sops configuration for it (--abort-on-empty-file)",
+            filepath.display()
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn has_comment_lines(filepath: &Path) -> Result<bool> {
+    use std::fs::File;
+    use std::io::{BufRead, BufReader};
+
+    let file = File::open(filepath)?;
+    let reader = BufReader::new(file);
+
+    for line in reader.lines().take(100) {
+        let line = line?;
+        if line.trim_start().starts_with('#') || line.trim_start().starts_with(';') {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+pub fn process_file(
+    filepath: &Path,
+    dry_run: bool,
+    options: &SyncOptions,
+    state: &mut RunState,
+) -> Result<(usize, usize)> {
+    let out = options.printer();
+    let file = filepath.display().to_string();
+    let started = Instant::now();
+
+    out.text(format_args!("\nProcessing {}...", file));
+    out.event(json!({ "event": "file_start", "file": file }));
+
+    state.file_deadline = options.timeout_per_file.map(|timeout| started + timeout);
+    state.file_timed_out = false;
+    let (secrets, updates) = process_file_contents(filepath, dry_run, options, state, &out)?;
+    if state.file_timed_out {
+        state.files_timed_out += 1;
+        state.errors += 1;
+    }
+
+    out.event(json!({
+        "event": "file_end",
+        "file": file,
+        "secrets": secrets,
+        "updates": updates,
+        "timed_out": state.file_timed_out,
+        "dry_run": dry_run,
+        "duration_ms": started.elapsed().as_millis(),
+    }));
+
+    Ok((secrets, updates))
+}
+
+fn process_file_contents(
+    filepath: &Path,
+    dry_run: bool,
+    options: &SyncOptions,
+    state: &mut RunState,
+    out: &Printer,
+) -> Result<(usize, usize)> {
+    let file = filepath.display().to_string();
+    let options = &options.for_file(filepath);
+
+    // Without directives, every key is an orphan
+    if !options.report_orphans && !has_comment_lines(filepath)? {
+        out.text("  No comment lines found, skipping decryption");
+        state.files_skipped += 1;
+        return Ok((0, 0));
+    }
+
+    // Held from decrypting until the last update, so overlapping runs can't interleave their writes
+    let _lock = if dry_run || options.detect_only || options.no_lock {
+        None
+    } else {
+        match lock_file(filepath, options.lock_wait) {
+            Ok(Some(lock)) => Some(lock),
+            Ok(None) => {
+                let message = "File is locked by another sops-shell run, skipping it (pass --lock-wait to wait instead)";
+                out.text(format_args!("  Warning: {}", message));
+                out.event(json!({ "event": "warning", "file": file, "message": message }));
+                state.files_skipped += 1;
+                return Ok((0, 0));
+            }
+            Err(e) => {
+                print_file_error(out, filepath, "lock", &e);
+                state.errors += 1;
+                state.files_errored += 1;
+                return Ok((0, 0));
+            }
+        }
+    };
+
+    let streamed = options.streams(filepath, dry_run);
+    let decrypted = if streamed {
+        stream_outline(filepath, options)
+    } else {
+        sops_decrypt(&*options.runner, filepath, &options.sops)
+    };
+    let decrypted = match decrypted {
+        Ok(content) => content,
+        Err(e) => {
+            print_file_error(out, filepath, "decrypt", &e);
+            state.errors += 1;
+            state.files_errored += 1;
+            state.record_file_error(filepath, "decrypt", &e);
+            return Ok((0, 0));
+        }
+    };
+    if options.abort_on_empty_file {
+        ensure_not_empty(filepath, &decrypted)?;
+    }
+    // Checked before every write, so changes made meanwhile, e.g. by a directive command, aren't overwritten
+    let fingerprint = match FileFingerprint::read(filepath) {
+        Ok(fingerprint) => fingerprint,
+        Err(e) => {
+            print_file_error(out, filepath, "read", &e);
+            state.errors += 1;
+            state.files_errored += 1;
+            return Ok((0, 0));
+        }
+    };
+
+    let mappings = match parse_commands_with_includes(&decrypted, filepath, &options.parse) {
+        Ok(parsed) => {
+            for issue in &parsed.issues {
+                out.text(format_args!("  Warning: {}", issue));
+                out.event(json!({
+                    "event": "warning",
+                    "file": file,
+                    "line": issue.line,
+                    "message": issue.message,
+                    "snippet": issue.snippet,
+                }));
+            }
+            state.unresolved_directives += parsed.issues.len();
+            if options.require_all_directives && !parsed.issues.is_empty() {
+                let e = anyhow!("{} directive(s) couldn't be matched to a key", parsed.issues.len());
+                print_file_error(out, filepath, "resolve directives", &e);
+                state.errors += 1;
+                state.files_errored += 1;
+                return Ok((0, 0));
+            }
+            parsed.mappings
+        }
+        Err(e) => {
+            print_file_error(out, filepath, "parse commands", &e);
+            state.errors += 1;
+            state.files_errored += 1;
+            state.record_file_error(filepath, "parse commands", &e);
+            return Ok((0, 0));
+        }
+    };
+
+    if options.report_orphans {
+        let orphans = orphaned_keys(&decrypted, &mappings, options);
+        if !orphans.is_empty() {
+            out.text(format_args!("  {} key(s) without a directive:", orphans.len()));
+            for key in &orphans {
+                out.text(format_args!("    {}", key));
+                out.event(json!({ "event": "orphan", "file": file, "key": key }));
+            }
+            out.text("");
+        }
+        state.orphans += orphans.len();
+    }
+
+    if mappings.is_empty() {
+        out.text("  No secret(s) with 'shell:' commands found");
+        return Ok((0, 0));
+    }
+
+    out.text(format_args!("  Found {} secret(s) with commands\n", mappings.len()));
+
+    // Of a streamed file, only the values of the keys being synced are needed
+    let decrypted = if streamed {
+        match stream_values(filepath, &decrypted, &covered_keys(&mappings), options) {
+            Ok(content) => content,
+            Err(e) => {
+                print_file_error(out, filepath, "decrypt", &e);
+                state.errors += 1;
+                state.files_errored += 1;
+                state.record_file_error(filepath, "decrypt", &e);
+                return Ok((0, 0));
+            }
+        }
+    } else {
+        decrypted
+    };
+
+    // A secret in plaintext in the encrypted file has likely been committed already
+    if let Ok(raw) = std::fs::read_to_string(filepath) {
+        let keys = mappings.iter().map(|mapping| mapping.key.as_str());
+        match plaintext_values(&raw, keys, |key| options.stored_value(&raw, key)) {
+            Ok(found) => {
+                for plaintext in &found {
+                    out.text(format_args!("  WARNING: {}\n", plaintext));
+                    out.event(json!({
+                        "event": "plaintext_value",
+                        "file": file,
+                        "key": plaintext.key,
+                        "exempted_by": plaintext.exempted_by.as_ref().map(|rule| rule.to_string()),
+                    }));
+                }
+                state.plaintext_values += found.len();
+            }
+            Err(e) => print_file_error(out, filepath, "check for plaintext values", &e),
+        }
+    }
+
+    let sync_times = state.sync_times.as_ref().map(|sync_times| sync_times.sync_times(filepath));
+    // The table replaces the lines of each key, events are still printed as each one is resolved
+    let key_out = options.printer().summary_only(options.summary_only || options.table);
+    let statuses = drift_for_mappings(filepath, &decrypted, &mappings, options, state, |status| {
+        let explanation = (options.explain && dry_run).then(|| {
+            let current_value = options.stored_value(&decrypted, &status.key);
+            explain_key_status(status, current_value.as_deref())
+        });
+        let last_synced = sync_times.as_ref().map(|sync_times| sync_times.get(&status.key).copied());
+        print_key_status(&key_out, &file, status, explanation, last_synced);
+    });
+
+    if options.table && !statuses.is_empty() {
+        for line in key_table(&statuses).render().lines() {
+            out.text(format_args!("  {}", line));
+        }
+    }
+
+    if let Some(dir) = &options.emit_dir {
+        for status in &statuses {
+            if let KeyOutcome::Checked { value, .. } = &status.outcome {
+                if let Err(e) = emit_value(dir, &status.key, value) {
+                    print_file_error(out, filepath, &format!("emit {}", status.key), &e);
+                    state.errors += 1;
+                }
+            }
+        }
+    }
+
+    if statuses.len() < mappings.len() {
+        let reason = if state.timed_out || state.file_timed_out { state.timeout_reason() } else { "Command limit reached" };
+        out.text(format_args!("  {}, skipping {} remaining secret(s)", reason, mappings.len() - statuses.len()));
+    }
+
+    if let Some(report) = state.report.as_mut() {
+        for status in &statuses {
+            let result = match &status.outcome {
+                KeyOutcome::Checked { status: SyncStatus::InSync, .. } => CaseResult::Passed,
+                // Passes once the update is written
+                KeyOutcome::Checked { status: SyncStatus::OutOfSync, .. } => CaseResult::Failed("out of sync".to_string()),
+                KeyOutcome::Checked { status: SyncStatus::Skipped, .. } => CaseResult::Skipped("skip-if matched".to_string()),
+                KeyOutcome::Refused(e) => CaseResult::Failed(format!("refused: {:#}", e)),
+                KeyOutcome::Failed(e) => CaseResult::Failed(format!("{:#}", e)),
+                KeyOutcome::Unchanged => CaseResult::Skipped("command unchanged since the last run".to_string()),
+                KeyOutcome::ConditionNotMet(condition) => CaseResult::Skipped(format!("condition {} doesn't hold", condition)),
+            };
+            report.record(filepath, &status.key, result, status.duration);
+        }
+    }
+
+    let checked = statuses.len();
+    state.errors += statuses
+        .iter()
+        .filter(|status| matches!(status.outcome, KeyOutcome::Refused(_) | KeyOutcome::Failed(_)))
+        .count();
+    state.command_failures += statuses.iter().filter(|status| matches!(status.outcome, KeyOutcome::Failed(_))).count();
+    let updates = pending_updates(statuses);
+
+    if dry_run && options.render {
+        let rendered = render_updated(&decrypted, &updates, options);
+        out.text("\n  As it would be after a sync, values masked:");
+        for line in rendered.lines() {
+            out.text(format_args!("    {}", line));
+        }
+        out.event(json!({ "event": "render", "file": file, "content": rendered }));
+    }
+
+    if !updates.is_empty() {
+        if dry_run {
+            out.text(format_args!("\n  Would update {} secrets (dry run)", updates.len()));
+            if let Some(plan) = state.plan.as_mut() {
+                for (key, value) in &updates {
+                    let replaces_value = options.stored_value(&decrypted, key).is_some_and(|value| !value.is_empty());
+                    plan.record(filepath, key, value, replaces_value);
+                }
+            }
+        } else if options.detect_only {
+            out.text(format_args!("\n  Not updating {} out of sync secrets (--detect-only)", updates.len()));
+            state.detected += updates.len();
+            return Ok((checked, 0));
+        } else if options.interactive_edit {
+            edit_interactively(out, filepath, &updates, &mappings, options, state);
+            return Ok((checked, 0));
+        } else {
+            if filepath.is_symlink() && !options.follow_symlinks && options.output_suffix.is_none() {
+                let message = "File is a symlink, sops may replace it with a regular file; pass --follow-symlinks to update its target instead";
+                out.text(format_args!("\n  Warning: {}", message));
+                out.event(json!({ "event": "warning", "file": file, "message": message }));
+            }
+
+            if options.require_clean_git {
+                if let Err(e) = git::ensure_clean(&*options.runner, filepath) {
+                    print_file_error(out, filepath, "check git status", &e);
+                    state.errors += 1;
+                    return Ok((checked, 0));
+                }
+            }
+
+            if let Err(e) = fingerprint.ensure_unchanged(filepath) {
+                print_file_error(out, filepath, "update", &e);
+                state.errors += 1;
+                return Ok((checked, 0));
+            }
+
+            let (target, sops) = match prepare_write_target(filepath, options) {
+                Ok(prepared) => prepared,
+                Err(e) => {
+                    print_file_error(out, filepath, "prepare write target", &e);
+                    state.errors += 1;
+                    return Ok((checked, 0));
+                }
+            };
+            // A copy has the file's content, but a modification time of its own
+            let mut fingerprint = match options.output_suffix {
+                Some(_) => match FileFingerprint::read(&target) {
+                    Ok(fingerprint) => fingerprint,
+                    Err(e) => {
+                        print_file_error(out, filepath, "prepare write target", &e);
+                        state.errors += 1;
+                        return Ok((checked, 0));
+                    }
+                },
+                None => fingerprint,
+            };
+
+            let original_permissions = std::fs::metadata(filepath).map(|metadata| metadata.permissions()).ok();
+            out.text(format_args!("\n  Updating {} secrets...", updates.len()));
+
+            let mut updated = 0;
+            let mut written = Vec::new();
+            for (key, value) in &updates {
+                if state.out_of_time() {
+                    out.text(format_args!("    {}, skipping remaining updates", state.timeout_reason()));
+                    break;
+                }
+                if let Err(e) = fingerprint.ensure_unchanged(&target) {
+                    print_file_error(out, filepath, "update", &e);
+                    state.errors += 1;
+                    break;
+                }
+
+                let started = Instant::now();
+                let stored_key = options.stored_key(&decrypted, key);
+                let result = sops_set(&*options.runner, &target, &stored_key, value, &sops);
+                if result.is_ok() {
+                    updated += 1;
+                    // The file as sops wrote it. Failing to read it fails the next check
+                    if let Ok(written) = FileFingerprint::read(&target) {
+                        fingerprint = written;
+                    }
+                }
+                if let Some(report) = state.report.as_mut() {
+                    let case = match &result {
+                        Ok(()) => CaseResult::Passed,
+                        Err(e) => CaseResult::Failed(format!("sops write failed: {:#}", e)),
+                    };
+                    report.resolve(filepath, key, case);
+                }
+                match &result {
+                    Ok(()) => {
+                        out.text(format_args!("    Updated {}", key));
+                        let command = mappings.iter().find(|mapping| mapping.key == *key).map(|mapping| mapping.command.as_str());
+                        let old_value = options.stored_value(&decrypted, key);
+                        let entry = AuditEntry { file: &target, key, command, old_value: old_value.as_deref(), new_value: value };
+                        record_audit(out, options, &entry, state);
+                        written.push((key.clone(), value.clone()));
+                        // A copy written with --output-suffix leaves the file itself stale
+                        if let Some(command_state) = state.command_state.as_mut().filter(|_| options.output_suffix.is_none()) {
+                            if let Some(mapping) = mappings.iter().find(|mapping| mapping.key == *key) {
+                                command_state.record(filepath, key, &mapping.command);
+                            }
+                            command_state.record_sync(filepath, key, SystemTime::now());
+                        }
+                    }
+                    Err(e) => {
+                        out.text(format_args!("    Error updating {}: {}", key, e));
+                        state.errors += 1;
+                    }
+                }
+                out.event(json!({
+                    "event": "update",
+                    "file": file,
+                    "key": key,
+                    "status": if result.is_ok() { "updated" } else { "error" },
+                    "error": result.err().map(|e| e.to_string()),
+                    "duration_ms": started.elapsed().as_millis(),
+                }));
+            }
+
+            if options.normalize {
+                normalize_and_report(out, &target, &mappings, options, &sops, state);
+            }
+
+            if !written.is_empty() {
+                write_also(out, &written, options, state);
+            }
+
+            // Not being allowed to chmod, e.g. a file owned by someone else, leaves it as sops wrote it
+            if let Err(e) = set_written_permissions(&target, original_permissions, options.chmod) {
+                let message = format!("Couldn't set permissions of {}: {:#}", target.display(), e);
+                out.text(format_args!("  Warning: {}", message));
+                out.event(json!({ "event": "warning", "file": file, "message": message }));
+            }
+
+            out.text(format_args!("\n  Updated {}", target.display()));
+
+            if let Some(hook) = options.post_sync.as_deref().filter(|_| updated > 0) {
+                if !run_post_sync_hook(out, &target, hook, updated) {
+                    state.errors += 1;
+                }
+            }
+            // Fewer than planned when the deadline or a modification of the file cut the writes short
+            return Ok((checked, updated));
+        }
+    } else {
+        out.text("\n  All secrets in sync");
+
+        // A copy is only written along with updates, and the original is never touched
+        if options.normalize && !dry_run && !options.detect_only && options.output_suffix.is_none() {
+            if options.require_clean_git {
+                if let Err(e) = git::ensure_clean(&*options.runner, filepath) {
+                    print_file_error(out, filepath, "check git status", &e);
+                    state.errors += 1;
+                    return Ok((checked, 0));
+                }
+            }
+            if let Err(e) = fingerprint.ensure_unchanged(filepath) {
+                print_file_error(out, filepath, "normalize", &e);
+                state.errors += 1;
+                return Ok((checked, 0));
+            }
+            match prepare_write_target(filepath, options) {
+                Ok((target, sops)) => {
+                    let original_permissions = std::fs::metadata(&target).map(|metadata| metadata.permissions()).ok();
+                    normalize_and_report(out, &target, &mappings, options, &sops, state);
+                    if let Err(e) = set_written_permissions(&target, original_permissions, options.chmod) {
+                        let message = format!("Couldn't set permissions of {}: {:#}", target.display(), e);
+                        out.text(format_args!("  Warning: {}", message));
+                        out.event(json!({ "event": "warning", "file": file, "message": message }));
+                    }
+                }
+                Err(e) => {
+                    print_file_error(out, filepath, "prepare write target", &e);
+                    state.errors += 1;
+                }
+            }
+        }
+    }
+
+    Ok((checked, updates.len()))
+}
+
+/// The keys `mappings` sync, with the companion entries of `compare-hash` and `also-set-sha256` directives
+fn covered_keys(mappings: &[CommandMapping]) -> Vec<String> {
+    mappings
+        .iter()
+        .flat_map(|mapping| {
+            let companion = mapping.compare_hash.then(|| hash_key(&mapping.key));
+            std::iter::once(mapping.key.clone()).chain(companion).chain(mapping.also_sha256.clone())
+        })
+        .collect()
+}
+
+/// Top-level keys of `decrypted` that no directive in `mappings` covers, e.g. added by hand and
+/// never given a command. The companion entries of `compare-hash` and `also-set-sha256` directives
+/// count as covered
+pub fn orphaned_keys(decrypted: &str, mappings: &[CommandMapping], options: &SyncOptions) -> Vec<String> {
+    let ignore_case = options.ignore_key_case && FileFormat::case_insensitive_keys(options.parse.format);
+    let covered = covered_keys(mappings);
+    let is_covered = |key: &str| {
+        covered.iter().any(|covered| if ignore_case { covered.eq_ignore_ascii_case(key) } else { covered == key })
+    };
+    decrypted_keys(decrypted, options.parse.format, &options.parse.assign_ops)
+        .into_iter()
+        .filter(|key| !is_covered(key))
+        .collect()
+}
+
+/// Lists the keys of `updates` with the commands computing them, then opens the file in sops' editor
+/// for the user to apply the changes by hand. Values aren't printed, they may be shown on screen
+fn edit_interactively(
+    out: &Printer,
+    filepath: &Path,
+    updates: &[(String, String)],
+    mappings: &[CommandMapping],
+    options: &SyncOptions,
+    state: &mut RunState,
+) {
+    let file = filepath.display().to_string();
+    out.text(format_args!("\n  {} secrets need updating:", updates.len()));
+    for (key, _) in updates {
+        match mappings.iter().find(|mapping| mapping.key == *key) {
+            Some(mapping) => out.text(format_args!("    {} (from: {})", key, mapping.command)),
+            None => out.text(format_args!("    {}", key)),
+        }
+    }
+    out.text("  Opening it in sops' editor...");
+
+    let result = prepare_write_target(filepath, options)
+        .and_then(|(target, sops)| sops_edit(&*options.runner, &target, &sops));
+    let status = match &result {
+        Ok(true) => {
+            out.text(format_args!("\n  Edited {}", filepath.display()));
+            "edited"
+        }
+        Ok(false) => {
+            out.text(format_args!("\n  Left {} unchanged", filepath.display()));
+            "unchanged"
+        }
+        Err(e) => {
+            print_file_error(out, filepath, "edit", e);
+            state.errors += 1;
+            "error"
+        }
+    };
+    out.event(json!({
+        "event": "edit",
+        "file": file,
+        "keys": updates.iter().map(|(key, _)| key).collect::<Vec<_>>(),
+        "status": status,
+    }));
+}
+
+/// Writes `updates` to each of `SyncOptions::also_write` as well, so secrets kept in several formats
+/// are only computed once. Every target is decrypted first: keys are written with the spelling the
+/// target already uses, matched case-insensitively in env and ini files where e.g. `api_key` is
+/// conventionally `API_KEY`, and values it already has aren't written again
+fn write_also(out: &Printer, updates: &[(String, String)], options: &SyncOptions, state: &mut RunState) {
+    for target in &options.also_write {
+        let format = FileFormat::resolve(target, None);
+        let sops = SopsOptions { format_as: format, ..options.sops.clone() };
+        let decrypted = match sops_decrypt(&*options.runner, target, &sops) {
+            Ok(decrypted) => decrypted,
+            Err(e) => {
+                print_file_error(out, target, "decrypt", &e);
+                state.errors += 1;
+                continue;
+            }
+        };
+
+        for (key, value) in updates {
+            let stored = find_decrypted_entry_with_ops(&decrypted, key, format, true, &options.parse.assign_ops);
+            if stored.as_ref().is_some_and(|(_, stored_value)| stored_value == value) {
+                continue;
+            }
+            let (stored_key, old_value) = stored.map_or_else(|| (key.clone(), None), |(stored_key, old)| (stored_key, Some(old)));
+            let result = sops_set(&*options.runner, target, &stored_key, value, &sops);
+            match &result {
+                Ok(()) => {
+                    out.text(format_args!("    Also updated {} in {}", stored_key, target.display()));
+                    let old_value = old_value.as_deref();
+                    let entry = AuditEntry { file: target, key: &stored_key, command: None, old_value, new_value: value };
+                    record_audit(out, options, &entry, state);
+                }
+                Err(e) => {
+                    out.text(format_args!("    Error updating {} in {}: {}", stored_key, target.display(), e));
+                    state.errors += 1;
+                }
+            }
+            out.event(json!({
+                "event": "update",
+                "file": target.display().to_string(),
+                "key": stored_key,
+                "status": if result.is_ok() { "updated" } else { "error" },
+                "error": result.err().map(|e| e.to_string()),
+            }));
+        }
+    }
+}
+
+/// Appends `entry` to `SyncOptions::audit_log`, if any. A failure is an error of the run, but
+/// the value has been written by then
+fn record_audit(out: &Printer, options: &SyncOptions, entry: &AuditEntry, state: &mut RunState) {
+    let Some(audit_log) = &options.audit_log else {
+        return;
+    };
+    if let Err(e) = audit_log.append(entry, SystemTime::now()) {
+        out.text(format_args!("    Error: {:#}", e));
+        state.errors += 1;
+    }
+}
+
+fn same_values(before: &str, after: &str, mappings: &[CommandMapping], options: &SyncOptions) -> bool {
+    before.split_whitespace().eq(after.split_whitespace())
+        && mappings
+            .iter()
+            .all(|mapping| options.stored_value(before, &mapping.key) == options.stored_value(after, &mapping.key))
+}
+
+fn normalize_and_report(
+    out: &Printer,
+    target: &Path,
+    mappings: &[CommandMapping],
+    options: &SyncOptions,
+    sops: &SopsOptions,
+    state: &mut RunState,
+) {
+    match normalize_file(&*options.runner, target, mappings, options, sops) {
+        Ok(true) => out.text(format_args!("  Normalized {}", target.display())),
+        Ok(false) => {
+            let message = "No key with a value to rewrite, not normalizing";
+            out.text(format_args!("  Warning: {}", message));
+            out.event(json!({ "event": "warning", "file": target.display().to_string(), "message": message }));
+        }
+        Err(e) => {
+            print_file_error(out, target, "normalize", &e);
+            state.errors += 1;
+        }
+    }
+}
+
+/// Rewrites `target` through sops by setting one of its keys to the value it already has, which
+/// makes sops lay out the whole file with its own emitter. Values never change: the decrypted
+/// content is compared before and after, ignoring whitespace between tokens, along with the exact
+/// value of every directive's key, and the file is restored if anything differs. Returns false if
+/// no key has a value to rewrite
+pub fn normalize_file(
+    runner: &dyn CommandRunner,
+    target: &Path,
+    mappings: &[CommandMapping],
+    options: &SyncOptions,
+    sops: &SopsOptions,
+) -> Result<bool> {
+    let before = sops_decrypt(runner, target, sops)?;
+    let Some((key, value)) = mappings
+        .iter()
+        .find_map(|mapping| Some((options.stored_key(&before, &mapping.key), options.stored_value(&before, &mapping.key)?)))
+    else {
+        return Ok(false);
+    };
+
+    let original = std::fs::read(target).with_context(|| format!("Failed to read {}", target.display()))?;
+    let restore = |reason: anyhow::Error| match std::fs::write(target, &original) {
+        Ok(()) => anyhow!("{:#}, restored the file as it was", reason),
+        Err(e) => anyhow!("{:#}, and failed to restore the file: {}", reason, e),
+    };
+
+    if let Err(e) = sops_set(runner, target, &key, &value, sops) {
+        return Err(restore(e));
+    }
+    match sops_decrypt(runner, target, sops) {
+        Ok(after) if same_values(&before, &after, mappings, options) => Ok(true),
+        Ok(_) => Err(restore(anyhow!("Rewriting {} through sops changed its decrypted content", key))),
+        Err(e) => Err(restore(e)),
+    }
+}
+
+// sops may leave a rewritten file with different permissions than it had, so they're restored,
+// or replaced with `chmod` when given
+fn set_written_permissions(target: &Path, original: Option<std::fs::Permissions>, chmod: Option<u32>) -> Result<()> {
+    let permissions = match chmod {
+        #[cfg(unix)]
+        Some(mode) => {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::Permissions::from_mode(mode)
+        }
+        #[cfg(not(unix))]
+        Some(_) => return Err(anyhow!("--chmod is only supported on unix")),
+        None => match original {
+            Some(permissions) => permissions,
+            None => return Ok(()),
+        },
+    };
+    std::fs::set_permissions(target, permissions)?;
+    Ok(())
+}
+
+/// Path that updates are written to, along with the sops options to write it with. With an
+/// output suffix the still-encrypted file is copied first and the updates are applied to the
+/// copy, so the original is never touched. The copy's extension no longer tells sops the
+/// format, so it is passed explicitly
+pub fn prepare_write_target(filepath: &Path, options: &SyncOptions) -> Result<(PathBuf, SopsOptions)> {
+    let forced_format = options.sops.format_as.or(options.parse.format);
+    let Some(suffix) = &options.output_suffix else {
+        if options.follow_symlinks && filepath.is_symlink() {
+            // Write through to the target, keeping the format detected from the link's name
+            let target = std::fs::canonicalize(filepath)
+                .with_context(|| format!("Failed to resolve symlink {}", filepath.display()))?;
+            let sops = SopsOptions {
+                format_as: FileFormat::resolve(filepath, forced_format),
+                ..options.sops.clone()
+            };
+            return Ok((target, sops));
+        }
+        return Ok((filepath.to_path_buf(), options.sops.clone()));
+    };
+
+    let format = FileFormat::resolve(filepath, forced_format)
+        .ok_or_else(|| anyhow!("Can't detect the format of {}, pass --format-as", filepath.display()))?;
+
+    let mut target = filepath.as_os_str().to_owned();
+    target.push(suffix);
+    let target = PathBuf::from(target);
+    std::fs::copy(filepath, &target).with_context(|| format!("Failed to copy to {}", target.display()))?;
+
+    let sops = SopsOptions {
+        format_as: Some(format),
+        ..options.sops.clone()
+    };
+    Ok((target, sops))
+}
+
+// The file has already been written at this point, so a failing hook is only reported
+fn run_post_sync_hook(out: &Printer, filepath: &Path, hook: &str, updated: usize) -> bool {
+    let envs = [
+        ("SOPS_SHELL_FILE", filepath.display().to_string()),
+        ("SOPS_SHELL_UPDATES", updated.to_string()),
+    ];
+
+    let result = run_hook(hook, &envs);
+    match &result {
+        Ok(()) => out.text("  Post-sync hook succeeded"),
+        Err(e) => out.text(format_args!("  Error: Post-sync hook failed: {}", e)),
+    }
+    out.event(json!({
+        "event": "post_sync",
+        "file": filepath.display().to_string(),
+        "status": if result.is_ok() { "ok" } else { "error" },
+        "error": result.as_ref().err().map(|e| e.to_string()),
+    }));
+
+    result.is_ok()
+}
+
+/// Totals of a completed run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RunSummary {
+    pub files: usize,
+    pub secrets: usize,
+    /// Secrets updated, or found out of sync in a dry run
+    pub updates: usize,
+    /// Files left undecrypted, see `RunState::files_skipped`
+    pub files_skipped: usize,
+    /// Files that failed as a whole, see `RunState::files_errored`
+    pub files_errored: usize,
+    pub errors: usize,
+}
+
+// Reports retries of sops along with the rest of the run's progress
+fn retry_progress(options: &SyncOptions) -> RetryHook {
+    let (format, summary_only) = (options.format, options.summary_only);
+    RetryHook::new(move |notice| {
+        let out = Printer::new(format).summary_only(summary_only);
+        out.text(format_args!("  {}", notice));
+        out.event(json!({
+            "event": "sops_retry",
+            "attempt": notice.attempt,
+            "retries": notice.retries,
+            "error": notice.error,
+        }));
+    })
+}
+
+pub fn process_files(files: &[impl AsRef<Path>], dry_run: bool, options: &SyncOptions) -> Result<RunSummary> {
+    // Decrypted plaintext is reused within the run only, and dropped with it
+    let sops = SopsOptions { on_retry: Some(retry_progress(options)), ..options.sops.with_cache() };
+    let options = &SyncOptions { sops, ..options.clone() };
+    let mut total_secrets = 0;
+    let mut total_updates = 0;
+    let started = Instant::now();
+
+    if let Some(hook) = &options.pre_sync {
+        let planned: Vec<String> = files.iter().map(|file| file.as_ref().display().to_string()).collect();
+        run_hook(hook, &[("SOPS_SHELL_FILES", planned.join("\n"))])
+            .context("Pre-sync hook failed, aborting before any file was processed")?;
+    }
+
+    if let Some(audit_log) = options.audit_log.as_ref().filter(|_| !dry_run) {
+        audit_log.ensure_writable()?;
+    }
+
+    let out = options.printer();
+    if let Some(dir) = &options.emit_dir {
+        let message = format!("--emit-dir writes secrets in plaintext to {}, remove them once consumed", dir.display());
+        out.text(format_args!("Warning: {}", message));
+        out.event(json!({ "event": "warning", "message": message }));
+    }
+
+    let mut state = RunState {
+        deadline: options.timeout_total.map(|timeout| started + timeout),
+        ..Default::default()
+    };
+    if let Some(path) = &options.command_state {
+        state.command_state = Some(CommandState::load(path)?);
+    }
+    if let Some(path) = &options.last_synced {
+        state.sync_times = Some(CommandState::load(path)?);
+    }
+    if dry_run {
+        state.plan = options.plan_out.as_deref().map(Plan::new);
+    }
+    state.report = options.report.as_ref().map(Report::new);
+
+    let mut files_processed = 0;
+    for file in files {
+        if state.deadline_passed() {
+            out.text(format_args!("\nTotal timeout reached, skipping {}", file.as_ref().display()));
+            state.files_skipped += 1;
+            continue;
+        }
+        if state.command_budget_exhausted(options) {
+            out.text(format_args!("\nCommand limit reached, skipping {}", file.as_ref().display()));
+            state.files_skipped += 1;
+            continue;
+        }
+
+        let (secrets, updates) = process_file(file.as_ref(), dry_run, options, &mut state)?;
+        files_processed += 1;
+        total_secrets += secrets;
+        total_updates += updates;
+    }
+
+    if let Some(command_state) = &state.command_state {
+        if let Err(e) = command_state.save() {
+            out.text(format_args!("\nError: {:#}", e));
+            state.errors += 1;
+        }
+    }
+    if let Some(plan) = &state.plan {
+        if let Err(e) = plan.save() {
+            out.text(format_args!("\nError: {:#}", e));
+            state.errors += 1;
+        }
+    }
+    if let Some(report) = &state.report {
+        if let Err(e) = report.save() {
+            out.text(format_args!("\nError: {:#}", e));
+            state.errors += 1;
+        }
+    }
+    if let Some(path) = &options.metrics_file {
+        let metrics = RunMetrics {
+            dry_run,
+            files: files_processed,
+            secrets_checked: total_secrets,
+            out_of_sync: total_updates + state.detected,
+            command_failures: state.command_failures,
+            errors: state.errors,
+            duration: started.elapsed(),
+        };
+        if let Err(e) = metrics.write(path) {
+            out.text(format_args!("\nError: {:#}", e));
+            state.errors += 1;
+        }
+    }
+
+    print_summary(&out, files.len(), total_secrets, total_updates, &state, dry_run);
+    if state.detected > 0 {
+        out.summary(format_args!("  Secrets out of sync, not updated (--detect-only): {}", state.detected));
+    }
+    if state.plaintext_values > 0 {
+        out.summary(format_args!("  WARNING: Secrets stored in plaintext: {}", state.plaintext_values));
+    }
+    if options.report_orphans {
+        out.summary(format_args!("  Keys without a directive: {}", state.orphans));
+    }
+    if state.command_limit_reached {
+        out.summary(format_args!(
+            "  Command limit reached: {} command(s) executed, remaining secrets were not checked",
+            state.commands_executed
+        ));
+    }
+    if state.files_timed_out > 0 {
+        out.summary(format_args!(
+            "  File timeout reached: {} file(s) stopped early, their remaining secrets were not checked",
+            state.files_timed_out
+        ));
+    }
+    if state.timed_out {
+        out.summary(format_args!(
+            "  Total timeout reached: {} of {} file(s) and {} secret(s) processed before the cutoff",
+            files_processed,
+            files.len(),
+            total_secrets
+        ));
+    }
+    out.event(json!({
+        "event": "summary",
+        "files": files.len(),
+        "secrets": total_secrets,
+        "updates": total_updates,
+        "commands_executed": state.commands_executed,
+        "command_limit_reached": state.command_limit_reached,
+        "files_processed": files_processed,
+        "timed_out": state.timed_out,
+        "files_timed_out": state.files_timed_out,
+        "detected": state.detected,
+        "plaintext_values": state.plaintext_values,
+        "orphans": state.orphans,
+        "files_skipped": state.files_skipped,
+        "files_errored": state.files_errored,
+        "errors": state.errors,
+        "dry_run": dry_run,
+        "duration_ms": started.elapsed().as_millis(),
+    }));
+
+    if let (true, Some(timeout)) = (state.timed_out, options.timeout_total) {
+        return Err(TimeoutExceeded { timeout }.into());
+    }
+
+    if options.require_all_directives && state.unresolved_directives > 0 {
+        return Err(anyhow!(
+            "{} directive(s) couldn't be matched to a key (--require-all-directives)",
+            state.unresolved_directives
+        ));
+    }
+
+    Ok(RunSummary {
+        files: files_processed,
+        secrets: total_secrets,
+        updates: total_updates,
+        files_skipped: state.files_skipped,
+        files_errored: state.files_errored,
+        errors: state.errors,
+    })
+}
+
+/// Bootstraps a missing file from a plaintext template of keys and their directives. The template
+/// is encrypted as-is, placeholder values included, and the secrets are filled in by the following
+/// sync through `sops --set`, so their plaintext never touches the disk
+pub fn create_from_template(filepath: &Path, template: &Path, options: &SyncOptions) -> Result<()> {
+    if filepath.exists() {
+        return Err(anyhow!("{} already exists", filepath.display()));
+    }
+
+    let content = std::fs::read_to_string(template)
+        .with_context(|| format!("Failed to read template {}", template.display()))?;
+    let options = options.for_file(filepath);
+    let parsed = parse_commands(&content, &options.parse)
+        .with_context(|| format!("Invalid template {}", template.display()))?;
+    if parsed.mappings.is_empty() {
+        return Err(anyhow!("Template {} has no 'shell:' directives", template.display()));
+    }
+
+    std::fs::write(filepath, &content).with_context(|| format!("Failed to create {}", filepath.display()))?;
+    let sops = SopsOptions {
+        format_as: options.sops.format_as.or(options.parse.format),
+        ..options.sops.clone()
+    };
+    if let Err(e) = sops_encrypt_in_place(&*options.runner, filepath, &sops) {
+        // Don't leave a plaintext file behind that a later run would mistake for an encrypted one
+        let _ = std::fs::remove_file(filepath);
+        return Err(e.context(format!("Failed to encrypt new file {}", filepath.display())));
+    }
+
+    options.printer().text(format_args!(
+        "Created {} from {} with {} secret(s)",
+        filepath.display(),
+        template.display(),
+        parsed.mappings.len()
+    ));
+    Ok(())
+}
+
+pub fn sync_files(files: &[impl AsRef<Path>], options: &SyncOptions) -> Result<RunSummary> {
+    process_files(files, false, options)
+}
+
+pub fn check_files(files: &[impl AsRef<Path>], options: &SyncOptions) -> Result<RunSummary> {
+    process_files(files, true, options)
+}
+
+fn print_summary(
+    out: &Printer,
+    files_count: usize,
+    total_secrets: usize,
+    total_updates: usize,
+    state: &RunState,
+    dry_run: bool,
+) {
+    out.summary(format_args!("\n{}", "=".repeat(60)));
+    out.summary("Summary:");
+    if dry_run {
+        out.summary(format_args!("  Files checked: {}", files_count));
+        out.summary(format_args!("  Secrets checked: {}", total_secrets));
+        out.summary(format_args!("  Secrets out of sync: {}", total_updates));
+    } else {
+        out.summary(format_args!("  Files processed: {}", files_count));
+        out.summary(format_args!("  Secrets checked: {}", total_secrets));
+        out.summary(format_args!("  Secrets updated: {}", total_updates));
+    }
+    if state.files_skipped > 0 {
+        out.summary(format_args!("  Files skipped: {}", state.files_skipped));
+    }
+    if state.files_errored > 0 {
+        out.summary(format_args!("  Files with errors: {}", state.files_errored));
+    }
+    if state.errors > 0 {
+        out.summary(format_args!("  Errors: {}", state.errors));
+    }
+
+    if dry_run && total_updates > 0 {
+        out.summary("\nRun 'sops-shell sync <files>' to update");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    fn create_test_file(content: &str) -> NamedTempFile {
+        let mut temp_file = NamedTempFile::new().expect("Failed to create temp file");
+        temp_file.write_all(content.as_bytes()).expect("Failed to write to temp file");
+        temp_file
+    }
+
+    mod has_comment_lines {
+        use super::*;
+
+        #[test]
+        fn test_yaml_with_comments() {
+            let yaml_content = r#"#ENC[AES256_GCM,data:XZKYdNp090c0OssVxy3nsbVjJyQEdxDs2+w6nXs21Pq8JeUGB3wHjuuaD1uz/OTIO1nF7jM0ZSb0ED5/oTxMRSkbXa3PtRDScFulxzgs9oqt,iv:WsFYuBa3wdEMSX8hklDVJCbJkwbuk2mUSrGh9819pEk=,tag:QRNJOJnTulAcu45x1rc70A==,type:comment]
+key1: ENC[AES256_GCM,data:z3lcZi7luB/Ni5FFIB8h98qLZDofk9g8KsQRIhVRwexmZSNihuTp58nzEueBQt8D,iv:MuIReuZYNIX0zi9hOBmpTK8tFhO+MtA27tvwimGusf4=,tag:CnwlixiANK6HGwb2GweIVw==,type:str]
+value2: ENC[AES256_GCM,data:hLAuHEcvnSXSuy/8UFnLrScX7o121EPEwNHKZQOqnIdyY1EM6w6Ej0zKAREHuBsoblZRoD6a8OHTd1CrWE/3oNmKt0Y5SbY693Y9HLTC716B7/gBzRhtqpxZL3vICG7MXfrI/f4dKtx0Kroro+69PU8/6hxi7WmMVk6hL2BnipUSlINho6ev/AZuKOY+x6HAPTXE34BwK0SkmgkFgviLU1BQRNwGc+rVvmItXZLrOhL7iOSfmlOZm7qTcxOUp8oNLX+RECxLzhLr/4fj4jlB8JaNWanCvBcCim12nS5Shzsl/ylnvczcYttFAQeqjU2it8Q1lF4QcHJDg1kiNYdVBG1AiD3x1JEYR34M2tAPAcnX1uoVoZAofMjiHOaz5mWV8MahLJDt9XU+J4I/y2X7qduyXPLlXf+tnIu5D5p+WMMC,iv:fq3nwR7vMx9E270pGPTyrxENaQQAF8EInvZX3Y+JMIQ=,tag:wNcFV/e8/nXDpAqLaRGIHw==,type:str]"#;
+
+            let temp_file = create_test_file(yaml_content);
+            let result = has_comment_lines(temp_file.path()).expect("Should not fail");
+            assert!(result, "YAML with comments should return true");
+        }
+
+        #[test]
+        fn test_yaml_without_comments() {
+            let yaml_content = r#"key1: ENC[AES256_GCM,data:z3lcZi7luB/Ni5FFIB8h98qLZDofk9g8KsQRIhVRwexmZSNihuTp58nzEueBQt8D,iv:MuIReuZYNIX0zi9hOBmpTK8tFhO+MtA27tvwimGusf4=,tag:CnwlixiANK6HGwb2GweIVw==,type:str]
+value2: ENC[AES256_GCM,data:hLAuHEcvnSXSuy/8UFnLrScX7o121EPEwNHKZQOqnIdyY1EM6w6Ej0zKAREHuBsoblZRoD6a8OHTd1CrWE/3oNmKt0Y5SbY693Y9HLTC716B7/gBzRhtqpxZL3vICG7MXfrI/f4dKtx0Kroro+69PU8/6hxi7WmMVk6hL2BnipUSlINho6ev/AZuKOY+x6HAPTXE34BwK0SkmgkFgviLU1BQRNwGc+rVvmItXZLrOhL7iOSfmlOZm7qTcxOUp8oNLX+RECxLzhLr/4fj4jlB8JaNWanCvBcCim12nS5Shzsl/ylnvczcYttFAQeqjU2it8Q1lF4QcHJDg1kiNYdVBG1AiD3x1JEYR34M2tAPAcnX1uoVoZAofMjiHOaz5mWV8MahLJDt9XU+J4I/y2X7qduyXPLlXf+tnIu5D5p+WMMC,iv:fq3nwR7vMx9E270pGPTyrxENaQQAF8EInvZX3Y+JMIQ=,tag:wNcFV/e8/nXDpAqLaRGIHw==,type:str]
+secret3: ENC[AES256_GCM,data:vbMDk21AfoTzfLgybWt52JytPaf103wqmQjxhJMG/WPul2eGtmcjlez/+BNb9xgYlNvxRt00ET2lpDx5WlBQylTWuUm/tlfXBM4Iu1vXsjRAsF8X/Q==,iv:BW8bJULqt+H///8aA9z43Vs7E+mAE4YrcOIjFnvg6Fk=,tag:Srt8W4GD7LICubVzI4DmXA==,type:str]"#;
+
+            let temp_file = create_test_file(yaml_content);
+            let result = has_comment_lines(temp_file.path()).expect("Should not fail");
+            assert!(!result, "YAML without comments should return false");
+        }
+
+        #[test]
+        fn test_env_with_comments() {
+            let env_content = r#"VAR1=ENC[AES256_GCM,data:plVGuA==,iv:G8F5FbNqTrcMnekeojVDiLnHm/P3zLK2J4hH0hAORdM=,tag:KRlxAFtLwqsnToYyH0gv5Q==,type:str]
+VAR2=ENC[AES256_GCM,data:Z1YXLA==,iv:RK8JpogKYXjtRZSvTKMLbHLIqmPcdg+XUyFIw4ScRN8=,tag:G7wq3M+eyqiRbuv+1CbgDg==,type:str]
+#ENC[AES256_GCM,data:EKnMX0aQPqY1y8FDE98ePqM3t8A/Jc6dX3IqVwhwuRkMaIOmO9Ak4iQtso17iCzVHcQ=,iv:8kKEKYUVeRul6GOWxc7HZQsSYAHJwqCXMajC2N1xgNI=,tag:Pb/hHvJQSLe4pQc9isqjqA==,type:comment]
+VAR3=ENC[AES256_GCM,data:z9jujEfYa8Y=,iv:E4CoMsU1OU1+QZErLIQquoiDI23vuDbXmBDtamL1kNk=,tag:ahQR7j8+RHJUO4mymWeMJw==,type:str]"#;
+
+            let temp_file = create_test_file(env_content);
+            let result = has_comment_lines(temp_file.path()).expect("Should not fail");
+            assert!(result, "ENV file with comments should return true");
+        }
+
+        #[test]
+        fn test_env_without_comments() {
+            let env_content = r#"VAR1=ENC[AES256_GCM,data:plVGuA==,iv:G8F5FbNqTrcMnekeojVDiLnHm/P3zLK2J4hH0hAORdM=,tag:KRlxAFtLwqsnToYyH0gv5Q==,type:str]
+VAR2=ENC[AES256_GCM,data:Z1YXLA==,iv:RK8JpogKYXjtRZSvTKMLbHLIqmPcdg+XUyFIw4ScRN8=,tag:G7wq3M+eyqiRbuv+1CbgDg==,type:str]
+VAR3=ENC[AES256_GCM,data:z9jujEfYa8Y=,iv:E4CoMsU1OU1+QZErLIQquoiDI23vuDbXmBDtamL1kNk=,tag:ahQR7j8+RHJUO4mymWeMJw==,type:str]"#;
+
+            let temp_file = create_test_file(env_content);
+            let result = has_comment_lines(temp_file.path()).expect("Should not fail");
+            assert!(!result, "ENV file without comments should return false");
+        }
+
+        #[test]
+        fn test_ini_with_comments() {
+            let ini_content = r#"[config]
+option1 = ENC[AES256_GCM,data:mJ5DKLvrKtiJ,iv:21wg1JWyntnndyHnyox1dwH4iA58CBUphqPmDvKz6b0=,tag:G+MxG7dK0KccQPpm6B8U3w==,type:str]
+option2    = ENC[AES256_GCM,data:0kk=,iv:+K69YIFcTuL3T3SzlPGcZkdSLSnoixo2CtM/lUwpZ7o=,tag:uPV3xSjHzJUjh0Urv6KN/Q==,type:str]
+; ENC[AES256_GCM,data:zYAvfyP84D8YP2Svs4+0W9M2eF7KhmS+17JIw7lIjeSIaTsdLCuzpgCbRL6ZfbCVPQci,iv:FAr+Za/MsmRqg1DTzPLcuOCH53a8n5UUQsqGE6Kv1p4=,tag:OOcIjJv+Tt+X+2QI2bdOOw==,type:comment]
+option3    = ENC[AES256_GCM,data:1GWHjsNTwQTA51jp+BOyHX+FhlF7CPfx6kyU0k658iG/k440NzIR4VK9qcFXrIrmQjhIwJaG5YxK5TiuK/qN7uuY2omz5gB0oHA=,iv:hDHT4OIUP6gZ27qGdHCkZoFc+hMkOFZ7EK9L/OBRLus=,tag:IaAF3T5RhVeDmg3eXU7Ymw==,type:str]"#;
+
+            let temp_file = create_test_file(ini_content);
+            let result = has_comment_lines(temp_file.path()).expect("Should not fail");
+            assert!(result, "INI file with comments should return true");
+        }
+
+        #[test]
+        fn test_ini_without_comments() {
+            let ini_content = r#"[config]
+option1 = ENC[AES256_GCM,data:mJ5DKLvrKtiJ,iv:21wg1JWyntnndyHnyox1dwH4iA58CBUphqPmDvKz6b0=,tag:G+MxG7dK0KccQPpm6B8U3w==,type:str]
+option2    = ENC[AES256_GCM,data:0kk=,iv:+K69YIFcTuL3T3SzlPGcZkdSLSnoixo2CtM/lUwpZ7o=,tag:uPV3xSjHzJUjh0Urv6KN/Q==,type:str]
+option3     = ENC[AES256_GCM,data:dGVzdA==,iv:abc123==,tag:test123==,type:str]"#;
+
+            let temp_file = create_test_file(ini_content);
+            let result = has_comment_lines(temp_file.path()).expect("Should not fail");
+            assert!(!result, "INI file without comments should return false");
+        }
+
+        #[test]
+        fn test_whitespace_before_comments() {
+            let content = r#"key: ENC[AES256_GCM,data:test,iv:test,tag:test,type:str]
+    #ENC[AES256_GCM,data:indented,iv:test,tag:test,type:comment]
+value: ENC[AES256_GCM,data:test,iv:test,tag:test,type:str]"#;
+
+            let temp_file = create_test_file(content);
+            let result = has_comment_lines(temp_file.path()).expect("Should not fail");
+            assert!(result, "Should detect comments with whitespace prefix");
+        }
+
+        #[test]
+        fn test_tab_before_comments() {
+            let content = r#"key: ENC[AES256_GCM,data:test,iv:test,tag:test,type:str]
+	#ENC[AES256_GCM,data:tabbed,iv:test,tag:test,type:comment]
+value: ENC[AES256_GCM,data:test,iv:test,tag:test,type:str]"#;
+
+            let temp_file = create_test_file(content);
+            let result = has_comment_lines(temp_file.path()).expect("Should not fail");
+            assert!(result, "Should detect comments with tab prefix");
+        }
+
+        #[test]
+        fn test_empty_file() {
+            let content = r#""#;
+
+            let temp_file = create_test_file(content);
+            let result = has_comment_lines(temp_file.path()).expect("Should not fail");
+            assert!(!result, "Empty file should return false");
+        }
+
+        #[test]
+        fn test_only_empty_lines() {
+            let content = r#"
+
+
+"#;
+
+            let temp_file = create_test_file(content);
+            let result = has_comment_lines(temp_file.path()).expect("Should not fail");
+            assert!(!result, "File with only empty lines should return false");
+        }
+
+        #[test]
+        fn test_shell_comments_specifically() {
+            let yaml_content = r#"#ENC[AES256_GCM,data:XZKYdNp090c0OssVxy3nsbVjJyQEdxDs2+w6nXs21Pq8JeUGB3wHjuuaD1uz/OTIO1nF7jM0ZSb0ED5/oTxMRSkbXa3PtRDScFulxzgs9oqt,iv:WsFYuBa3wdEMSX8hklDVJCbJkwbuk2mUSrGh9819pEk=,tag:QRNJOJnTulAcu45x1rc70A==,type:comment]
+key: ENC[AES256_GCM,data:test,iv:test,tag:test,type:str]"#;
+
+            let temp_file = create_test_file(yaml_content);
+            let result = has_comment_lines(temp_file.path()).expect("Should not fail");
+            assert!(result, "Should detect SOPS encrypted comment lines");
+        }
+
+        #[test]
+        fn test_large_file_without_comments() {
+            let mut content = String::new();
+            for i in 0..150 {
+                content.push_str(&format!("line{}: ENC[AES256_GCM,data:test{}{},iv:test123,tag:test456,type:str]\n", i, i, i));
+            }
+
+            let temp_file = create_test_file(&content);
+            let result = has_comment_lines(temp_file.path()).expect("Should not fail");
+            assert!(!result, "Large file without comments should return false");
+        }
+    }
+
+    mod skip_commented_key_mappings {
+        use crate::parser::{parse_commands, ParseOptions};
+
+        #[test]
+        fn test_ini_skip_when_next_key_commented() {
+            let content = r#"[config]
+resolution = 1920x1080
+; shell: echo hi
+; val = hi
+fps = 120"#;
+
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            assert_eq!(mappings.len(), 0, "Should skip INI mapping when next key is commented");
+        }
+
+        #[test]
+        fn test_env_skip_when_next_key_commented() {
+            let content = r#"MEILI_MASTER_KEY=ENC[AES256_GCM,data:test1,iv:test,tag:test,type:str]
+# shell: rbw get 79da561c-beaa-430e-9ee9-fa2a218eb700 --field "API Key"
+# OPENAI_API_KEY=ENC[AES256_GCM,data:test2,iv:test,tag:test,type:str]
+NEXTAUTH_URL=ENC[AES256_GCM,data:test3,iv:test,tag:test,type:str]"#;
+
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            assert_eq!(mappings.len(), 0, "Should skip ENV mapping when next key is commented");
+        }
+
+        #[test]
+        fn test_yaml_skip_when_next_key_commented() {
+            let content = r#"key1: ENC[AES256_GCM,data:test,iv:test,tag:test,type:str]
+# shell: echo "secret_value"
+# key2: ENC[AES256_GCM,data:commented_secret,iv:test,tag:test,type:str]
+key3: ENC[AES256_GCM,data:value3,iv:test,tag:test,type:str]"#;
+
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            assert_eq!(mappings.len(), 0, "Should skip YAML mapping when next key is commented");
+        }
+    }
+
+    mod skip_if_directive {
+        use super::*;
+
+        #[test]
+        fn test_parse_skip_if_option() {
+            let content = r#"# shell(skip-if=^NO_CHANGE$): fetch-secret
+api_key: old-value"#;
+
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            assert_eq!(mappings.len(), 1);
+            assert_eq!(mappings[0].key, "api_key");
+            assert_eq!(mappings[0].command, "fetch-secret");
+            assert!(mappings[0].skip_if.is_some(), "skip-if regex should be parsed");
+        }
+
+        #[test]
+        fn test_skip_if_regex_with_comma() {
+            let content = r#"# shell(skip-if=^\d{1,3}$): fetch-secret
+api_key: old-value"#;
+
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            let skip_if = mappings[0].skip_if.as_ref().expect("skip-if should be set");
+            assert!(skip_if.is_match("42"));
+            assert!(!skip_if.is_match("4242"));
+        }
+
+        #[test]
+        fn test_matching_sentinel_is_skipped() {
+            let content = r#"# shell(skip-if=^NO_CHANGE$): fetch-secret
+api_key: old-value"#;
+
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            let current = parse_decrypted_value(content, "api_key", None);
+            let status = compare_value(&mappings[0], "NO_CHANGE", current.as_deref(), CompareMode::Exact);
+            assert_eq!(status, SyncStatus::Skipped, "Sentinel output should not be written");
+        }
+
+        #[test]
+        fn test_real_value_is_written() {
+            let content = r#"# shell(skip-if=^NO_CHANGE$): fetch-secret
+api_key: old-value"#;
+
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            let current = parse_decrypted_value(content, "api_key", None);
+            let status = compare_value(&mappings[0], "new-value", current.as_deref(), CompareMode::Exact);
+            assert_eq!(status, SyncStatus::OutOfSync, "Real value should be written");
+        }
+
+        #[test]
+        fn test_invalid_skip_if_regex() {
+            let content = r#"# shell(skip-if=[unclosed): fetch-secret
+api_key: old-value"#;
+
+            assert!(parse_commands(content, &ParseOptions::default()).is_err(), "Invalid regex should be rejected");
+        }
+
+        #[test]
+        fn test_unknown_directive_option() {
+            let content = r#"# shell(bogus=1): fetch-secret
+api_key: old-value"#;
+
+            assert!(parse_commands(content, &ParseOptions::default()).is_err(), "Unknown option should be rejected");
+        }
+    }
+
+    mod compare_mode {
+        use super::*;
+        use crate::runner::Runner;
+        use crate::testing::FakeRunner;
+
+        fn mapping() -> CommandMapping {
+            parse_commands("# shell: fetch-secret\napi_key: stored", &ParseOptions::default())
+                .expect("Should parse successfully")
+                .mappings
+                .remove(0)
+        }
+
+        #[test]
+        fn test_exact_reports_trailing_newline_mismatch() {
+            let status = compare_value(&mapping(), "secret", Some("secret\n"), CompareMode::Exact);
+            assert_eq!(status, SyncStatus::OutOfSync);
+        }
+
+        #[test]
+        fn test_trim_stored_ignores_trailing_newline() {
+            let status = compare_value(&mapping(), "secret", Some("secret\n"), CompareMode::TrimStored);
+            assert_eq!(status, SyncStatus::InSync);
+        }
+
+        #[test]
+        fn test_trim_stored_keeps_output_exact() {
+            let status = compare_value(&mapping(), "secret\n", Some("secret"), CompareMode::TrimStored);
+            assert_eq!(status, SyncStatus::OutOfSync, "Command output must not be trimmed");
+        }
+
+        #[test]
+        fn test_trim_output_ignores_trailing_newline() {
+            let status = compare_value(&mapping(), "secret\n", Some("secret"), CompareMode::TrimOutput);
+            assert_eq!(status, SyncStatus::InSync);
+        }
+
+        #[test]
+        fn test_trim_modes_still_detect_real_changes() {
+            let status = compare_value(&mapping(), "new", Some("old\n"), CompareMode::TrimStored);
+            assert_eq!(status, SyncStatus::OutOfSync);
+        }
+
+        /// Syncs `file` twice with the command printing `output`, returning the writes of each sync
+        fn sync_twice(file: &Path, options: SyncOptions, output: &str) -> (Vec<String>, Vec<String>) {
+            let sync = || {
+                let runner = FakeRunner::new().output("print-x", output).applying_sets();
+                let options = SyncOptions { runner: Runner::new(runner.clone()), summary_only: true, ..options.clone() };
+                sync_files(&[file], &options).expect("Should sync");
+                runner.sets()
+            };
+            (sync(), sync())
+        }
+
+        fn sync(compare_mode: CompareMode, stored: &str) -> Vec<String> {
+            let file = create_test_file(&format!("# shell: print-x\napi_key: {}\n", stored));
+            sync_twice(file.path(), SyncOptions { compare_mode, ..Default::default() }, "x").0
+        }
+
+        #[test]
+        fn test_trim_stored_ignores_whitespace_kept_by_sops() {
+            // Read from the decrypted content, where quotes keep the whitespace
+            assert_eq!(sync(CompareMode::Exact, r#""x ""#), vec![r#"["api_key"] "x""#]);
+            assert!(sync(CompareMode::TrimStored, r#""x ""#).is_empty());
+            assert_eq!(sync(CompareMode::TrimOutput, r#""x ""#), vec![r#"["api_key"] "x""#]);
+        }
+
+        #[test]
+        fn test_trim_output_ignores_newline_printed_by_command() {
+            // trim-output trims what's written even with --no-trim
+            let cases = [(CompareMode::TrimStored, false), (CompareMode::TrimOutput, false), (CompareMode::TrimOutput, true)];
+            for (compare_mode, no_trim) in cases {
+                let file = create_test_file("# shell: print-x\napi_key: old\n");
+                let options = SyncOptions { compare_mode, no_trim, ..Default::default() };
+
+                let (first, second) = sync_twice(file.path(), options, "x\n");
+                assert_eq!(first, vec![r#"["api_key"] "x""#], "{:?}", compare_mode);
+                assert!(second.is_empty(), "A second sync must be a no-op with {:?}: {:?}", compare_mode, second);
+            }
+        }
+    }
+
+    mod lint_directives {
+        use crate::parser::{lint_directives, DirectivePosition};
+
+        #[test]
+        fn test_well_formed_directives() {
+            let content = r#"# shell: echo hi
+key1: value1
+# shell(skip-if=^NO_CHANGE$): echo hi
+key2: value2"#;
+
+            let issues = lint_directives(content, DirectivePosition::Before).expect("Should lint successfully");
+            assert!(issues.is_empty(), "Well-formed directives should have no issues: {:?}", issues);
+        }
+
+        #[test]
+        fn test_unclosed_options() {
+            let content = r#"# shell(skip-if=x echo hi
+key1: value1"#;
+
+            let issues = lint_directives(content, DirectivePosition::Before).expect("Should lint successfully");
+            assert_eq!(issues.len(), 1);
+            assert_eq!(issues[0].line, 1);
+        }
+
+        #[test]
+        fn test_unknown_option() {
+            let content = r#"key0: value0
+; shell(bogus=1): echo hi
+key1 = value1"#;
+
+            let issues = lint_directives(content, DirectivePosition::Before).expect("Should lint successfully");
+            assert_eq!(issues.len(), 1);
+            assert_eq!(issues[0].line, 2);
+            assert!(issues[0].message.contains("bogus"));
+        }
+
+        #[test]
+        fn test_empty_command() {
+            let content = r#"# shell:
+key1: value1"#;
+
+            let issues = lint_directives(content, DirectivePosition::Before).expect("Should lint successfully");
+            assert_eq!(issues.len(), 1);
+            assert_eq!(issues[0].message, "Directive has no command");
+        }
+
+        #[test]
+        fn test_directive_without_key() {
+            let content = r#"# shell: echo hi
+# key1: value1
+key2: value2"#;
+
+            let issues = lint_directives(content, DirectivePosition::Before).expect("Should lint successfully");
+            assert_eq!(issues.len(), 1);
+            assert_eq!(issues[0].message, "Directive is not followed by a key");
+        }
+
+        #[test]
+        fn test_encrypted_comments_are_ignored() {
+            let content = r#"#ENC[AES256_GCM,data:XZKYdNp090c0OssVxy3nsbVjJyQ,iv:WsFYuBa3wdEMSX8h=,tag:QRNJOJnTulAc==,type:comment]
+key1: ENC[AES256_GCM,data:z3lcZi7luB,iv:MuIReuZYNIX0zi9h=,tag:CnwlixiANK6H==,type:str]"#;
+
+            let issues = lint_directives(content, DirectivePosition::Before).expect("Should lint successfully");
+            assert!(issues.is_empty());
+        }
+
+        #[test]
+        fn test_unrelated_comments_are_ignored() {
+            let content = r#"# shellcheck disable=SC2034
+# shell scripts live elsewhere
+key1: value1"#;
+
+            let issues = lint_directives(content, DirectivePosition::Before).expect("Should lint successfully");
+            assert!(issues.is_empty());
+        }
+    }
+
+    mod env_profile_directives {
+        use crate::parser::{lint_directives, parse_commands, DirectivePosition, ParseOptions};
+
+        fn profile(name: &str) -> ParseOptions {
+            ParseOptions {
+                env_profile: Some(name.to_string()),
+                ..Default::default()
+            }
+        }
+
+        const CONTENT: &str = r#"# shell[prod]: aws --profile prod secretsmanager get-secret-value
+# shell[staging]: aws --profile staging secretsmanager get-secret-value
+db_password: old-value
+# shell: echo shared
+shared_key: shared-value"#;
+
+        #[test]
+        fn test_selects_prod_command() {
+            let mappings = parse_commands(CONTENT, &profile("prod")).expect("Should parse successfully").mappings;
+            assert_eq!(mappings.len(), 2);
+            assert_eq!(mappings[0].key, "db_password");
+            assert_eq!(mappings[0].command, "aws --profile prod secretsmanager get-secret-value");
+            assert_eq!(mappings[1].key, "shared_key");
+        }
+
+        #[test]
+        fn test_selects_staging_command() {
+            let mappings = parse_commands(CONTENT, &profile("staging")).expect("Should parse successfully").mappings;
+            assert_eq!(mappings.len(), 2);
+            assert_eq!(mappings[0].command, "aws --profile staging secretsmanager get-secret-value");
+        }
+
+        #[test]
+        fn test_tagged_directives_ignored_without_profile() {
+            let mappings = parse_commands(CONTENT, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            assert_eq!(mappings.len(), 1);
+            assert_eq!(mappings[0].key, "shared_key");
+        }
+
+        #[test]
+        fn test_unknown_profile_matches_nothing_tagged() {
+            let mappings = parse_commands(CONTENT, &profile("dev")).expect("Should parse successfully").mappings;
+            assert_eq!(mappings.len(), 1);
+            assert_eq!(mappings[0].key, "shared_key");
+        }
+
+        #[test]
+        fn test_tagged_directive_overrides_untagged() {
+            let content = r#"# shell: echo default
+# shell[prod]: echo prod
+api_key: value"#;
+
+            let mappings = parse_commands(content, &profile("prod")).expect("Should parse successfully").mappings;
+            assert_eq!(mappings.len(), 1);
+            assert_eq!(mappings[0].command, "echo prod");
+
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            assert_eq!(mappings.len(), 1);
+            assert_eq!(mappings[0].command, "echo default");
+        }
+
+        #[test]
+        fn test_tag_with_options() {
+            let content = r#"# shell[prod](skip-if=^NO_CHANGE$): echo prod
+api_key: value"#;
+
+            let mappings = parse_commands(content, &profile("prod")).expect("Should parse successfully").mappings;
+            assert_eq!(mappings.len(), 1);
+            assert!(mappings[0].skip_if.is_some());
+        }
+
+        #[test]
+        fn test_lint_accepts_tags_and_rejects_empty_tag() {
+            let issues = lint_directives(CONTENT, DirectivePosition::Before).expect("Should lint successfully");
+            assert!(issues.is_empty(), "Tagged directives should be valid: {:?}", issues);
+
+            let issues = lint_directives("# shell[]: echo hi\nkey: value", DirectivePosition::Before).expect("Should lint successfully");
+            assert_eq!(issues.len(), 1);
+        }
+    }
+
+    mod jsonl_status {
+        use super::*;
+
+        #[test]
+        fn test_status_names_are_stable() {
+            assert_eq!(SyncStatus::InSync.as_str(), "in_sync");
+            assert_eq!(SyncStatus::OutOfSync.as_str(), "out_of_sync");
+            assert_eq!(SyncStatus::Skipped.as_str(), "skipped");
+        }
+    }
+
+    mod hooks {
+        use super::*;
+        use crate::hooks::run_hook;
+        use tempfile::NamedTempFile;
+
+        #[test]
+        fn test_hook_receives_environment() {
+            let output = NamedTempFile::new().expect("Failed to create temp file");
+            let command = format!(
+                "printf '%s %s' \"$SOPS_SHELL_FILE\" \"$SOPS_SHELL_UPDATES\" > {}",
+                output.path().display()
+            );
+
+            run_hook(&command, &[("SOPS_SHELL_FILE", "secrets.yaml".to_string()), ("SOPS_SHELL_UPDATES", "2".to_string())])
+                .expect("Hook should succeed");
+
+            let written = std::fs::read_to_string(output.path()).expect("Failed to read hook output");
+            assert_eq!(written, "secrets.yaml 2");
+        }
+
+        #[test]
+        fn test_failing_pre_sync_hook_aborts_run() {
+            let options = SyncOptions {
+                pre_sync: Some("exit 1".to_string()),
+                ..Default::default()
+            };
+
+            let result = process_files(&["does-not-exist.yaml"], true, &options);
+            let error = result.expect_err("Failing pre-sync hook should abort the run");
+            assert!(format!("{:#}", error).contains("Pre-sync hook failed"));
+        }
+
+        #[test]
+        fn test_pre_sync_hook_receives_planned_files() {
+            let output = NamedTempFile::new().expect("Failed to create temp file");
+            let options = SyncOptions {
+                pre_sync: Some(format!("printf '%s' \"$SOPS_SHELL_FILES\" > {}; exit 1", output.path().display())),
+                ..Default::default()
+            };
+
+            let _ = process_files(&["a.yaml", "b.env"], true, &options);
+            let written = std::fs::read_to_string(output.path()).expect("Failed to read hook output");
+            assert_eq!(written, "a.yaml\nb.env");
+        }
+
+        #[test]
+        fn test_failing_hook_is_reported() {
+            let result = run_hook("exit 4", &[]);
+            assert!(result.is_err(), "Non-zero hook exit should be an error");
+        }
+    }
+
+    mod sequence_items {
+        use crate::parser::{lint_directives, parse_commands, DirectivePosition, ParseOptions};
+
+        #[test]
+        fn test_directive_before_sequence_item_warns() {
+            let content = r#"hosts:
+  # shell: echo db.internal
+  - db.example.com
+api_key: value"#;
+
+            let parsed = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully");
+            let (mappings, issues) = (parsed.mappings, parsed.issues);
+            assert!(mappings.is_empty(), "Sequence items can't be targeted");
+            assert_eq!(issues.len(), 1);
+            assert_eq!(issues[0].line, 2);
+            assert!(issues[0].message.contains("sequence item on line 3"), "{}", issues[0].message);
+        }
+
+        #[test]
+        fn test_lint_reports_sequence_item() {
+            let content = r#"# shell: echo one
+- item"#;
+
+            let issues = lint_directives(content, DirectivePosition::Before).expect("Should lint successfully");
+            assert_eq!(issues.len(), 1);
+            assert!(issues[0].message.contains("sequence item"));
+        }
+
+        #[test]
+        fn test_commented_key_does_not_warn() {
+            let content = r#"# shell: echo hi
+# key1: value1
+key2: value2"#;
+
+            let parsed = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully");
+            let (mappings, issues) = (parsed.mappings, parsed.issues);
+            assert!(mappings.is_empty());
+            assert!(issues.is_empty(), "Commented-out keys intentionally disable a directive");
+        }
+    }
+
+    mod exit_code_values {
+        use super::*;
+        use crate::runner::SystemRunner;
+
+        fn env() -> CommandEnv {
+            CommandEnv::default()
+        }
+
+        #[test]
+        fn test_exit_code_zero() {
+            let value = execute_command(&SystemRunner, "true", None, &env(), ValueSource::ExitCode, Capture::Stdout, None)
+                .expect("Should not fail");
+            assert_eq!(value, "0");
+        }
+
+        #[test]
+        fn test_non_zero_exit_code_is_a_value() {
+            let command = "echo ignored; exit 3";
+            let value = execute_command(&SystemRunner, command, None, &env(), ValueSource::ExitCode, Capture::Stdout, None)
+                .expect("Should not fail");
+            assert_eq!(value, "3");
+        }
+
+        #[test]
+        fn test_non_zero_exit_fails_for_stdout() {
+            assert!(execute_command(&SystemRunner, "exit 3", None, &env(), ValueSource::Stdout, Capture::Stdout, None).is_err());
+        }
+
+        #[test]
+        fn test_parse_value_from_option() {
+            let content = r#"# shell(value-from=exit-code): curl -sf https://example.com/health
+service_healthy: 0"#;
+
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            assert_eq!(mappings[0].value_from, ValueSource::ExitCode);
+
+            let content = "# shell(value-from=stderr): echo hi\nkey: value";
+            assert!(parse_commands(content, &ParseOptions::default()).is_err(), "Unknown value source should be rejected");
+        }
+    }
+
+    mod max_commands {
+        use super::*;
+        use crate::runner::Runner;
+        use crate::testing::FakeRunner;
+
+        #[test]
+        fn test_budget_unlimited_by_default() {
+            let mut state = RunState { commands_executed: 1000, ..Default::default() };
+            assert!(!state.command_budget_exhausted(&SyncOptions::default()));
+        }
+
+        #[test]
+        fn test_budget_exhausted_at_limit() {
+            let options = SyncOptions { max_commands: Some(2), ..Default::default() };
+            let mut state = RunState { commands_executed: 1, ..Default::default() };
+
+            assert!(!state.command_budget_exhausted(&options));
+            state.commands_executed = 2;
+            assert!(state.command_budget_exhausted(&options));
+            assert!(state.command_limit_reached);
+        }
+
+        #[test]
+        fn test_zero_limit_runs_nothing() {
+            let options = SyncOptions { max_commands: Some(0), ..Default::default() };
+            let mut state = RunState::default();
+            assert!(state.command_budget_exhausted(&options));
+        }
+
+        /// Checks a key whose command always fails and is retried twice, returning the commands run
+        fn drift_failing(max_commands: Option<usize>) -> (Vec<String>, RunState) {
+            let content = "# shell(retries=2): fetch\ntoken: old\n";
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            let runner = FakeRunner::new().failing("fetch", "unavailable");
+            let options = SyncOptions { runner: Runner::new(runner.clone()), max_commands, ..Default::default() };
+            let mut state = RunState::default();
+            drift_for_mappings(Path::new("secrets.yaml"), content, &mappings, &options, &mut state, |_| {});
+            (runner.commands(), state)
+        }
+
+        #[test]
+        fn test_retries_count_against_budget() {
+            let (commands, state) = drift_failing(None);
+            assert_eq!(commands.len(), 3);
+            assert_eq!(state.commands_executed, 3);
+        }
+
+        #[test]
+        fn test_retries_stop_when_budget_is_used_up() {
+            let (commands, state) = drift_failing(Some(1));
+            assert_eq!(commands, vec!["fetch"]);
+            assert_eq!(state.commands_executed, 1);
+            assert!(state.command_limit_reached);
+        }
+    }
+
+    mod command_policy {
+        use super::*;
+
+        fn policy(allow: &[&str], deny: &[&str]) -> CommandPolicy {
+            CommandPolicy {
+                allow: allow.iter().map(|s| s.to_string()).collect(),
+                deny: deny.iter().map(|s| s.to_string()).collect(),
+            }
+        }
+
+        #[test]
+        fn test_empty_policy_allows_everything() {
+            assert!(policy(&[], &[]).check("rm -rf /tmp/nothing").is_ok());
+        }
+
+        #[test]
+        fn test_allowlist() {
+            let policy = policy(&["aws", "vault"], &[]);
+            assert!(policy.check("aws secretsmanager get-secret-value --secret-id db").is_ok());
+            assert!(policy.check("curl https://example.com").is_err());
+        }
+
+        #[test]
+        fn test_allowlist_checks_every_pipeline_stage() {
+            let rbw_only = policy(&["rbw"], &[]);
+            assert!(rbw_only.check(r#"rbw get abc --raw | jq -r ".data.uris[0].uri""#).is_err());
+
+            let rbw_and_jq = policy(&["rbw", "jq"], &[]);
+            assert!(rbw_and_jq.check(r#"rbw get abc --raw | jq -r ".data | .uri""#).is_ok());
+        }
+
+        #[test]
+        fn test_allowlist_checks_command_lists() {
+            let policy = policy(&["aws"], &[]);
+            assert!(policy.check("aws sts get-caller-identity; curl evil.example.com").is_err());
+            assert!(policy.check("aws sts get-caller-identity && rm -rf ~").is_err());
+        }
+
+        #[test]
+        fn test_env_assignments_are_skipped() {
+            let policy = policy(&["aws"], &[]);
+            assert!(policy.check("AWS_PROFILE=prod aws sts get-caller-identity").is_ok());
+        }
+
+        #[test]
+        fn test_denylist() {
+            let policy = policy(&[], &["curl"]);
+            assert!(policy.check("curl https://example.com").is_err());
+            assert!(policy.check("vault kv get -field=pass secret/db").is_ok());
+        }
+
+        #[test]
+        fn test_paths_match_by_file_name() {
+            let policy = policy(&["aws"], &[]);
+            assert!(policy.check("/usr/local/bin/aws sts get-caller-identity").is_ok());
+            assert!(policy.check("/usr/local/bin/curl https://example.com").is_err());
+        }
+
+        #[test]
+        fn test_denylist_matches_absolute_path() {
+            assert!(policy(&[], &["curl"]).check("/usr/bin/curl https://example.com").is_err());
+        }
+
+        #[test]
+        fn test_denylist_matches_relative_path() {
+            assert!(policy(&[], &["curl"]).check("./curl https://example.com").is_err());
+        }
+
+        #[test]
+        fn test_denylist_sees_through_env() {
+            let policy = policy(&[], &["curl"]);
+            assert!(policy.check("env curl https://example.com").is_err());
+            assert!(policy.check("env -i HOME=/tmp curl https://example.com").is_err());
+            assert!(policy.check("/usr/bin/env -u HOME -- curl https://example.com").is_err());
+        }
+
+        #[test]
+        fn test_denylist_sees_through_command_exec_and_sudo() {
+            let policy = policy(&[], &["curl"]);
+            assert!(policy.check("command curl https://example.com").is_err());
+            assert!(policy.check("exec curl https://example.com").is_err());
+            assert!(policy.check("sudo -u root curl https://example.com").is_err());
+            assert!(policy.check("sudo env curl https://example.com").is_err());
+        }
+
+        #[test]
+        fn test_allowlist_checks_wrapper_and_wrapped_binary() {
+            assert!(policy(&["aws"], &[]).check("env aws sts get-caller-identity").is_err());
+            assert!(policy(&["aws", "env"], &[]).check("env AWS_PROFILE=prod aws sts get-caller-identity").is_ok());
+            assert!(policy(&["env", "aws"], &[]).check("env curl https://example.com").is_err());
+        }
+    }
+
+    mod compute_drift {
+        use super::*;
+
+        const CONTENT: &str = r#"# shell: echo new-value
+changed: old-value
+# shell: echo same-value
+unchanged: same-value
+# shell: echo oops >&2; exit 1
+broken: value"#;
+
+        fn drift(options: &SyncOptions) -> Vec<KeyStatus> {
+            let mappings = parse_commands(CONTENT, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            drift_for_mappings(Path::new("secrets.yaml"), CONTENT, &mappings, options, &mut RunState::default(), |_| {})
+        }
+
+        #[test]
+        fn test_reports_each_key() {
+            let statuses = drift(&SyncOptions::default());
+            let summary: Vec<(&str, &str)> = statuses.iter().map(|s| (s.key.as_str(), s.outcome.as_str())).collect();
+            assert_eq!(summary, vec![("changed", "out_of_sync"), ("unchanged", "in_sync"), ("broken", "error")]);
+        }
+
+        #[test]
+        fn test_out_of_sync_carries_new_value() {
+            let statuses = drift(&SyncOptions::default());
+            match &statuses[0].outcome {
+                KeyOutcome::Checked { value, .. } => assert_eq!(value, "new-value"),
+                other => panic!("Unexpected outcome: {:?}", other),
+            }
+        }
+
+        #[test]
+        fn test_callback_sees_every_key() {
+            let mappings = parse_commands(CONTENT, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            let mut seen = Vec::new();
+            drift_for_mappings(Path::new("secrets.yaml"), CONTENT, &mappings, &SyncOptions::default(), &mut RunState::default(), |status| {
+                seen.push(status.key.clone())
+            });
+            assert_eq!(seen, vec!["changed", "unchanged", "broken"]);
+        }
+
+        #[test]
+        fn test_stops_at_command_limit() {
+            let options = SyncOptions { max_commands: Some(1), ..Default::default() };
+            let statuses = drift(&options);
+            assert_eq!(statuses.len(), 1);
+        }
+
+        #[test]
+        fn test_refused_commands_are_not_executed() {
+            let options = SyncOptions {
+                policy: CommandPolicy { allow: vec!["true".to_string()], deny: Vec::new() },
+                ..Default::default()
+            };
+            let mappings = parse_commands(CONTENT, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            let mut state = RunState::default();
+            let statuses = drift_for_mappings(Path::new("secrets.yaml"), CONTENT, &mappings, &options, &mut state, |_| {});
+
+            assert!(statuses.iter().all(|s| matches!(s.outcome, KeyOutcome::Refused(_))));
+            assert_eq!(state.commands_executed, 0);
+        }
+    }
+
+    mod directive_position {
+        use crate::parser::{lint_directives, parse_commands, DirectivePosition, ParseOptions};
+
+        fn after() -> ParseOptions {
+            ParseOptions {
+                directive_position: DirectivePosition::After,
+                ..Default::default()
+            }
+        }
+
+        #[test]
+        fn test_directive_following_its_key() {
+            let content = r#"postgres_user: postgresness
+github_token: some-secret
+# shell: rbw get github --field token
+other: value"#;
+
+            let mappings = parse_commands(content, &after()).expect("Should parse successfully").mappings;
+            assert_eq!(mappings.len(), 1);
+            assert_eq!(mappings[0].key, "github_token");
+            assert_eq!(mappings[0].command, "rbw get github --field token");
+        }
+
+        #[test]
+        fn test_default_position_targets_following_key() {
+            let content = r#"github_token: some-secret
+# shell: rbw get github --field token
+other: value"#;
+
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            assert_eq!(mappings[0].key, "other");
+        }
+
+        #[test]
+        fn test_stacked_directives_after_key() {
+            let content = r#"db_password = secret
+
+; shell[prod]: echo prod
+; shell[staging]: echo staging"#;
+
+            let options = ParseOptions {
+                env_profile: Some("staging".to_string()),
+                directive_position: DirectivePosition::After,
+                ..Default::default()
+            };
+            let mappings = parse_commands(content, &options).expect("Should parse successfully").mappings;
+            assert_eq!(mappings.len(), 1);
+            assert_eq!(mappings[0].key, "db_password");
+            assert_eq!(mappings[0].command, "echo staging");
+        }
+
+        #[test]
+        fn test_skip_when_previous_key_commented() {
+            let content = r#"key1: value1
+# key2: value2
+# shell: echo hi"#;
+
+            let parsed = parse_commands(content, &after()).expect("Should parse successfully");
+            assert!(parsed.mappings.is_empty());
+            assert!(parsed.issues.is_empty());
+        }
+
+        #[test]
+        fn test_directive_at_top_of_file() {
+            let content = r#"# shell: echo hi
+key1: value1"#;
+
+            let parsed = parse_commands(content, &after()).expect("Should parse successfully");
+            assert!(parsed.mappings.is_empty());
+            assert_eq!(parsed.issues[0].message, "Directive is not preceded by a key");
+
+            let issues = lint_directives(content, DirectivePosition::After).expect("Should lint successfully");
+            assert_eq!(issues.len(), 1);
+        }
+    }
+
+    mod sops_value_formatting {
+        use crate::sops::format_value_for_sops;
+
+        #[test]
+        fn test_object_keys_are_sorted() {
+            let formatted = format_value_for_sops(r#"{"zeta": 1, "alpha": {"d": [3, {"y": 1, "x": 2}], "c": null}}"#)
+                .expect("Should format");
+            assert_eq!(formatted, r#"{"alpha":{"c":null,"d":[3,{"x":2,"y":1}]},"zeta":1}"#);
+        }
+
+        #[test]
+        fn test_formatting_is_stable_across_input_order() {
+            let first = format_value_for_sops(r#"{"b": "two", "a": "one"}"#).expect("Should format");
+            let second = format_value_for_sops(r#"{ "a": "one",  "b": "two" }"#).expect("Should format");
+            assert_eq!(first, second);
+            assert_eq!(first, format_value_for_sops(&first).expect("Should format"));
+        }
+
+        #[test]
+        fn test_plain_strings_are_quoted() {
+            let formatted = format_value_for_sops(r#"not "json""#).expect("Should format");
+            assert_eq!(formatted, r#""not \"json\"""#);
+        }
+    }
+
+    mod self_referencing_commands {
+        use super::*;
+
+        fn statuses(content: &str, strict: bool) -> Vec<KeyStatus> {
+            let options = SyncOptions { strict, ..Default::default() };
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            drift_for_mappings(Path::new("secrets/prod.yaml"), content, &mappings, &options, &mut RunState::default(), |_| {})
+        }
+
+        #[test]
+        fn test_path_reference_detected() {
+            assert!(references_file("cat secrets/prod.yaml | head -1", Path::new("secrets/prod.yaml")));
+            assert!(!references_file("rbw get github", Path::new("secrets/prod.yaml")));
+        }
+
+        #[test]
+        fn test_sops_against_same_file_detected() {
+            assert!(references_file("sops --set '[\"a\"] 1' ../prod.yaml", Path::new("secrets/prod.yaml")));
+            assert!(!references_file("cat ../prod.yaml", Path::new("secrets/prod.yaml")));
+        }
+
+        #[test]
+        fn test_warns_by_default() {
+            let statuses = statuses("# shell: echo secrets/prod.yaml\nkey: secrets/prod.yaml", false);
+            assert_eq!(statuses[0].warnings.len(), 1);
+            assert_eq!(statuses[0].outcome.as_str(), "in_sync");
+        }
+
+        #[test]
+        fn test_refused_when_strict() {
+            let statuses = statuses("# shell: echo secrets/prod.yaml\nkey: secrets/prod.yaml", true);
+            assert!(matches!(statuses[0].outcome, KeyOutcome::Refused(_)));
+        }
+    }
+
+    mod format_override {
+        use super::*;
+        use crate::format::FileFormat;
+
+        #[test]
+        fn test_forced_format_wins_over_detection() {
+            assert_eq!(FileFormat::resolve(Path::new("secrets"), None), None);
+            assert_eq!(FileFormat::resolve(Path::new("secrets"), Some(FileFormat::Env)), Some(FileFormat::Env));
+            assert_eq!(FileFormat::resolve(Path::new("app.yaml"), Some(FileFormat::Env)), Some(FileFormat::Env));
+            assert_eq!(FileFormat::resolve(Path::new("app.yml"), None), Some(FileFormat::Yaml));
+        }
+
+        #[test]
+        fn test_env_parsing_on_extensionless_file() {
+            let content = r#"# shell: echo http://localhost:8080
+SERVICE_URL=http://db:5432
+NOTE: not a key in env files"#;
+
+            let options = SyncOptions {
+                parse: ParseOptions {
+                    format: Some(FileFormat::Env),
+                    ..Default::default()
+                },
+                ..Default::default()
+            }
+            .for_file(Path::new("secrets"));
+
+            let mappings = parse_commands(content, &options.parse).expect("Should parse successfully").mappings;
+            assert_eq!(mappings[0].key, "SERVICE_URL");
+            assert_eq!(
+                parse_decrypted_value(content, "SERVICE_URL", options.parse.format).as_deref(),
+                Some("http://db:5432")
+            );
+            assert_eq!(parse_decrypted_value(content, "NOTE", options.parse.format), None);
+        }
+
+        #[test]
+        fn test_yaml_keys_only_use_colon() {
+            let content = r#"# shell: echo value
+a=b: value"#;
+
+            let options = ParseOptions {
+                format: Some(FileFormat::Yaml),
+                ..Default::default()
+            };
+            let mappings = parse_commands(content, &options).expect("Should parse successfully").mappings;
+            assert_eq!(mappings[0].key, "a=b");
+        }
+    }
+
+    mod output_suffix {
+        use super::*;
+        use crate::runner::Runner;
+        use crate::testing::FakeRunner;
+
+        const ORIGINAL: &str = "# shell: echo new\napi_key: ENC[AES256_GCM,data:old,iv:test,tag:test,type:str]\n";
+
+        fn suffixed() -> SyncOptions {
+            SyncOptions { output_suffix: Some(".synced".to_string()), ..Default::default() }
+        }
+
+        #[test]
+        fn test_in_place_by_default() {
+            let (target, sops) = prepare_write_target(Path::new("secrets.yaml"), &SyncOptions::default())
+                .expect("Should not fail");
+            assert_eq!(target, PathBuf::from("secrets.yaml"));
+            assert_eq!(sops.format_as, None);
+        }
+
+        #[test]
+        fn test_copy_leaves_original_untouched() {
+            let dir = tempfile::tempdir().expect("Failed to create temp dir");
+            let original = dir.path().join("secrets.yaml");
+            std::fs::write(&original, ORIGINAL).expect("Failed to write file");
+
+            let (target, sops) = prepare_write_target(&original, &suffixed()).expect("Should not fail");
+            std::fs::write(&target, "modified").expect("Failed to write copy");
+
+            assert_eq!(target, dir.path().join("secrets.yaml.synced"));
+            assert_eq!(sops.format_as, Some(FileFormat::Yaml));
+            assert_eq!(std::fs::read_to_string(&original).expect("Failed to read file"), ORIGINAL);
+        }
+
+        #[test]
+        fn test_copy_starts_from_encrypted_original() {
+            let dir = tempfile::tempdir().expect("Failed to create temp dir");
+            let original = dir.path().join("app.env");
+            std::fs::write(&original, ORIGINAL).expect("Failed to write file");
+
+            let (target, _) = prepare_write_target(&original, &suffixed()).expect("Should not fail");
+            assert_eq!(std::fs::read_to_string(target).expect("Failed to read copy"), ORIGINAL);
+        }
+
+        #[test]
+        fn test_unknown_format_is_rejected() {
+            let dir = tempfile::tempdir().expect("Failed to create temp dir");
+            let original = dir.path().join("secrets");
+            std::fs::write(&original, ORIGINAL).expect("Failed to write file");
+
+            assert!(prepare_write_target(&original, &suffixed()).is_err());
+        }
+
+        #[test]
+        fn test_failed_copy_reports_no_updates() {
+            let dir = tempfile::tempdir().expect("Failed to create temp dir");
+            let original = dir.path().join("secrets");
+            std::fs::write(&original, ORIGINAL).expect("Failed to write file");
+            let options = SyncOptions { runner: Runner::new(FakeRunner::new().read_only()), ..suffixed() };
+
+            let mut state = RunState::default();
+            let (secrets, updates) = process_file(&original, false, &options, &mut state).expect("Should process file");
+            assert_eq!((secrets, updates), (1, 0));
+            assert_eq!(state.errors, 1);
+        }
+    }
+
+    mod error_context {
+        use super::*;
+        use crate::parser::{lint_directives, DirectivePosition};
+
+        #[test]
+        fn test_mapping_records_directive_line() {
+            let content = "key0: value\n\n# shell: echo hi\nkey1: value";
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            assert_eq!(mappings[0].line, 3);
+        }
+
+        #[test]
+        fn test_invalid_option_error_contains_line() {
+            let content = "key0: value\n  # shell(bogus=1): echo hi\nkey1: value";
+            let error = parse_commands(content, &ParseOptions::default()).expect_err("Should fail to parse");
+            let message = format!("{:#}", error);
+            assert!(message.contains("line 2"), "{}", message);
+            assert!(message.contains("`# shell(bogus=1): echo hi`"), "{}", message);
+        }
+
+        #[test]
+        fn test_unresolved_issue_contains_snippet() {
+            let content = "key0: value\n# shell: echo orphan";
+            let parsed = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully");
+            let rendered = parsed.issues[0].to_string();
+            assert_eq!(rendered, "Line 2: Directive is not followed by a key: `# shell: echo orphan`");
+        }
+
+        #[test]
+        fn test_lint_issue_contains_snippet() {
+            let issues = lint_directives("# shell(skip-if=x echo hi\nkey: v", DirectivePosition::Before)
+                .expect("Should lint successfully");
+            assert!(issues[0].to_string().contains("`# shell(skip-if=x echo hi`"));
+        }
+    }
+
+    mod json_path_extraction {
+        use super::*;
+        use crate::json_path::JsonPath;
+
+        const OUTPUT: &str = r#"{"SecretString": "hunter2", "data": {"uris": [{"uri": "https://a"}, {"uri": "https://b"}], "port": 5432}, "dotted.key": "x"}"#;
+
+        fn extract(path: &str) -> Result<String> {
+            JsonPath::parse(path)?.extract(OUTPUT)
+        }
+
+        #[test]
+        fn test_top_level_field() {
+            assert_eq!(extract(".SecretString").expect("Should extract"), "hunter2");
+        }
+
+        #[test]
+        fn test_nested_fields() {
+            assert_eq!(extract(".data.uris[1].uri").expect("Should extract"), "https://b");
+            assert_eq!(extract(".data.uris.[0].uri").expect("Should extract"), "https://a");
+            assert_eq!(extract(".data.port").expect("Should extract"), "5432");
+            assert_eq!(extract(r#".["dotted.key"]"#).expect("Should extract"), "x");
+        }
+
+        #[test]
+        fn test_non_string_values_are_json() {
+            assert_eq!(extract(".data.uris[0]").expect("Should extract"), r#"{"uri":"https://a"}"#);
+        }
+
+        #[test]
+        fn test_missing_field_and_invalid_json() {
+            assert!(extract(".nope").is_err());
+            assert!(JsonPath::parse(".a").expect("Should parse").extract("not json").is_err());
+        }
+
+        #[test]
+        fn test_invalid_paths() {
+            assert!(JsonPath::parse("SecretString").is_err());
+            assert!(JsonPath::parse(".a[0").is_err());
+            assert!(JsonPath::parse(".a[x]").is_err());
+        }
+
+        #[test]
+        fn test_directive_extracts_before_comparison() {
+            let content = r#"# shell(json-path=.data.password): echo '{"data": {"password": "s3cret"}}'
+db_password: s3cret"#;
+
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            let statuses =
+                drift_for_mappings(Path::new("secrets.yaml"), content, &mappings, &SyncOptions::default(), &mut RunState::default(), |_| {});
+            assert_eq!(statuses[0].outcome.as_str(), "in_sync");
+        }
+    }
+
+    mod regex_extraction {
+        use super::*;
+
+        fn drift(content: &str) -> Vec<KeyStatus> {
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            drift_for_mappings(Path::new("secrets.yaml"), content, &mappings, &SyncOptions::default(), &mut RunState::default(), |_| {})
+        }
+
+        #[test]
+        fn test_first_capture_group_is_the_value() {
+            let content = r"# shell(extract=token=(\w+)): echo 'issued token=abc123 expires=3600'
+api_token: abc123";
+            let statuses = drift(content);
+            assert_eq!(statuses[0].outcome.as_str(), "in_sync");
+        }
+
+        #[test]
+        fn test_applies_after_json_path() {
+            let content = r#"# shell(json-path=.url extract=://\w+:([^@]+)@): echo '{"url": "postgres://app:s3cret@db"}'
+db_password: old"#;
+            let statuses = drift(content);
+            match &statuses[0].outcome {
+                KeyOutcome::Checked { value, status: SyncStatus::OutOfSync, .. } => assert_eq!(value, "s3cret"),
+                other => panic!("Expected an out of sync value, got {}", other.as_str()),
+            }
+        }
+
+        #[test]
+        fn test_no_match_fails_the_key() {
+            let content = r"# shell(extract=token=(\w+)): echo 'error: s3cret-ish output'
+api_token: abc123";
+            let statuses = drift(content);
+            match &statuses[0].outcome {
+                KeyOutcome::Failed(e) => {
+                    let message = format!("{:#}", e);
+                    assert!(message.contains("doesn't match the command output"));
+                    assert!(!message.contains("s3cret"));
+                }
+                other => panic!("Expected a failure, got {}", other.as_str()),
+            }
+        }
+
+        #[test]
+        fn test_unmatched_optional_group_fails() {
+            let regex = Regex::new(r"token(=(\w+))?").expect("Should be a valid regex");
+            assert!(extract_capture(&regex, "token").is_err());
+            assert_eq!(extract_capture(&regex, "token=abc").expect("Should extract"), "=abc");
+        }
+
+        #[test]
+        fn test_regex_without_group_is_rejected() {
+            let content = "# shell(extract=token=\\w+): echo token=abc\napi_token: abc";
+            let error = parse_commands(content, &ParseOptions::default()).expect_err("Should reject");
+            assert!(format!("{:#}", error).contains("has no capture group"));
+
+            let content = "# shell(extract=token=(\\w+): echo token=abc\napi_token: abc";
+            assert!(parse_commands(content, &ParseOptions::default()).is_err());
+        }
+    }
+
+    mod hash_comparison {
+        use super::*;
+        use crate::hash::verify;
+
+        fn drift(content: &str) -> Vec<KeyStatus> {
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            drift_for_mappings(Path::new("secrets.yaml"), content, &mappings, &SyncOptions::default(), &mut RunState::default(), |_| {})
+        }
+
+        #[test]
+        fn test_sha256_known_digest() {
+            assert_eq!(sha256_hex("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        }
+
+        #[test]
+        fn test_sha256_empty_input() {
+            assert_eq!(sha256_hex(""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        }
+
+        #[test]
+        fn test_sha256_padding_spills_into_a_second_block() {
+            // 56 bytes leaves no room for the length in the first block
+            let input = "abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+            assert_eq!(input.len(), 56);
+            assert_eq!(sha256_hex(input), "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+        }
+
+        #[test]
+        fn test_sha256_multiple_blocks() {
+            let input = "abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmnhijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu";
+            assert_eq!(input.len(), 112);
+            assert_eq!(sha256_hex(input), "cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1");
+            assert_eq!(sha256_hex(&"a".repeat(1_000_000)), "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0");
+        }
+
+        #[test]
+        fn test_salted_hash_verifies_only_its_value() {
+            let first = salted_hash("s3cret").expect("Should hash");
+            let second = salted_hash("s3cret").expect("Should hash");
+            assert_ne!(first, second, "Each hash should get a fresh salt");
+            assert!(verify("s3cret", &first));
+            assert!(!verify("other", &first));
+            assert!(!verify("s3cret", "not-a-hash"));
+            assert!(!verify("s3cret", &first[..first.len() - 1]), "A truncated digest should not verify");
+        }
+
+        #[test]
+        fn test_matching_hash_is_in_sync() {
+            let stored = salted_hash("s3cret").expect("Should hash");
+            let content = format!("# shell(compare=hash): echo s3cret\napi_key: redacted\napi_key_sha256: {}", stored);
+
+            let statuses = drift(&content);
+            assert_eq!(statuses[0].outcome.as_str(), "in_sync", "The stored value itself shouldn't be compared");
+        }
+
+        #[test]
+        fn test_hash_mismatch_is_drift() {
+            let stored = salted_hash("old-secret").expect("Should hash");
+            let content = format!("# shell(compare=hash): echo s3cret\napi_key: s3cret\napi_key_sha256: {}", stored);
+
+            let statuses = drift(&content);
+            match &statuses[0].outcome {
+                KeyOutcome::Checked { status, hash: Some(hash), .. } => {
+                    assert_eq!(*status, SyncStatus::OutOfSync);
+                    assert!(verify("s3cret", hash), "The new hash should match the new value");
+                }
+                other => panic!("Expected an out of sync result with a new hash, got {:?}", other),
+            }
+        }
+
+        #[test]
+        fn test_missing_hash_is_drift() {
+            let statuses = drift("# shell(compare=hash): echo s3cret\napi_key: s3cret");
+            assert_eq!(statuses[0].outcome.as_str(), "out_of_sync");
+        }
+
+        #[test]
+        fn test_unknown_compare_option() {
+            let content = "# shell(compare=md5): echo s3cret\napi_key: s3cret";
+            assert!(parse_commands(content, &ParseOptions::default()).is_err());
+        }
+    }
+
+    mod sops_retries {
+        use super::*;
+        use crate::sops::{with_retries, RetryHook};
+        use std::cell::Cell;
+        use std::rc::Rc;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        fn options(retries: u32) -> SopsOptions {
+            SopsOptions {
+                retries,
+                retry_delay: Duration::ZERO,
+                ..Default::default()
+            }
+        }
+
+        // Fails with each of `errors` in turn, then succeeds
+        fn flaky(errors: &'static [&'static str]) -> (impl FnMut() -> Result<String>, Rc<Cell<usize>>) {
+            let calls = Rc::new(Cell::new(0));
+            let counter = calls.clone();
+            let runner = move || {
+                let call = counter.get();
+                counter.set(call + 1);
+                match errors.get(call) {
+                    Some(error) => Err(anyhow!("SOPS command failed: {}", error)),
+                    None => Ok("decrypted".to_string()),
+                }
+            };
+            (runner, calls)
+        }
+
+        #[test]
+        fn test_retries_throttling_then_succeeds() {
+            let (runner, calls) = flaky(&["ThrottlingException: Rate exceeded"]);
+            let result = with_retries(&options(2), runner);
+            assert_eq!(result.expect("Should succeed on retry"), "decrypted");
+            assert_eq!(calls.get(), 2);
+        }
+
+        #[test]
+        fn test_does_not_retry_auth_errors() {
+            let (runner, calls) = flaky(&["AccessDeniedException: not authorized to perform kms:Decrypt"]);
+            assert!(with_retries(&options(3), runner).is_err());
+            assert_eq!(calls.get(), 1, "Non-retryable errors should fail immediately");
+        }
+
+        #[test]
+        fn test_gives_up_after_retries() {
+            let (runner, calls) = flaky(&["request timed out", "request timed out", "request timed out"]);
+            assert!(with_retries(&options(2), runner).is_err());
+            assert_eq!(calls.get(), 3, "Should make the first attempt plus two retries");
+        }
+
+        #[test]
+        fn test_retries_are_reported_to_the_hook() {
+            let notices = Arc::new(Mutex::new(Vec::new()));
+            let recorded = Arc::clone(&notices);
+            let hook = RetryHook::new(move |notice| recorded.lock().unwrap().push(notice.to_string()));
+            let options = SopsOptions { on_retry: Some(hook), ..options(2) };
+
+            let (runner, _) = flaky(&["ThrottlingException: Rate exceeded"]);
+            with_retries(&options, runner).expect("Should succeed on retry");
+            assert_eq!(
+                *notices.lock().unwrap(),
+                ["Retrying sops after transient failure (attempt 1/2): SOPS command failed: ThrottlingException: Rate exceeded"]
+            );
+        }
+
+        #[test]
+        fn test_no_retries_by_default() {
+            let (runner, calls) = flaky(&["ThrottlingException"]);
+            assert!(with_retries(&SopsOptions::default(), runner).is_err());
+            assert_eq!(calls.get(), 1);
+        }
+    }
+
+    mod fake_runner {
+        use super::*;
+        use crate::runner::Runner;
+        use crate::testing::{secrets_file, FakeRunner, Plaintext};
+
+        fn content() -> String {
+            Plaintext::yaml().secret("fetch-api-key", "api_key", "old").secret("fetch-db-password", "db_password", "same").build()
+        }
+
+        fn fake() -> FakeRunner {
+            FakeRunner::new().decrypting_to(content()).output("fetch-api-key", "new\n").output("fetch-db-password", "same")
+        }
+
+        #[test]
+        fn test_drift_uses_injected_runner() {
+            let file = secrets_file(&content(), ".yaml");
+            let options = SyncOptions { runner: Runner::new(fake()), ..Default::default() };
+
+            let statuses = compute_drift(file.path(), &options).expect("Should compute drift");
+            let outcomes: Vec<&str> = statuses.iter().map(|status| status.outcome.as_str()).collect();
+            assert_eq!(outcomes, vec!["out_of_sync", "in_sync"]);
+        }
+
+        #[test]
+        fn test_process_file_sets_only_drifted_keys() {
+            let file = secrets_file(&content(), ".yaml");
+            let runner = fake();
+            let options = SyncOptions { runner: Runner::new(runner.clone()), ..Default::default() };
+
+            let (secrets, updates) =
+                process_file(file.path(), false, &options, &mut RunState::default()).expect("Should process file");
+            assert_eq!((secrets, updates), (2, 1));
+            assert_eq!(runner.sets(), vec![r#"["api_key"] "new""#.to_string()]);
+        }
+
+        #[test]
+        fn test_failed_command_is_reported() {
+            let file = secrets_file(&content(), ".yaml");
+            let runner = fake().failing("fetch-api-key", "denied");
+            let options = SyncOptions { runner: Runner::new(runner), ..Default::default() };
+
+            let statuses = compute_drift(file.path(), &options).expect("Should compute drift");
+            match &statuses[0].outcome {
+                KeyOutcome::Failed(e) => assert!(e.to_string().contains("denied")),
+                other => panic!("Expected a failure, got {:?}", other),
+            }
+        }
+    }
+
+    mod env_export_prefix {
+        use super::*;
+        use crate::testing::FakeRunner;
+
+        const CONTENT: &str = "# shell: fetch-token\nexport API_TOKEN=\"old\"\n# shell: fetch-host\nDB_HOST=db.internal\n";
+
+        fn env_options() -> ParseOptions {
+            ParseOptions { format: Some(FileFormat::Env), ..Default::default() }
+        }
+
+        #[test]
+        fn test_keys_found_with_and_without_export() {
+            let mappings = parse_commands(CONTENT, &env_options()).expect("Should parse successfully").mappings;
+            let keys: Vec<&str> = mappings.iter().map(|mapping| mapping.key.as_str()).collect();
+            assert_eq!(keys, vec!["API_TOKEN", "DB_HOST"]);
+        }
+
+        #[test]
+        fn test_values_read_with_and_without_export() {
+            assert_eq!(parse_decrypted_value(CONTENT, "API_TOKEN", Some(FileFormat::Env)), Some("old".to_string()));
+            assert_eq!(parse_decrypted_value(CONTENT, "DB_HOST", Some(FileFormat::Env)), Some("db.internal".to_string()));
+        }
+
+        #[test]
+        fn test_export_is_a_key_in_other_formats() {
+            let content = "# shell: fetch-token\nexport API_TOKEN: old\n";
+            let options = ParseOptions { format: Some(FileFormat::Yaml), ..Default::default() };
+            assert!(parse_commands(content, &options).expect("Should parse successfully").mappings.is_empty());
+        }
+
+        #[test]
+        fn test_set_targets_bare_key() {
+            let mappings = parse_commands(CONTENT, &env_options()).expect("Should parse successfully").mappings;
+            let runner = FakeRunner::new();
+            sops_set(&runner, Path::new("secrets.env"), &mappings[0].key, "new", &SopsOptions::default())
+                .expect("Should set");
+            assert_eq!(runner.sets(), vec![r#"["API_TOKEN"] "new""#.to_string()]);
+        }
+    }
+
+    mod only_changed_commands {
+        use super::*;
+
+        const FILE: &str = "secrets.yaml";
+
+        fn run(content: &str, command_state: CommandState) -> (Vec<KeyStatus>, RunState) {
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            let mut state = RunState { command_state: Some(command_state), ..Default::default() };
+            let statuses =
+                drift_for_mappings(Path::new(FILE), content, &mappings, &SyncOptions::default(), &mut state, |_| {});
+            (statuses, state)
+        }
+
+        fn recorded(command: &str) -> CommandState {
+            let mut command_state = CommandState::default();
+            command_state.record(Path::new(FILE), "api_key", command);
+            command_state
+        }
+
+        #[test]
+        fn test_unchanged_command_is_not_run() {
+            let (statuses, state) = run("# shell: echo new\napi_key: old", recorded("echo new"));
+            assert_eq!(statuses[0].outcome.as_str(), "unchanged");
+            assert_eq!(state.commands_executed, 0);
+        }
+
+        #[test]
+        fn test_changed_command_is_run() {
+            let (statuses, state) = run("# shell: echo new\napi_key: old", recorded("echo old"));
+            assert_eq!(statuses[0].outcome.as_str(), "out_of_sync");
+            assert_eq!(state.commands_executed, 1);
+        }
+
+        #[test]
+        fn test_unchanged_command_without_value_is_run() {
+            let (statuses, state) = run("# shell: echo new\napi_key:", recorded("echo new"));
+            assert_eq!(statuses[0].outcome.as_str(), "out_of_sync");
+            assert_eq!(state.commands_executed, 1);
+        }
+
+        #[test]
+        fn test_in_sync_command_is_recorded() {
+            let (_, state) = run("# shell: echo same\napi_key: same", CommandState::default());
+            let command_state = state.command_state.expect("State should be kept");
+            assert!(command_state.is_unchanged(Path::new(FILE), "api_key", "echo same"));
+        }
+
+        #[test]
+        fn test_out_of_sync_command_is_not_recorded_before_update() {
+            let (_, state) = run("# shell: echo new\napi_key: old", CommandState::default());
+            let command_state = state.command_state.expect("State should be kept");
+            assert!(!command_state.is_unchanged(Path::new(FILE), "api_key", "echo new"));
+        }
+
+        #[test]
+        fn test_state_file_round_trip() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let path = dir.path().join("state.json");
+
+            let mut command_state = CommandState::load(&path).expect("A missing state file should load empty");
+            command_state.record(Path::new(FILE), "api_key", "echo new");
+            command_state.save().expect("Should save");
+
+            let loaded = CommandState::load(&path).expect("Should load");
+            assert!(loaded.is_unchanged(Path::new(FILE), "api_key", "echo new"));
+            assert!(!loaded.is_unchanged(Path::new(FILE), "api_key", "echo changed"));
+        }
+    }
+
+    mod explain {
+        use super::*;
+
+        fn key_status(outcome: KeyOutcome) -> KeyStatus {
+            KeyStatus {
+                key: "API_KEY".to_string(),
+                command: "aws secretsmanager get-secret-value".to_string(),
+                outcome,
+                warnings: Vec::new(),
+                duration: Duration::ZERO,
+            }
+        }
+
+        fn checked(status: SyncStatus) -> KeyOutcome {
+            KeyOutcome::Checked { status, value: "new".to_string(), hash: None, checksum: None }
+        }
+
+        #[test]
+        fn test_out_of_sync_with_current_value() {
+            let explanation = explain_key_status(&key_status(checked(SyncStatus::OutOfSync)), Some("old"));
+            assert_eq!(
+                explanation,
+                "API_KEY will run `aws secretsmanager get-secret-value`; current value present; output differs; will update."
+            );
+        }
+
+        #[test]
+        fn test_in_sync_and_missing_value() {
+            let explanation = explain_key_status(&key_status(checked(SyncStatus::InSync)), None);
+            assert!(explanation.contains("no current value; output matches; nothing to do."), "{}", explanation);
+        }
+
+        #[test]
+        fn test_failures_and_warnings() {
+            let mut status = key_status(KeyOutcome::Failed(anyhow!("Command failed: denied")));
+            status.warnings.push("Command references the file being synced".to_string());
+
+            let explanation = explain_key_status(&status, Some("old"));
+            assert!(explanation.contains("command fails (Command failed: denied); will be left as is."), "{}", explanation);
+            assert!(explanation.ends_with("Note: Command references the file being synced."), "{}", explanation);
+        }
+
+        #[test]
+        fn test_unchanged_command_is_not_run() {
+            let explanation = explain_key_status(&key_status(KeyOutcome::Unchanged), Some("old"));
+            assert!(explanation.starts_with("API_KEY won't run"), "{}", explanation);
+        }
+    }
+
+    #[cfg(unix)]
+    mod symlinks {
+        use super::*;
+        use crate::runner::{CommandOutput, CommandRunner, Runner};
+        use std::os::unix::fs::symlink;
+
+        const CONTENT: &str = "# shell: echo new\napi_key: old\n";
+
+        /// Serves `CONTENT` and rewrites the file on `--set` by renaming a new file over it,
+        /// which replaces a symlink rather than writing through it
+        struct ReplacingSops;
+
+        impl CommandRunner for ReplacingSops {
+            fn run(&self, program: &str, args: &[&str]) -> std::io::Result<CommandOutput> {
+                let stdout = match (program, args) {
+                    ("sh", ["-c", command]) => command.trim_start_matches("echo ").to_string(),
+                    (_, ["--decrypt", _]) => CONTENT.to_string(),
+                    (_, [.., "--set", _, path]) => {
+                        let replacement = format!("{}.tmp", path);
+                        std::fs::write(&replacement, CONTENT.replace("old", "new"))?;
+                        std::fs::rename(&replacement, path)?;
+                        String::new()
+                    }
+                    _ => String::new(),
+                };
+                Ok(CommandOutput { code: Some(0), stdout: stdout.into_bytes(), stderr: Vec::new() })
+            }
+        }
+
+        fn sync_link(follow_symlinks: bool) -> (tempfile::TempDir, PathBuf, PathBuf) {
+            let dir = tempfile::tempdir().expect("Failed to create temp dir");
+            let target = dir.path().join("store").join("secrets-v1");
+            std::fs::create_dir(target.parent().unwrap()).expect("Failed to create dir");
+            std::fs::write(&target, CONTENT).expect("Failed to write file");
+            let link = dir.path().join("secrets.yaml");
+            symlink(&target, &link).expect("Failed to create symlink");
+
+            let options = SyncOptions { runner: Runner::new(ReplacingSops), follow_symlinks, ..Default::default() };
+            let (_, updates) = process_file(&link, false, &options, &mut RunState::default()).expect("Should sync");
+            assert_eq!(updates, 1);
+            (dir, link, target)
+        }
+
+        #[test]
+        fn test_follow_symlinks_keeps_link() {
+            let (_dir, link, target) = sync_link(true);
+            assert!(link.is_symlink(), "The link should survive the update");
+            assert!(std::fs::read_to_string(&target).expect("Failed to read target").contains("api_key: new"));
+        }
+
+        #[test]
+        fn test_without_follow_updates_link_path() {
+            let (_dir, link, target) = sync_link(false);
+            assert!(!link.is_symlink(), "sops replaced the link, which is what the warning is about");
+            assert!(std::fs::read_to_string(&target).expect("Failed to read target").contains("api_key: old"));
+        }
+
+        #[test]
+        fn test_followed_link_keeps_format_of_link_name() {
+            let dir = tempfile::tempdir().expect("Failed to create temp dir");
+            let target = dir.path().join("secrets-v1");
+            std::fs::write(&target, CONTENT).expect("Failed to write file");
+            let link = dir.path().join("secrets.env");
+            symlink(&target, &link).expect("Failed to create symlink");
+
+            let options = SyncOptions { follow_symlinks: true, ..Default::default() };
+            let (resolved, sops) = prepare_write_target(&link, &options).expect("Should resolve");
+            assert_eq!(resolved, std::fs::canonicalize(&target).unwrap());
+            assert_eq!(sops.format_as, Some(FileFormat::Env));
+        }
+    }
+
+    mod require_clean_git {
+        use super::*;
+        use crate::runner::{CommandOutput, CommandRunner, Runner, SystemRunner};
+        use std::sync::{Arc, Mutex};
+
+        const CONTENT: &str = "# shell: echo new\napi_key: old\n";
+
+        /// Answers `git status` with `git_status` and records `--set` calls
+        struct FakeGit {
+            git_status: CommandOutput,
+            sets: Arc<Mutex<usize>>,
+        }
+
+        impl CommandRunner for FakeGit {
+            fn run(&self, program: &str, args: &[&str]) -> std::io::Result<CommandOutput> {
+                let stdout = match (program, args) {
+                    ("git", [.., "status", "--porcelain", "--untracked-files=no", "--", _]) => return Ok(self.git_status.clone()),
+                    ("sh", ["-c", command]) => command.trim_start_matches("echo ").to_string(),
+                    (_, ["--decrypt", _]) => CONTENT.to_string(),
+                    (_, ["--set", _, _]) => {
+                        *self.sets.lock().unwrap() += 1;
+                        String::new()
+                    }
+                    _ => String::new(),
+                };
+                Ok(CommandOutput { code: Some(0), stdout: stdout.into_bytes(), stderr: Vec::new() })
+            }
+        }
+
+        /// The `--set` calls made, the updates reported and the run's state
+        fn sync_with_git(code: i32, stdout: &str) -> (usize, usize, RunState) {
+            let file = create_test_file(CONTENT);
+            let sets = Arc::new(Mutex::new(0));
+            let runner = FakeGit {
+                git_status: CommandOutput { code: Some(code), stdout: stdout.as_bytes().to_vec(), stderr: Vec::new() },
+                sets: sets.clone(),
+            };
+            let options = SyncOptions { runner: Runner::new(runner), require_clean_git: true, ..Default::default() };
+
+            let mut state = RunState::default();
+            let (_, updates) = process_file(file.path(), false, &options, &mut state).expect("Should process file");
+            let sets = *sets.lock().unwrap();
+            (sets, updates, state)
+        }
+
+        #[test]
+        fn test_dirty_file_is_not_written() {
+            let (sets, _, state) = sync_with_git(0, " M secrets.yaml\n");
+            assert_eq!(sets, 0);
+            assert_eq!(state.errors, 1);
+        }
+
+        #[test]
+        fn test_refused_write_reports_no_updates() {
+            let (_, updates, _) = sync_with_git(0, " M secrets.yaml\n");
+            assert_eq!(updates, 0);
+        }
+
+        #[test]
+        fn test_clean_file_is_written() {
+            let (sets, updates, state) = sync_with_git(0, "");
+            assert_eq!((sets, updates), (1, 1));
+            assert_eq!(state.errors, 0);
+        }
+
+        #[test]
+        fn test_outside_repository_is_written() {
+            let (sets, _, state) = sync_with_git(128, "");
+            assert_eq!(sets, 1, "Files outside a repository have nothing to protect");
+            assert_eq!(state.errors, 0);
+        }
+
+        /// Runs git in `dir`, or returns false when git isn't installed
+        fn git(dir: &Path, args: &[&str]) -> bool {
+            let dir = dir.to_string_lossy();
+            let args = [&["-C", &dir, "-c", "user.name=test", "-c", "user.email=test@example.com"], args].concat();
+            match SystemRunner.run("git", &args) {
+                Ok(output) => {
+                    assert!(output.success(), "git {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+                    true
+                }
+                Err(_) => false,
+            }
+        }
+
+        #[test]
+        fn test_untracked_file_is_clean() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = dir.path().join("secrets.yaml");
+            std::fs::write(&file, CONTENT).expect("Should write file");
+            if !git(dir.path(), &["init", "--quiet"]) {
+                return;
+            }
+            assert!(crate::git::ensure_clean(&SystemRunner, &file).is_ok());
+
+            git(dir.path(), &["add", "secrets.yaml"]);
+            git(dir.path(), &["commit", "--quiet", "-m", "Add secrets"]);
+            std::fs::write(&file, "api_key: edited\n").expect("Should write file");
+            assert!(crate::git::ensure_clean(&SystemRunner, &file).is_err(), "Changes to a committed file are still refused");
+        }
+    }
+
+    mod run_as_user {
+        use super::*;
+        use crate::runner::{CommandOutput, CommandRunner};
+
+        /// Pretends only `installed` programs exist
+        struct Installed(&'static [&'static str]);
+
+        impl CommandRunner for Installed {
+            fn run(&self, program: &str, _args: &[&str]) -> std::io::Result<CommandOutput> {
+                if self.0.contains(&program) {
+                    Ok(CommandOutput { code: Some(0), ..Default::default() })
+                } else {
+                    Err(std::io::Error::from(std::io::ErrorKind::NotFound))
+                }
+            }
+        }
+
+        #[test]
+        fn test_user_option_is_parsed() {
+            let content = "# shell(user=deploy): cat /etc/deploy/key\ndeploy_key: old";
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            assert_eq!(mappings[0].user.as_deref(), Some("deploy"));
+
+            let invalid = "# shell(user=-r): cat /etc/deploy/key\ndeploy_key: old";
+            assert!(parse_commands(invalid, &ParseOptions::default()).is_err());
+        }
+
+        #[test]
+        fn test_without_user_runs_sh() {
+            let (program, args) = shell_invocation(&Installed(&[]), "echo hi", None).expect("Should not fail");
+            assert_eq!(program, "sh");
+            assert_eq!(args, vec!["-c", "echo hi"]);
+        }
+
+        #[test]
+        fn test_prefers_sudo() {
+            let (program, args) =
+                shell_invocation(&Installed(&["sudo", "su"]), "echo hi", Some("deploy")).expect("Should not fail");
+            assert_eq!(program, "sudo");
+            assert_eq!(args, vec!["-n", "-u", "deploy", "--", "sh", "-c", "echo hi"]);
+        }
+
+        #[test]
+        fn test_falls_back_to_su() {
+            let (program, args) = shell_invocation(&Installed(&["su"]), "echo hi", Some("deploy")).expect("Should not fail");
+            assert_eq!(program, "su");
+            assert_eq!(args, vec!["-s", "/bin/sh", "-c", "echo hi", "deploy"]);
+        }
+
+        #[test]
+        fn test_fails_without_escalation() {
+            let error = shell_invocation(&Installed(&[]), "echo hi", Some("deploy")).expect_err("Should fail");
+            assert!(error.to_string().contains("requires sudo or su"));
+        }
+    }
+
+    mod timeout_total {
+        use super::*;
+        use crate::runner::{CommandOutput, CommandRunner, Runner};
+        use crate::testing::FakeRunner;
+
+        #[test]
+        fn test_no_commands_run_after_deadline() {
+            let content = "# shell: echo a\nfirst: a\n# shell: echo b\nsecond: b";
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            let mut state = RunState { deadline: Some(Instant::now()), ..Default::default() };
+
+            let statuses =
+                drift_for_mappings(Path::new("secrets.yaml"), content, &mappings, &SyncOptions::default(), &mut state, |_| {});
+            assert!(statuses.is_empty());
+            assert!(state.timed_out);
+            assert_eq!(state.commands_executed, 0);
+        }
+
+        #[test]
+        fn test_commands_run_before_deadline() {
+            let content = "# shell: echo a\nfirst: a";
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            let mut state = RunState { deadline: Some(Instant::now() + Duration::from_secs(60)), ..Default::default() };
+
+            let statuses =
+                drift_for_mappings(Path::new("secrets.yaml"), content, &mappings, &SyncOptions::default(), &mut state, |_| {});
+            assert_eq!(statuses.len(), 1);
+            assert!(!state.timed_out);
+        }
+
+        /// Decrypts by reading the file and takes 100ms for each `--set`
+        struct SlowWrites(FakeRunner);
+
+        impl CommandRunner for SlowWrites {
+            fn run(&self, program: &str, args: &[&str]) -> std::io::Result<CommandOutput> {
+                if args.contains(&"--set") {
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+                self.0.run(program, args)
+            }
+        }
+
+        #[test]
+        fn test_writes_cut_short_are_not_reported() {
+            let file = create_test_file("# shell: echo a\nfirst: old\n# shell: echo b\nsecond: old\n");
+            let runner = FakeRunner::new();
+            let options = SyncOptions { runner: Runner::new(SlowWrites(runner.clone())), ..Default::default() };
+            // Passes during the first write
+            let mut state = RunState { deadline: Some(Instant::now() + Duration::from_millis(50)), ..Default::default() };
+
+            let (secrets, updates) = process_file(file.path(), false, &options, &mut state).expect("Should process file");
+            assert_eq!(runner.sets().len(), 1);
+            assert_eq!((secrets, updates), (2, 1));
+        }
+
+        #[test]
+        fn test_run_fails_with_timeout_error() {
+            let options = SyncOptions { timeout_total: Some(Duration::ZERO), ..Default::default() };
+            let error = check_files(&["missing.yaml"], &options).expect_err("Should time out");
+            assert!(error.is::<TimeoutExceeded>());
+        }
+    }
+
+    mod timeout_per_file {
+        use super::*;
+        use crate::runner::{CommandOutput, CommandRunner, Runner};
+
+        /// Decrypts by reading the file, with `slow` commands taking 100ms each
+        struct SlowRunner;
+
+        impl CommandRunner for SlowRunner {
+            fn run(&self, program: &str, args: &[&str]) -> std::io::Result<CommandOutput> {
+                let stdout = match (program, args) {
+                    ("sops", [.., "--decrypt", path]) => std::fs::read_to_string(path)?,
+                    ("sh", ["-c", command]) => {
+                        if command.contains("slow") {
+                            std::thread::sleep(Duration::from_millis(100));
+                        }
+                        "value\n".to_string()
+                    }
+                    _ => String::new(),
+                };
+                Ok(CommandOutput { code: Some(0), stdout: stdout.into_bytes(), stderr: Vec::new() })
+            }
+        }
+
+        #[test]
+        fn test_file_over_budget_is_cut_short() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let slow = dir.path().join("slow.yaml");
+            let fast = dir.path().join("fast.yaml");
+            let keys: String = (1..=4).map(|i| format!("# shell: slow {}\nkey{}: value\n", i, i)).collect();
+            std::fs::write(&slow, keys).expect("Should write file");
+            std::fs::write(&fast, "# shell: echo value\ntoken: value\n").expect("Should write file");
+
+            let options = SyncOptions {
+                runner: Runner::new(SlowRunner),
+                timeout_per_file: Some(Duration::from_millis(150)),
+                summary_only: true,
+                ..Default::default()
+            };
+            let summary = check_files(&[&slow, &fast], &options).expect("Should check");
+            // The slow file stops after a command or two, the budget starts over for the fast one
+            assert!((2..=4).contains(&summary.secrets), "Checked {} secrets", summary.secrets);
+            assert_eq!(summary.files, 2);
+            assert_eq!(summary.errors, 1);
+        }
+
+        #[test]
+        fn test_file_within_budget_is_processed_whole() {
+            let content = "# shell: echo a\nfirst: a\n# shell: echo b\nsecond: b";
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            let mut state = RunState { file_deadline: Some(Instant::now() + Duration::from_secs(60)), ..Default::default() };
+
+            let statuses =
+                drift_for_mappings(Path::new("secrets.yaml"), content, &mappings, &SyncOptions::default(), &mut state, |_| {});
+            assert_eq!(statuses.len(), 2);
+            assert!(!state.file_timed_out);
+        }
+
+        #[test]
+        fn test_no_commands_run_past_the_file_deadline() {
+            let content = "# shell: echo a\nfirst: a";
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            let mut state = RunState { file_deadline: Some(Instant::now()), ..Default::default() };
+
+            let statuses =
+                drift_for_mappings(Path::new("secrets.yaml"), content, &mappings, &SyncOptions::default(), &mut state, |_| {});
+            assert!(statuses.is_empty());
+            assert!(state.file_timed_out);
+            assert!(!state.timed_out);
+        }
+    }
+
+    mod shell_map {
+        use super::*;
+        use crate::parser::{lint_directives, DirectivePosition};
+
+        const CONTENT: &str = r#"# shell-map: echo '{"data": {"user": "app", "pass": "s3cret"}}'
+# username=.data.user
+# password=.data.pass
+username: app
+password: old"#;
+
+        #[test]
+        fn test_fields_become_mappings() {
+            let mappings = parse_commands(CONTENT, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            let keys: Vec<&str> = mappings.iter().map(|mapping| mapping.key.as_str()).collect();
+            assert_eq!(keys, vec!["username", "password"]);
+            assert!(mappings.iter().all(|mapping| mapping.shared && mapping.line == 1));
+        }
+
+        #[test]
+        fn test_fields_on_one_line() {
+            let content = "# shell-map: vault read -format=json secret/app\n# username=.data.user password=.data.pass\nusername: a";
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            assert_eq!(mappings.len(), 2);
+        }
+
+        #[test]
+        fn test_command_runs_once_for_all_fields() {
+            let mappings = parse_commands(CONTENT, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            let mut state = RunState::default();
+            let statuses =
+                drift_for_mappings(Path::new("secrets.yaml"), CONTENT, &mappings, &SyncOptions::default(), &mut state, |_| {});
+
+            assert_eq!(state.commands_executed, 1);
+            let outcomes: Vec<&str> = statuses.iter().map(|status| status.outcome.as_str()).collect();
+            assert_eq!(outcomes, vec!["in_sync", "out_of_sync"]);
+            match &statuses[1].outcome {
+                KeyOutcome::Checked { value, .. } => assert_eq!(value, "s3cret"),
+                other => panic!("Expected a checked result, got {:?}", other),
+            }
+        }
+
+        #[test]
+        fn test_missing_fields_are_reported() {
+            let content = "# shell-map: vault read -format=json secret/app\nusername: a";
+            let parsed = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully");
+            assert!(parsed.mappings.is_empty());
+            assert_eq!(parsed.issues.len(), 1);
+
+            let issues = lint_directives(content, DirectivePosition::Before).expect("Should lint");
+            assert_eq!(issues.len(), 1);
+            assert!(issues[0].message.contains("<key>=<json path>"));
+        }
+    }
+
+    mod require_all_directives {
+        use super::*;
+        use crate::runner::Runner;
+        use crate::testing::FakeRunner;
+
+        const DANGLING: &str = "# shell: echo a\nfirst: a\n# shell: echo b";
+
+        fn check(require_all_directives: bool) -> (Result<RunSummary>, usize) {
+            let file = create_test_file(DANGLING);
+            let options = SyncOptions { runner: Runner::new(FakeRunner::new()), require_all_directives, ..Default::default() };
+            let mut state = RunState::default();
+            process_file(file.path(), true, &options, &mut state).expect("Should process file");
+            (check_files(&[file.path()], &options), state.commands_executed)
+        }
+
+        #[test]
+        fn test_dangling_directive_at_end_of_file_is_reported() {
+            let parsed = parse_commands(DANGLING, &ParseOptions::default()).expect("Should parse successfully");
+            assert_eq!(parsed.mappings.len(), 1);
+            assert_eq!(parsed.issues.len(), 1);
+            assert_eq!(parsed.issues[0].line, 3);
+        }
+
+        #[test]
+        fn test_dangling_directive_only_warns_by_default() {
+            let (result, commands) = check(false);
+            assert!(result.is_ok());
+            assert_eq!(commands, 1);
+        }
+
+        #[test]
+        fn test_dangling_directive_fails_run() {
+            let (result, commands) = check(true);
+            let error = result.expect_err("Should fail the run");
+            assert!(error.to_string().contains("1 directive(s) couldn't be matched"), "{}", error);
+            assert_eq!(commands, 0, "No commands should run for a file with unresolved directives");
+        }
+
+        #[test]
+        fn test_commented_out_key_is_not_unresolved() {
+            let content = "# shell: echo a\n# first: a";
+            let parsed = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully");
+            assert!(parsed.issues.is_empty(), "Commenting out the key is how a directive is disabled");
+        }
+    }
+
+    mod create_from_template {
+        use super::*;
+        use crate::runner::{CommandOutput, CommandRunner, Runner};
+        use std::sync::{Arc, Mutex};
+
+        const TEMPLATE: &str = "# shell: echo fresh\napi_key: placeholder\n";
+
+        /// Records `--encrypt` calls, failing them when `fail` is set
+        #[derive(Default)]
+        struct Encrypting {
+            fail: bool,
+            encrypted: Arc<Mutex<Vec<String>>>,
+        }
+
+        impl CommandRunner for Encrypting {
+            fn run(&self, _program: &str, args: &[&str]) -> std::io::Result<CommandOutput> {
+                if let [.., "--encrypt", "--in-place", path] = args {
+                    self.encrypted.lock().unwrap().push(path.to_string());
+                    if self.fail {
+                        return Ok(CommandOutput { code: Some(1), stdout: Vec::new(), stderr: b"no matching creation rules".to_vec() });
+                    }
+                }
+                Ok(CommandOutput { code: Some(0), ..Default::default() })
+            }
+        }
+
+        fn setup() -> (tempfile::TempDir, PathBuf, PathBuf) {
+            let dir = tempfile::tempdir().expect("Failed to create temp dir");
+            let template = dir.path().join("template.yaml");
+            std::fs::write(&template, TEMPLATE).expect("Failed to write template");
+            let target = dir.path().join("secrets.yaml");
+            (dir, template, target)
+        }
+
+        #[test]
+        fn test_creates_and_encrypts_new_file() {
+            let (_dir, template, target) = setup();
+            let runner = Encrypting::default();
+            let encrypted = runner.encrypted.clone();
+            let options = SyncOptions { runner: Runner::new(runner), ..Default::default() };
+
+            create_from_template(&target, &template, &options).expect("Should create file");
+            assert_eq!(std::fs::read_to_string(&target).expect("Failed to read file"), TEMPLATE);
+            assert_eq!(*encrypted.lock().unwrap(), vec![target.display().to_string()]);
+        }
+
+        #[test]
+        fn test_failed_encryption_removes_plaintext() {
+            let (_dir, template, target) = setup();
+            let options = SyncOptions { runner: Runner::new(Encrypting { fail: true, ..Default::default() }), ..Default::default() };
+
+            let error = create_from_template(&target, &template, &options).expect_err("Should fail");
+            assert!(format!("{:#}", error).contains("no matching creation rules"));
+            assert!(!target.exists());
+        }
+
+        #[test]
+        fn test_existing_file_is_not_replaced() {
+            let (_dir, template, target) = setup();
+            std::fs::write(&target, "existing").expect("Failed to write file");
+            let options = SyncOptions { runner: Runner::new(Encrypting::default()), ..Default::default() };
+
+            assert!(create_from_template(&target, &template, &options).is_err());
+            assert_eq!(std::fs::read_to_string(&target).expect("Failed to read file"), "existing");
+        }
+
+        #[test]
+        fn test_template_without_directives_is_rejected() {
+            let (_dir, template, target) = setup();
+            std::fs::write(&template, "api_key: placeholder\n").expect("Failed to write template");
+            let options = SyncOptions { runner: Runner::new(Encrypting::default()), ..Default::default() };
+
+            assert!(create_from_template(&target, &template, &options).is_err());
+            assert!(!target.exists());
+        }
+    }
+
+    mod skip_annotation {
+        use super::*;
+
+        fn drift(content: &str, options: &ParseOptions) -> (Vec<KeyStatus>, RunState) {
+            let mappings = parse_commands(content, options).expect("Should parse successfully").mappings;
+            let mut state = RunState::default();
+            let statuses =
+                drift_for_mappings(Path::new("secrets.yaml"), content, &mappings, &SyncOptions::default(), &mut state, |_| {});
+            (statuses, state)
+        }
+
+        #[test]
+        fn test_annotation_before_directive() {
+            let content = "# shell-skip\n# shell: exit 1\nfirst: a\n# shell: echo b\nsecond: b";
+            let (statuses, state) = drift(content, &ParseOptions::default());
+            let keys: Vec<&str> = statuses.iter().map(|status| status.key.as_str()).collect();
+            assert_eq!(keys, vec!["second"]);
+            assert_eq!(state.commands_executed, 1, "The skipped command should never run");
+        }
+
+        #[test]
+        fn test_annotation_after_directive_has_no_effect() {
+            let content = "# shell: exit 1\n# shell-skip\nfirst: a";
+            let parsed = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully");
+            assert_eq!(parsed.mappings.len(), 1);
+            assert!(parsed.issues.is_empty());
+        }
+
+        #[test]
+        fn test_annotation_between_stacked_directives() {
+            let content = "# shell: echo a\n# shell-skip\n# shell: exit 1\nfirst: a";
+            let parsed = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully");
+            let commands: Vec<&str> = parsed.mappings.iter().map(|mapping| mapping.command.as_str()).collect();
+            assert_eq!(commands, vec!["echo a"], "Only the directive below the annotation should be skipped");
+
+            let (statuses, state) = drift(content, &ParseOptions::default());
+            assert_eq!(statuses.len(), 1);
+            assert_eq!(statuses[0].key, "first");
+            assert_eq!(state.commands_executed, 1, "The directive above the annotation should still run");
+        }
+
+        #[test]
+        fn test_skip_key_option() {
+            let content = "# shell: exit 1\nfirst: a\n# shell: echo b\nsecond: b";
+            let options = ParseOptions { skip_keys: vec!["first".to_string()], ..Default::default() };
+            let (statuses, state) = drift(content, &options);
+            assert_eq!(statuses.len(), 1);
+            assert_eq!(statuses[0].key, "second");
+            assert_eq!(state.commands_executed, 1);
+        }
+
+        #[test]
+        fn test_annotation_elsewhere_has_no_effect() {
+            let content = "# shell-skip\n\n# shell: echo a\nfirst: a";
+            let parsed = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully");
+            assert_eq!(parsed.mappings.len(), 1);
+        }
+    }
+
+    mod command_defaults {
+        use super::*;
+        use crate::parser::CommandSettings;
+        use crate::runner::{CommandRunner, SystemRunner};
+
+        const CONTENT: &str = "# shell-defaults: timeout=30 retries=2\n# shell: echo a\nfirst: a\n# shell(timeout=5): echo b\nsecond: b";
+
+        fn settings(content: &str, options: &ParseOptions) -> Vec<CommandSettings> {
+            let mappings = parse_commands(content, options).expect("Should parse successfully").mappings;
+            mappings.into_iter().map(|mapping| mapping.settings).collect()
+        }
+
+        fn seconds(seconds: u64) -> Option<Duration> {
+            Some(Duration::from_secs(seconds))
+        }
+
+        #[test]
+        fn test_file_defaults_apply() {
+            let settings = settings(CONTENT, &ParseOptions::default());
+            assert_eq!(settings[0], CommandSettings { timeout: seconds(30), retries: Some(2) });
+        }
+
+        #[test]
+        fn test_directive_overrides_file_defaults() {
+            let settings = settings(CONTENT, &ParseOptions::default());
+            assert_eq!(settings[1], CommandSettings { timeout: seconds(5), retries: Some(2) });
+        }
+
+        #[test]
+        fn test_command_line_overrides_file_defaults() {
+            let options = ParseOptions {
+                command_settings: CommandSettings { timeout: seconds(60), retries: None },
+                ..Default::default()
+            };
+            let settings = settings(CONTENT, &options);
+            assert_eq!(settings[0], CommandSettings { timeout: seconds(60), retries: Some(2) });
+            assert_eq!(settings[1].timeout, seconds(5), "Directive options still win");
+        }
+
+        #[test]
+        fn test_invalid_defaults() {
+            assert!(parse_commands("# shell-defaults: timeout=soon\n# shell: echo a\nfirst: a", &ParseOptions::default()).is_err());
+            assert!(parse_commands("# shell-defaults: shell=bash\n# shell: echo a\nfirst: a", &ParseOptions::default()).is_err());
+        }
+
+        #[test]
+        fn test_retries_rerun_failing_command() {
+            let dir = tempfile::tempdir().expect("Failed to create temp dir");
+            let attempts = dir.path().join("attempts");
+            // Fails until it has been run three times
+            let command = format!("echo x >> {0}; [ $(wc -l < {0}) -ge 3 ] && echo ok", attempts.display());
+            let content = format!("# shell-defaults: retries=2\n# shell: {}\nfirst: ok", command);
+
+            let mappings = parse_commands(&content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            let statuses = drift_for_mappings(Path::new("secrets.yaml"), &content, &mappings, &SyncOptions::default(), &mut RunState::default(), |_| {});
+            assert_eq!(statuses[0].outcome.as_str(), "in_sync");
+        }
+
+        #[test]
+        fn test_timeout_kills_command() {
+            let started = Instant::now();
+            let result = SystemRunner.run_with_timeout("sh", &["-c", "sleep 5"], Some(Duration::from_millis(100)));
+            assert_eq!(result.expect_err("Should time out").kind(), std::io::ErrorKind::TimedOut);
+            assert!(started.elapsed() < Duration::from_secs(4));
+        }
+    }
+
+    mod streaming_decrypt {
+        use super::*;
+        use crate::runner::{CommandOutput, CommandRunner, Runner, SystemRunner};
+        use crate::testing::{secrets_file, FakeRunner};
+
+        const CONTENT: &str = "# shell: fetch-api-key\napi_key: old\nnote: |\n  kept out of memory\n# shell: fetch-db-password\ndb_password: \"same\"\nother: unsynced\n";
+
+        fn streaming(runner: &FakeRunner) -> SyncOptions {
+            let runner = runner.clone();
+            SyncOptions { runner: Runner::new(runner), stream_above: Some(0), ..Default::default() }
+        }
+
+        #[test]
+        fn test_sync_never_buffers_the_plaintext() {
+            let file = secrets_file(CONTENT, ".yaml");
+            // A decrypt that isn't a line at a time panics
+            let runner = FakeRunner::new().streaming().output("fetch-api-key", "new").output("fetch-db-password", "same");
+
+            let (secrets, updates) =
+                process_file(file.path(), false, &streaming(&runner), &mut RunState::default()).expect("Should process file");
+            assert_eq!((secrets, updates), (2, 1));
+            assert_eq!(runner.sets(), vec![r#"["api_key"] "new""#.to_string()]);
+        }
+
+        #[test]
+        fn test_small_files_are_decrypted_whole() {
+            let file = secrets_file(CONTENT, ".yaml");
+            let runner = FakeRunner::new().output("fetch-api-key", "new").output("fetch-db-password", "same");
+            let options = SyncOptions { stream_above: Some(1024 * 1024), ..streaming(&runner) };
+
+            let (secrets, updates) =
+                process_file(file.path(), false, &options, &mut RunState::default()).expect("Should process file");
+            assert_eq!((secrets, updates), (2, 1));
+        }
+
+        #[test]
+        fn test_outline_finds_the_same_directives() {
+            let format = Some(FileFormat::Yaml);
+            let outline: String = CONTENT.lines().map(|line| outline_line(line, format, &[]) + "\n").collect();
+
+            assert_eq!(outline, "# shell: fetch-api-key\napi_key:\nnote:\n  -\n# shell: fetch-db-password\ndb_password:\nother:\n");
+            let keys = |content: &str| {
+                let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse").mappings;
+                mappings.into_iter().map(|mapping| (mapping.key, mapping.line)).collect::<Vec<_>>()
+            };
+            assert_eq!(keys(&outline), keys(CONTENT));
+        }
+
+        #[test]
+        fn test_outline_cuts_env_lines_after_the_separator() {
+            let format = Some(FileFormat::Env);
+            assert_eq!(outline_line("export API_KEY=secret", format, &[]), "export API_KEY=");
+            assert_eq!(outline_line("\"quoted key\"=secret", format, &[]), "\"quoted key\"=");
+            assert_eq!(outline_line("[section]", format, &[]), "[section]");
+        }
+
+        #[test]
+        fn test_only_synced_values_are_kept() {
+            let file = secrets_file(CONTENT, ".yaml");
+            let options = streaming(&FakeRunner::new().streaming());
+            let keys = vec!["api_key".to_string(), "db_password".to_string()];
+
+            let outline = stream_outline(file.path(), &options).expect("Should stream outline");
+            let content = stream_values(file.path(), &outline, &keys, &options).expect("Should stream values");
+
+            assert!(!content.contains("unsynced") && !content.contains("kept out of memory"));
+            assert_eq!(options.stored_value(&content, "api_key").as_deref(), Some("old"));
+            assert_eq!(options.stored_value(&content, "db_password").as_deref(), Some("same"));
+        }
+
+        #[test]
+        fn test_decrypt_failure_is_an_error() {
+            struct Failing;
+            impl CommandRunner for Failing {
+                fn run(&self, _program: &str, args: &[&str]) -> std::io::Result<CommandOutput> {
+                    Ok(match args {
+                        ["--version"] => crate::testing::stdout("sops 3.9.0"),
+                        _ => crate::testing::failure("decrypt failed"),
+                    })
+                }
+            }
+            let options = SyncOptions { runner: Runner::new(Failing), ..Default::default() };
+
+            let error = stream_outline(Path::new("secrets.yaml"), &options).expect_err("Should fail when sops fails");
+            assert!(format!("{:#}", error).contains("decrypt failed"));
+        }
+
+        #[test]
+        fn test_system_runner_streams_process_output() {
+            let mut lines = Vec::new();
+            let script = "printf 'first\\nsecond\\n'; echo oops >&2; exit 3";
+            let output = SystemRunner
+                .run_lines("sh", &["-c", script], None, false, &mut |line| lines.push(line.to_string()))
+                .expect("Should spawn sh");
+
+            assert_eq!(lines, ["first", "second"]);
+            assert_eq!(output.code, Some(3));
+            assert_eq!(String::from_utf8_lossy(&output.stderr).trim(), "oops");
+        }
+
+        #[test]
+        fn test_system_runner_stops_streaming_at_the_timeout() {
+            let started = Instant::now();
+            let result = SystemRunner.run_lines("sh", &["-c", "echo first; sleep 5"], Some(Duration::from_millis(100)), false, &mut |_| {});
+            assert_eq!(result.expect_err("Should time out").kind(), std::io::ErrorKind::TimedOut);
+            assert!(started.elapsed() < Duration::from_secs(4));
+        }
+    }
+
+    mod ignore_key_case {
+        use super::*;
+        use crate::runner::Runner;
+        use crate::testing::{secrets_file, FakeRunner};
+
+        const CONTENT: &str = "# shell-map: echo '{\"key\": \"new\"}'\n# api_key=.key\nAPI_KEY=old\n";
+
+        #[test]
+        fn test_exact_match_by_default() {
+            assert_eq!(find_decrypted_entry(CONTENT, "api_key", Some(FileFormat::Env), false), None);
+            assert_eq!(parse_decrypted_value(CONTENT, "api_key", Some(FileFormat::Env)), None);
+        }
+
+        #[test]
+        fn test_mismatched_case_found_with_stored_spelling() {
+            let entry = find_decrypted_entry(CONTENT, "api_key", Some(FileFormat::Env), true);
+            assert_eq!(entry, Some(("API_KEY".to_string(), "old".to_string())));
+
+            let ini = "[config]\nApi_Key = old\n";
+            let entry = find_decrypted_entry(ini, "api_key", Some(FileFormat::Ini), true);
+            assert_eq!(entry, Some(("Api_Key".to_string(), "old".to_string())));
+        }
+
+        #[test]
+        fn test_case_sensitive_formats_still_match_exactly() {
+            let content = "API_KEY: old\n";
+            assert_eq!(find_decrypted_entry(content, "api_key", Some(FileFormat::Yaml), true), None);
+            assert_eq!(find_decrypted_entry(content, "api_key", None, true), None);
+        }
+
+        #[test]
+        fn test_longer_key_is_not_a_match() {
+            let content = "API_KEY_OLD=old\nAPI_KEY=current\n";
+            let entry = find_decrypted_entry(content, "api_key", Some(FileFormat::Env), true);
+            assert_eq!(entry, Some(("API_KEY".to_string(), "current".to_string())));
+        }
+
+        fn sync(ignore_key_case: bool) -> Vec<String> {
+            let file = secrets_file(CONTENT, ".env");
+            let runner = FakeRunner::new().otherwise(r#"{"key": "new"}"#);
+            let options =
+                SyncOptions { runner: Runner::new(runner.clone()), ignore_key_case, summary_only: true, ..Default::default() };
+
+            sync_files(&[file.path()], &options).expect("Should sync");
+            runner.sets()
+        }
+
+        #[test]
+        fn test_set_targets_stored_spelling() {
+            assert_eq!(sync(true), vec![r#"["API_KEY"] "new""#.to_string()]);
+        }
+
+        #[test]
+        fn test_set_targets_directive_spelling_without_flag() {
+            assert_eq!(sync(false), vec![r#"["api_key"] "new""#.to_string()]);
+        }
+    }
+
+    mod capture {
+        use super::*;
+        use crate::runner::SystemRunner;
+
+        fn run(command: &str, capture: Capture) -> Result<String> {
+            execute_command(&SystemRunner, command, None, &CommandEnv::default(), ValueSource::Stdout, capture, None)
+        }
+
+        #[test]
+        fn test_value_from_stderr() {
+            let value = run("echo noise; echo s3cret >&2", Capture::Stderr).expect("Should run");
+            assert_eq!(value, "s3cret");
+        }
+
+        #[test]
+        fn test_value_from_both_streams() {
+            let value = run("echo first; echo second >&2", Capture::Both).expect("Should run");
+            assert_eq!(value, "first\nsecond");
+
+            let value = run("echo only-stderr >&2", Capture::Both).expect("Should run");
+            assert_eq!(value, "only-stderr");
+        }
+
+        #[test]
+        fn test_stdout_is_the_default() {
+            let value = run("echo out; echo err >&2", Capture::Stdout).expect("Should run");
+            assert_eq!(value, "out");
+
+            let mappings = parse_commands("# shell: fetch\nkey: value", &ParseOptions::default())
+                .expect("Should parse successfully")
+                .mappings;
+            assert_eq!(mappings[0].capture, Capture::Stdout);
+        }
+
+        #[test]
+        fn test_failure_still_reported_for_stderr_capture() {
+            let error = run("echo denied >&2; exit 1", Capture::Stderr).expect_err("Should fail");
+            assert!(error.to_string().contains("denied"));
+        }
+
+        #[test]
+        fn test_parse_capture_option() {
+            let content = "# shell(capture=stderr): legacy-tool get token\ntoken: value";
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            assert_eq!(mappings[0].capture, Capture::Stderr);
+
+            let content = "# shell(capture=both): legacy-tool get token\ntoken: value";
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            assert_eq!(mappings[0].capture, Capture::Both);
+
+            let content = "# shell(capture=stdin): legacy-tool get token\ntoken: value";
+            assert!(parse_commands(content, &ParseOptions::default()).is_err(), "Unknown capture should be rejected");
+        }
+
+        #[test]
+        fn test_drift_reads_stderr_value() {
+            let content = "# shell(capture=stderr): echo s3cret >&2\ntoken: old";
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            let statuses = drift_for_mappings(
+                Path::new("secrets.yaml"),
+                content,
+                &mappings,
+                &SyncOptions::default(),
+                &mut RunState::default(),
+                |_| {},
+            );
+            match &statuses[0].outcome {
+                KeyOutcome::Checked { status, value, .. } => {
+                    assert_eq!(*status, SyncStatus::OutOfSync);
+                    assert_eq!(value, "s3cret");
+                }
+                other => panic!("Expected a checked outcome, got {:?}", other),
+            }
+        }
+    }
+
+    mod sops_prompts {
+        use super::*;
+        use crate::runner::{CommandOutput, CommandRunner};
+        use std::sync::Mutex;
+
+        /// Records how sops was run and hangs, i.e. times out, unless run interactively
+        #[derive(Default)]
+        struct PromptingRunner {
+            calls: Mutex<Vec<&'static str>>,
+        }
+
+        impl CommandRunner for PromptingRunner {
+            fn run(&self, _program: &str, _args: &[&str]) -> std::io::Result<CommandOutput> {
+                Ok(CommandOutput { code: Some(0), ..Default::default() })
+            }
+
+            fn run_with_timeout(&self, _program: &str, _args: &[&str], timeout: Option<Duration>) -> std::io::Result<CommandOutput> {
+                self.calls.lock().unwrap().push("captured");
+                match timeout {
+                    Some(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out")),
+                    None => Ok(CommandOutput { code: Some(0), stdout: b"key: value".to_vec(), stderr: Vec::new() }),
+                }
+            }
+
+            fn run_interactive(&self, _program: &str, _args: &[&str]) -> std::io::Result<CommandOutput> {
+                self.calls.lock().unwrap().push("interactive");
+                Ok(CommandOutput { code: Some(0), stdout: b"key: value".to_vec(), stderr: Vec::new() })
+            }
+        }
+
+        fn options(interactive: bool) -> SopsOptions {
+            SopsOptions {
+                interactive,
+                prompt_timeout: Some(Duration::from_secs(5)),
+                retries: 2,
+                retry_delay: Duration::ZERO,
+                ..Default::default()
+            }
+        }
+
+        #[test]
+        fn test_hanging_decrypt_suggests_interactive() {
+            let runner = PromptingRunner::default();
+            let error = sops_decrypt(&runner, Path::new("secrets.yaml"), &options(false)).expect_err("Should time out");
+
+            let message = error.to_string();
+            assert!(message.contains("5s"), "{}", message);
+            assert!(message.contains("--interactive"), "{}", message);
+            assert_eq!(*runner.calls.lock().unwrap(), vec!["captured"], "A prompt isn't worth retrying");
+        }
+
+        #[test]
+        fn test_interactive_decrypt_leaves_stderr_on_terminal() {
+            let runner = PromptingRunner::default();
+            let decrypted = sops_decrypt(&runner, Path::new("secrets.yaml"), &options(true)).expect("Should decrypt");
+
+            assert_eq!(decrypted, "key: value");
+            assert_eq!(*runner.calls.lock().unwrap(), vec!["interactive"]);
+        }
+
+        #[test]
+        fn test_no_timeout_by_default() {
+            let runner = PromptingRunner::default();
+            sops_decrypt(&runner, Path::new("secrets.yaml"), &SopsOptions::default()).expect("Should decrypt");
+            assert_eq!(*runner.calls.lock().unwrap(), vec!["captured"]);
+        }
+    }
+
+    mod plan_out {
+        use super::*;
+        use crate::runner::Runner;
+        use crate::testing::{secrets_file, FakeRunner};
+
+        const CONTENT: &str = "# shell: fetch-api-key\napi_key: old\n# shell: fetch-db-password\ndb_password: same\n# shell: fetch-token\ntoken:\n";
+
+        fn check_with_plan() -> (tempfile::NamedTempFile, tempfile::TempDir, PathBuf) {
+            let file = secrets_file(CONTENT, ".yaml");
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let plan_path = dir.path().join("plan.json");
+            let runner = FakeRunner::new()
+                .read_only()
+                .output("fetch-api-key", "n3w-s3cret")
+                .output("fetch-db-password", "same")
+                .output("fetch-token", "t0ken");
+            let options = SyncOptions {
+                runner: Runner::new(runner),
+                plan_out: Some(plan_path.clone()),
+                summary_only: true,
+                ..Default::default()
+            };
+
+            check_files(&[file.path()], &options).expect("Should check");
+            (file, dir, plan_path)
+        }
+
+        #[test]
+        fn test_original_file_unchanged() {
+            let (file, _dir, _) = check_with_plan();
+            assert_eq!(std::fs::read_to_string(file.path()).expect("Should read file"), CONTENT);
+        }
+
+        #[test]
+        fn test_plan_lists_out_of_sync_keys() {
+            let (file, _dir, plan_path) = check_with_plan();
+            let plan: serde_json::Value =
+                serde_json::from_str(&std::fs::read_to_string(plan_path).expect("Should write plan")).expect("Should be JSON");
+
+            let updates = plan["updates"].as_array().expect("Should list updates");
+            let keys: Vec<&str> = updates.iter().map(|update| update["key"].as_str().unwrap()).collect();
+            assert_eq!(keys, vec!["api_key", "token"]);
+            assert_eq!(updates[0]["file"], file.path().display().to_string());
+            assert_eq!(updates[0]["action"], "update");
+            assert_eq!(updates[1]["action"], "add");
+            assert_eq!(updates[0]["value_length"], 10);
+        }
+
+        #[test]
+        fn test_plan_masks_values() {
+            let (_file, _dir, plan_path) = check_with_plan();
+            let plan = std::fs::read_to_string(plan_path).expect("Should write plan");
+            assert!(!plan.contains("n3w-s3cret"));
+            assert!(!plan.contains("t0ken"));
+        }
+
+        #[test]
+        fn test_no_plan_without_dry_run() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let plan_path = dir.path().join("plan.json");
+            let options = SyncOptions { plan_out: Some(plan_path.clone()), summary_only: true, ..Default::default() };
+
+            let _ = sync_files(&["does-not-exist.yaml"], &options);
+            assert!(!plan_path.exists());
+        }
+    }
+
+    mod file_lock {
+        use super::*;
+        use crate::runner::Runner;
+        use crate::testing::FakeRunner;
+        use std::thread;
+
+        const CONTENT: &str = "# shell: fetch-api-key\napi_key: old\n";
+
+        fn secrets_file() -> tempfile::NamedTempFile {
+            crate::testing::secrets_file(CONTENT, ".yaml")
+        }
+
+        fn fake() -> FakeRunner {
+            FakeRunner::new().otherwise("new")
+        }
+
+        fn sync(file: &Path, configure: impl FnOnce(&mut SyncOptions)) -> (RunSummary, usize) {
+            let runner = fake();
+            let mut options = SyncOptions { runner: Runner::new(runner.clone()), summary_only: true, ..Default::default() };
+            configure(&mut options);
+
+            let summary = sync_files(&[file], &options).expect("Should sync");
+            (summary, runner.sets().len())
+        }
+
+        #[test]
+        fn test_locked_file_is_skipped() {
+            let file = secrets_file();
+            let held = lock_file(file.path(), false).expect("Should lock").expect("Should not be contended");
+
+            let (summary, sets) = sync(file.path(), |_| {});
+            assert_eq!(sets, 0, "A locked file must not be written");
+            assert_eq!(summary.errors, 0, "Contention is a warning, not an error");
+            drop(held);
+
+            let (_, sets) = sync(file.path(), |_| {});
+            assert_eq!(sets, 1);
+        }
+
+        #[test]
+        fn test_lock_wait_waits_for_release() {
+            let file = secrets_file();
+            let held = lock_file(file.path(), false).expect("Should lock").expect("Should not be contended");
+            let release = thread::spawn(move || {
+                thread::sleep(Duration::from_millis(100));
+                drop(held);
+            });
+
+            let started = Instant::now();
+            let (_, sets) = sync(file.path(), |options| options.lock_wait = true);
+            release.join().unwrap();
+            assert_eq!(sets, 1);
+            assert!(started.elapsed() >= Duration::from_millis(100));
+        }
+
+        #[test]
+        fn test_no_lock_ignores_held_lock() {
+            let file = secrets_file();
+            let _held = lock_file(file.path(), false).expect("Should lock").expect("Should not be contended");
+
+            let (_, sets) = sync(file.path(), |options| options.no_lock = true);
+            assert_eq!(sets, 1);
+        }
+
+        #[test]
+        fn test_check_does_not_lock() {
+            let file = secrets_file();
+            let _held = lock_file(file.path(), false).expect("Should lock").expect("Should not be contended");
+            let options = SyncOptions { runner: Runner::new(fake()), summary_only: true, ..Default::default() };
+
+            let summary = check_files(&[file.path()], &options).expect("Should check");
+            assert_eq!(summary.updates, 1);
+        }
+
+        #[test]
+        fn test_lock_released_on_drop() {
+            let file = secrets_file();
+            let held = lock_file(file.path(), false).expect("Should lock");
+            assert!(held.is_some());
+            assert!(lock_file(file.path(), false).expect("Should try to lock").is_none());
+            drop(held);
+            assert!(lock_file(file.path(), false).expect("Should lock").is_some());
+        }
+    }
+
+    mod emit_dir {
+        use super::*;
+        use crate::runner::Runner;
+        use crate::testing::{secrets_file, FakeRunner};
+
+        const CONTENT: &str = "# shell: fetch-api-key\napi_key: old\n# shell: fetch-db-password\ndb_password: same\n# shell: broken\ntoken: x\n";
+
+        fn check_emitting(dir: &Path) -> RunSummary {
+            let file = secrets_file(CONTENT, ".yaml");
+            let runner =
+                FakeRunner::new().output("fetch-api-key", "n3w").output("fetch-db-password", "same").failing("broken", "");
+            let options = SyncOptions {
+                runner: Runner::new(runner),
+                emit_dir: Some(dir.to_path_buf()),
+                summary_only: true,
+                ..Default::default()
+            };
+            check_files(&[file.path()], &options).expect("Should check")
+        }
+
+        #[test]
+        fn test_computed_values_written_per_key() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let emit = dir.path().join("values");
+            check_emitting(&emit);
+
+            assert_eq!(std::fs::read_to_string(emit.join("api_key")).expect("Should emit api_key"), "n3w");
+            assert_eq!(std::fs::read_to_string(emit.join("db_password")).expect("Should emit db_password"), "same");
+            assert!(!emit.join("token").exists(), "A failed command has no value to emit");
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn test_files_are_owner_only() {
+            use std::os::unix::fs::PermissionsExt;
+
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let emit = dir.path().join("values");
+            std::fs::create_dir(&emit).expect("Should create emit dir");
+            std::fs::write(emit.join("api_key"), "stale").expect("Should write stale value");
+            std::fs::set_permissions(emit.join("api_key"), std::fs::Permissions::from_mode(0o644)).unwrap();
+            check_emitting(&emit);
+
+            for key in ["api_key", "db_password"] {
+                let mode = std::fs::metadata(emit.join(key)).expect("Should emit").permissions().mode();
+                assert_eq!(mode & 0o777, 0o600, "{} should be owner-only", key);
+            }
+            assert_eq!(std::fs::read_to_string(emit.join("api_key")).unwrap(), "n3w");
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn test_created_dir_is_owner_only() {
+            use std::os::unix::fs::PermissionsExt;
+
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let emit = dir.path().join("values");
+            check_emitting(&emit);
+
+            let mode = std::fs::metadata(&emit).expect("Should create dir").permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+
+        #[test]
+        fn test_keys_that_are_not_file_names_are_rejected() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            for key in ["", "..", "nested/key"] {
+                assert!(emit_value(dir.path(), key, "value").is_err(), "'{}' should be rejected", key);
+            }
+        }
+    }
+
+    mod quoted_keys {
+        use super::*;
+        use crate::testing::FakeRunner;
+
+        const CONTENT: &str = "# shell: fetch-weird\n\"weird:key\": old\n# shell: fetch-single\n'single:quoted': \"value\"\n";
+
+        fn yaml() -> ParseOptions {
+            ParseOptions { format: Some(FileFormat::Yaml), ..Default::default() }
+        }
+
+        #[test]
+        fn test_quoted_keys_are_unquoted() {
+            let mappings = parse_commands(CONTENT, &yaml()).expect("Should parse successfully").mappings;
+            let keys: Vec<&str> = mappings.iter().map(|mapping| mapping.key.as_str()).collect();
+            assert_eq!(keys, vec!["weird:key", "single:quoted"]);
+        }
+
+        #[test]
+        fn test_values_of_colon_keys() {
+            assert_eq!(parse_decrypted_value(CONTENT, "weird:key", Some(FileFormat::Yaml)), Some("old".to_string()));
+            assert_eq!(parse_decrypted_value(CONTENT, "single:quoted", Some(FileFormat::Yaml)), Some("value".to_string()));
+            assert_eq!(parse_decrypted_value(CONTENT, "weird", Some(FileFormat::Yaml)), None);
+        }
+
+        #[test]
+        fn test_equals_in_quoted_env_key() {
+            let content = "# shell: fetch\n\"A=B\"=old\n";
+            let options = ParseOptions { format: Some(FileFormat::Env), ..Default::default() };
+            let mappings = parse_commands(content, &options).expect("Should parse successfully").mappings;
+            assert_eq!(mappings[0].key, "A=B");
+            assert_eq!(parse_decrypted_value(content, "A=B", Some(FileFormat::Env)), Some("old".to_string()));
+        }
+
+        #[test]
+        fn test_quoted_map_fields() {
+            let content = "# shell-map: vault read -format=json secret/app\n# \"db:user\"=.data.user plain=.data.plain\n\"db:user\": a\nplain: b";
+            let mappings = parse_commands(content, &yaml()).expect("Should parse successfully").mappings;
+            let keys: Vec<&str> = mappings.iter().map(|mapping| mapping.key.as_str()).collect();
+            assert_eq!(keys, vec!["db:user", "plain"]);
+        }
+
+        #[test]
+        fn test_unterminated_quote_is_not_a_key() {
+            let content = "# shell: fetch\n\"weird:key: old\n";
+            let parsed = parse_commands(content, &yaml()).expect("Should parse successfully");
+            assert!(parsed.mappings.is_empty());
+        }
+
+        #[test]
+        fn test_set_targets_unquoted_key() {
+            let mappings = parse_commands(CONTENT, &yaml()).expect("Should parse successfully").mappings;
+            let runner = FakeRunner::new();
+            sops_set(&runner, Path::new("secrets.yaml"), &mappings[0].key, "new", &SopsOptions::default())
+                .expect("Should set");
+            assert_eq!(runner.sets(), vec![r#"["weird:key"] "new""#.to_string()]);
+        }
+    }
+
+    #[cfg(unix)]
+    mod written_permissions {
+        use super::*;
+        use crate::runner::{CommandOutput, CommandRunner, Runner};
+        use std::os::unix::fs::PermissionsExt;
+
+        const CONTENT: &str = "# shell: fetch-api-key\napi_key: old\n";
+
+        /// Loosens the permissions of the file it updates, like a sops that rewrites it with the default umask
+        struct LooseningRunner;
+
+        impl CommandRunner for LooseningRunner {
+            fn run(&self, program: &str, args: &[&str]) -> std::io::Result<CommandOutput> {
+                let stdout: &str = match (program, args) {
+                    ("sops", [.., "--decrypt", _]) => CONTENT,
+                    ("sops", [.., "--set", _, target]) => {
+                        std::fs::set_permissions(target, std::fs::Permissions::from_mode(0o644))?;
+                        ""
+                    }
+                    ("sh", ["-c", _]) => "new",
+                    _ => "",
+                };
+                Ok(CommandOutput { code: Some(0), stdout: stdout.as_bytes().to_vec(), stderr: Vec::new() })
+            }
+        }
+
+        fn mode(path: &Path) -> u32 {
+            std::fs::metadata(path).expect("Should stat").permissions().mode() & 0o7777
+        }
+
+        fn sync(configure: impl FnOnce(&mut SyncOptions)) -> (tempfile::TempDir, PathBuf) {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let path = dir.path().join("secrets.yaml");
+            std::fs::write(&path, CONTENT).expect("Should write file");
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).expect("Should chmod");
+
+            let mut options = SyncOptions { runner: Runner::new(LooseningRunner), summary_only: true, ..Default::default() };
+            configure(&mut options);
+            let summary = sync_files(&[&path], &options).expect("Should sync");
+            assert_eq!(summary.errors, 0);
+            (dir, path)
+        }
+
+        #[test]
+        fn test_original_permissions_preserved() {
+            let (_dir, path) = sync(|_| {});
+            assert_eq!(mode(&path), 0o600);
+        }
+
+        #[test]
+        fn test_chmod_applied_in_place() {
+            let (_dir, path) = sync(|options| options.chmod = Some(0o640));
+            assert_eq!(mode(&path), 0o640);
+        }
+
+        #[test]
+        fn test_chmod_applied_to_copy() {
+            let (_dir, path) = sync(|options| {
+                options.output_suffix = Some(".synced".to_string());
+                options.chmod = Some(0o400);
+            });
+            let copy = path.with_file_name("secrets.yaml.synced");
+            assert_eq!(mode(&copy), 0o400);
+            assert_eq!(mode(&path), 0o600, "The original isn't written with an output suffix");
+        }
+    }
+
+    mod when_condition {
+        use super::*;
+
+        fn lookup(name: &str) -> Option<String> {
+            match name {
+                "DEPLOY_ENV" => Some("prod".to_string()),
+                "EMPTY" => Some(String::new()),
+                _ => None,
+            }
+        }
+
+        fn holds(raw: &str) -> bool {
+            Condition::parse(raw).expect("Should parse").holds_with(lookup)
+        }
+
+        #[test]
+        fn test_equality() {
+            assert!(holds("$DEPLOY_ENV == prod"));
+            assert!(holds("$DEPLOY_ENV == \"prod\""));
+            assert!(!holds("$DEPLOY_ENV == staging"));
+            assert!(!holds("$UNSET == prod"));
+        }
+
+        #[test]
+        fn test_inequality() {
+            assert!(holds("$DEPLOY_ENV != staging"));
+            assert!(!holds("$DEPLOY_ENV != prod"));
+            assert!(holds("$UNSET != prod"));
+        }
+
+        #[test]
+        fn test_presence() {
+            assert!(holds("$DEPLOY_ENV"));
+            assert!(!holds("$EMPTY"));
+            assert!(!holds("$UNSET"));
+        }
+
+        #[test]
+        fn test_invalid_conditions_rejected() {
+            for raw in ["DEPLOY_ENV == prod", "$ == prod", "$DEPLOY-ENV", ""] {
+                assert!(Condition::parse(raw).is_err(), "'{}' should be rejected", raw);
+            }
+        }
+
+        fn drift(content: &str) -> Vec<KeyStatus> {
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            let mut state = RunState::default();
+            let statuses =
+                drift_for_mappings(Path::new("secrets.yaml"), content, &mappings, &SyncOptions::default(), &mut state, |_| {});
+            assert_eq!(state.commands_executed, statuses.iter().filter(|status| status.outcome.as_str() != "skipped").count());
+            statuses
+        }
+
+        #[test]
+        fn test_matching_condition_runs_command() {
+            std::env::set_var("SOPS_SHELL_TEST_WHEN_MATCH", "prod");
+            let statuses = drift("# shell(when=$SOPS_SHELL_TEST_WHEN_MATCH == prod): echo new\nkey: old");
+            assert!(matches!(statuses[0].outcome, KeyOutcome::Checked { status: SyncStatus::OutOfSync, .. }));
+        }
+
+        #[test]
+        fn test_non_matching_condition_is_skipped() {
+            std::env::set_var("SOPS_SHELL_TEST_WHEN_MISMATCH", "staging");
+            let statuses = drift("# shell(when=$SOPS_SHELL_TEST_WHEN_MISMATCH == prod): echo new\nkey: old");
+            assert_eq!(statuses[0].outcome.as_str(), "skipped");
+            assert!(matches!(&statuses[0].outcome, KeyOutcome::ConditionNotMet(condition)
+                if condition.to_string() == "$SOPS_SHELL_TEST_WHEN_MISMATCH == prod"));
+        }
+
+        #[test]
+        fn test_invalid_when_option_is_a_parse_error() {
+            let content = "# shell(when=DEPLOY_ENV = prod): echo new\nkey: old";
+            assert!(parse_commands(content, &ParseOptions::default()).is_err());
+        }
+    }
+
+    mod detect_only {
+        use super::*;
+        use crate::runner::Runner;
+        use crate::testing::{FakeRunner, Plaintext};
+
+        fn content() -> String {
+            Plaintext::yaml().secret("fetch-api-key", "api_key", "old").secret("fetch-db-password", "db_password", "same").build()
+        }
+
+        fn fake() -> FakeRunner {
+            FakeRunner::new().read_only().output("fetch-api-key", "new").output("fetch-db-password", "same")
+        }
+
+        fn secrets_file() -> tempfile::NamedTempFile {
+            crate::testing::secrets_file(&content(), ".yaml")
+        }
+
+        #[test]
+        fn test_out_of_sync_secrets_are_not_written() {
+            let file = secrets_file();
+            let options = SyncOptions { runner: Runner::new(fake()), detect_only: true, summary_only: true, ..Default::default() };
+
+            let summary = sync_files(&[file.path()], &options).expect("Should sync");
+            assert_eq!(summary.secrets, 2);
+            assert_eq!(summary.updates, 0);
+            assert_eq!(summary.errors, 0);
+            assert_eq!(std::fs::read_to_string(file.path()).expect("Should read file"), content());
+        }
+
+        #[test]
+        fn test_no_post_sync_hook_or_copy() {
+            let file = secrets_file();
+            let marker = tempfile::tempdir().expect("Should create temp dir");
+            let hook_ran = marker.path().join("hook-ran");
+            let options = SyncOptions {
+                runner: Runner::new(fake()),
+                detect_only: true,
+                summary_only: true,
+                output_suffix: Some(".synced".to_string()),
+                post_sync: Some(format!("touch {}", hook_ran.display())),
+                ..Default::default()
+            };
+
+            sync_files(&[file.path()], &options).expect("Should sync");
+            assert!(!hook_ran.exists());
+            let mut copy = file.path().as_os_str().to_owned();
+            copy.push(".synced");
+            assert!(!PathBuf::from(copy).exists());
+        }
+    }
+
+    mod shell_include {
+        use super::*;
+        use crate::parser::ParsedCommands;
+
+        fn write(dir: &Path, name: &str, content: &str) -> PathBuf {
+            let path = dir.join(name);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent).expect("Should create dir");
+            }
+            std::fs::write(&path, content).expect("Should write file");
+            path
+        }
+
+        fn keys(parsed: &ParsedCommands) -> Vec<(&str, &str)> {
+            parsed.mappings.iter().map(|mapping| (mapping.key.as_str(), mapping.command.as_str())).collect()
+        }
+
+        #[test]
+        fn test_included_directives_are_merged() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            write(dir.path(), "common.shell.toml", "# shell: vault read -field=password secret/db\ndb_password = \"\"\n");
+            let content = "# shell-include: common.shell.toml\n# shell: fetch-api-key\napi_key: old\n";
+            let secrets = write(dir.path(), "secrets.yaml", content);
+
+            let parsed = parse_commands_with_includes(content, &secrets, &ParseOptions::default()).expect("Should parse");
+            assert_eq!(keys(&parsed), vec![("api_key", "fetch-api-key"), ("db_password", "vault read -field=password secret/db")]);
+        }
+
+        #[test]
+        fn test_own_directives_win_over_included() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            write(dir.path(), "common.shell.toml", "# shell: shared-fetch\napi_key = \"\"\n");
+            let content = "# shell-include: common.shell.toml\n# shell: own-fetch\napi_key: old\n";
+            let secrets = write(dir.path(), "secrets.yaml", content);
+
+            let parsed = parse_commands_with_includes(content, &secrets, &ParseOptions::default()).expect("Should parse");
+            assert_eq!(keys(&parsed), vec![("api_key", "own-fetch")]);
+        }
+
+        #[test]
+        fn test_nested_includes_resolve_relative_to_including_file() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            write(dir.path(), "shared/common.shell.toml", "# shell-include: db.shell.toml\n");
+            write(dir.path(), "shared/db.shell.toml", "# shell: fetch-db\ndb_password = \"\"\n");
+            let content = "# shell-include: shared/common.shell.toml\n";
+            let secrets = write(dir.path(), "secrets.yaml", content);
+
+            let parsed = parse_commands_with_includes(content, &secrets, &ParseOptions::default()).expect("Should parse");
+            assert_eq!(keys(&parsed), vec![("db_password", "fetch-db")]);
+        }
+
+        #[test]
+        fn test_cycle_is_an_error() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            write(dir.path(), "a.shell.toml", "# shell-include: b.shell.toml\n");
+            write(dir.path(), "b.shell.toml", "# shell-include: a.shell.toml\n");
+            let content = "# shell-include: a.shell.toml\n";
+            let secrets = write(dir.path(), "secrets.yaml", content);
+
+            let error = parse_commands_with_includes(content, &secrets, &ParseOptions::default()).expect_err("Should detect the cycle");
+            let message = format!("{:#}", error);
+            assert!(message.contains("shell-include cycle"), "{}", message);
+            assert!(message.contains("a.shell.toml -> ") && message.contains("b.shell.toml"), "{}", message);
+        }
+
+        #[test]
+        fn test_self_include_is_a_cycle() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let content = "# shell-include: secrets.yaml\n";
+            let secrets = write(dir.path(), "secrets.yaml", content);
+
+            assert!(parse_commands_with_includes(content, &secrets, &ParseOptions::default()).is_err());
+        }
+
+        #[test]
+        fn test_missing_include_is_an_error() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let content = "# shell-include: missing.shell.toml\n";
+            let secrets = write(dir.path(), "secrets.yaml", content);
+
+            let error = parse_commands_with_includes(content, &secrets, &ParseOptions::default()).expect_err("Should fail");
+            assert!(error.to_string().contains("missing.shell.toml"));
+        }
+    }
+
+    mod metrics_file {
+        use super::*;
+        use crate::runner::Runner;
+        use crate::testing::{secrets_file, FakeRunner, Plaintext};
+        use std::collections::BTreeMap;
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        // Sample lines by metric name, e.g. `sops_shell_errors{mode="check"} 1`
+        fn samples(text: &str) -> BTreeMap<String, String> {
+            text.lines()
+                .filter(|line| !line.starts_with('#'))
+                .map(|line| {
+                    let (series, value) = line.rsplit_once(' ').expect("Should be '<series> <value>'");
+                    let name = series.split('{').next().unwrap();
+                    assert_eq!(&series[name.len()..], "{mode=\"check\"}");
+                    (name.to_string(), value.to_string())
+                })
+                .collect()
+        }
+
+        #[test]
+        fn test_metrics_written_from_run_totals() {
+            let content = Plaintext::yaml()
+                .secret("fetch-api-key", "api_key", "old")
+                .secret("fetch-db-password", "db_password", "same")
+                .secret("broken", "token", "x")
+                .build();
+            let file = secrets_file(&content, ".yaml");
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let metrics_path = dir.path().join("sops_shell.prom");
+            let runner =
+                FakeRunner::new().output("fetch-api-key", "new").output("fetch-db-password", "same").failing("broken", "");
+            let options = SyncOptions {
+                runner: Runner::new(runner),
+                metrics_file: Some(metrics_path.clone()),
+                summary_only: true,
+                ..Default::default()
+            };
+
+            check_files(&[file.path()], &options).expect("Should check");
+            let text = std::fs::read_to_string(&metrics_path).expect("Should write metrics");
+            let samples = samples(&text);
+
+            assert_eq!(samples["sops_shell_files"], "1");
+            assert_eq!(samples["sops_shell_secrets_checked"], "3");
+            assert_eq!(samples["sops_shell_secrets_out_of_sync"], "1");
+            assert_eq!(samples["sops_shell_command_failures"], "1");
+            assert_eq!(samples["sops_shell_errors"], "1");
+            let timestamp: u64 = samples["sops_shell_last_run_timestamp_seconds"].parse().expect("Should be an integer");
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            assert!(now - timestamp < 60);
+            assert!(!dir.path().join("sops_shell.prom.tmp").exists());
+        }
+
+        #[test]
+        fn test_every_metric_has_help_and_type() {
+            let text = RunMetrics::default().render(UNIX_EPOCH);
+            let names: Vec<&str> = text
+                .lines()
+                .filter(|line| !line.starts_with('#'))
+                .map(|line| line.split('{').next().unwrap())
+                .collect();
+
+            assert_eq!(names.len(), 7);
+            for name in names {
+                assert!(name.starts_with("sops_shell_"));
+                assert!(name.chars().all(|ch| ch.is_ascii_lowercase() || ch == '_'), "{}", name);
+                assert!(text.contains(&format!("# HELP {} ", name)));
+                assert!(text.contains(&format!("# TYPE {} gauge\n", name)));
+            }
+            assert!(text.contains("sops_shell_last_run_timestamp_seconds{mode=\"sync\"} 0\n"));
+        }
+    }
+
+    mod block_directives {
+        use super::*;
+        use crate::parser::{lint_directives, DirectivePosition};
+        use crate::runner::Runner;
+        use crate::testing::FakeRunner;
+
+        const CONTENT: &str = "# shell:\n#   setup: vault login -method=oidc\n#   value: vault read -field=password secret/db\ndb_password: old\n";
+
+        #[test]
+        fn test_block_sets_setup_and_value_commands() {
+            let mappings = parse_commands(CONTENT, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            assert_eq!(mappings.len(), 1);
+            assert_eq!(mappings[0].key, "db_password");
+            assert_eq!(mappings[0].command, "vault read -field=password secret/db");
+            assert_eq!(mappings[0].setup.as_deref(), Some("vault login -method=oidc"));
+        }
+
+        #[test]
+        fn test_value_only_block() {
+            let content = "# shell(timeout=5):\n# value: fetch-token\ntoken: old\n";
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            assert_eq!(mappings[0].command, "fetch-token");
+            assert_eq!(mappings[0].setup, None);
+            assert_eq!(mappings[0].settings.timeout, Some(Duration::from_secs(5)));
+        }
+
+        #[test]
+        fn test_setup_without_value_is_rejected() {
+            let content = "# shell:\n# setup: vault login\ntoken: old\n";
+            assert!(parse_commands(content, &ParseOptions::default()).is_err());
+            let issues = lint_directives(content, DirectivePosition::Before).expect("Should lint");
+            assert_eq!(issues.len(), 1);
+            assert!(issues[0].message.contains("no 'value:' line"), "{}", issues[0].message);
+        }
+
+        #[test]
+        fn test_lint_accepts_block() {
+            assert!(lint_directives(CONTENT, DirectivePosition::Before).expect("Should lint").is_empty());
+        }
+
+        // Every command prints "output of <command>", but the ones in `failing`
+        fn drift(failing: &[&str]) -> (Vec<KeyStatus>, Vec<String>) {
+            let runner = ["vault login -method=oidc", "vault read -field=password secret/db"]
+                .into_iter()
+                .fold(FakeRunner::new(), |runner, command| runner.output(command, &format!("output of {}", command)));
+            let runner = failing.iter().fold(runner, |runner, command| runner.failing(command, "login required"));
+            let options = SyncOptions { runner: Runner::new(runner.clone()), ..Default::default() };
+            let mappings = parse_commands(CONTENT, &options.parse).expect("Should parse successfully").mappings;
+            let statuses =
+                drift_for_mappings(Path::new("secrets.yaml"), CONTENT, &mappings, &options, &mut RunState::default(), |_| {});
+            (statuses, runner.commands())
+        }
+
+        #[test]
+        fn test_setup_runs_first_and_its_output_is_ignored() {
+            let (statuses, commands) = drift(&[]);
+            assert_eq!(commands, vec!["vault login -method=oidc", "vault read -field=password secret/db"]);
+            match &statuses[0].outcome {
+                KeyOutcome::Checked { value, .. } => assert_eq!(value, "output of vault read -field=password secret/db"),
+                other => panic!("Expected a checked outcome, got {:?}", other),
+            }
+        }
+
+        #[test]
+        fn test_setup_failure_aborts_value_command() {
+            let (statuses, commands) = drift(&["vault login -method=oidc"]);
+            assert_eq!(commands, vec!["vault login -method=oidc"]);
+            match &statuses[0].outcome {
+                KeyOutcome::Failed(e) => {
+                    let message = format!("{:#}", e);
+                    assert!(message.contains("Setup command failed") && message.contains("login required"), "{}", message);
+                }
+                other => panic!("Expected a failure, got {:?}", other),
+            }
+        }
+
+        #[test]
+        fn test_policy_applies_to_setup() {
+            let mappings = parse_commands(CONTENT, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            let options = SyncOptions {
+                policy: CommandPolicy { allow: Vec::new(), deny: vec!["vault".to_string()] },
+                ..Default::default()
+            };
+            let statuses =
+                drift_for_mappings(Path::new("secrets.yaml"), CONTENT, &mappings, &options, &mut RunState::default(), |_| {});
+            assert!(matches!(statuses[0].outcome, KeyOutcome::Refused(_)));
+        }
+    }
+
+    mod normalize {
+        use super::*;
+        use crate::runner::{CommandOutput, CommandRunner, Runner};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        const MESSY: &str = "# shell: fetch-api-key\napi_key:    abc   \n\n\n# shell: fetch-db-password\ndb_password:  p4ss\n";
+
+        /// Keeps the "encrypted" file in plaintext. A `--set` rewrites every line as `key: value`
+        /// without extra whitespace or blank lines, or also garbles the value when `garble` is set
+        struct LayoutSops {
+            garble: bool,
+            sets: Arc<AtomicUsize>,
+        }
+
+        impl CommandRunner for LayoutSops {
+            fn run(&self, program: &str, args: &[&str]) -> std::io::Result<CommandOutput> {
+                let stdout = match (program, args) {
+                    ("sops", ["--decrypt", path]) => std::fs::read_to_string(path)?,
+                    ("sops", ["--set", assignment, path]) => {
+                        self.sets.fetch_add(1, Ordering::SeqCst);
+                        let (key, value) = assignment.trim_start_matches("[\"").split_once("\"] ").unwrap();
+                        let value: String = serde_json::from_str(value).unwrap();
+                        let value = if self.garble { format!("{}!", value) } else { value };
+                        let content = std::fs::read_to_string(path)?;
+                        let rewritten: String = content
+                            .lines()
+                            .filter(|line| !line.trim().is_empty())
+                            .map(|line| match line.split_once(':') {
+                                Some((k, _)) if k == key => format!("{}: {}\n", k, value),
+                                Some((k, v)) if !k.starts_with('#') => format!("{}: {}\n", k, v.trim()),
+                                _ => format!("{}\n", line.trim_end()),
+                            })
+                            .collect();
+                        std::fs::write(path, rewritten)?;
+                        String::new()
+                    }
+                    ("sh", ["-c", "fetch-api-key"]) => "abc".to_string(),
+                    ("sh", ["-c", "fetch-db-password"]) => "p4ss".to_string(),
+                    _ => String::new(),
+                };
+                Ok(CommandOutput { code: Some(0), stdout: stdout.into_bytes(), stderr: Vec::new() })
+            }
+        }
+
+        fn sync(normalize: bool, garble: bool) -> (String, RunSummary, usize) {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let path = dir.path().join("secrets.yaml");
+            std::fs::write(&path, MESSY).expect("Should write file");
+
+            let sets = Arc::new(AtomicUsize::new(0));
+            let runner = LayoutSops { garble, sets: Arc::clone(&sets) };
+            let options = SyncOptions { runner: Runner::new(runner), normalize, summary_only: true, ..Default::default() };
+            let summary = sync_files(&[&path], &options).expect("Should sync");
+            (std::fs::read_to_string(&path).expect("Should read file"), summary, sets.load(Ordering::SeqCst))
+        }
+
+        fn value(content: &str, key: &str) -> Option<String> {
+            find_decrypted_entry(content, key, Some(FileFormat::Yaml), false).map(|(_, value)| value)
+        }
+
+        #[test]
+        fn test_normalize_changes_layout_not_values() {
+            let (content, summary, sets) = sync(true, false);
+            assert_eq!(summary.errors, 0);
+            assert_eq!(sets, 1);
+            assert_eq!(content, "# shell: fetch-api-key\napi_key: abc\n# shell: fetch-db-password\ndb_password: p4ss\n");
+            for key in ["api_key", "db_password"] {
+                assert_eq!(value(&content, key), value(MESSY, key), "{} changed", key);
+            }
+        }
+
+        #[test]
+        fn test_off_by_default() {
+            let (content, summary, sets) = sync(false, false);
+            assert_eq!(summary.errors, 0);
+            assert_eq!(sets, 0);
+            assert_eq!(content, MESSY);
+        }
+
+        #[test]
+        fn test_changed_value_restores_file() {
+            let (content, summary, sets) = sync(true, true);
+            assert_eq!(sets, 1);
+            assert_eq!(summary.errors, 1);
+            assert_eq!(content, MESSY);
+        }
+    }
+
+    mod command_env {
+        use super::*;
+        use crate::runner::{SystemRunner, MINIMAL_PATH};
+
+        const INHERITED: &str = "SOPS_SHELL_TEST_INHERITED";
+
+        fn run(command: &str, env: &CommandEnv) -> String {
+            std::env::set_var(INHERITED, "leaked");
+            execute_command(&SystemRunner, command, None, env, ValueSource::Stdout, Capture::Stdout, None)
+                .expect("Should run command")
+        }
+
+        fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+            vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+        }
+
+        #[test]
+        fn test_inherited_by_default() {
+            assert_eq!(run("echo ${SOPS_SHELL_TEST_INHERITED-unset}", &CommandEnv::default()), "leaked");
+        }
+
+        #[test]
+        fn test_no_inherit_env_drops_inherited_variable() {
+            let env = CommandEnv { clear: true, ..Default::default() };
+            assert_eq!(run("echo ${SOPS_SHELL_TEST_INHERITED-unset}", &env), "unset");
+            assert_eq!(run("echo $PATH", &env), MINIMAL_PATH);
+        }
+
+        #[test]
+        fn test_no_inherit_env_keeps_explicit_variables() {
+            let vars = vars(&[("VAULT_ADDR", "https://vault"), ("PATH", "/bin")]);
+            let env = CommandEnv { clear: true, vars, ..Default::default() };
+            assert_eq!(run("echo $VAULT_ADDR $PATH", &env), "https://vault /bin");
+        }
+
+        #[test]
+        fn test_explicit_variables_added_to_inherited() {
+            let env = CommandEnv { clear: false, vars: vars(&[("VAULT_ADDR", "https://vault")]), ..Default::default() };
+            assert_eq!(run("echo $VAULT_ADDR ${SOPS_SHELL_TEST_INHERITED-unset}", &env), "https://vault leaked");
+        }
+
+        fn passing(names: &[&str]) -> CommandEnv {
+            CommandEnv { clear: true, pass: names.iter().map(|name| name.to_string()).collect(), ..Default::default() }
+        }
+
+        #[test]
+        fn test_only_passed_variables_are_forwarded() {
+            std::env::set_var("SOPS_SHELL_TEST_PASSED", "forwarded");
+            let env = passing(&["SOPS_SHELL_TEST_PASSED"]);
+            let command = "echo ${SOPS_SHELL_TEST_PASSED-unset} ${SOPS_SHELL_TEST_INHERITED-unset}";
+            assert_eq!(run(command, &env), "forwarded unset");
+            assert_eq!(run("echo $PATH", &env), MINIMAL_PATH);
+        }
+
+        #[test]
+        fn test_child_environment_holds_nothing_else() {
+            std::env::set_var("SOPS_SHELL_TEST_PASSED", "forwarded");
+            let env = passing(&["SOPS_SHELL_TEST_PASSED"]);
+            // sh itself adds e.g. PWD and SHLVL, so only exported variables are compared
+            let names = run("env | cut -d= -f1 | grep -v -e '^PWD$' -e '^SHLVL$' -e '^_$' | sort | tr '\\n' ' '", &env);
+            assert_eq!(names, "PATH SOPS_SHELL_TEST_PASSED");
+        }
+
+        #[test]
+        fn test_passed_variables_unset_here_stay_unset() {
+            let env = passing(&["SOPS_SHELL_TEST_NEVER_SET"]);
+            assert_eq!(run("echo ${SOPS_SHELL_TEST_NEVER_SET-unset}", &env), "unset");
+        }
+
+        #[test]
+        fn test_explicit_variables_win_over_passed_ones() {
+            std::env::set_var("SOPS_SHELL_TEST_PASSED", "forwarded");
+            let env = CommandEnv {
+                vars: vars(&[("SOPS_SHELL_TEST_PASSED", "explicit")]),
+                ..passing(&["SOPS_SHELL_TEST_PASSED", "PATH"])
+            };
+            assert_eq!(run("echo $SOPS_SHELL_TEST_PASSED", &env), "explicit");
+            assert_eq!(run("echo $PATH", &env), std::env::var("PATH").unwrap());
+        }
+    }
+
+    mod validate_commands {
+        use super::*;
+        use crate::runner::Runner;
+        use crate::testing::FakeRunner;
+        use crate::validate::{validate_file, validate_files, CommandCheck};
+
+        const CONTENT: &str = "# shell: fetch-api-key\napi_key: stale\n\
+                               # shell: expired-login\ndb_password: old\n\
+                               # shell(when=$SOPS_SHELL_TEST_VALIDATE_UNSET): fetch-prod-token\nprod_token: old\n\
+                               # shell-map: fetch-app\n# user=.user pass=.pass\nuser: a\npass: b\n";
+
+        /// Fails `expired-login` and refuses to write
+        fn options() -> (SyncOptions, FakeRunner) {
+            let runner = FakeRunner::new()
+                .decrypting_to(CONTENT)
+                .read_only()
+                .failing("expired-login", "token expired")
+                .output("fetch-app", r#"{"user": "app", "pass": "secret"}"#)
+                .otherwise("fresh");
+            (SyncOptions { runner: Runner::new(runner.clone()), ..Default::default() }, runner)
+        }
+
+        #[test]
+        fn test_reports_each_command() {
+            let (options, runner) = options();
+            let checks = validate_file(Path::new("secrets.yaml"), &options).expect("Should validate");
+            let results: Vec<(&str, &str)> = checks
+                .iter()
+                .map(|(key, check)| {
+                    let result = match check {
+                        CommandCheck::Passed => "passed",
+                        CommandCheck::Failed(_) => "failed",
+                        CommandCheck::Skipped => "skipped",
+                    };
+                    (key.as_str(), result)
+                })
+                .collect();
+            assert_eq!(
+                results,
+                vec![
+                    ("api_key", "passed"),
+                    ("db_password", "failed"),
+                    ("prod_token", "skipped"),
+                    ("user", "passed"),
+                    ("pass", "passed"),
+                ]
+            );
+            // The shared command runs once, and the one behind an unmet condition not at all
+            assert_eq!(runner.commands(), vec!["fetch-api-key", "expired-login", "fetch-app"]);
+        }
+
+        #[test]
+        fn test_failure_message_includes_stderr() {
+            let (options, _) = options();
+            let checks = validate_file(Path::new("secrets.yaml"), &options).expect("Should validate");
+            match &checks[1].1 {
+                CommandCheck::Failed(e) => assert!(format!("{:#}", e).contains("token expired")),
+                other => panic!("Expected a failure, got {:?}", other),
+            }
+        }
+
+        #[test]
+        fn test_any_failure_fails_the_run() {
+            let (options, _) = options();
+            let error = validate_files(&["secrets.yaml"], &options).expect_err("Should fail");
+            assert!(error.to_string().contains("1 command(s) failed"), "{}", error);
+        }
+
+        #[test]
+        fn test_refused_command_fails() {
+            let (options, runner) = options();
+            let options = SyncOptions {
+                policy: CommandPolicy { allow: vec!["fetch-api-key".to_string()], deny: Vec::new() },
+                ..options
+            };
+            let checks = validate_file(Path::new("secrets.yaml"), &options).expect("Should validate");
+            assert!(matches!(checks[0].1, CommandCheck::Passed));
+            assert!(matches!(checks[3].1, CommandCheck::Failed(_)));
+            assert_eq!(runner.commands(), vec!["fetch-api-key"]);
+        }
+    }
+
+    #[cfg(feature = "keyring")]
+    mod keyring_directives {
+        use super::*;
+        use crate::keyring::{parse_reference, Keyring, KeyringStore};
+        use crate::runner::{CommandOutput, CommandRunner, Runner};
+
+        const CONTENT: &str = "# shell: keyring://github/ci-bot\ngithub_token: old\n# shell: keyring://npm/publisher\nnpm_token: same\n";
+
+        /// Keeps secrets in memory, keyed by service and account
+        struct MockKeyring(HashMap<(&'static str, &'static str), &'static str>);
+
+        impl Keyring for MockKeyring {
+            fn get(&self, service: &str, account: &str) -> Result<Option<String>> {
+                Ok(self.0.iter().find(|((s, a), _)| *s == service && *a == account).map(|(_, secret)| secret.to_string()))
+            }
+        }
+
+        /// Keyring directives never spawn a process
+        struct NoCommands;
+
+        impl CommandRunner for NoCommands {
+            fn run(&self, program: &str, args: &[&str]) -> std::io::Result<CommandOutput> {
+                panic!("Unexpected invocation of {} {:?}", program, args)
+            }
+        }
+
+        fn store() -> KeyringStore {
+            KeyringStore::new(MockKeyring(HashMap::from([(("github", "ci-bot"), "ghp_new"), (("npm", "publisher"), "same")])))
+        }
+
+        fn drift(keyring: Option<KeyringStore>, policy: CommandPolicy) -> Vec<KeyStatus> {
+            let options = SyncOptions { runner: Runner::new(NoCommands), keyring, policy, ..Default::default() };
+            let mappings = parse_commands(CONTENT, &options.parse).expect("Should parse successfully").mappings;
+            drift_for_mappings(Path::new("secrets.yaml"), CONTENT, &mappings, &options, &mut RunState::default(), |_| {})
+        }
+
+        #[test]
+        fn test_parse_reference() {
+            assert_eq!(parse_reference("keyring://github/ci-bot").unwrap().unwrap(), ("github", "ci-bot"));
+            assert_eq!(parse_reference(" keyring://svc/team/bot ").unwrap().unwrap(), ("svc", "team/bot"));
+            assert!(parse_reference("keyring://github").unwrap().is_err());
+            assert!(parse_reference("keyring:///ci-bot").unwrap().is_err());
+            assert!(parse_reference("echo keyring://github/ci-bot").is_none());
+        }
+
+        #[test]
+        fn test_values_read_from_keyring() {
+            let statuses = drift(Some(store()), CommandPolicy::default());
+            let outcomes: Vec<(&str, Option<&str>)> = statuses
+                .iter()
+                .map(|status| match &status.outcome {
+                    KeyOutcome::Checked { status: sync_status, value, .. } => (sync_status.as_str(), Some(value.as_str())),
+                    other => (other.as_str(), None),
+                })
+                .collect();
+            assert_eq!(outcomes, vec![("out_of_sync", Some("ghp_new")), ("in_sync", Some("same"))]);
+        }
+
+        #[test]
+        fn test_missing_secret_fails() {
+            let keyring = KeyringStore::new(MockKeyring(HashMap::from([(("npm", "publisher"), "same")])));
+            let statuses = drift(Some(keyring), CommandPolicy::default());
+            match &statuses[0].outcome {
+                KeyOutcome::Failed(e) => assert!(e.to_string().contains("No secret for account 'ci-bot'"), "{}", e),
+                other => panic!("Expected a failure, got {:?}", other),
+            }
+        }
+
+        #[test]
+        fn test_no_keyring_configured_fails() {
+            let statuses = drift(None, CommandPolicy::default());
+            assert!(statuses.iter().all(|status| matches!(status.outcome, KeyOutcome::Failed(_))));
+        }
+
+        #[test]
+        fn test_binary_policy_does_not_apply() {
+            let policy = CommandPolicy { allow: vec!["vault".to_string()], deny: Vec::new() };
+            let statuses = drift(Some(store()), policy);
+            assert!(statuses.iter().all(|status| matches!(status.outcome, KeyOutcome::Checked { .. })));
+        }
+    }
+
+    mod junit_report {
+        use super::*;
+        use crate::runner::Runner;
+        use crate::testing::FakeRunner;
+        use regex::Regex;
+
+        const CONTENT: &str = "# shell: echo same\nin_sync: same\n\
+                               # shell: echo new\nupdated: old\n\
+                               # shell: fail <hard> & \"loud\"\nbroken: old\n\
+                               # shell: echo locked\nunwritable: old\n\
+                               # shell(when=$SOPS_SHELL_TEST_REPORT_UNSET): echo never\nskipped: old\n";
+
+        /// Minimal well-formedness check: balanced and properly nested tags, quoted attributes
+        /// without raw markup, and only known entities
+        fn assert_well_formed(xml: &str) {
+            let body = xml.strip_prefix("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n").expect("Should have an XML declaration");
+            let entity = Regex::new(r"^&(amp|lt|gt|quot|apos|#[0-9]+);").unwrap();
+            let attribute = Regex::new(r#"^\s+[A-Za-z_][\w.-]*="([^"<]*)""#).unwrap();
+            let mut open: Vec<&str> = Vec::new();
+            let mut rest = body;
+            while let Some(start) = rest.find(['<', '&']) {
+                assert!(rest[..start].trim().is_empty(), "Unexpected text {:?}", &rest[..start]);
+                rest = &rest[start..];
+                if rest.starts_with('&') {
+                    let matched = entity.find(rest).unwrap_or_else(|| panic!("Bad entity at {:?}", rest));
+                    rest = &rest[matched.end()..];
+                    continue;
+                }
+                let end = rest.find('>').expect("Unterminated tag");
+                let tag = &rest[1..end];
+                rest = &rest[end + 1..];
+                if let Some(name) = tag.strip_prefix('/') {
+                    assert_eq!(open.pop(), Some(name), "Mismatched closing tag");
+                    continue;
+                }
+                let (tag, self_closing) = match tag.strip_suffix('/') {
+                    Some(tag) => (tag, true),
+                    None => (tag, false),
+                };
+                let name_end = tag.find(char::is_whitespace).unwrap_or(tag.len());
+                let (name, mut attributes) = tag.split_at(name_end);
+                assert!(!name.is_empty() && name.chars().all(|c| c.is_alphanumeric()), "Bad tag name {:?}", name);
+                while let Some(matched) = attribute.captures(attributes) {
+                    let value = matched.get(1).unwrap().as_str();
+                    let mut value_rest = value;
+                    while let Some(amp) = value_rest.find('&') {
+                        let matched = entity.find(&value_rest[amp..]).unwrap_or_else(|| panic!("Bad entity in {:?}", value));
+                        value_rest = &value_rest[amp + matched.end()..];
+                    }
+                    attributes = &attributes[matched.get(0).unwrap().end()..];
+                }
+                assert!(attributes.trim().is_empty(), "Malformed attributes {:?}", attributes);
+                if !self_closing {
+                    open.push(name);
+                }
+            }
+            assert!(rest.trim().is_empty() && open.is_empty(), "Unclosed tags {:?}", open);
+        }
+
+        fn run(dry_run: bool) -> String {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let secrets = dir.path().join("secrets.yaml");
+            std::fs::write(&secrets, CONTENT).expect("Should write file");
+            let report = dir.path().join("report.xml");
+            // Fails the `fail` command and any write to `unwritable`
+            let runner = FakeRunner::new()
+                .failing("fail <hard> & \"loud\"", "exploded <badly>")
+                .refusing_set("unwritable", "permission denied");
+            let options = SyncOptions {
+                runner: Runner::new(runner),
+                summary_only: true,
+                report: Some(format!("junit:{}", report.display()).parse().expect("Should parse target")),
+                ..Default::default()
+            };
+            process_files(&[&secrets], dry_run, &options).expect("Should run");
+            let xml = std::fs::read_to_string(&report).expect("Should write report");
+            assert_well_formed(&xml);
+            xml
+        }
+
+        fn testcase<'a>(xml: &'a str, key: &str) -> &'a str {
+            let start = xml.find(&format!("name=\"{}\"", key)).unwrap_or_else(|| panic!("No test case for {}", key));
+            let end = xml[start..].find("/>\n").map(|end| start + end).unwrap();
+            &xml[start..end]
+        }
+
+        #[test]
+        fn test_sync_report() {
+            let xml = run(false);
+            assert!(xml.contains("<testsuites name=\"sops-shell\" tests=\"5\" failures=\"2\" skipped=\"1\">"), "{}", xml);
+            assert!(!testcase(&xml, "in_sync").contains('>'));
+            assert!(!testcase(&xml, "updated").contains('>'), "A written update passes");
+            assert!(testcase(&xml, "broken").contains("<failure message=\"Command failed: exploded &lt;badly&gt;\""));
+            assert!(testcase(&xml, "unwritable").contains("<failure message=\"sops write failed: SOPS command failed: permission denied"));
+            assert!(testcase(&xml, "skipped").contains("<skipped message="));
+        }
+
+        #[test]
+        fn test_check_fails_out_of_sync_keys() {
+            let xml = run(true);
+            assert!(testcase(&xml, "updated").contains("<failure message=\"out of sync\""));
+            assert!(testcase(&xml, "unwritable").contains("<failure message=\"out of sync\""));
+        }
+
+        #[test]
+        fn test_values_not_included() {
+            let xml = run(false);
+            assert!(!xml.contains("new") && !xml.contains("locked"), "{}", xml);
+        }
+
+        #[test]
+        fn test_parse_target() {
+            let target: ReportTarget = "junit:out/report.xml".parse().expect("Should parse");
+            assert_eq!(target.path, PathBuf::from("out/report.xml"));
+            assert!("junit:".parse::<ReportTarget>().is_err());
+            assert!("report.xml".parse::<ReportTarget>().is_err());
+            assert!("tap:report.tap".parse::<ReportTarget>().is_err());
+        }
+    }
+
+    mod name_regex {
+        use super::*;
+        use regex::Regex;
+
+        const CONTENT: &str = "# shell: echo a\ndb_user: a\n# shell: echo b\ndb_password: b\n# shell: echo c\napi_key: c\n# shell: echo d\nold_db_host: d\n";
+
+        fn keys(options: &ParseOptions) -> Vec<String> {
+            let mappings = parse_commands(CONTENT, options).expect("Should parse successfully").mappings;
+            mappings.into_iter().map(|mapping| mapping.key).collect()
+        }
+
+        fn regex(pattern: &str) -> Option<Regex> {
+            Some(Regex::new(pattern).expect("Should be a valid regex"))
+        }
+
+        #[test]
+        fn test_matches_subset() {
+            let options = ParseOptions { name_regex: regex("^db_"), ..Default::default() };
+            assert_eq!(keys(&options), vec!["db_user", "db_password"]);
+        }
+
+        #[test]
+        fn test_unanchored_pattern_matches_anywhere() {
+            let options = ParseOptions { name_regex: regex("db_"), ..Default::default() };
+            assert_eq!(keys(&options), vec!["db_user", "db_password", "old_db_host"]);
+        }
+
+        #[test]
+        fn test_intersects_with_skip_key() {
+            let options = ParseOptions {
+                name_regex: regex("^db_"),
+                skip_keys: vec!["db_password".to_string()],
+                ..Default::default()
+            };
+            assert_eq!(keys(&options), vec!["db_user"]);
+        }
+
+        #[test]
+        fn test_no_match_leaves_nothing() {
+            let options = ParseOptions { name_regex: regex("^redis_"), ..Default::default() };
+            assert!(keys(&options).is_empty());
+        }
+    }
+
+    mod exclude_key {
+        use super::*;
+        use crate::parser::KeyGlob;
+        use regex::Regex;
+
+        const CONTENT: &str = "# shell: echo a\ndb_user: a\n# shell: echo b\ndb_password: b\n# shell: echo c\napi_key: c\n# shell: echo d\nold_db_host: d\n";
+
+        fn keys(options: &ParseOptions) -> Vec<String> {
+            let mappings = parse_commands(CONTENT, options).expect("Should parse successfully").mappings;
+            mappings.into_iter().map(|mapping| mapping.key).collect()
+        }
+
+        fn globs(patterns: &[&str]) -> Vec<KeyGlob> {
+            patterns.iter().map(|pattern| KeyGlob::new(pattern).expect("Should be a valid glob")).collect()
+        }
+
+        #[test]
+        fn test_excludes_exact_key() {
+            let options = ParseOptions { exclude_keys: globs(&["api_key"]), ..Default::default() };
+            assert_eq!(keys(&options), vec!["db_user", "db_password", "old_db_host"]);
+        }
+
+        #[test]
+        fn test_glob_matches_whole_key() {
+            let options = ParseOptions { exclude_keys: globs(&["db_*"]), ..Default::default() };
+            assert_eq!(keys(&options), vec!["api_key", "old_db_host"]);
+
+            let options = ParseOptions { exclude_keys: globs(&["*_?ost", "api_*"]), ..Default::default() };
+            assert_eq!(keys(&options), vec!["db_user", "db_password"]);
+        }
+
+        #[test]
+        fn test_glob_characters_are_literal_otherwise() {
+            let glob = KeyGlob::new("db.user").expect("Should be a valid glob");
+            assert!(glob.is_match("db.user"));
+            assert!(!glob.is_match("db_user"));
+            assert!(KeyGlob::new("").is_err());
+        }
+
+        #[test]
+        fn test_exclusion_wins_over_name_regex() {
+            let options = ParseOptions {
+                name_regex: Some(Regex::new("db_").expect("Should be a valid regex")),
+                exclude_keys: globs(&["*password"]),
+                ..Default::default()
+            };
+            assert_eq!(keys(&options), vec!["db_user", "old_db_host"]);
+        }
+    }
+
+    mod plaintext_detection {
+        use super::*;
+        use crate::plaintext::{EncryptionRule, PlaintextValue};
+        use crate::runner::Runner;
+        use crate::testing::{secrets_file, FakeRunner, Plaintext};
+
+        const ENCRYPTED: &str = "\
+# shell: fetch-api-key
+api_key: ENC[AES256_GCM,data:8Jx1,iv:aa,tag:bb,type:str]
+# shell: fetch-db-password
+db_password: hunter2
+# shell: fetch-region
+region_unencrypted: eu-west-1
+sops:
+    mac: ENC[AES256_GCM,data:cc,iv:dd,tag:ee,type:str]
+    unencrypted_suffix: _unencrypted
+    version: 3.9.0
+";
+
+        fn found(raw: &str, keys: &[&str]) -> Vec<PlaintextValue> {
+            plaintext_values(raw, keys.iter().copied(), |key| parse_decrypted_value(raw, key, None)).expect("Should check")
+        }
+
+        #[test]
+        fn test_flags_unencrypted_key() {
+            let found = found(ENCRYPTED, &["api_key", "db_password", "region_unencrypted"]);
+            assert_eq!(
+                found,
+                vec![
+                    PlaintextValue { key: "db_password".to_string(), exempted_by: None },
+                    PlaintextValue {
+                        key: "region_unencrypted".to_string(),
+                        exempted_by: Some(EncryptionRule::UnencryptedSuffix("_unencrypted".to_string())),
+                    },
+                ]
+            );
+            assert!(found[0].to_string().contains("likely added without sops"));
+        }
+
+        #[test]
+        fn test_plaintext_file_not_checked() {
+            assert!(found("# shell: fetch\ndb_password: hunter2\n", &["db_password"]).is_empty());
+        }
+
+        #[test]
+        fn test_rules_from_metadata() {
+            let yaml = "sops:\n    encrypted_regex: ^(data|stringData)$\n";
+            assert_eq!(EncryptionRule::from_metadata(yaml).unwrap(), EncryptionRule::EncryptedRegex("^(data|stringData)$".to_string()));
+            let json = "{\n\t\"sops\": {\n\t\t\"unencrypted_regex\": \"^public_\",\n\t\t\"version\": \"3.9.0\"\n\t}\n}";
+            assert_eq!(EncryptionRule::from_metadata(json).unwrap(), EncryptionRule::UnencryptedRegex("^public_".to_string()));
+            let env = "API_KEY=ENC[AES256_GCM,data:aa]\nsops_encrypted_suffix=_secret\n";
+            assert_eq!(EncryptionRule::from_metadata(env).unwrap(), EncryptionRule::EncryptedSuffix("_secret".to_string()));
+            assert_eq!(
+                EncryptionRule::from_metadata("sops:\n    version: 3.9.0\n").unwrap(),
+                EncryptionRule::UnencryptedSuffix("_unencrypted".to_string())
+            );
+        }
+
+        #[test]
+        fn test_encrypted_regex_exempts_other_keys() {
+            let raw = "data: ENC[AES256_GCM,data:aa]\nkind: Secret\nsops:\n    encrypted_regex: ^data$\n";
+            let found = found(raw, &["data", "kind"]);
+            assert_eq!(found.len(), 1);
+            assert_eq!(found[0].exempted_by, Some(EncryptionRule::EncryptedRegex("^data$".to_string())));
+        }
+
+        #[test]
+        fn test_counted_during_check() {
+            let file = secrets_file(ENCRYPTED, ".yaml");
+            // The decrypted counterpart of `ENCRYPTED`, with every command matching its stored value
+            let decrypted = Plaintext::yaml()
+                .secret("fetch-api-key", "api_key", "secret")
+                .secret("fetch-db-password", "db_password", "hunter2")
+                .build();
+            let runner = FakeRunner::new().decrypting_to(decrypted).output("fetch-api-key", "secret").otherwise("hunter2");
+            let options = SyncOptions { runner: Runner::new(runner), summary_only: true, ..Default::default() };
+            let mut state = RunState::default();
+            process_file(file.path(), true, &options, &mut state).expect("Should check");
+            assert_eq!(state.plaintext_values, 1);
+        }
+    }
+
+    mod parse_directives_api {
+        use crate::parser::{ParseOptions, ParsedDirective};
+        use crate::{parse_directives, DirectiveIssue};
+
+        const TEXT: &str = "\
+# shell: fetch-api-key
+api_key: a
+# shell(timeout=soon): fetch-token
+token: b
+# shell-map: fetch-db
+# user=.user pass=.pass
+user: c
+pass: d
+# shell: fetch-orphan
+";
+
+        fn summary(directives: &[ParsedDirective]) -> Vec<(usize, String)> {
+            directives
+                .iter()
+                .map(|directive| match directive {
+                    ParsedDirective::Resolved(mapping) => (directive.line(), format!("{} <- {}", mapping.key, mapping.command)),
+                    ParsedDirective::Unresolved(DirectiveIssue { message, .. }) => (directive.line(), message.clone()),
+                })
+                .collect()
+        }
+
+        #[test]
+        fn test_resolved_and_unresolved_in_line_order() {
+            let directives = parse_directives(TEXT, &ParseOptions::default()).expect("Should parse");
+            let summary = summary(&directives);
+            assert_eq!(summary.len(), 5);
+            assert_eq!(summary[0], (1, "api_key <- fetch-api-key".to_string()));
+            assert_eq!(summary[1].0, 3);
+            assert!(summary[1].1.contains("timeout"), "{}", summary[1].1);
+            assert_eq!(summary[2], (5, "user <- fetch-db".to_string()));
+            assert_eq!(summary[3], (5, "pass <- fetch-db".to_string()));
+            assert_eq!(summary[4].0, 9);
+            assert!(summary[4].1.contains("not followed by a key"), "{}", summary[4].1);
+        }
+
+        #[test]
+        fn test_malformed_directive_does_not_hide_others() {
+            let text = "# shell[]: fetch\nfirst: a\n# shell: fetch-second\nsecond: b\n";
+            let directives = parse_directives(text, &ParseOptions::default()).expect("Should parse");
+            assert!(matches!(&directives[0], ParsedDirective::Unresolved(issue) if issue.snippet == "# shell[]: fetch"));
+            assert!(matches!(&directives[1], ParsedDirective::Resolved(mapping) if mapping.key == "second" && mapping.line == 3));
+        }
+
+        #[test]
+        fn test_no_directives() {
+            assert!(parse_directives("plain: value\n", &ParseOptions::default()).expect("Should parse").is_empty());
+        }
+    }
+
+    mod confirm_each {
+        use super::*;
+        use std::io::Cursor;
+
+        const CONTENT: &str = "# shell: echo one\nfirst: one\n# shell: echo two\nsecond: two\n\
+                               # shell: echo three\nthird: three\n# shell: echo four\nfourth: four\n";
+
+        fn outcomes(content: &str, answers: &str, approve_all: bool) -> Vec<(String, &'static str)> {
+            let options = SyncOptions { confirm: Some(Confirmer::new(Cursor::new(answers.to_string()), approve_all)), ..Default::default() };
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse").mappings;
+            drift_for_mappings(Path::new("secrets.yaml"), content, &mappings, &options, &mut RunState::default(), |_| {})
+                .into_iter()
+                .map(|status| (status.key, status.outcome.as_str()))
+                .collect()
+        }
+
+        fn results(outcomes: &[(String, &'static str)]) -> Vec<&'static str> {
+            outcomes.iter().map(|(_, outcome)| *outcome).collect()
+        }
+
+        #[test]
+        fn test_answers_apply_per_command_and_all_stops_asking() {
+            // Input runs out after "all", so the fourth command would be declined if it were asked about
+            let outcomes = outcomes(CONTENT, "y\nn\nall\n", false);
+            assert_eq!(results(&outcomes), vec!["in_sync", "refused", "in_sync", "in_sync"]);
+        }
+
+        #[test]
+        fn test_yes_approves_without_reading() {
+            assert_eq!(results(&outcomes(CONTENT, "", true)), vec!["in_sync"; 4]);
+        }
+
+        #[test]
+        fn test_end_of_input_and_empty_answer_decline() {
+            let outcomes = outcomes(CONTENT, "\nY\n", false);
+            assert_eq!(results(&outcomes), vec!["refused", "in_sync", "refused", "refused"]);
+        }
+
+        #[test]
+        fn test_unknown_answer_asks_again() {
+            let outcomes = outcomes(CONTENT, "sure\nyes\nno\n", false);
+            assert_eq!(results(&outcomes), vec!["in_sync", "refused", "refused", "refused"]);
+        }
+
+        #[test]
+        fn test_shell_map_asked_once() {
+            let content = "# shell-map: echo '{\"user\": \"app\", \"pass\": \"secret\"}'\n# user=.user pass=.pass\nuser: app\npass: secret\n";
+            assert_eq!(results(&outcomes(content, "y\n", false)), vec!["in_sync", "in_sync"]);
+        }
+
+        #[test]
+        fn test_declined_command_is_not_run() {
+            let dir = tempfile::tempdir().expect("Failed to create temp dir");
+            let marker = dir.path().join("ran");
+            let content = format!("# shell: touch {} && echo x\nkey: x\n", marker.display());
+            assert_eq!(results(&outcomes(&content, "n\n", false)), vec!["refused"]);
+            assert!(!marker.exists());
+        }
+    }
+
+    mod concurrent_modification {
+        use super::*;
+        use crate::runner::{CommandOutput, CommandRunner, Runner};
+        use std::fs::OpenOptions;
+        use std::io::Write;
+        use std::sync::{Arc, Mutex};
+
+        const CONTENT: &str = "# shell: fetch-api-key\napi_key: old\n# shell: fetch-token\ntoken: old\n";
+
+        fn append(path: &str, text: &str) {
+            let mut file = OpenOptions::new().append(true).open(path).expect("Should open file");
+            file.write_all(text.as_bytes()).expect("Should append");
+        }
+
+        /// Decrypts and writes the real file, and runs `edit <path>` by appending to it, the way
+        /// someone editing the file during the run would
+        #[derive(Default)]
+        struct EditingRunner {
+            sets: Arc<Mutex<usize>>,
+        }
+
+        impl CommandRunner for EditingRunner {
+            fn run(&self, program: &str, args: &[&str]) -> std::io::Result<CommandOutput> {
+                let stdout = match (program, args) {
+                    ("sops", [.., "--decrypt", path]) => std::fs::read_to_string(path)?,
+                    ("sops", ["--set", .., path]) => {
+                        *self.sets.lock().unwrap() += 1;
+                        append(path, "# written by sops\n");
+                        String::new()
+                    }
+                    ("sh", ["-c", command]) => match command.strip_prefix("edit ") {
+                        Some(path) => {
+                            append(path, "edited: by hand\n");
+                            "new".to_string()
+                        }
+                        None => "new".to_string(),
+                    },
+                    _ => String::new(),
+                };
+                Ok(CommandOutput { code: Some(0), stdout: stdout.into_bytes(), stderr: Vec::new() })
+            }
+        }
+
+        fn sync(content: &str, configure: impl FnOnce(&mut SyncOptions)) -> (RunSummary, usize, String) {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = dir.path().join("secrets.yaml");
+            std::fs::write(&file, content.replace("{file}", &file.display().to_string())).expect("Should write file");
+
+            let runner = EditingRunner::default();
+            let sets = Arc::clone(&runner.sets);
+            let mut options = SyncOptions { runner: Runner::new(runner), summary_only: true, ..Default::default() };
+            configure(&mut options);
+            let summary = sync_files(&[&file], &options).expect("Should sync");
+            let sets = *sets.lock().unwrap();
+            (summary, sets, std::fs::read_to_string(&file).expect("Should read file"))
+        }
+
+        #[test]
+        fn test_sops_writes_do_not_count_as_modifications() {
+            let (summary, sets, _) = sync(CONTENT, |_| {});
+            assert_eq!(sets, 2);
+            assert_eq!(summary.errors, 0);
+        }
+
+        #[test]
+        fn test_modified_during_run_is_not_written() {
+            let content = "# shell: edit {file}\napi_key: old\n# shell: fetch-token\ntoken: old\n";
+            let (summary, sets, written) = sync(content, |_| {});
+            assert_eq!(sets, 0, "The edit must not be overwritten");
+            assert_eq!((summary.updates, summary.errors), (0, 1));
+            assert!(written.ends_with("edited: by hand\n"), "{}", written);
+        }
+
+        #[test]
+        fn test_modified_during_run_with_output_suffix() {
+            let content = "# shell: edit {file}\napi_key: old\n";
+            let (summary, sets, _) = sync(content, |options| options.output_suffix = Some(".synced".to_string()));
+            assert_eq!(sets, 0);
+            assert_eq!((summary.updates, summary.errors), (0, 1));
+        }
+
+        #[test]
+        fn test_error_names_the_modification() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = dir.path().join("secrets.yaml");
+            std::fs::write(&file, CONTENT).expect("Should write file");
+            let fingerprint = FileFingerprint::read(&file).expect("Should fingerprint");
+            assert!(fingerprint.ensure_unchanged(&file).is_ok());
+
+            append(&file.display().to_string(), "edited: by hand\n");
+            let error = fingerprint.ensure_unchanged(&file).expect_err("Should notice the edit");
+            assert!(error.to_string().contains("file modified during processing"), "{}", error);
+        }
+    }
+
+    mod last_synced {
+        use super::*;
+        use crate::runner::Runner;
+        use crate::testing::FakeRunner;
+        use std::time::UNIX_EPOCH;
+
+        #[test]
+        fn test_format_timestamp() {
+            assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+            assert_eq!(format_timestamp(951_782_400), "2000-02-29T00:00:00Z");
+            assert_eq!(format_timestamp(1_714_555_800), "2024-05-01T09:30:00Z");
+            assert_eq!(format_timestamp(4_102_444_799), "2099-12-31T23:59:59Z");
+        }
+
+        #[test]
+        fn test_sync_times_round_trip() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let path = dir.path().join("state.json");
+
+            let mut command_state = CommandState::load(&path).expect("A missing state file should load empty");
+            command_state.record_sync(Path::new("secrets.yaml"), "api_key", UNIX_EPOCH + Duration::from_secs(1_714_555_800));
+            command_state.save().expect("Should save");
+
+            let loaded = CommandState::load(&path).expect("Should load");
+            let times = loaded.sync_times(Path::new("secrets.yaml"));
+            assert_eq!(times.get("api_key"), Some(&1_714_555_800));
+            assert!(loaded.sync_times(Path::new("other.yaml")).is_empty());
+        }
+
+        #[test]
+        fn test_state_file_without_sync_times_loads() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let path = dir.path().join("state.json");
+            std::fs::write(&path, r#"{"files": {"secrets.yaml": {"api_key": "abc"}}}"#).expect("Should write state");
+
+            let loaded = CommandState::load(&path).expect("Should load");
+            assert!(loaded.sync_times(Path::new("secrets.yaml")).is_empty());
+        }
+
+        #[test]
+        fn test_updates_record_sync_time() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = dir.path().join("secrets.yaml");
+            std::fs::write(&file, "# shell: fetch-api-key\napi_key: old\n# shell: echo same\ntoken: same\n")
+                .expect("Should write file");
+            let state_path = dir.path().join("state.json");
+
+            let options = SyncOptions {
+                runner: Runner::new(FakeRunner::new().output("fetch-api-key", "new")),
+                command_state: Some(state_path.clone()),
+                summary_only: true,
+                ..Default::default()
+            };
+            let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            sync_files(&[&file], &options).expect("Should sync");
+
+            let times = CommandState::load(&state_path).expect("Should load").sync_times(&file);
+            assert!(times.get("api_key").is_some_and(|time| *time >= before), "{:?}", times);
+            // Only updates count, a key that was already in sync wasn't written
+            assert!(!times.contains_key("token"));
+        }
+    }
+
+    mod also_write {
+        use super::*;
+        use crate::runner::Runner;
+        use crate::testing::FakeRunner;
+
+        /// Every write, as `<file name> <type> <path and value>`
+        fn writes(runner: &FakeRunner) -> Vec<String> {
+            runner
+                .calls()
+                .iter()
+                .filter(|call| call[0] == "sops")
+                .filter_map(|call| match &call[1..] {
+                    [types @ .., set_flag, set, path] if set_flag == "--set" => {
+                        let file_name = Path::new(path).file_name().unwrap().to_string_lossy().into_owned();
+                        let input_type = types.get(1).map_or("-", String::as_str);
+                        Some(format!("{} {} {}", file_name, input_type, set))
+                    }
+                    _ => None,
+                })
+                .collect()
+        }
+
+        fn sync(targets: &[(&str, &str)]) -> (RunSummary, Vec<String>) {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = dir.path().join("secrets.yaml");
+            std::fs::write(&file, "# shell: fetch-api-key\napi_key: old\n").expect("Should write file");
+            let also_write = targets
+                .iter()
+                .map(|(name, content)| {
+                    let target = dir.path().join(name);
+                    std::fs::write(&target, content).expect("Should write target");
+                    target
+                })
+                .collect();
+
+            let runner = FakeRunner::new().output("fetch-api-key", "new");
+            let options =
+                SyncOptions { runner: Runner::new(runner.clone()), summary_only: true, also_write, ..Default::default() };
+            let summary = sync_files(&[&file], &options).expect("Should sync");
+            (summary, writes(&runner))
+        }
+
+        #[test]
+        fn test_every_target_receives_the_value() {
+            let (summary, sets) = sync(&[("secrets.env", "API_KEY=old\n"), ("secrets.json", "{\"api_key\": \"old\"}\n")]);
+            assert_eq!(
+                sets,
+                vec![
+                    r#"secrets.yaml - ["api_key"] "new""#,
+                    r#"secrets.env dotenv ["API_KEY"] "new""#,
+                    r#"secrets.json json ["api_key"] "new""#,
+                ]
+            );
+            assert_eq!(summary.errors, 0);
+        }
+
+        #[test]
+        fn test_ini_target_uses_its_section() {
+            let (_, sets) = sync(&[("secrets.ini", "[config]\napi_key = old\n")]);
+            assert_eq!(sets[1], r#"secrets.ini ini ["config"]["api_key"] "new""#);
+        }
+
+        #[test]
+        fn test_missing_key_is_added() {
+            let (_, sets) = sync(&[("secrets.env", "OTHER=x\n")]);
+            assert_eq!(sets[1], r#"secrets.env dotenv ["api_key"] "new""#);
+        }
+
+        #[test]
+        fn test_target_with_value_already_is_not_written() {
+            let (summary, sets) = sync(&[("secrets.env", "API_KEY=new\n")]);
+            assert_eq!(sets, vec![r#"secrets.yaml - ["api_key"] "new""#]);
+            assert_eq!(summary.errors, 0);
+        }
+
+        #[test]
+        fn test_unreadable_target_is_an_error() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = dir.path().join("secrets.yaml");
+            std::fs::write(&file, "# shell: fetch-api-key\napi_key: old\n").expect("Should write file");
+            let options = SyncOptions {
+                runner: Runner::new(FakeRunner::new().output("fetch-api-key", "new")),
+                summary_only: true,
+                also_write: vec![dir.path().join("missing.env")],
+                ..Default::default()
+            };
+            assert_eq!(sync_files(&[&file], &options).expect("Should sync").errors, 1);
+        }
+    }
+
+    mod no_trim {
+        use super::*;
+        use crate::runner::Runner;
+        use crate::sops::format_value_for_sops;
+        use crate::testing::FakeRunner;
+
+        const PEM: &str = "-----BEGIN KEY-----\nabc\n-----END KEY-----\n";
+
+        fn output(stdout: &str, stderr: &str) -> CommandOutput {
+            CommandOutput { code: Some(0), stdout: stdout.as_bytes().to_vec(), stderr: stderr.as_bytes().to_vec() }
+        }
+
+        /// Syncs a file holding the first line of `PEM` while the command prints `output` and the exact
+        /// stored value is `stored`, returning the extracts and writes as `extract <path>` and `set <path and value>`
+        fn sync_file(content: &str, output: &str, stored: &str, no_trim: bool) -> Vec<String> {
+            let file = create_test_file(content);
+            let runner = FakeRunner::new().otherwise(output).extracting(r#"["key"]"#, stored);
+            let options = SyncOptions { runner: Runner::new(runner.clone()), no_trim, summary_only: true, ..Default::default() };
+            sync_files(&[file.path()], &options).expect("Should sync");
+            runner
+                .calls()
+                .iter()
+                .filter_map(|call| match call.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+                    ["sops", .., "--extract", path, _] => Some(format!("extract {}", path)),
+                    ["sops", .., "--set", set, _] => Some(format!("set {}", set)),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        fn sync(stored: &str, no_trim: bool) -> Vec<String> {
+            sync_file("# shell: fetch-key\nkey: |\n  -----BEGIN KEY-----\n", PEM, stored, no_trim)
+        }
+
+        #[test]
+        fn test_command_value_keeps_whitespace() {
+            assert_eq!(command_value(output(PEM, ""), ValueSource::Stdout, Capture::Stdout, false).unwrap(), PEM);
+            assert_eq!(command_value(output(" a \n", ""), ValueSource::Stdout, Capture::Stdout, true).unwrap(), "a");
+        }
+
+        #[test]
+        fn test_capture_both_joins_as_is() {
+            let both = |trim| command_value(output("out\n", "err\n"), ValueSource::Stdout, Capture::Both, trim).unwrap();
+            assert_eq!(both(false), "out\nerr\n");
+            assert_eq!(both(true), "out\nerr");
+        }
+
+        #[test]
+        fn test_whitespace_is_written() {
+            let calls = sync("", true);
+            assert_eq!(calls, vec![r#"extract ["key"]"#.to_string(), format!(r#"set ["key"] {}"#, serde_json::json!(PEM))]);
+        }
+
+        #[test]
+        fn test_exact_stored_value_is_compared() {
+            assert_eq!(sync(PEM, true), vec![r#"extract ["key"]"#]);
+        }
+
+        #[test]
+        fn test_trimmed_by_default() {
+            let calls = sync(PEM, false);
+            assert_eq!(calls, vec![format!(r#"set ["key"] {}"#, serde_json::json!(PEM.trim()))]);
+        }
+
+        fn sync_keeping_newline(output: &str, stored: &str) -> Vec<String> {
+            let content = "# shell(keep-trailing-newline): fetch-key\nkey: |\n  -----BEGIN KEY-----\n";
+            sync_file(content, output, stored, false)
+        }
+
+        #[test]
+        fn test_keep_trailing_newline_round_trips() {
+            // Written with a single newline, however much whitespace the command printed
+            let calls = sync_keeping_newline("-----BEGIN KEY-----\nabc\n-----END KEY-----\n\n", "");
+            assert_eq!(calls, vec![r#"extract ["key"]"#.to_string(), format!(r#"set ["key"] {}"#, serde_json::json!(PEM))]);
+
+            // Then read back as written, so it doesn't flap, even from output without the newline
+            assert_eq!(sync_keeping_newline(PEM, PEM), vec![r#"extract ["key"]"#]);
+            assert_eq!(sync_keeping_newline(PEM.trim_end(), PEM), vec![r#"extract ["key"]"#]);
+        }
+
+        #[test]
+        fn test_keep_trailing_newline_is_a_flag() {
+            let content = "# shell(keep-trailing-newline, timeout=5): fetch-key\nkey: a\n# shell(timeout=5 keep-trailing-newline): fetch-key\nother: b\n";
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            assert!(mappings.iter().all(|mapping| mapping.keep_trailing_newline));
+            assert!(mappings.iter().all(|mapping| mapping.settings.timeout == Some(Duration::from_secs(5))));
+
+            let content = "# shell(keep-trailing-newline=yes): fetch-key\nkey: a\n";
+            assert!(parse_commands(content, &ParseOptions::default()).is_err());
+        }
+
+        #[test]
+        fn test_json_with_whitespace_stays_a_string() {
+            assert_eq!(format_value_for_sops("123\n").unwrap(), r#""123\n""#);
+            assert_eq!(format_value_for_sops("123").unwrap(), "123");
+        }
+    }
+
+    mod assign_ops {
+        use super::*;
+        use crate::parser::{lint_directives_with_ops, validate_assign_op, DirectivePosition};
+
+        fn options(ops: &[&str], format: Option<FileFormat>) -> ParseOptions {
+            ParseOptions { assign_ops: ops.iter().map(|op| op.to_string()).collect(), format, ..Default::default() }
+        }
+
+        fn keys(content: &str, options: &ParseOptions) -> Vec<String> {
+            parse_commands(content, options).expect("Should parse").mappings.into_iter().map(|mapping| mapping.key).collect()
+        }
+
+        fn value(content: &str, key: &str, options: &ParseOptions) -> Option<String> {
+            find_decrypted_entry_with_ops(content, key, options.format, false, &options.assign_ops).map(|(_, value)| value)
+        }
+
+        #[test]
+        fn test_colon_equals() {
+            let content = "# shell: fetch-api-key\napi_key := old\n# shell: fetch-token\ntoken:=other\n";
+            let options = options(&[":="], None);
+            assert_eq!(keys(content, &options), vec!["api_key", "token"]);
+            assert_eq!(value(content, "api_key", &options).as_deref(), Some("old"));
+            assert_eq!(value(content, "token", &options).as_deref(), Some("other"));
+            // Without the operator, `:` is taken as the separator and `=` ends up in the value
+            assert_eq!(value(content, "api_key", &ParseOptions::default()).as_deref(), Some("= old"));
+        }
+
+        #[test]
+        fn test_colon_equals_with_forced_format() {
+            let content = "# shell: fetch-api-key\nAPI_KEY := old\n";
+            let options = options(&[":="], Some(FileFormat::Env));
+            assert_eq!(keys(content, &options), vec!["API_KEY"]);
+            assert_eq!(value(content, "API_KEY", &options).as_deref(), Some("old"));
+        }
+
+        #[test]
+        fn test_operator_without_format_separator() {
+            let content = "# shell: fetch-api-key\napi_key <- old\n";
+            assert!(keys(content, &ParseOptions::default()).is_empty());
+            let options = options(&["<-", "=>"], None);
+            assert_eq!(keys(content, &options), vec!["api_key"]);
+            assert_eq!(value(content, "api_key", &options).as_deref(), Some("old"));
+        }
+
+        #[test]
+        fn test_key_ends_at_first_operator() {
+            let content = "# shell: fetch\na <- b <- c\n";
+            let options = options(&["<-"], None);
+            assert_eq!(keys(content, &options), vec!["a"]);
+            assert_eq!(value(content, "a", &options).as_deref(), Some("b <- c"));
+        }
+
+        #[test]
+        fn test_quoted_key() {
+            let content = "# shell: fetch\n\"a b\" => old\n";
+            let options = options(&["=>"], None);
+            assert_eq!(keys(content, &options), vec!["a b"]);
+            assert_eq!(value(content, "a b", &options).as_deref(), Some("old"));
+        }
+
+        #[test]
+        fn test_stored_value_uses_operators() {
+            let options = SyncOptions { parse: options(&[":="], None), ..Default::default() };
+            assert_eq!(options.stored_value("api_key := old\n", "api_key").as_deref(), Some("old"));
+        }
+
+        #[test]
+        fn test_lint_resolves_keys_with_operators() {
+            let content = "# shell: fetch\napi_key <- old\n";
+            let ops = vec!["<-".to_string()];
+            assert!(lint_directives_with_ops(content, DirectivePosition::Before, &ops).expect("Should lint").is_empty());
+            assert_eq!(lint_directives_with_ops(content, DirectivePosition::Before, &[]).expect("Should lint").len(), 1);
+        }
+
+        #[test]
+        fn test_regex_characters_are_literal() {
+            let options = options(&[".*", "|"], None);
+            assert!(keys("# shell: fetch\nfoo bar\n", &options).is_empty());
+            assert_eq!(keys("# shell: fetch\nfoo .* bar\n", &options), vec!["foo"]);
+            assert_eq!(keys("# shell: fetch\nfoo | bar\n", &options), vec!["foo"]);
+        }
+
+        #[test]
+        fn test_validate_assign_op() {
+            for op in [":=", "<-", "=>", "|", "::="] {
+                assert_eq!(validate_assign_op(op).expect("Should be valid"), op);
+            }
+            for op in ["", "=====", "a=", "=>x", ": =", "\"=", "'", "#=", ";", "[=", "]", "→"] {
+                assert!(validate_assign_op(op).is_err(), "{:?} should be rejected", op);
+            }
+        }
+    }
+
+    mod interactive_edit {
+        use super::*;
+        use crate::runner::{CommandOutput, CommandRunner, Runner};
+        use crate::sops::SOPS_FILE_UNCHANGED;
+        use std::sync::{Arc, Mutex};
+
+        /// Decrypts the real file, and records sops calls other than decrypts and the editor's exit status
+        struct EditorRunner {
+            editor_exit: Option<i32>,
+            calls: Arc<Mutex<Vec<String>>>,
+        }
+
+        impl CommandRunner for EditorRunner {
+            fn run(&self, program: &str, args: &[&str]) -> std::io::Result<CommandOutput> {
+                let stdout = match (program, args) {
+                    ("sops", [.., "--decrypt", path]) => std::fs::read_to_string(path)?,
+                    ("sops", ["--version"]) => String::new(),
+                    ("sops", args) => {
+                        self.calls.lock().unwrap().push(format!("set {}", args.join(" ")));
+                        String::new()
+                    }
+                    ("sh", ["-c", _]) => "new".to_string(),
+                    _ => String::new(),
+                };
+                Ok(CommandOutput { code: Some(0), stdout: stdout.into_bytes(), stderr: Vec::new() })
+            }
+
+            fn run_attached(&self, program: &str, args: &[&str]) -> std::io::Result<CommandOutput> {
+                self.calls.lock().unwrap().push(format!("edit {} {}", program, args.join(" ")));
+                Ok(CommandOutput { code: self.editor_exit, ..Default::default() })
+            }
+        }
+
+        /// Returns the recorded calls with the file's path replaced by `FILE`
+        fn sync(content: &str, editor_exit: Option<i32>) -> (RunSummary, Vec<String>) {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = dir.path().join("secrets.yaml");
+            std::fs::write(&file, content).expect("Should write file");
+
+            let calls = Arc::new(Mutex::new(Vec::new()));
+            let runner = EditorRunner { editor_exit, calls: Arc::clone(&calls) };
+            let options = SyncOptions { runner: Runner::new(runner), summary_only: true, interactive_edit: true, ..Default::default() };
+            let summary = sync_files(&[&file], &options).expect("Should sync");
+            let path = file.display().to_string();
+            let calls = calls.lock().unwrap().iter().map(|call| call.replace(&path, "FILE")).collect();
+            (summary, calls)
+        }
+
+        #[test]
+        fn test_out_of_sync_file_is_opened_instead_of_written() {
+            let (summary, calls) = sync("# shell: fetch-api-key\napi_key: old\n", Some(0));
+            assert_eq!(calls, vec!["edit sops FILE"]);
+            assert_eq!(summary.updates, 0);
+            assert_eq!(summary.errors, 0);
+        }
+
+        #[test]
+        fn test_file_in_sync_is_not_opened() {
+            let (_, calls) = sync("# shell: fetch-api-key\napi_key: new\n", Some(0));
+            assert!(calls.is_empty());
+        }
+
+        #[test]
+        fn test_editor_closed_without_changes_is_not_an_error() {
+            let (summary, _) = sync("# shell: fetch-api-key\napi_key: old\n", Some(SOPS_FILE_UNCHANGED));
+            assert_eq!(summary.errors, 0);
+        }
+
+        #[test]
+        fn test_editor_failing_is_an_error() {
+            let (summary, _) = sync("# shell: fetch-api-key\napi_key: old\n", Some(1));
+            assert_eq!(summary.errors, 1);
+
+            let (summary, _) = sync("# shell: fetch-api-key\napi_key: old\n", None);
+            assert_eq!(summary.errors, 1);
+        }
+    }
+
+    mod shell_template {
+        use super::*;
+        use crate::parser::{lint_directives, DirectivePosition};
+        use crate::runner::Runner;
+        use crate::testing::FakeRunner;
+
+        const CONTENT: &str = "# shell-template: postgres://{{cmd:get-user}}:{{cmd:get-pass}}@db:5432/app\ndb_url: old\n";
+
+        /// Runs `get-user` and `get-pass`, any other command fails
+        fn sync(content: &str, policy: CommandPolicy) -> (RunSummary, Vec<String>) {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = dir.path().join("secrets.yaml");
+            std::fs::write(&file, content).expect("Should write file");
+
+            let runner = FakeRunner::new().output("get-user", "app\n").output("get-pass", "s3cret\n");
+            let options = SyncOptions { runner: Runner::new(runner.clone()), summary_only: true, policy, ..Default::default() };
+            let summary = sync_files(&[&file], &options).expect("Should sync");
+            (summary, runner.sets())
+        }
+
+        #[test]
+        fn test_parse_template() {
+            let mappings = parse_commands(CONTENT, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            assert_eq!(mappings.len(), 1);
+            assert_eq!(mappings[0].key, "db_url");
+            assert_eq!(mappings[0].command, "postgres://{{cmd:get-user}}:{{cmd:get-pass}}@db:5432/app");
+            assert_eq!(mappings[0].commands(), vec!["get-user", "get-pass"]);
+        }
+
+        #[test]
+        fn test_two_commands_are_substituted() {
+            let (summary, sets) = sync(CONTENT, CommandPolicy::default());
+            assert_eq!(sets, vec![r#"["db_url"] "postgres://app:s3cret@db:5432/app""#]);
+            assert_eq!(summary.errors, 0);
+        }
+
+        #[test]
+        fn test_rendered_value_in_sync_is_not_written() {
+            let content = "# shell-template: {{cmd:get-user}}:{{cmd:get-pass}}\ncredentials: app:s3cret\n";
+            let (summary, sets) = sync(content, CommandPolicy::default());
+            assert!(sets.is_empty());
+            assert_eq!(summary.errors, 0);
+        }
+
+        #[test]
+        fn test_failing_command_fails_the_key() {
+            let content = "# shell-template: postgres://{{cmd:get-user}}:{{cmd:missing-pass}}@db\ndb_url: old\n";
+            let (summary, sets) = sync(content, CommandPolicy::default());
+            assert!(sets.is_empty(), "Nothing should be written for a partly rendered template");
+            assert_eq!(summary.errors, 1);
+        }
+
+        #[test]
+        fn test_policy_applies_to_each_command() {
+            let policy = CommandPolicy { allow: vec!["get-user".to_string()], deny: Vec::new() };
+            let (summary, sets) = sync(CONTENT, policy);
+            assert!(sets.is_empty());
+            assert_eq!(summary.errors, 1);
+        }
+
+        #[test]
+        fn test_malformed_templates_are_rejected() {
+            for template in ["postgres://{{cmd:get-user@db", "postgres://db", "{{cmd: }}"] {
+                let content = format!("# shell-template: {}\ndb_url: old\n", template);
+                assert!(parse_commands(&content, &ParseOptions::default()).is_err(), "{} should be rejected", template);
+                let issues = lint_directives(&content, DirectivePosition::Before).expect("Should lint");
+                assert_eq!(issues.len(), 1, "{} should be reported", template);
+            }
+        }
+
+        #[test]
+        fn test_template_without_key_is_reported() {
+            let content = "# shell-template: {{cmd:get-user}}\n";
+            let issues = lint_directives(content, DirectivePosition::Before).expect("Should lint");
+            assert_eq!(issues.len(), 1);
+        }
+    }
+
+    mod report_orphans {
+        use super::*;
+        use crate::runner::Runner;
+        use crate::testing::{secrets_file, FakeRunner};
+
+        fn mappings(content: &str, options: &SyncOptions) -> Vec<CommandMapping> {
+            parse_commands(content, &options.parse).expect("Should parse successfully").mappings
+        }
+
+        fn orphans(content: &str, format: FileFormat) -> Vec<String> {
+            let mut options = SyncOptions::default();
+            options.parse.format = Some(format);
+            orphaned_keys(content, &mappings(content, &options), &options)
+        }
+
+        #[test]
+        fn test_yaml_keys_without_directive() {
+            let content = "# shell: fetch-api-key\napi_key: a\ndb_host: db\ndb:\n  user: app\n  port: 5432\nlist:\n  - item: x\n";
+            assert_eq!(orphans(content, FileFormat::Yaml), vec!["db", "db_host", "list"]);
+        }
+
+        #[test]
+        fn test_env_and_ini_keys() {
+            let env = "# shell: fetch-api-key\nAPI_KEY=a\nexport DB_HOST=db\n  TOKEN=t\n";
+            assert_eq!(orphans(env, FileFormat::Env), vec!["DB_HOST", "TOKEN"]);
+            let ini = "[config]\n# shell: fetch-api-key\napi_key = a\ndb_host = db\n";
+            assert_eq!(orphans(ini, FileFormat::Ini), vec!["db_host"]);
+        }
+
+        #[test]
+        fn test_json_top_level_keys() {
+            let content = "{\n    \"api_key\": \"a\",\n    \"db\": {\n        \"user\": \"app\"\n    }\n}\n";
+            assert_eq!(orphans(content, FileFormat::Json), vec!["api_key", "db"]);
+        }
+
+        #[test]
+        fn test_hash_companion_is_covered() {
+            let content = "# shell(compare=hash): fetch-api-key\napi_key: a\napi_key_sha256: salt:hash\nother: b\n";
+            assert_eq!(orphans(content, FileFormat::Yaml), vec!["other"]);
+        }
+
+        #[test]
+        fn test_case_is_ignored_only_when_asked() {
+            let content = "# shell: fetch-api-key\napi_key=a\nAPI_KEY=b\n";
+            assert_eq!(orphans(content, FileFormat::Env), vec!["API_KEY"]);
+
+            let mut options = SyncOptions { ignore_key_case: true, ..Default::default() };
+            options.parse.format = Some(FileFormat::Env);
+            assert!(orphaned_keys(content, &mappings(content, &options), &options).is_empty());
+        }
+
+        fn count(content: &str, report_orphans: bool) -> usize {
+            let file = secrets_file(content, ".yaml");
+            let runner = FakeRunner::new().otherwise("a");
+            let options = SyncOptions { runner: Runner::new(runner), summary_only: true, report_orphans, ..Default::default() };
+            let mut state = RunState::default();
+            process_file(file.path(), true, &options, &mut state).expect("Should check");
+            state.orphans
+        }
+
+        #[test]
+        fn test_counted_during_check() {
+            assert_eq!(count("# shell: fetch-api-key\napi_key: a\ndb_host: db\nregion: eu\n", true), 2);
+            assert_eq!(count("# shell: fetch-api-key\napi_key: a\ndb_host: db\n", false), 0);
+        }
+
+        #[test]
+        fn test_file_without_directives_is_all_orphans() {
+            assert_eq!(count("api_key: a\ndb_host: db\n", true), 2);
+        }
+    }
+
+    mod table {
+        use super::*;
+
+        fn status(key: &str, command: &str, outcome: KeyOutcome, millis: u64) -> KeyStatus {
+            KeyStatus {
+                key: key.to_string(),
+                command: command.to_string(),
+                outcome,
+                warnings: Vec::new(),
+                duration: Duration::from_millis(millis),
+            }
+        }
+
+        fn checked(status: SyncStatus, value: &str) -> KeyOutcome {
+            KeyOutcome::Checked { status, value: value.to_string(), hash: None, checksum: None }
+        }
+
+        #[test]
+        fn test_columns_are_aligned() {
+            let statuses = [
+                status("api_key", "rbw get api", checked(SyncStatus::InSync, "abcdef"), 12),
+                status("db_password", "vault read -field=password secret/db", checked(SyncStatus::OutOfSync, "x"), 340),
+            ];
+            assert_eq!(
+                key_table(&statuses).render(),
+                "\
+Key         | Status      | Command                              | Duration
+------------+-------------+--------------------------------------+---------
+api_key     | in_sync     | rbw get api                          | 12ms
+db_password | out_of_sync | vault read -field=password secret/db | 340ms"
+            );
+        }
+
+        #[test]
+        fn test_value_in_command_is_masked() {
+            let statuses = [
+                status("token", "echo s3cret-token", checked(SyncStatus::InSync, "s3cret-token\n"), 1),
+                status("flag", "exit 1", checked(SyncStatus::InSync, "1"), 1),
+            ];
+            let rendered = key_table(&statuses).render();
+            assert!(rendered.contains("echo ********"), "{}", rendered);
+            assert!(!rendered.contains("s3cret"), "{}", rendered);
+            assert!(rendered.contains("exit 1"), "Short values shouldn't be masked: {}", rendered);
+        }
+
+        #[test]
+        fn test_failed_keys_show_status_only() {
+            let statuses = [status("token", "fetch-token", KeyOutcome::Failed(anyhow!("leaked stderr")), 5)];
+            let rendered = key_table(&statuses).render();
+            assert!(rendered.contains("token | error  | fetch-token | 5ms"), "{}", rendered);
+            assert!(!rendered.contains("leaked"));
+        }
+
+        #[test]
+        fn test_long_cells_are_truncated() {
+            let mut table = Table::new(&["Command"]);
+            table.push(vec!["x".repeat(100)]);
+            table.push(vec!["multi\nline".to_string()]);
+            let rendered = table.render();
+            let rows: Vec<&str> = rendered.lines().skip(2).collect();
+            assert_eq!(rows[0].chars().count(), crate::table::MAX_CELL_WIDTH);
+            assert!(rows[0].ends_with('…'));
+            assert_eq!(rows[1], "multi line");
+        }
+    }
+
+    mod abort_on_empty_file {
+        use super::*;
+        use crate::runner::Runner;
+        use crate::testing::FakeRunner;
+
+        /// Syncs a file that decrypts to `decrypted`, e.g. when sops picks up the wrong configuration
+        fn sync(decrypted: &str, abort_on_empty_file: bool) -> Result<RunSummary> {
+            let file = create_test_file("#ENC[AES256_GCM,data:aa,type:comment]\napi_key: ENC[AES256_GCM,data:bb,type:str]\n");
+            let options = SyncOptions {
+                runner: Runner::new(FakeRunner::new().decrypting_to(decrypted)),
+                summary_only: true,
+                abort_on_empty_file,
+                ..Default::default()
+            };
+            sync_files(&[file.path()], &options)
+        }
+
+        #[test]
+        fn test_empty_decrypt_aborts() {
+            let e = sync("", true).expect_err("Empty content should abort the run");
+            assert!(e.to_string().contains("decrypted to empty content"), "{}", e);
+        }
+
+        #[test]
+        fn test_whitespace_only_decrypt_aborts() {
+            assert!(sync(" \n\t\n", true).is_err());
+        }
+
+        #[test]
+        fn test_lenient_by_default() {
+            let summary = sync("", false).expect("Should sync");
+            assert_eq!((summary.secrets, summary.errors), (0, 0));
+        }
+
+        #[test]
+        fn test_content_without_directives_is_fine() {
+            let summary = sync("api_key: a\n", true).expect("Should sync");
+            assert_eq!(summary.errors, 0);
+        }
+    }
+
+    mod shell_file {
+        use super::*;
+        use crate::parser::{lint_directives, DirectivePosition};
+        use crate::runner::{CommandOutput, CommandRunner, Runner, SystemRunner};
+        use std::os::unix::fs::PermissionsExt;
+        use std::sync::{Arc, Mutex};
+
+        const SCRIPT: &str = "#!/bin/sh\necho \"key-$1-$2\"\n";
+
+        /// Decrypts by reading the file and records the values written, leaving the shell to `SystemRunner`
+        #[derive(Default)]
+        struct ScriptRunner {
+            sets: Arc<Mutex<Vec<String>>>,
+        }
+
+        impl CommandRunner for ScriptRunner {
+            fn run(&self, program: &str, args: &[&str]) -> std::io::Result<CommandOutput> {
+                match (program, args) {
+                    ("sops", [.., "--decrypt", path]) => {
+                        let stdout = std::fs::read(path)?;
+                        Ok(CommandOutput { code: Some(0), stdout, stderr: Vec::new() })
+                    }
+                    ("sops", [.., "--set", set, _]) => {
+                        self.sets.lock().unwrap().push(set.to_string());
+                        Ok(CommandOutput { code: Some(0), stdout: Vec::new(), stderr: Vec::new() })
+                    }
+                    ("sh", _) => SystemRunner.run(program, args),
+                    _ => Ok(CommandOutput { code: Some(0), stdout: Vec::new(), stderr: Vec::new() }),
+                }
+            }
+        }
+
+        /// Syncs `content` from a file next to `scripts/gen.sh`, created with `mode` unless `None`
+        fn sync(content: &str, mode: Option<u32>) -> (RunSummary, Vec<String>) {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = dir.path().join("secrets.yaml");
+            std::fs::write(&file, content).expect("Should write file");
+            if let Some(mode) = mode {
+                let script = dir.path().join("scripts").join("gen.sh");
+                std::fs::create_dir(dir.path().join("scripts")).expect("Should create scripts dir");
+                std::fs::write(&script, SCRIPT).expect("Should write script");
+                std::fs::set_permissions(&script, std::fs::Permissions::from_mode(mode)).expect("Should chmod script");
+            }
+
+            let runner = ScriptRunner::default();
+            let sets = Arc::clone(&runner.sets);
+            let options = SyncOptions { runner: Runner::new(runner), summary_only: true, ..Default::default() };
+            let summary = sync_files(&[&file], &options).expect("Should sync");
+            let sets = sets.lock().unwrap().clone();
+            (summary, sets)
+        }
+
+        #[test]
+        fn test_parse_script_reference() {
+            let content = "# shell-file: scripts/gen.sh api 2\napi_key: old\n";
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            assert_eq!(mappings.len(), 1);
+            assert_eq!(mappings[0].command, "scripts/gen.sh api 2");
+            assert!(mappings[0].script);
+        }
+
+        #[test]
+        fn test_script_runs_with_arguments() {
+            let (summary, sets) = sync("# shell-file: scripts/gen.sh api 2\napi_key: old\n", Some(0o755));
+            assert_eq!(sets, vec![r#"["api_key"] "key-api-2""#]);
+            assert_eq!(summary.errors, 0);
+        }
+
+        #[test]
+        fn test_script_is_resolved_against_the_file_directory() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = dir.path().join("secrets.yaml");
+            let content = "# shell-file: ./gen.sh\napi_key: old\n";
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            let command = resolved_command(&mappings[0], &file);
+            let error = command.expect_err("Should fail for a missing script").to_string();
+            assert!(error.contains(&dir.path().join("./gen.sh").display().to_string()), "{}", error);
+        }
+
+        #[test]
+        fn test_non_executable_script_fails_the_key() {
+            let (summary, sets) = sync("# shell-file: scripts/gen.sh\napi_key: old\n", Some(0o644));
+            assert!(sets.is_empty());
+            assert_eq!(summary.errors, 1);
+        }
+
+        #[test]
+        fn test_missing_script_fails_the_key() {
+            let (summary, sets) = sync("# shell-file: scripts/missing.sh\napi_key: old\n", None);
+            assert!(sets.is_empty());
+            assert_eq!(summary.errors, 1);
+        }
+
+        #[test]
+        fn test_directive_without_script_is_reported() {
+            let issues = lint_directives("# shell-file:\napi_key: old\n", DirectivePosition::Before).expect("Should lint");
+            assert_eq!(issues.len(), 1);
+        }
+    }
+
+    mod max_output {
+        use super::*;
+        use crate::runner::{CommandRunner, Runner, SystemRunner};
+        use crate::testing::FakeRunner;
+
+        fn limited(max_output: usize) -> CommandEnv {
+            CommandEnv { max_output: Some(max_output), ..Default::default() }
+        }
+
+        fn execute(runner: &dyn CommandRunner, command: &str, env: &CommandEnv) -> Result<String> {
+            execute_command(runner, command, None, env, ValueSource::Stdout, Capture::Stdout, None)
+        }
+
+        /// Prints ten bytes for every command, without enforcing any limit itself
+        fn chatty() -> FakeRunner {
+            FakeRunner::new().otherwise("0123456789")
+        }
+
+        #[test]
+        fn test_output_over_the_limit_stops_the_command() {
+            let error = execute(&SystemRunner, "yes", &limited(1024)).expect_err("Should exceed the limit");
+            assert!(error.to_string().contains("exceeded the limit of 1024 bytes"), "{}", error);
+        }
+
+        #[test]
+        fn test_output_within_the_limit_is_kept() {
+            let value = execute(&SystemRunner, "echo fits", &limited(1024)).expect("Should run");
+            assert_eq!(value, "fits");
+        }
+
+        #[test]
+        fn test_stderr_counts_too() {
+            let error = execute(&SystemRunner, "head -c 2048 /dev/zero >&2", &limited(1024)).expect_err("Should exceed the limit");
+            assert!(error.to_string().contains("exceeded"), "{}", error);
+        }
+
+        #[test]
+        fn test_limit_is_checked_for_other_runners() {
+            assert!(execute(&chatty(), "anything", &limited(4)).is_err());
+            assert_eq!(execute(&chatty(), "anything", &limited(10)).expect("Should run"), "0123456789");
+        }
+
+        #[test]
+        fn test_key_over_the_limit_fails() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = dir.path().join("secrets.yaml");
+            std::fs::write(&file, "# shell: generate\ntoken: old\n").expect("Should write file");
+
+            let runner = chatty();
+            let options = SyncOptions {
+                runner: Runner::new(runner.clone()),
+                command_env: limited(4),
+                summary_only: true,
+                ..Default::default()
+            };
+            let summary = sync_files(&[&file], &options).expect("Should sync");
+            assert!(runner.sets().is_empty());
+            assert_eq!(summary.errors, 1);
+        }
+    }
+
+    mod reencrypt {
+        use super::*;
+        use crate::reencrypt::reencrypt_files;
+        use crate::runner::{CommandOutput, CommandRunner};
+        use std::sync::Mutex;
+
+        /// Records every sops invocation but `--version`, failing rotation of files named `broken.*`
+        #[derive(Default)]
+        struct RotateRunner {
+            calls: Mutex<Vec<Vec<String>>>,
+        }
+
+        impl CommandRunner for RotateRunner {
+            fn run(&self, program: &str, args: &[&str]) -> std::io::Result<CommandOutput> {
+                if args != ["--version"] {
+                    self.calls.lock().unwrap().push(std::iter::once(program).chain(args.iter().copied()).map(String::from).collect());
+                }
+                let code = match args {
+                    [.., "--rotate", "--in-place", path] if path.contains("broken") => 1,
+                    _ => 0,
+                };
+                Ok(CommandOutput { code: Some(code), stdout: Vec::new(), stderr: b"no access".to_vec() })
+            }
+        }
+
+        #[test]
+        fn test_rotates_each_file_in_place() {
+            let runner = RotateRunner::default();
+            reencrypt_files(&runner, &["a.yaml", "b.env"], &SopsOptions::default()).expect("Should re-encrypt");
+            assert_eq!(
+                *runner.calls.lock().unwrap(),
+                vec![
+                    vec!["sops", "--rotate", "--in-place", "a.yaml"],
+                    vec!["sops", "--rotate", "--in-place", "b.env"],
+                ]
+            );
+        }
+
+        #[test]
+        fn test_sops_args_come_first() {
+            let runner = RotateRunner::default();
+            let sops = SopsOptions { extra_args: vec!["--config".to_string(), "sops.yaml".to_string()], ..Default::default() };
+            reencrypt_files(&runner, &["a.yaml"], &sops).expect("Should re-encrypt");
+            assert_eq!(runner.calls.lock().unwrap()[0], vec!["sops", "--config", "sops.yaml", "--rotate", "--in-place", "a.yaml"]);
+        }
+
+        #[test]
+        fn test_failed_file_doesnt_stop_the_others() {
+            let runner = RotateRunner::default();
+            let error = reencrypt_files(&runner, &["broken.yaml", "b.yaml"], &SopsOptions::default()).expect_err("Should fail");
+            assert!(error.to_string().contains("1 file(s)"), "{}", error);
+            assert_eq!(runner.calls.lock().unwrap().len(), 2);
+        }
+    }
+
+    mod file_counts {
+        use super::*;
+        use crate::runner::{CommandOutput, CommandRunner, Runner};
+
+        /// Decrypts by reading the file, failing for files named `broken.*`
+        struct FileRunner;
+
+        impl CommandRunner for FileRunner {
+            fn run(&self, program: &str, args: &[&str]) -> std::io::Result<CommandOutput> {
+                let (code, stdout) = match (program, args) {
+                    ("sops", [.., "--decrypt", path]) if path.contains("broken") => (1, String::new()),
+                    ("sops", [.., "--decrypt", path]) => (0, std::fs::read_to_string(path)?),
+                    ("sh", ["-c", _]) => (0, "value\n".to_string()),
+                    _ => (0, String::new()),
+                };
+                Ok(CommandOutput { code: Some(code), stdout: stdout.into_bytes(), stderr: b"no key".to_vec() })
+            }
+        }
+
+        #[test]
+        fn test_skipped_and_errored_files_are_counted() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let files = [
+                ("synced.yaml", "# shell: echo value\ntoken: value\n"),
+                ("plain.yaml", "token: value\n"),
+                ("also-plain.yaml", "other: value\n"),
+                ("broken.yaml", "# shell: echo value\ntoken: value\n"),
+            ];
+            let paths: Vec<PathBuf> = files
+                .iter()
+                .map(|(name, content)| {
+                    let path = dir.path().join(name);
+                    std::fs::write(&path, content).expect("Should write file");
+                    path
+                })
+                .collect();
+
+            let options = SyncOptions { runner: Runner::new(FileRunner), summary_only: true, ..Default::default() };
+            let summary = check_files(&paths, &options).expect("Should check");
+            assert_eq!(summary.files, 4);
+            assert_eq!(summary.secrets, 1);
+            assert_eq!(summary.files_skipped, 2);
+            assert_eq!(summary.files_errored, 1);
+            assert_eq!(summary.errors, 1);
+        }
+
+        #[test]
+        fn test_files_cut_off_by_the_command_limit_are_skipped() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let paths: Vec<PathBuf> = ["first.yaml", "second.yaml"]
+                .iter()
+                .map(|name| {
+                    let path = dir.path().join(name);
+                    std::fs::write(&path, "# shell: echo value\ntoken: value\n").expect("Should write file");
+                    path
+                })
+                .collect();
+
+            let options =
+                SyncOptions { runner: Runner::new(FileRunner), max_commands: Some(1), summary_only: true, ..Default::default() };
+            let summary = check_files(&paths, &options).expect("Should check");
+            assert_eq!(summary.files_skipped, 1);
+            assert_eq!(summary.files_errored, 0);
+        }
+    }
+
+    mod compare_cmd {
+        use super::*;
+        use crate::runner::{CommandOutput, CommandRunner, Runner, SystemRunner};
+        use std::os::unix::fs::PermissionsExt;
+        use std::sync::{Arc, Mutex};
+
+        // Case-insensitive, e.g. for hex digests written in either case
+        const COMPARATOR: &str = "#!/bin/sh\n[ \"$(printf %s \"$1\" | tr A-Z a-z)\" = \"$(printf %s \"$2\" | tr A-Z a-z)\" ]\n";
+
+        /// Decrypts by reading the file and records the values written, leaving other programs to `SystemRunner`
+        #[derive(Default)]
+        struct ComparingRunner {
+            sets: Arc<Mutex<Vec<String>>>,
+        }
+
+        impl CommandRunner for ComparingRunner {
+            fn run(&self, program: &str, args: &[&str]) -> std::io::Result<CommandOutput> {
+                match (program, args) {
+                    ("sops", [.., "--decrypt", path]) => {
+                        let stdout = std::fs::read(path)?;
+                        Ok(CommandOutput { code: Some(0), stdout, stderr: Vec::new() })
+                    }
+                    ("sops", [.., "--set", set, _]) => {
+                        self.sets.lock().unwrap().push(set.to_string());
+                        Ok(CommandOutput { code: Some(0), stdout: Vec::new(), stderr: Vec::new() })
+                    }
+                    ("sops", _) => Ok(CommandOutput { code: Some(0), stdout: Vec::new(), stderr: Vec::new() }),
+                    _ => SystemRunner.run(program, args),
+                }
+            }
+        }
+
+        /// Syncs `content` from a file next to the comparator `eq.sh`
+        fn sync(dir: &Path, content: &str) -> (RunSummary, Vec<String>) {
+            let file = dir.join("secrets.yaml");
+            std::fs::write(&file, content).expect("Should write file");
+            let comparator = dir.join("eq.sh");
+            std::fs::write(&comparator, COMPARATOR).expect("Should write comparator");
+            std::fs::set_permissions(&comparator, std::fs::Permissions::from_mode(0o755)).expect("Should chmod comparator");
+
+            let runner = ComparingRunner::default();
+            let sets = Arc::clone(&runner.sets);
+            let options = SyncOptions { runner: Runner::new(runner), summary_only: true, ..Default::default() };
+            let summary = sync_files(&[&file], &options).expect("Should sync");
+            let sets = sets.lock().unwrap().clone();
+            (summary, sets)
+        }
+
+        #[test]
+        fn test_parse_compare_cmd() {
+            let content = "# shell(compare-cmd=./eq.sh): echo ABC\ndigest: abc\n";
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            assert_eq!(mappings[0].compare_cmd.as_deref(), Some("./eq.sh"));
+            assert_eq!(mappings[0].commands(), vec!["echo ABC", "./eq.sh"]);
+        }
+
+        #[test]
+        fn test_invalid_compare_cmd_is_rejected() {
+            for options in ["compare-cmd=", "compare-cmd=./eq.sh --strict", "compare=hash, compare-cmd=./eq.sh"] {
+                let content = format!("# shell({}): echo ABC\ndigest: abc\n", options);
+                assert!(parse_commands(&content, &ParseOptions::default()).is_err(), "{} should be rejected", options);
+            }
+        }
+
+        #[test]
+        fn test_matching_values_are_in_sync() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let (summary, sets) = sync(dir.path(), "# shell(compare-cmd=./eq.sh): echo ABC\ndigest: abc\n");
+            assert!(sets.is_empty(), "The comparator considers abc and ABC equal");
+            assert_eq!(summary.updates, 0);
+            assert_eq!(summary.errors, 0);
+        }
+
+        #[test]
+        fn test_differing_values_are_updated() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let (summary, sets) = sync(dir.path(), "# shell(compare-cmd=./eq.sh): echo ABD\ndigest: abc\n");
+            assert_eq!(sets, vec![r#"["digest"] "ABD""#]);
+            assert_eq!(summary.updates, 1);
+        }
+
+        #[test]
+        fn test_values_are_not_interpreted_by_a_shell() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let marker = dir.path().join("marker");
+            let stored = format!("x$(touch {})", marker.display());
+            let content = format!("# shell(compare-cmd=./eq.sh): printf '%s' '{}'\ndigest: {}\n", stored, stored);
+            let (summary, sets) = sync(dir.path(), &content);
+            assert!(sets.is_empty());
+            assert_eq!(summary.errors, 0);
+            assert!(!marker.exists(), "The values should reach the comparator verbatim");
+        }
+
+        #[test]
+        fn test_missing_comparator_fails_the_key() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let (summary, sets) = sync(dir.path(), "# shell(compare-cmd=./missing.sh): echo ABC\ndigest: abc\n");
+            assert!(sets.is_empty());
+            assert_eq!(summary.errors, 1);
+        }
+    }
+
+    mod sops_config {
+        use crate::sops_config::{creation_rule_patterns, discover_files};
+
+        const CONFIG: &str = r#"# Encrypted with age, prod with its own key too
+creation_rules:
+  - path_regex: secrets/.*\.yaml$
+    age: age1dev
+  - path_regex: "secrets/prod/.*\\.(yaml|env)$" # prod
+    age: age1prod
+  - path_regex: 'config/app''s\.env$'
+    age: age1app
+  - age: age1fallback
+"#;
+
+        #[test]
+        fn test_patterns_of_every_rule() {
+            let patterns = creation_rule_patterns(CONFIG).expect("Should parse");
+            let patterns: Vec<&str> = patterns.iter().map(|pattern| pattern.as_str()).collect();
+            assert_eq!(patterns, vec![r"secrets/.*\.yaml$", r"secrets/prod/.*\.(yaml|env)$", r"config/app's\.env$"]);
+        }
+
+        #[test]
+        fn test_invalid_pattern_is_rejected() {
+            assert!(creation_rule_patterns("creation_rules:\n  - path_regex: secrets/(.*\n").is_err());
+            assert!(creation_rule_patterns("creation_rules:\n  - path_regex: ''\n").is_err());
+        }
+
+        #[test]
+        fn test_matching_files_are_found_once() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let config = dir.path().join(".sops.yaml");
+            std::fs::write(&config, CONFIG).expect("Should write config");
+            for file in ["secrets/a.yaml", "secrets/prod/b.yaml", "secrets/prod/c.env", "config/app's.env", "other/d.yaml", "secrets/e.txt"] {
+                let path = dir.path().join(file);
+                std::fs::create_dir_all(path.parent().unwrap()).expect("Should create dir");
+                std::fs::write(&path, "").expect("Should write file");
+            }
+
+            let files = discover_files(&config).expect("Should discover");
+            let relative: Vec<String> = files
+                .iter()
+                .map(|file| file.strip_prefix(dir.path()).unwrap().to_string_lossy().into_owned())
+                .collect();
+            assert_eq!(relative, vec!["config/app's.env", "secrets/a.yaml", "secrets/prod/b.yaml", "secrets/prod/c.env"]);
+        }
+
+        #[test]
+        fn test_config_without_path_regex_is_rejected() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let config = dir.path().join(".sops.yaml");
+            std::fs::write(&config, "creation_rules:\n  - age: age1fallback\n").expect("Should write config");
+            assert!(discover_files(&config).is_err());
+        }
+    }
+
+    mod value_checks {
+        use super::*;
+        use crate::runner::Runner;
+        use crate::testing::FakeRunner;
+        use crate::value_check::{Charset, ValueCheck};
+
+        const HEX: &str = "0123456789abcdef0123456789abcdef";
+
+        fn sync(content: &str) -> (RunSummary, Vec<String>) {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = dir.path().join("secrets.yaml");
+            std::fs::write(&file, content).expect("Should write file");
+
+            let runner = FakeRunner::new()
+                .output("gen-hex", HEX)
+                .output("gen-short", "abc123")
+                .output("gen-not-hex", &HEX.replace('f', "g"))
+                .output("gen-unchanged", "NO_CHANGE");
+            let options = SyncOptions { runner: Runner::new(runner.clone()), summary_only: true, ..Default::default() };
+            let summary = sync_files(&[&file], &options).expect("Should sync");
+            (summary, runner.sets())
+        }
+
+        #[test]
+        fn test_parse_space_and_comma_separated_checks() {
+            for options in ["min-len=32 charset=hex", "min-len=32, charset=hex"] {
+                let content = format!("# shell({}): gen-hex\ntoken: old\n", options);
+                let mappings = parse_commands(&content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+                assert_eq!(mappings[0].checks, ValueCheck { min_len: Some(32), max_len: None, charset: Some(Charset::Hex) });
+                assert_eq!(mappings[0].command, "gen-hex");
+            }
+        }
+
+        #[test]
+        fn test_space_separated_options_keep_spaces_in_values() {
+            let content = "# shell(when=$DEPLOY_ENV == prod timeout=5): gen-hex\ntoken: old\n";
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            assert_eq!(mappings[0].when.as_ref().map(ToString::to_string).as_deref(), Some("$DEPLOY_ENV == prod"));
+            assert_eq!(mappings[0].settings.timeout, Some(Duration::from_secs(5)));
+        }
+
+        #[test]
+        fn test_invalid_checks_are_rejected() {
+            for options in ["min-len=many", "charset=emoji", "min-len=32 max-len=16"] {
+                let content = format!("# shell({}): gen-hex\ntoken: old\n", options);
+                assert!(parse_commands(&content, &ParseOptions::default()).is_err(), "{} should be rejected", options);
+            }
+        }
+
+        #[test]
+        fn test_valid_value_is_written() {
+            let (summary, sets) = sync("# shell(min-len=32 charset=hex): gen-hex\ntoken: old\n");
+            assert_eq!(sets, vec![format!(r#"["token"] "{}""#, HEX)]);
+            assert_eq!(summary.errors, 0);
+        }
+
+        #[test]
+        fn test_too_short_value_fails_the_key() {
+            let (summary, sets) = sync("# shell(min-len=32): gen-short\ntoken: old\n");
+            assert!(sets.is_empty());
+            assert_eq!(summary.errors, 1);
+        }
+
+        #[test]
+        fn test_value_outside_charset_fails_the_key() {
+            let (summary, sets) = sync("# shell(charset=hex): gen-not-hex\ntoken: old\n");
+            assert!(sets.is_empty());
+            assert_eq!(summary.errors, 1);
+        }
+
+        #[test]
+        fn test_skip_if_sentinel_is_not_checked() {
+            let (summary, sets) = sync("# shell(skip-if=^NO_CHANGE$, min-len=32 charset=hex): gen-unchanged\ntoken: old\n");
+            assert!(sets.is_empty());
+            assert_eq!(summary.errors, 0);
+        }
+
+        #[test]
+        fn test_errors_name_the_constraint_but_not_the_value() {
+            let checks = ValueCheck { min_len: Some(8), max_len: Some(10), charset: Some(Charset::Alphanumeric) };
+            let short = checks.check("abc").expect_err("Should be too short").to_string();
+            assert!(short.contains("min-len=8") && !short.contains("abc"), "{}", short);
+            assert!(checks.check("abcdefghijk").expect_err("Should be too long").to_string().contains("max-len=10"));
+            let charset = checks.check("abc-defgh").expect_err("Should be outside the charset").to_string();
+            assert!(charset.contains("charset=alphanumeric") && charset.contains("position 4"), "{}", charset);
+            assert!(checks.check("abcdefgh").is_ok());
+        }
+    }
+
+    mod since_commit {
+        use super::*;
+        use crate::git::{changed_since, filter_changed};
+        use crate::runner::{CommandOutput, CommandRunner};
+
+        /// Answers `git rev-parse` with `toplevel`, when in a repository, and `git diff` with `diff`
+        struct GitRunner {
+            toplevel: Option<PathBuf>,
+            diff: Result<&'static str, &'static str>,
+        }
+
+        impl CommandRunner for GitRunner {
+            fn run(&self, program: &str, args: &[&str]) -> std::io::Result<CommandOutput> {
+                let (code, stdout, stderr) = match (program, args, &self.toplevel) {
+                    ("git", ["rev-parse", "--show-toplevel"], Some(toplevel)) => (0, format!("{}\n", toplevel.display()), ""),
+                    ("git", ["diff", "--name-only", "-z", _, "--"], Some(_)) => match self.diff {
+                        Ok(names) => (0, names.to_string(), ""),
+                        Err(stderr) => (128, String::new(), stderr),
+                    },
+                    _ => (128, String::new(), "fatal: not a git repository"),
+                };
+                Ok(CommandOutput { code: Some(code), stdout: stdout.into_bytes(), stderr: stderr.as_bytes().to_vec() })
+            }
+        }
+
+        fn repository(files: &[&str]) -> tempfile::TempDir {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            for file in files {
+                let path = dir.path().join(file);
+                std::fs::create_dir_all(path.parent().unwrap()).expect("Should create dir");
+                std::fs::write(&path, "").expect("Should write file");
+            }
+            dir
+        }
+
+        #[test]
+        fn test_only_changed_files_are_kept() {
+            let dir = repository(&["secrets/a.yaml", "secrets/b.yaml", "c.env"]);
+            let runner = GitRunner { toplevel: Some(dir.path().to_path_buf()), diff: Ok("secrets/a.yaml\0c.env\0README.md\0") };
+            let changed = changed_since(&runner, "origin/main").expect("Should diff").expect("Should be a repository");
+
+            let files = ["c.env", "secrets/b.yaml", "secrets/a.yaml"].iter().map(|file| dir.path().join(file)).collect();
+            let kept = filter_changed(files, &changed);
+            assert_eq!(kept, vec![dir.path().join("c.env"), dir.path().join("secrets/a.yaml")]);
+        }
+
+        #[test]
+        fn test_other_spellings_of_a_changed_file_match() {
+            let dir = repository(&["secrets/a.yaml"]);
+            let runner = GitRunner { toplevel: Some(dir.path().to_path_buf()), diff: Ok("secrets/a.yaml\0") };
+            let changed = changed_since(&runner, "HEAD~1").expect("Should diff").expect("Should be a repository");
+
+            let file = dir.path().join("secrets/../secrets/./a.yaml");
+            assert_eq!(filter_changed(vec![file.clone()], &changed), vec![file]);
+        }
+
+        #[test]
+        fn test_outside_a_repository_nothing_is_filtered() {
+            let runner = GitRunner { toplevel: None, diff: Ok("") };
+            assert!(changed_since(&runner, "origin/main").expect("Should not fail").is_none());
+        }
+
+        #[test]
+        fn test_unknown_reference_fails() {
+            let dir = repository(&[]);
+            let runner = GitRunner { toplevel: Some(dir.path().to_path_buf()), diff: Err("fatal: bad revision 'nope'") };
+            let error = changed_since(&runner, "nope").expect_err("Should fail");
+            assert!(error.to_string().contains("bad revision"), "{}", error);
+        }
+
+        #[test]
+        fn test_option_like_reference_is_rejected() {
+            let runner = GitRunner { toplevel: None, diff: Ok("") };
+            assert!(changed_since(&runner, "--output=/tmp/x").is_err());
+        }
+    }
+
+    mod audit_log {
+        use super::*;
+        use crate::runner::Runner;
+        use crate::testing::FakeRunner;
+
+        fn options(log: &Path, redact_commands: bool) -> SyncOptions {
+            SyncOptions {
+                runner: Runner::new(FakeRunner::new()),
+                summary_only: true,
+                audit_log: Some(AuditLog { path: log.to_path_buf(), redact_commands }),
+                ..Default::default()
+            }
+        }
+
+        #[test]
+        fn test_each_write_appends_a_line_without_secrets() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = dir.path().join("secrets.yaml");
+            let log = dir.path().join("audit.log");
+            std::fs::write(&file, "# shell: echo first-secret-value\ntoken: stale-secret-value\n").expect("Should write file");
+            sync_files(&[&file], &options(&log, false)).expect("Should sync");
+            std::fs::write(&file, "# shell: echo second-secret-value\ntoken: first-secret-value\n").expect("Should write file");
+            sync_files(&[&file], &options(&log, false)).expect("Should sync");
+
+            let content = std::fs::read_to_string(&log).expect("Should read log");
+            let lines: Vec<&str> = content.lines().collect();
+            assert_eq!(lines.len(), 2);
+            assert!(lines[0].contains(&format!("file={:?}", file.display().to_string())));
+            assert!(lines[0].contains("update file="));
+            assert!(lines[0].contains(r#"key="token" command="echo ********" old=********(18) new=********(18)"#));
+            assert!(lines[1].contains("new=********(19)"));
+            for secret in ["first-secret", "second-secret", "stale-secret"] {
+                assert!(!content.contains(secret), "Log leaks {}: {}", secret, content);
+            }
+        }
+
+        #[test]
+        fn test_redacted_commands_are_left_out() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = dir.path().join("secrets.yaml");
+            let log = dir.path().join("audit.log");
+            std::fs::write(&file, "# shell: echo internal-host\nhost: old-host\n").expect("Should write file");
+            sync_files(&[&file], &options(&log, true)).expect("Should sync");
+
+            let content = std::fs::read_to_string(&log).expect("Should read log");
+            assert!(content.contains("command=[redacted]"));
+            assert!(!content.contains("echo"));
+        }
+
+        #[test]
+        fn test_nothing_is_logged_without_writes() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = dir.path().join("secrets.yaml");
+            let log = dir.path().join("audit.log");
+            std::fs::write(&file, "# shell: echo value\ntoken: value\n").expect("Should write file");
+            sync_files(&[&file], &options(&log, false)).expect("Should sync");
+            std::fs::write(&file, "# shell: echo new-value\ntoken: value\n").expect("Should write file");
+            check_files(&[&file], &options(&log, false)).expect("Should check");
+
+            assert_eq!(std::fs::read_to_string(&log).unwrap_or_default(), "");
+        }
+
+        #[test]
+        fn test_unwritable_log_fails_the_run() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = dir.path().join("secrets.yaml");
+            std::fs::write(&file, "# shell: echo value\ntoken: old\n").expect("Should write file");
+            let log = dir.path().join("missing").join("audit.log");
+
+            let err = sync_files(&[&file], &options(&log, false)).expect_err("Should fail");
+            assert!(format!("{:#}", err).contains("Failed to open audit log"));
+        }
+    }
+
+    mod inline_comments {
+        use super::*;
+        use crate::runner::Runner;
+        use crate::testing::FakeRunner;
+
+        #[test]
+        fn test_env_comment_is_left_out_of_the_value() {
+            let content = "FOO=bar # note\nQUOTED=\"a # b\" # note\nHASH=pass#word\nEMPTY= # nothing yet\n";
+            let format = Some(FileFormat::Env);
+            assert_eq!(parse_decrypted_value(content, "FOO", format).as_deref(), Some("bar"));
+            assert_eq!(parse_decrypted_value(content, "QUOTED", format).as_deref(), Some("a # b"));
+            assert_eq!(parse_decrypted_value(content, "HASH", format).as_deref(), Some("pass#word"));
+            assert_eq!(parse_decrypted_value(content, "EMPTY", format).as_deref(), Some(""));
+        }
+
+        #[test]
+        fn test_ini_accepts_both_comment_markers() {
+            let content = "[db]\nhost = db.internal ; primary\nport = 5432 # default\nnote = a;b\n";
+            let format = Some(FileFormat::Ini);
+            assert_eq!(parse_decrypted_value(content, "host", format).as_deref(), Some("db.internal"));
+            assert_eq!(parse_decrypted_value(content, "port", format).as_deref(), Some("5432"));
+            assert_eq!(parse_decrypted_value(content, "note", format).as_deref(), Some("a;b"));
+        }
+
+        #[test]
+        fn test_semicolon_is_part_of_env_values() {
+            let content = "FOO=bar ; baz\n";
+            assert_eq!(parse_decrypted_value(content, "FOO", Some(FileFormat::Env)).as_deref(), Some("bar ; baz"));
+        }
+
+        #[test]
+        fn test_json_values_are_kept_whole() {
+            let content = "{\n  \"token\": \"a #b\"\n}\n";
+            assert_eq!(FileFormat::strip_inline_comment(" \"a #b\"", Some(FileFormat::Json)), " \"a #b\"");
+            assert!(parse_decrypted_value(content, "token", Some(FileFormat::Json)).is_some());
+        }
+
+        #[test]
+        fn test_commented_value_is_in_sync() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let env = dir.path().join("app.env");
+            let ini = dir.path().join("app.ini");
+            std::fs::write(&env, "# shell: echo bar\nFOO=bar # rotated monthly\n").expect("Should write file");
+            std::fs::write(&ini, "[app]\n# shell: echo bar\nfoo = bar ; rotated monthly\n").expect("Should write file");
+
+            let runner = FakeRunner::new();
+            let options = SyncOptions { runner: Runner::new(runner.clone()), summary_only: true, ..Default::default() };
+            let summary = sync_files(&[&env, &ini], &options).expect("Should sync");
+            assert_eq!(summary.secrets, 2);
+            assert_eq!(summary.updates, 0);
+            assert!(runner.sets().is_empty());
+        }
+    }
+
+    mod list {
+        use super::*;
+        use crate::list::list_file;
+        use crate::testing::FakeRunner;
+
+        #[test]
+        fn test_files_without_comments_are_not_decrypted() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = dir.path().join("plain.yaml");
+            std::fs::write(&file, "token: value\n").expect("Should write file");
+
+            let runner = FakeRunner::new();
+            let directives = list_file(&runner, &file, &ParseOptions::default(), &SopsOptions::default()).expect("Should list");
+            assert!(directives.is_empty());
+            assert!(runner.calls().is_empty());
+        }
+
+        #[test]
+        fn test_only_sops_runs() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = dir.path().join("secrets.yaml");
+            std::fs::write(&file, "# shell: echo new\ntoken: old\n").expect("Should write file");
+
+            let runner = FakeRunner::new();
+            let directives = list_file(&runner, &file, &ParseOptions::default(), &SopsOptions::default()).expect("Should list");
+            assert_eq!(directives.len(), 1);
+            let calls = runner.calls();
+            assert!(calls.iter().all(|call| call[0] == "sops"), "Ran {:?}", calls);
+        }
+    }
+
+    mod path_expansion {
+        use crate::paths::expand_path_with;
+        use std::path::{Path, PathBuf};
+
+        fn expand(path: &str) -> anyhow::Result<PathBuf> {
+            expand_path_with(Path::new(path), |name| match name {
+                "HOME" => Some("/home/ci".to_string()),
+                "SECRETS_DIR" => Some("/srv/secrets".to_string()),
+                "ENV" => Some("prod".to_string()),
+                _ => None,
+            })
+        }
+
+        #[test]
+        fn test_variables_are_expanded() {
+            assert_eq!(expand("$SECRETS_DIR/app.yaml").unwrap(), Path::new("/srv/secrets/app.yaml"));
+            assert_eq!(expand("${SECRETS_DIR}/app-${ENV}.yaml").unwrap(), Path::new("/srv/secrets/app-prod.yaml"));
+            assert_eq!(expand("$HOME/secrets/$ENV.env").unwrap(), Path::new("/home/ci/secrets/prod.env"));
+        }
+
+        #[test]
+        fn test_tilde_is_home() {
+            assert_eq!(expand("~/secrets/app.yaml").unwrap(), Path::new("/home/ci/secrets/app.yaml"));
+            assert_eq!(expand("~").unwrap(), Path::new("/home/ci"));
+            assert_eq!(expand("~other/app.yaml").unwrap(), Path::new("~other/app.yaml"));
+            assert_eq!(expand("secrets/~/app.yaml").unwrap(), Path::new("secrets/~/app.yaml"));
+        }
+
+        #[test]
+        fn test_other_dollar_signs_are_kept() {
+            assert_eq!(expand("price$5.yaml").unwrap(), Path::new("price$5.yaml"));
+            assert_eq!(expand("trailing$").unwrap(), Path::new("trailing$"));
+            assert_eq!(expand("plain/secrets.yaml").unwrap(), Path::new("plain/secrets.yaml"));
+        }
+
+        #[test]
+        fn test_unset_variable_is_an_error() {
+            let error = expand("$MISSING/app.yaml").expect_err("Should fail");
+            assert!(format!("{:#}", error).contains("MISSING"));
+            assert!(expand("${SECRETS_DIR/app.yaml").is_err());
+        }
+    }
+
+    mod retry_on_empty {
+        use super::*;
+        use crate::runner::{CommandOutput, CommandRunner, Runner};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        /// Prints nothing for the first `empty` calls, then a value
+        struct WarmingUpRunner {
+            empty: usize,
+            calls: Arc<AtomicUsize>,
+        }
+
+        impl CommandRunner for WarmingUpRunner {
+            fn run(&self, _program: &str, _args: &[&str]) -> std::io::Result<CommandOutput> {
+                let call = self.calls.fetch_add(1, Ordering::SeqCst);
+                let stdout = if call < self.empty { "\n" } else { "s3cret\n" };
+                Ok(CommandOutput { code: Some(0), stdout: stdout.as_bytes().to_vec(), stderr: Vec::new() })
+            }
+        }
+
+        fn drift(content: &str, empty: usize, retry_on_empty: bool) -> (KeyStatus, usize) {
+            let calls = Arc::new(AtomicUsize::new(0));
+            let options = SyncOptions {
+                runner: Runner::new(WarmingUpRunner { empty, calls: calls.clone() }),
+                retry_on_empty,
+                ..Default::default()
+            };
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            let mut statuses =
+                drift_for_mappings(Path::new("secrets.yaml"), content, &mappings, &options, &mut RunState::default(), |_| {});
+            (statuses.remove(0), calls.load(Ordering::SeqCst))
+        }
+
+        #[test]
+        fn test_empty_output_is_retried() {
+            let (status, calls) = drift("# shell(retries=2): fetch\ntoken: s3cret", 1, true);
+            assert_eq!(status.outcome.as_str(), "in_sync");
+            assert_eq!(calls, 2);
+        }
+
+        #[test]
+        fn test_still_empty_fails_the_key() {
+            let (status, calls) = drift("# shell(retries=2): fetch\ntoken: s3cret", 5, true);
+            match &status.outcome {
+                KeyOutcome::Failed(e) => assert!(format!("{:#}", e).contains("still empty after 2 retries")),
+                other => panic!("Expected a failure, got {}", other.as_str()),
+            }
+            assert_eq!(calls, 3);
+        }
+
+        #[test]
+        fn test_empty_output_is_a_value_without_the_flag() {
+            let (status, calls) = drift("# shell(retries=2): fetch\ntoken: s3cret", 1, false);
+            assert_eq!(status.outcome.as_str(), "out_of_sync");
+            assert_eq!(calls, 1);
+        }
+
+        #[test]
+        fn test_no_effect_without_retries() {
+            let (status, calls) = drift("# shell: fetch\ntoken: s3cret", 1, true);
+            assert_eq!(status.outcome.as_str(), "out_of_sync");
+            assert_eq!(calls, 1);
+        }
+    }
+
+    mod apply_updates {
+        use super::*;
+        use crate::runner::Runner;
+        use crate::testing::FakeRunner;
+        use crate::{apply_updates, compute_drift, pending_updates};
+
+        fn options() -> SyncOptions {
+            SyncOptions { runner: Runner::new(FakeRunner::new().applying_sets()), ..Default::default() }
+        }
+
+        #[test]
+        fn test_compute_then_apply() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = dir.path().join("secrets.yaml");
+            std::fs::write(&file, "# shell: echo new-token\ntoken: old\n# shell: echo same\nother: same\n").expect("Should write file");
+            let options = options();
+
+            let updates = pending_updates(compute_drift(&file, &options).expect("Should compute"));
+            assert_eq!(updates, vec![("token".to_string(), "new-token".to_string())]);
+            // Nothing is written until the updates are applied
+            assert!(std::fs::read_to_string(&file).unwrap().contains("token: old"));
+
+            let written = apply_updates(&file, &updates, &options).expect("Should apply");
+            assert_eq!(written, file);
+            assert!(std::fs::read_to_string(&file).unwrap().contains("token: new-token"));
+            assert!(pending_updates(compute_drift(&file, &options).expect("Should compute")).is_empty());
+        }
+
+        #[test]
+        fn test_reviewed_updates_can_be_dropped() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = dir.path().join("secrets.yaml");
+            std::fs::write(&file, "# shell: echo a2\na: a1\n# shell: echo b2\nb: b1\n").expect("Should write file");
+            let options = options();
+
+            let updates = pending_updates(compute_drift(&file, &options).expect("Should compute"));
+            let reviewed: Vec<(String, String)> = updates.into_iter().filter(|(key, _)| key != "b").collect();
+            apply_updates(&file, &reviewed, &options).expect("Should apply");
+
+            let content = std::fs::read_to_string(&file).unwrap();
+            assert!(content.contains("a: a2"));
+            assert!(content.contains("b: b1"));
+        }
 
-pub fn parse_decrypted_value(decrypted_content: &str, key: &str) -> Option<String> {
-    decrypted_content.lines()
-        .filter(|line| {
-            let trimmed = line.trim();
-            !trimmed.is_empty() && !trimmed.starts_with('#') && !trimmed.starts_with(';')
-        })
-        .find_map(|line| {
-            let trimmed = line.trim();
-            if let Some(rest) = trimmed.strip_prefix(key) {
-                if let Some(value_part) = rest.trim().strip_prefix('=') {
-                    return Some(value_part.trim().trim_matches('"').to_string());
-                }
-                if let Some(value_part) = rest.trim().strip_prefix(':') {
-                    return Some(value_part.trim().trim_matches('"').to_string());
-                }
-            }
-            None
-        })
-}
+        #[test]
+        fn test_hash_companion_is_an_update() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = dir.path().join("secrets.yaml");
+            std::fs::write(&file, "# shell(compare=hash): echo value\ntoken: old\n").expect("Should write file");
+            let options = options();
 
-fn has_comment_lines(filepath: &Path) -> Result<bool> {
-    use std::fs::File;
-    use std::io::{BufRead, BufReader};
+            let updates = pending_updates(compute_drift(&file, &options).expect("Should compute"));
+            let keys: Vec<&str> = updates.iter().map(|(key, _)| key.as_str()).collect();
+            assert_eq!(keys, vec!["token", "token_sha256"]);
+        }
 
-    let file = File::open(filepath)?;
-    let reader = BufReader::new(file);
+        #[test]
+        fn test_output_suffix_writes_a_copy() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = dir.path().join("secrets.yaml");
+            std::fs::write(&file, "token: old\n").expect("Should write file");
+            let options = SyncOptions { output_suffix: Some(".new".to_string()), ..options() };
 
-    for line in reader.lines().take(100) {
-        let line = line?;
-        if line.trim_start().starts_with('#') || line.trim_start().starts_with(';') {
-            return Ok(true);
+            let written = apply_updates(&file, &[("token".to_string(), "new".to_string())], &options).expect("Should apply");
+            assert_eq!(written, dir.path().join("secrets.yaml.new"));
+            assert_eq!(std::fs::read_to_string(&file).unwrap(), "token: old\n");
+            assert_eq!(std::fs::read_to_string(&written).unwrap(), "token: new\n");
         }
     }
-    Ok(false)
-}
 
-pub fn process_file(filepath: &Path, dry_run: bool) -> Result<(usize, usize)> {
-    println!("\nProcessing {}...", filepath.display());
+    mod render {
+        use super::*;
+        use crate::render::render_updated;
 
-    if !has_comment_lines(filepath)? {
-        println!("  No comment lines found, skipping decryption");
-        return Ok((0, 0));
-    }
+        fn options(format: FileFormat) -> SyncOptions {
+            let mut options = SyncOptions::default();
+            options.parse.format = Some(format);
+            options
+        }
 
-    let decrypted = match sops_decrypt(filepath) {
-        Ok(content) => content,
-        Err(e) => {
-            print_file_error("decrypt", &e);
-            return Ok((0, 0));
+        fn updates(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+            pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
         }
-    };
 
-    let mappings = match parse_commands(&decrypted) {
-        Ok(m) => m,
-        Err(e) => {
-            print_file_error("parse commands", &e);
-            return Ok((0, 0));
+        #[test]
+        fn test_yaml_values_are_masked_and_updates_marked() {
+            let content = "# shell: echo hunter2\napi_key: hunter2\nname: \"app\" # kept\ncert: |\n  line one\n  line two\nnested:\n  inner: x\n";
+            let rendered = render_updated(
+                content,
+                &updates(&[("api_key", "new-secret"), ("token", "t0ken")]),
+                &options(FileFormat::Yaml),
+            );
+            assert_eq!(
+                rendered,
+                "# shell: echo ********\napi_key: ******** (updated)\nname: \"********\" # kept\ncert: |\n  ********\n  ********\nnested:\n  inner: ********\ntoken: ******** (added)\n"
+            );
+            assert!(!rendered.contains("hunter2") && !rendered.contains("new-secret"));
         }
-    };
 
-    if mappings.is_empty() {
-        println!("  No secret(s) with 'shell:' commands found");
-        return Ok((0, 0));
-    }
+        #[test]
+        fn test_updated_block_scalar_is_replaced_by_a_single_line() {
+            let content = "cert: |-\n  line one\n  line two\nother: y\n";
+            let rendered = render_updated(content, &updates(&[("cert", "pem")]), &options(FileFormat::Yaml));
+            assert_eq!(rendered, "cert: ******** (updated)\nother: ********\n");
+        }
 
-    println!("  Found {} secret(s) with commands\n", mappings.len());
+        #[test]
+        fn test_env_values_are_masked() {
+            let content = "export API_KEY=old\nNAME=app\n";
+            let rendered = render_updated(content, &updates(&[("API_KEY", "new"), ("EXTRA", "x")]), &options(FileFormat::Env));
+            assert_eq!(rendered, "export API_KEY=******** (updated)\nNAME=********\nEXTRA=******** (added)\n");
+        }
 
-    let mut updates = Vec::new();
+        #[test]
+        fn test_json_added_keys_go_inside_the_object() {
+            let content = "{\n  \"api_key\": \"old\",\n  \"name\": \"app\"\n}\n";
+            let rendered = render_updated(content, &updates(&[("api_key", "new"), ("token", "t")]), &options(FileFormat::Json));
+            assert_eq!(
+                rendered,
+                "{\n  \"api_key\": \"******** (updated)\",\n  \"name\": \"********\",\n  \"token\": \"******** (added)\"\n}\n"
+            );
+        }
+    }
 
-    for mapping in &mappings {
-        println!("  {}", mapping.key);
-        println!("    Command: {}", mapping.command);
+    mod duplicate_keys {
+        use super::*;
+        use crate::format::FileFormat;
 
-        match execute_command(&mapping.command) {
-            Ok(value) => {
-                let current_value = parse_decrypted_value(&decrypted, &mapping.key);
+        const CONTENT: &str = "# shell: echo second\nAPI_KEY=first\nAPI_KEY=second\n";
 
-                if Some(&value) != current_value.as_ref() {
-                    updates.push((mapping.key.clone(), value.clone()));
-                    println!("    Status: OUT OF SYNC");
-                } else {
-                    println!("    Status: IN SYNC");
-                }
-            }
-            Err(e) => {
-                print_command_error(&e);
-            }
+        fn env_options(strict: bool) -> SyncOptions {
+            let mut options = SyncOptions { strict, ..Default::default() };
+            options.parse.format = Some(FileFormat::Env);
+            options
         }
-    }
 
-    if !updates.is_empty() {
-        if dry_run {
-            println!("\n  Would update {} secrets (dry run)", updates.len());
-        } else {
-            println!("\n  Updating {} secrets...", updates.len());
+        fn statuses(options: &SyncOptions) -> Vec<KeyStatus> {
+            let mappings = parse_commands(CONTENT, &options.parse).expect("Should parse successfully").mappings;
+            drift_for_mappings(Path::new(".env"), CONTENT, &mappings, options, &mut RunState::default(), |_| {})
+        }
 
-            for (key, value) in &updates {
-                match sops_set(filepath, key, value) {
-                    Ok(()) => {
-                        println!("    Updated {}", key);
-                    }
-                    Err(e) => {
-                        println!("    Error updating {}: {}", key, e);
-                    }
-                }
-            }
+        #[test]
+        fn test_last_definition_wins_in_env_files() {
+            assert_eq!(parse_decrypted_value(CONTENT, "API_KEY", Some(FileFormat::Env)).as_deref(), Some("second"));
+            assert_eq!(key_definitions(CONTENT, "API_KEY", Some(FileFormat::Env), false, &[]), 2);
+        }
+
+        #[test]
+        fn test_first_definition_elsewhere() {
+            let content = "db:\n  password: nested\npassword: top\n";
+            assert_eq!(parse_decrypted_value(content, "password", Some(FileFormat::Yaml)).as_deref(), Some("nested"));
+            assert_eq!(key_definitions(content, "password", Some(FileFormat::Yaml), false, &[]), 1);
+        }
 
-            println!("\n  Updated {}", filepath.display());
+        #[test]
+        fn test_case_insensitive_duplicates_counted() {
+            let content = "api_key=a\nAPI_KEY=b\n";
+            assert_eq!(key_definitions(content, "API_KEY", Some(FileFormat::Env), true, &[]), 2);
+            assert_eq!(key_definitions(content, "API_KEY", Some(FileFormat::Env), false, &[]), 1);
         }
-    } else {
-        println!("\n  All secrets in sync");
-    }
 
-    Ok((mappings.len(), updates.len()))
-}
+        #[test]
+        fn test_duplicate_warns_and_compares_last_value() {
+            let statuses = statuses(&env_options(false));
+            assert_eq!(statuses[0].outcome.as_str(), "in_sync");
+            assert_eq!(statuses[0].warnings.len(), 1);
+            assert!(statuses[0].warnings[0].contains("defined 2 times"));
+        }
 
-pub fn process_files(files: &[impl AsRef<Path>], dry_run: bool) -> Result<()> {
-    let mut total_secrets = 0;
-    let mut total_updates = 0;
+        #[test]
+        fn test_duplicate_refused_when_strict() {
+            let statuses = statuses(&env_options(true));
+            assert!(matches!(statuses[0].outcome, KeyOutcome::Refused(_)));
+            assert!(statuses[0].warnings.is_empty());
+        }
 
-    for file in files {
-        let (secrets, updates) = process_file(file.as_ref(), dry_run)?;
-        total_secrets += secrets;
-        total_updates += updates;
+        #[test]
+        fn test_single_definition_has_no_warning() {
+            let content = "# shell: echo value\nAPI_KEY=value\n";
+            let options = env_options(true);
+            let mappings = parse_commands(content, &options.parse).expect("Should parse successfully").mappings;
+            let statuses =
+                drift_for_mappings(Path::new(".env"), content, &mappings, &options, &mut RunState::default(), |_| {});
+            assert_eq!(statuses[0].outcome.as_str(), "in_sync");
+            assert!(statuses[0].warnings.is_empty());
+        }
     }
 
-    print_summary(files.len(), total_secrets, total_updates, dry_run);
+    mod profile {
+        use crate::profile::Profile;
+        use std::path::PathBuf;
 
-    Ok(())
-}
+        #[test]
+        fn test_profile_fields_parsed() {
+            let content = r#"{"profiles": {"prod": {"env": {"A": "1"}, "sops_config": "prod/.sops.yaml", "sops_args": ["--verbose"]}}}"#;
+            let profile = Profile::parse(content, "prod").expect("Should parse the profile");
+            assert_eq!(profile.env, vec![("A".to_string(), "1".to_string())]);
+            assert_eq!(profile.sops_config, Some(PathBuf::from("prod/.sops.yaml")));
+            assert_eq!(profile.env_profile, None);
+            assert_eq!(profile.sops_args(), vec!["--config", "prod/.sops.yaml", "--verbose"]);
+            assert_eq!(profile.settings(), vec!["env_vars", "sops_args"]);
+        }
 
-pub fn sync_files(files: &[impl AsRef<Path>]) -> Result<()> {
-    process_files(files, false)
-}
+        #[test]
+        fn test_empty_profile_changes_nothing() {
+            let profile = Profile::parse(r#"{"profiles": {"dev": {}}}"#, "dev").expect("Should parse the profile");
+            assert!(profile.sops_args().is_empty());
+            assert!(profile.settings().is_empty());
+        }
 
-pub fn check_files(files: &[impl AsRef<Path>]) -> Result<()> {
-    process_files(files, true)
-}
+        #[test]
+        fn test_unknown_field_rejected() {
+            let err = Profile::parse(r#"{"profiles": {"prod": {"env_profiles": "prod"}}}"#, "prod").unwrap_err();
+            assert!(err.to_string().contains("Unknown field 'env_profiles'"), "{}", err);
+        }
 
-fn print_summary(files_count: usize, total_secrets: usize, total_updates: usize, dry_run: bool) {
-    println!("\n{}", "=".repeat(60));
-    println!("Summary:");
-    if dry_run {
-        println!("  Files checked: {}", files_count);
-        println!("  Secrets checked: {}", total_secrets);
-        println!("  Secrets out of sync: {}", total_updates);
+        #[test]
+        fn test_wrong_types_rejected() {
+            let err = Profile::parse(r#"{"profiles": {"prod": {"env": {"A": 1}}}}"#, "prod").unwrap_err();
+            assert!(err.to_string().contains("'env' of profile 'prod' must be an object of strings"), "{}", err);
+            let err = Profile::parse(r#"{"profiles": {"prod": {"sops_args": "--verbose"}}}"#, "prod").unwrap_err();
+            assert!(err.to_string().contains("must be an array of strings"), "{}", err);
+            assert!(Profile::parse(r#"{"prod": {}}"#, "prod").is_err());
+        }
 
-        if total_updates > 0 {
-            println!("\nRun 'sops-shell sync <files>' to update");
+        #[test]
+        fn test_sops_config_relative_to_config_file() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let path = dir.path().join("sops-shell.json");
+            let content = r#"{"profiles": {"prod": {"sops_config": "prod/.sops.yaml"}}}"#;
+            std::fs::write(&path, content).expect("Should write config file");
+            let profile = Profile::load(&path, "prod").expect("Should load the profile");
+            assert_eq!(profile.sops_config, Some(dir.path().join("prod/.sops.yaml")));
         }
-    } else {
-        println!("  Files processed: {}", files_count);
-        println!("  Secrets checked: {}", total_secrets);
-        println!("  Secrets updated: {}", total_updates);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::Write;
-    use tempfile::NamedTempFile;
+    mod decrypt_cache {
+        use crate::runner::{CommandOutput, CommandRunner, Runner};
+        use crate::sops::{sops_decrypt, sops_set, SopsOptions};
+        use crate::sync::{check_files, SyncOptions};
+        use crate::testing::FakeRunner;
+        use std::path::{Path, PathBuf};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::{Duration, SystemTime};
 
-    fn create_test_file(content: &str) -> NamedTempFile {
-        let mut temp_file = NamedTempFile::new().expect("Failed to create temp file");
-        temp_file.write_all(content.as_bytes()).expect("Failed to write to temp file");
-        temp_file
-    }
+        /// Decrypts by reading the file, counting the decrypts
+        #[derive(Default)]
+        struct CountingSops {
+            decrypts: AtomicUsize,
+        }
 
-    mod has_comment_lines {
-        use super::*;
+        impl CommandRunner for CountingSops {
+            fn run(&self, program: &str, args: &[&str]) -> std::io::Result<CommandOutput> {
+                let stdout = match (program, args) {
+                    ("sops", [.., "--decrypt", path]) => {
+                        self.decrypts.fetch_add(1, Ordering::SeqCst);
+                        std::fs::read(path)?
+                    }
+                    _ => Vec::new(),
+                };
+                Ok(CommandOutput { code: Some(0), stdout, stderr: Vec::new() })
+            }
+        }
 
-        #[test]
-        fn test_yaml_with_comments() {
-            let yaml_content = r#"#ENC[AES256_GCM,data:XZKYdNp090c0OssVxy3nsbVjJyQEdxDs2+w6nXs21Pq8JeUGB3wHjuuaD1uz/OTIO1nF7jM0ZSb0ED5/oTxMRSkbXa3PtRDScFulxzgs9oqt,iv:WsFYuBa3wdEMSX8hklDVJCbJkwbuk2mUSrGh9819pEk=,tag:QRNJOJnTulAcu45x1rc70A==,type:comment]
-key1: ENC[AES256_GCM,data:z3lcZi7luB/Ni5FFIB8h98qLZDofk9g8KsQRIhVRwexmZSNihuTp58nzEueBQt8D,iv:MuIReuZYNIX0zi9hOBmpTK8tFhO+MtA27tvwimGusf4=,tag:CnwlixiANK6HGwb2GweIVw==,type:str]
-value2: ENC[AES256_GCM,data:hLAuHEcvnSXSuy/8UFnLrScX7o121EPEwNHKZQOqnIdyY1EM6w6Ej0zKAREHuBsoblZRoD6a8OHTd1CrWE/3oNmKt0Y5SbY693Y9HLTC716B7/gBzRhtqpxZL3vICG7MXfrI/f4dKtx0Kroro+69PU8/6hxi7WmMVk6hL2BnipUSlINho6ev/AZuKOY+x6HAPTXE34BwK0SkmgkFgviLU1BQRNwGc+rVvmItXZLrOhL7iOSfmlOZm7qTcxOUp8oNLX+RECxLzhLr/4fj4jlB8JaNWanCvBcCim12nS5Shzsl/ylnvczcYttFAQeqjU2it8Q1lF4QcHJDg1kiNYdVBG1AiD3x1JEYR34M2tAPAcnX1uoVoZAofMjiHOaz5mWV8MahLJDt9XU+J4I/y2X7qduyXPLlXf+tnIu5D5p+WMMC,iv:fq3nwR7vMx9E270pGPTyrxENaQQAF8EInvZX3Y+JMIQ=,tag:wNcFV/e8/nXDpAqLaRGIHw==,type:str]"#;
+        // Written a while ago, as files modified just before their decrypt aren't cached
+        fn settled_file(dir: &Path, content: &str) -> PathBuf {
+            let file = dir.join("secrets.yaml");
+            std::fs::write(&file, content).expect("Should write file");
+            set_modified(&file, SystemTime::now() - Duration::from_secs(60));
+            file
+        }
 
-            let temp_file = create_test_file(yaml_content);
-            let result = has_comment_lines(temp_file.path()).expect("Should not fail");
-            assert!(result, "YAML with comments should return true");
+        fn set_modified(file: &Path, time: SystemTime) {
+            let handle = std::fs::File::options().write(true).open(file).expect("Should open file");
+            handle.set_modified(time).expect("Should set modification time");
         }
 
         #[test]
-        fn test_yaml_without_comments() {
-            let yaml_content = r#"key1: ENC[AES256_GCM,data:z3lcZi7luB/Ni5FFIB8h98qLZDofk9g8KsQRIhVRwexmZSNihuTp58nzEueBQt8D,iv:MuIReuZYNIX0zi9hOBmpTK8tFhO+MtA27tvwimGusf4=,tag:CnwlixiANK6HGwb2GweIVw==,type:str]
-value2: ENC[AES256_GCM,data:hLAuHEcvnSXSuy/8UFnLrScX7o121EPEwNHKZQOqnIdyY1EM6w6Ej0zKAREHuBsoblZRoD6a8OHTd1CrWE/3oNmKt0Y5SbY693Y9HLTC716B7/gBzRhtqpxZL3vICG7MXfrI/f4dKtx0Kroro+69PU8/6hxi7WmMVk6hL2BnipUSlINho6ev/AZuKOY+x6HAPTXE34BwK0SkmgkFgviLU1BQRNwGc+rVvmItXZLrOhL7iOSfmlOZm7qTcxOUp8oNLX+RECxLzhLr/4fj4jlB8JaNWanCvBcCim12nS5Shzsl/ylnvczcYttFAQeqjU2it8Q1lF4QcHJDg1kiNYdVBG1AiD3x1JEYR34M2tAPAcnX1uoVoZAofMjiHOaz5mWV8MahLJDt9XU+J4I/y2X7qduyXPLlXf+tnIu5D5p+WMMC,iv:fq3nwR7vMx9E270pGPTyrxENaQQAF8EInvZX3Y+JMIQ=,tag:wNcFV/e8/nXDpAqLaRGIHw==,type:str]
-secret3: ENC[AES256_GCM,data:vbMDk21AfoTzfLgybWt52JytPaf103wqmQjxhJMG/WPul2eGtmcjlez/+BNb9xgYlNvxRt00ET2lpDx5WlBQylTWuUm/tlfXBM4Iu1vXsjRAsF8X/Q==,iv:BW8bJULqt+H///8aA9z43Vs7E+mAE4YrcOIjFnvg6Fk=,tag:Srt8W4GD7LICubVzI4DmXA==,type:str]"#;
+        fn test_second_decrypt_of_unchanged_file_is_cached() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = settled_file(dir.path(), "api_key: value\n");
+            let runner = CountingSops::default();
+            let sops = SopsOptions::default().with_cache();
 
-            let temp_file = create_test_file(yaml_content);
-            let result = has_comment_lines(temp_file.path()).expect("Should not fail");
-            assert!(!result, "YAML without comments should return false");
+            assert_eq!(sops_decrypt(&runner, &file, &sops).unwrap(), "api_key: value\n");
+            assert_eq!(sops_decrypt(&runner, &file, &sops).unwrap(), "api_key: value\n");
+            assert_eq!(runner.decrypts.load(Ordering::SeqCst), 1, "The second decrypt shouldn't spawn sops");
         }
 
         #[test]
-        fn test_env_with_comments() {
-            let env_content = r#"VAR1=ENC[AES256_GCM,data:plVGuA==,iv:G8F5FbNqTrcMnekeojVDiLnHm/P3zLK2J4hH0hAORdM=,tag:KRlxAFtLwqsnToYyH0gv5Q==,type:str]
-VAR2=ENC[AES256_GCM,data:Z1YXLA==,iv:RK8JpogKYXjtRZSvTKMLbHLIqmPcdg+XUyFIw4ScRN8=,tag:G7wq3M+eyqiRbuv+1CbgDg==,type:str]
-#ENC[AES256_GCM,data:EKnMX0aQPqY1y8FDE98ePqM3t8A/Jc6dX3IqVwhwuRkMaIOmO9Ak4iQtso17iCzVHcQ=,iv:8kKEKYUVeRul6GOWxc7HZQsSYAHJwqCXMajC2N1xgNI=,tag:Pb/hHvJQSLe4pQc9isqjqA==,type:comment]
-VAR3=ENC[AES256_GCM,data:z9jujEfYa8Y=,iv:E4CoMsU1OU1+QZErLIQquoiDI23vuDbXmBDtamL1kNk=,tag:ahQR7j8+RHJUO4mymWeMJw==,type:str]"#;
+        fn test_changed_file_is_decrypted_again() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = settled_file(dir.path(), "api_key: value\n");
+            let runner = CountingSops::default();
+            let sops = SopsOptions::default().with_cache();
+            sops_decrypt(&runner, &file, &sops).unwrap();
 
-            let temp_file = create_test_file(env_content);
-            let result = has_comment_lines(temp_file.path()).expect("Should not fail");
-            assert!(result, "ENV file with comments should return true");
+            std::fs::write(&file, "api_key: other\n").expect("Should write file");
+            set_modified(&file, SystemTime::now() - Duration::from_secs(30));
+            assert_eq!(sops_decrypt(&runner, &file, &sops).unwrap(), "api_key: other\n");
+            assert_eq!(runner.decrypts.load(Ordering::SeqCst), 2);
         }
 
         #[test]
-        fn test_env_without_comments() {
-            let env_content = r#"VAR1=ENC[AES256_GCM,data:plVGuA==,iv:G8F5FbNqTrcMnekeojVDiLnHm/P3zLK2J4hH0hAORdM=,tag:KRlxAFtLwqsnToYyH0gv5Q==,type:str]
-VAR2=ENC[AES256_GCM,data:Z1YXLA==,iv:RK8JpogKYXjtRZSvTKMLbHLIqmPcdg+XUyFIw4ScRN8=,tag:G7wq3M+eyqiRbuv+1CbgDg==,type:str]
-VAR3=ENC[AES256_GCM,data:z9jujEfYa8Y=,iv:E4CoMsU1OU1+QZErLIQquoiDI23vuDbXmBDtamL1kNk=,tag:ahQR7j8+RHJUO4mymWeMJw==,type:str]"#;
+        fn test_set_invalidates() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = settled_file(dir.path(), "api_key: value\n");
+            let runner = CountingSops::default();
+            let sops = SopsOptions::default().with_cache();
+            sops_decrypt(&runner, &file, &sops).unwrap();
 
-            let temp_file = create_test_file(env_content);
-            let result = has_comment_lines(temp_file.path()).expect("Should not fail");
-            assert!(!result, "ENV file without comments should return false");
+            // The fake set leaves the file untouched, so only the invalidation can cause a decrypt
+            sops_set(&runner, &file, "api_key", "new", &sops).unwrap();
+            sops_decrypt(&runner, &file, &sops).unwrap();
+            assert_eq!(runner.decrypts.load(Ordering::SeqCst), 2);
         }
 
         #[test]
-        fn test_ini_with_comments() {
-            let ini_content = r#"[config]
-option1 = ENC[AES256_GCM,data:mJ5DKLvrKtiJ,iv:21wg1JWyntnndyHnyox1dwH4iA58CBUphqPmDvKz6b0=,tag:G+MxG7dK0KccQPpm6B8U3w==,type:str]
-option2    = ENC[AES256_GCM,data:0kk=,iv:+K69YIFcTuL3T3SzlPGcZkdSLSnoixo2CtM/lUwpZ7o=,tag:uPV3xSjHzJUjh0Urv6KN/Q==,type:str]
-; ENC[AES256_GCM,data:zYAvfyP84D8YP2Svs4+0W9M2eF7KhmS+17JIw7lIjeSIaTsdLCuzpgCbRL6ZfbCVPQci,iv:FAr+Za/MsmRqg1DTzPLcuOCH53a8n5UUQsqGE6Kv1p4=,tag:OOcIjJv+Tt+X+2QI2bdOOw==,type:comment]
-option3    = ENC[AES256_GCM,data:1GWHjsNTwQTA51jp+BOyHX+FhlF7CPfx6kyU0k658iG/k440NzIR4VK9qcFXrIrmQjhIwJaG5YxK5TiuK/qN7uuY2omz5gB0oHA=,iv:hDHT4OIUP6gZ27qGdHCkZoFc+hMkOFZ7EK9L/OBRLus=,tag:IaAF3T5RhVeDmg3eXU7Ymw==,type:str]"#;
+        fn test_recently_modified_file_is_not_cached() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = dir.path().join("secrets.yaml");
+            std::fs::write(&file, "api_key: value\n").expect("Should write file");
+            let runner = CountingSops::default();
+            let sops = SopsOptions::default().with_cache();
 
-            let temp_file = create_test_file(ini_content);
-            let result = has_comment_lines(temp_file.path()).expect("Should not fail");
-            assert!(result, "INI file with comments should return true");
+            sops_decrypt(&runner, &file, &sops).unwrap();
+            sops_decrypt(&runner, &file, &sops).unwrap();
+            assert_eq!(runner.decrypts.load(Ordering::SeqCst), 2);
         }
 
         #[test]
-        fn test_ini_without_comments() {
-            let ini_content = r#"[config]
-option1 = ENC[AES256_GCM,data:mJ5DKLvrKtiJ,iv:21wg1JWyntnndyHnyox1dwH4iA58CBUphqPmDvKz6b0=,tag:G+MxG7dK0KccQPpm6B8U3w==,type:str]
-option2    = ENC[AES256_GCM,data:0kk=,iv:+K69YIFcTuL3T3SzlPGcZkdSLSnoixo2CtM/lUwpZ7o=,tag:uPV3xSjHzJUjh0Urv6KN/Q==,type:str]
-option3     = ENC[AES256_GCM,data:dGVzdA==,iv:abc123==,tag:test123==,type:str]"#;
+        fn test_cached_per_sops_arguments() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = settled_file(dir.path(), "api_key: value\n");
+            let runner = CountingSops::default();
+            let sops = SopsOptions::default().with_cache();
+            let other = SopsOptions { extra_args: vec!["--config".to_string(), "prod.sops.yaml".to_string()], ..sops.clone() };
 
-            let temp_file = create_test_file(ini_content);
-            let result = has_comment_lines(temp_file.path()).expect("Should not fail");
-            assert!(!result, "INI file without comments should return false");
+            sops_decrypt(&runner, &file, &sops).unwrap();
+            sops_decrypt(&runner, &file, &other).unwrap();
+            sops_decrypt(&runner, &file, &other).unwrap();
+            assert_eq!(runner.decrypts.load(Ordering::SeqCst), 2);
         }
 
         #[test]
-        fn test_whitespace_before_comments() {
-            let content = r#"key: ENC[AES256_GCM,data:test,iv:test,tag:test,type:str]
-    #ENC[AES256_GCM,data:indented,iv:test,tag:test,type:comment]
-value: ENC[AES256_GCM,data:test,iv:test,tag:test,type:str]"#;
+        fn test_uncached_without_a_cache() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = settled_file(dir.path(), "api_key: value\n");
+            let runner = CountingSops::default();
 
-            let temp_file = create_test_file(content);
-            let result = has_comment_lines(temp_file.path()).expect("Should not fail");
-            assert!(result, "Should detect comments with whitespace prefix");
+            sops_decrypt(&runner, &file, &SopsOptions::default()).unwrap();
+            sops_decrypt(&runner, &file, &SopsOptions::default()).unwrap();
+            assert_eq!(runner.decrypts.load(Ordering::SeqCst), 2);
         }
 
         #[test]
-        fn test_tab_before_comments() {
-            let content = r#"key: ENC[AES256_GCM,data:test,iv:test,tag:test,type:str]
-	#ENC[AES256_GCM,data:tabbed,iv:test,tag:test,type:comment]
-value: ENC[AES256_GCM,data:test,iv:test,tag:test,type:str]"#;
+        fn test_runs_do_not_share_plaintext() {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = settled_file(dir.path(), "# shell: echo a\napi_key: a\n");
+            let decrypts = |runner: &FakeRunner| runner.calls().iter().filter(|call| call.contains(&"--decrypt".to_string())).count();
 
-            let temp_file = create_test_file(content);
-            let result = has_comment_lines(temp_file.path()).expect("Should not fail");
-            assert!(result, "Should detect comments with tab prefix");
+            let first = FakeRunner::new();
+            check_files(&[&file], &SyncOptions { runner: Runner::new(first.clone()), ..Default::default() }).unwrap();
+            let second = FakeRunner::new().decrypting_to("# shell: echo a\napi_key: b\n");
+            let summary = check_files(&[&file], &SyncOptions { runner: Runner::new(second.clone()), ..Default::default() }).unwrap();
+
+            assert_eq!((decrypts(&first), decrypts(&second)), (1, 1), "Each run decrypts with its own runner");
+            assert_eq!(summary.updates, 1, "The second run compares its own plaintext");
+        }
+    }
+
+    mod command_wrapper {
+        use super::*;
+        use crate::runner::SystemRunner;
+        use crate::testing::FakeRunner;
+
+        fn wrapper(prefix: Option<&str>, suffix: Option<&str>) -> CommandEnv {
+            CommandEnv { prefix: prefix.map(str::to_string), suffix: suffix.map(str::to_string), ..Default::default() }
         }
 
         #[test]
-        fn test_empty_file() {
-            let content = r#""#;
+        fn test_wrap_joins_with_spaces() {
+            assert_eq!(wrapper(None, None).wrap("echo hi"), "echo hi");
+            assert_eq!(wrapper(Some("timeout 30"), None).wrap("echo hi"), "timeout 30 echo hi");
+            assert_eq!(wrapper(None, Some("2>/dev/null")).wrap("echo hi"), "echo hi 2>/dev/null");
+            assert_eq!(
+                wrapper(Some("aws-vault exec prod --"), Some("| head -1")).wrap("vault read x"),
+                "aws-vault exec prod -- vault read x | head -1"
+            );
+        }
 
-            let temp_file = create_test_file(content);
-            let result = has_comment_lines(temp_file.path()).expect("Should not fail");
-            assert!(!result, "Empty file should return false");
+        #[test]
+        fn test_wrapper_applied_to_setup_and_value_commands() {
+            let content = "# shell:\n#   setup: vault login\n#   value: vault read x\napi_key: value\n";
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            let runner = FakeRunner::new().otherwise("value\n");
+            let options = SyncOptions {
+                runner: Runner::new(runner.clone()),
+                command_env: wrapper(Some("aws-vault exec prod --"), None),
+                ..Default::default()
+            };
+
+            let statuses =
+                drift_for_mappings(Path::new("secrets.yaml"), content, &mappings, &options, &mut RunState::default(), |_| {});
+            assert_eq!(statuses[0].outcome.as_str(), "in_sync");
+            assert_eq!(runner.commands(), vec!["aws-vault exec prod -- vault login", "aws-vault exec prod -- vault read x"]);
         }
 
         #[test]
-        fn test_only_empty_lines() {
-            let content = r#"
+        fn test_commands_run_wrapped() {
+            let runner = FakeRunner::new().otherwise("value\n");
+            let env = wrapper(Some("aws-vault exec prod --"), Some("2>/dev/null"));
+            run_command(&runner, "vault read x", None, &env, None).expect("Should run");
+            assert_eq!(runner.commands(), vec!["aws-vault exec prod -- vault read x 2>/dev/null"]);
+        }
 
+        #[test]
+        fn test_wrapped_command_runs_under_sh() {
+            let env = wrapper(Some("FOO=wrapped"), Some("| tr a-z A-Z"));
+            let value = execute_command(&SystemRunner, "sh -c 'echo $FOO'", None, &env, ValueSource::Stdout, Capture::Stdout, None)
+                .expect("Should run");
+            assert_eq!(value, "WRAPPED");
+        }
+    }
 
-"#;
+    mod line_ending {
+        use super::*;
+        use crate::sops::LineEnding;
+        use crate::testing::FakeRunner;
 
-            let temp_file = create_test_file(content);
-            let result = has_comment_lines(temp_file.path()).expect("Should not fail");
-            assert!(!result, "File with only empty lines should return false");
+        /// Every command prints a two-line value
+        fn runner() -> FakeRunner {
+            FakeRunner::new().otherwise("first\r\nsecond\n")
         }
 
         #[test]
-        fn test_shell_comments_specifically() {
-            let yaml_content = r#"#ENC[AES256_GCM,data:XZKYdNp090c0OssVxy3nsbVjJyQEdxDs2+w6nXs21Pq8JeUGB3wHjuuaD1uz/OTIO1nF7jM0ZSb0ED5/oTxMRSkbXa3PtRDScFulxzgs9oqt,iv:WsFYuBa3wdEMSX8hklDVJCbJkwbuk2mUSrGh9819pEk=,tag:QRNJOJnTulAcu45x1rc70A==,type:comment]
-key: ENC[AES256_GCM,data:test,iv:test,tag:test,type:str]"#;
+        fn test_newlines_converted() {
+            assert_eq!(LineEnding::Crlf.apply("a\nb\r\nc"), "a\r\nb\r\nc");
+            assert_eq!(LineEnding::Lf.apply("a\r\nb\nc\r\n"), "a\nb\nc\n");
+            assert_eq!(LineEnding::Crlf.apply("single"), "single");
+        }
 
-            let temp_file = create_test_file(yaml_content);
-            let result = has_comment_lines(temp_file.path()).expect("Should not fail");
-            assert!(result, "Should detect SOPS encrypted comment lines");
+        fn written(line_ending: Option<LineEnding>) -> Vec<String> {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = dir.path().join("secrets.yaml");
+            std::fs::write(&file, "cert: old\n").expect("Should write file");
+
+            let runner = runner();
+            let options = SyncOptions {
+                runner: Runner::new(runner.clone()),
+                sops: SopsOptions { line_ending, ..Default::default() },
+                ..Default::default()
+            };
+            apply_updates(&file, &[("cert".to_string(), "first\r\nsecond".to_string())], &options).expect("Should write");
+            runner.sets()
         }
 
         #[test]
-        fn test_large_file_without_comments() {
-            let mut content = String::new();
-            for i in 0..150 {
-                content.push_str(&format!("line{}: ENC[AES256_GCM,data:test{}{},iv:test123,tag:test456,type:str]\n", i, i, i));
-            }
+        fn test_written_value_converted() {
+            assert_eq!(written(Some(LineEnding::Lf)), vec![r#"["cert"] "first\nsecond""#]);
+            assert_eq!(written(Some(LineEnding::Crlf)), vec![r#"["cert"] "first\r\nsecond""#]);
+        }
 
-            let temp_file = create_test_file(&content);
-            let result = has_comment_lines(temp_file.path()).expect("Should not fail");
-            assert!(!result, "Large file without comments should return false");
+        #[test]
+        fn test_kept_as_is_by_default() {
+            assert_eq!(written(None), vec![r#"["cert"] "first\r\nsecond""#]);
+        }
+
+        #[test]
+        fn test_compared_as_written() {
+            let content = "# shell: cat cert.pem\ncert: x\n";
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse successfully").mappings;
+            let options = SyncOptions {
+                runner: Runner::new(runner()),
+                sops: SopsOptions { line_ending: Some(LineEnding::Lf), ..Default::default() },
+                no_trim: true,
+                ..Default::default()
+            };
+            let statuses =
+                drift_for_mappings(Path::new("secrets.yaml"), content, &mappings, &options, &mut RunState::default(), |_| {});
+            match &statuses[0].outcome {
+                KeyOutcome::Checked { value, .. } => assert_eq!(value, "first\nsecond\n"),
+                outcome => panic!("Unexpected outcome {}", outcome.as_str()),
+            }
         }
     }
 
-    mod skip_commented_key_mappings {
-        use crate::parser::parse_commands;
+    mod also_set_sha256 {
+        use super::*;
+        use crate::hash::sha256_hex;
+        use crate::runner::Runner;
+        use crate::testing::FakeRunner;
+
+        fn sync(content: &str) -> (String, RunSummary) {
+            let dir = tempfile::tempdir().expect("Should create temp dir");
+            let file = dir.path().join("app.env");
+            std::fs::write(&file, content).expect("Should write file");
+            let options = SyncOptions { runner: Runner::new(FakeRunner::new().applying_sets()), summary_only: true, ..Default::default() };
+            let summary = sync_files(&[&file], &options).expect("Should sync");
+            (std::fs::read_to_string(&file).unwrap(), summary)
+        }
+
+        fn mapping(content: &str) -> Result<CommandMapping> {
+            let mut mappings = parse_commands(content, &ParseOptions::default())?.mappings;
+            Ok(mappings.remove(0))
+        }
 
         #[test]
-        fn test_ini_skip_when_next_key_commented() {
-            let content = r#"[config]
-resolution = 1920x1080
-; shell: echo hi
-; val = hi
-fps = 120"#;
+        fn test_sha256_hex() {
+            assert_eq!(sha256_hex("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        }
 
-            let mappings = parse_commands(content).expect("Should parse successfully");
-            assert_eq!(mappings.len(), 0, "Should skip INI mapping when next key is commented");
+        #[test]
+        fn test_option_parsed() {
+            let mapping = mapping("# shell(also-set-sha256=API_KEY_SHA): echo abc\nAPI_KEY=old\n").expect("Should parse");
+            assert_eq!(mapping.also_sha256.as_deref(), Some("API_KEY_SHA"));
         }
 
         #[test]
-        fn test_env_skip_when_next_key_commented() {
-            let content = r#"MEILI_MASTER_KEY=ENC[AES256_GCM,data:test1,iv:test,tag:test,type:str]
-# shell: rbw get 79da561c-beaa-430e-9ee9-fa2a218eb700 --field "API Key"
-# OPENAI_API_KEY=ENC[AES256_GCM,data:test2,iv:test,tag:test,type:str]
-NEXTAUTH_URL=ENC[AES256_GCM,data:test3,iv:test,tag:test,type:str]"#;
+        fn test_invalid_companion_rejected() {
+            assert!(mapping("# shell(also-set-sha256=): echo abc\nAPI_KEY=old\n").is_err());
+            assert!(mapping("# shell(also-set-sha256=a b): echo abc\nAPI_KEY=old\n").is_err());
+            let err = mapping("# shell(also-set-sha256=API_KEY): echo abc\nAPI_KEY=old\n").unwrap_err();
+            assert!(format!("{:#}", err).contains("can't name the directive's own key 'API_KEY'"), "{:#}", err);
+        }
 
-            let mappings = parse_commands(content).expect("Should parse successfully");
-            assert_eq!(mappings.len(), 0, "Should skip ENV mapping when next key is commented");
+        #[test]
+        fn test_both_keys_written() {
+            let (content, summary) = sync("# shell(also-set-sha256=API_KEY_SHA): echo abc\nAPI_KEY=old\n");
+            assert!(content.contains("API_KEY=abc\n"), "{}", content);
+            assert!(content.contains(&format!("API_KEY_SHA={}\n", sha256_hex("abc"))), "{}", content);
+            assert_eq!(summary.updates, 2);
         }
 
         #[test]
-        fn test_yaml_skip_when_next_key_commented() {
-            let content = r#"key1: ENC[AES256_GCM,data:test,iv:test,tag:test,type:str]
-# shell: echo "secret_value"
-# key2: ENC[AES256_GCM,data:commented_secret,iv:test,tag:test,type:str]
-key3: ENC[AES256_GCM,data:value3,iv:test,tag:test,type:str]"#;
+        fn test_stale_checksum_rewritten_for_value_in_sync() {
+            let (content, summary) = sync("# shell(also-set-sha256=API_KEY_SHA): echo abc\nAPI_KEY=abc\nAPI_KEY_SHA=stale\n");
+            assert!(content.contains(&format!("API_KEY_SHA={}\n", sha256_hex("abc"))), "{}", content);
+            assert!(!content.contains("stale"));
+            assert_eq!(summary.updates, 2);
+        }
 
-            let mappings = parse_commands(content).expect("Should parse successfully");
-            assert_eq!(mappings.len(), 0, "Should skip YAML mapping when next key is commented");
+        #[test]
+        fn test_in_sync_with_checksum() {
+            let content = format!("# shell(also-set-sha256=API_KEY_SHA): echo abc\nAPI_KEY=abc\nAPI_KEY_SHA={}\n", sha256_hex("abc"));
+            let (written, summary) = sync(&content);
+            assert_eq!(written, content);
+            assert_eq!(summary.updates, 0);
+        }
+
+        #[test]
+        fn test_companion_is_not_an_orphan() {
+            let content = "# shell(also-set-sha256=API_KEY_SHA): echo abc\nAPI_KEY=abc\nAPI_KEY_SHA=x\nOTHER=y\n";
+            let mappings = parse_commands(content, &ParseOptions::default()).expect("Should parse").mappings;
+            assert_eq!(orphaned_keys(content, &mappings, &SyncOptions::default()), vec!["OTHER"]);
         }
     }
 }
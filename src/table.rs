@@ -0,0 +1,66 @@
+//! Columnar rendering of per-key results for `--table`
+
+use std::fmt::Write as _;
+
+/// Cells longer than this are cut short with an ellipsis, so a long pipeline doesn't push the
+/// other columns off screen
+pub const MAX_CELL_WIDTH: usize = 60;
+
+/// Rows of text cells, rendered with every column padded to its widest cell
+#[derive(Debug, Clone)]
+pub struct Table {
+    headers: Vec<String>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    pub fn new(headers: &[&str]) -> Self {
+        Table { headers: headers.iter().map(|header| header.to_string()).collect(), rows: Vec::new() }
+    }
+
+    /// Adds a row. Missing cells are left blank and cells beyond the headers are dropped
+    pub fn push(&mut self, row: Vec<String>) {
+        let mut row: Vec<String> = row.into_iter().take(self.headers.len()).map(|cell| truncate(&cell)).collect();
+        row.resize(self.headers.len(), String::new());
+        self.rows.push(row);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// The header, a rule under it and each row, as lines joined by `\n`, e.g.
+    /// `Key     | Status  ` over `--------+--------`
+    pub fn render(&self) -> String {
+        let widths: Vec<usize> = (0..self.headers.len())
+            .map(|column| {
+                let cells = std::iter::once(&self.headers[column]).chain(self.rows.iter().map(|row| &row[column]));
+                cells.map(|cell| cell.chars().count()).max().unwrap_or(0)
+            })
+            .collect();
+
+        let line = |cells: &[String]| {
+            let padded: Vec<String> = cells.iter().zip(&widths).map(|(cell, width)| format!("{:<width$}", cell)).collect();
+            padded.join(" | ").trim_end().to_string()
+        };
+        let rule: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+
+        let mut out = line(&self.headers);
+        let _ = write!(out, "\n{}", rule.join("-+-"));
+        for row in &self.rows {
+            let _ = write!(out, "\n{}", line(row));
+        }
+        out
+    }
+}
+
+// Cells are single line, so newlines, e.g. of a multi-line command, are shown as spaces
+fn truncate(cell: &str) -> String {
+    let cell = cell.replace(['\r', '\n'], " ");
+    if cell.chars().count() <= MAX_CELL_WIDTH {
+        return cell;
+    }
+    let mut truncated: String = cell.chars().take(MAX_CELL_WIDTH - 1).collect();
+    truncated.push('…');
+    truncated
+}
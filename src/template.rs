@@ -0,0 +1,78 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+
+const OPEN: &str = "{{cmd:";
+const CLOSE: &str = "}}";
+
+/// The value of a `shell-template` directive: text with `{{cmd:<command>}}` placeholders, each
+/// replaced by the output of its command, e.g. `postgres://{{cmd:get-user}}:{{cmd:get-pass}}@host`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    raw: String,
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Command(String),
+}
+
+impl Template {
+    pub fn parse(raw: &str) -> Result<Self> {
+        let raw = raw.trim();
+        let mut parts = Vec::new();
+        let mut rest = raw;
+
+        while let Some(start) = rest.find(OPEN) {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            let after = &rest[start + OPEN.len()..];
+            let end = after
+                .find(CLOSE)
+                .ok_or_else(|| anyhow!("Template placeholder at '{}' isn't closed with '{}'", &rest[start..], CLOSE))?;
+            let command = after[..end].trim();
+            if command.is_empty() {
+                return Err(anyhow!("Template has an empty '{}{}' placeholder", OPEN, CLOSE));
+            }
+            parts.push(Part::Command(command.to_string()));
+            rest = &after[end + CLOSE.len()..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+
+        if !parts.iter().any(|part| matches!(part, Part::Command(_))) {
+            return Err(anyhow!("Template has no '{}<command>{}' placeholder", OPEN, CLOSE));
+        }
+        Ok(Template { raw: raw.to_string(), parts })
+    }
+
+    /// The command of every placeholder, in order
+    pub fn commands(&self) -> impl Iterator<Item = &str> {
+        self.parts.iter().filter_map(|part| match part {
+            Part::Command(command) => Some(command.as_str()),
+            Part::Text(_) => None,
+        })
+    }
+
+    /// The template with each placeholder replaced by what `run` returns for its command.
+    /// Stops at the first command that fails
+    pub fn render(&self, mut run: impl FnMut(&str) -> Result<String>) -> Result<String> {
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => rendered.push_str(text),
+                Part::Command(command) => rendered.push_str(&run(command)?),
+            }
+        }
+        Ok(rendered)
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.raw)
+    }
+}
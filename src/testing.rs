@@ -0,0 +1,256 @@
+//! Fakes for unit tests that run the sync logic without sops or a shell
+
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::runner::{CommandOutput, CommandRunner};
+
+/// A successful run that printed `stdout`
+pub fn stdout(stdout: &str) -> CommandOutput {
+    CommandOutput { code: Some(0), stdout: stdout.as_bytes().to_vec(), stderr: Vec::new() }
+}
+
+/// A run that exited with 1 after printing `stderr`
+pub fn failure(stderr: &str) -> CommandOutput {
+    CommandOutput { code: Some(1), stdout: Vec::new(), stderr: stderr.as_bytes().to_vec() }
+}
+
+/// Stands in for sops and `sh -c`. Decrypts a file by reading it, as if it were kept in plaintext,
+/// unless `decrypting_to` says otherwise, and accepts every `--set` without changing the file unless
+/// `applying_sets` is used. `echo <text>` prints the text like the shell would, other commands print
+/// what's given for them with `output`, `failing` or `otherwise`, or fail to start
+///
+/// Clones share the recorded calls, so a test can keep one to inspect after handing the other to
+/// `Runner::new`
+#[derive(Debug, Clone, Default)]
+pub struct FakeRunner {
+    decrypted: Option<String>,
+    outputs: Vec<(String, CommandOutput)>,
+    otherwise: Option<CommandOutput>,
+    extracted: Vec<(String, String)>,
+    refused_sets: Vec<(String, String)>,
+    apply_sets: bool,
+    read_only: bool,
+    streaming: bool,
+    calls: Arc<Mutex<Vec<Vec<String>>>>,
+}
+
+impl FakeRunner {
+    pub fn new() -> Self {
+        FakeRunner::default()
+    }
+
+    /// Decrypts every file to `plaintext` instead of reading it
+    pub fn decrypting_to(mut self, plaintext: impl Into<String>) -> Self {
+        self.decrypted = Some(plaintext.into());
+        self
+    }
+
+    /// `command` prints `stdout` and succeeds
+    pub fn output(self, command: &str, output: &str) -> Self {
+        self.outcome(command, stdout(output))
+    }
+
+    /// `command` prints `stderr` and exits with 1
+    pub fn failing(self, command: &str, stderr: &str) -> Self {
+        self.outcome(command, failure(stderr))
+    }
+
+    /// `command` finishes with `output`, replacing any output given for it before
+    pub fn outcome(mut self, command: &str, output: CommandOutput) -> Self {
+        self.outputs.push((command.to_string(), output));
+        self
+    }
+
+    /// Commands without an output of their own print `stdout`, `echo` included
+    pub fn otherwise(mut self, output: &str) -> Self {
+        self.otherwise = Some(stdout(output));
+        self
+    }
+
+    /// `--extract` of the tree path `key`, e.g. `["api_key"]`, prints `value`. Other keys aren't found
+    pub fn extracting(mut self, key: &str, value: &str) -> Self {
+        self.extracted.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// A `--set` of `key` fails with `stderr`
+    pub fn refusing_set(mut self, key: &str, stderr: &str) -> Self {
+        self.refused_sets.push((key.to_string(), stderr.to_string()));
+        self
+    }
+
+    /// A `--set` rewrites the line of its key, or appends one, as `key: value` or, in `.env` files, `key=value`
+    pub fn applying_sets(mut self) -> Self {
+        self.apply_sets = true;
+        self
+    }
+
+    /// Panics on a `--set`, for runs that must not write
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Decrypts only a line at a time through `run_lines`, and panics on a decrypt that would hold the
+    /// whole plaintext
+    pub fn streaming(mut self) -> Self {
+        self.streaming = true;
+        self
+    }
+
+    /// Every invocation, as the program followed by its arguments
+    pub fn calls(&self) -> Vec<Vec<String>> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// The tree path and value of every `--set`, e.g. `["api_key"] "new"`
+    pub fn sets(&self) -> Vec<String> {
+        self.calls()
+            .into_iter()
+            .filter(|call| call[0] == "sops")
+            .filter_map(|call| call.iter().position(|arg| arg == "--set").map(|at| call[at + 1].clone()))
+            .collect()
+    }
+
+    /// Every command run through `sh -c`
+    pub fn commands(&self) -> Vec<String> {
+        self.calls().into_iter().filter(|call| call.len() == 3 && call[..2] == ["sh", "-c"]).map(|call| call[2].clone()).collect()
+    }
+
+    fn run_sops(&self, args: &[&str]) -> std::io::Result<CommandOutput> {
+        match args {
+            [.., "--decrypt", "--extract", key, _] => {
+                let value = self.extracted.iter().find(|(extracted, _)| extracted == key);
+                Ok(value.map_or_else(|| failure("component not found"), |(_, value)| stdout(value)))
+            }
+            [.., "--decrypt", path] => {
+                assert!(!self.streaming, "Unexpected buffered decrypt of {}", path);
+                Ok(stdout(&self.plaintext(path)?))
+            }
+            [.., "--set", set, path] => {
+                assert!(!self.read_only, "Unexpected write to {}: {}", path, set);
+                let (key, value) = set.split_once(' ').expect("Should be a tree path and a value");
+                if let Some((_, stderr)) = self.refused_sets.iter().find(|(refused, _)| key.contains(refused.as_str())) {
+                    return Ok(failure(stderr));
+                }
+                if self.apply_sets {
+                    apply_set(Path::new(path), key, value)?;
+                }
+                Ok(stdout(""))
+            }
+            _ => Ok(stdout("")),
+        }
+    }
+
+    fn plaintext(&self, path: &str) -> std::io::Result<String> {
+        match &self.decrypted {
+            Some(plaintext) => Ok(plaintext.clone()),
+            None => std::fs::read_to_string(path),
+        }
+    }
+
+    fn run_shell(&self, command: &str) -> std::io::Result<CommandOutput> {
+        if let Some((_, output)) = self.outputs.iter().rev().find(|(canned, _)| canned == command) {
+            return Ok(output.clone());
+        }
+        match (&self.otherwise, command.strip_prefix("echo ")) {
+            (Some(output), _) => Ok(output.clone()),
+            (None, Some(text)) => Ok(stdout(&format!("{}\n", text))),
+            (None, None) => Err(std::io::Error::other(format!("unexpected command {}", command))),
+        }
+    }
+}
+
+impl CommandRunner for FakeRunner {
+    fn run(&self, program: &str, args: &[&str]) -> std::io::Result<CommandOutput> {
+        let call = std::iter::once(program).chain(args.iter().copied()).map(String::from).collect();
+        self.calls.lock().unwrap().push(call);
+        match (program, args) {
+            ("sops", ["--version"]) => Ok(stdout("sops 3.9.0")),
+            ("sops", args) => self.run_sops(args),
+            ("sh", ["-c", command]) => self.run_shell(command),
+            _ => Err(std::io::Error::other(format!("unexpected invocation of {}", program))),
+        }
+    }
+
+    fn run_lines(
+        &self,
+        program: &str,
+        args: &[&str],
+        _timeout: Option<Duration>,
+        _interactive: bool,
+        on_line: &mut dyn FnMut(&str),
+    ) -> std::io::Result<CommandOutput> {
+        let output = match (program, args) {
+            ("sops", [.., "--decrypt", path]) if !args.contains(&"--extract") => {
+                let call = std::iter::once(program).chain(args.iter().copied()).map(String::from).collect();
+                self.calls.lock().unwrap().push(call);
+                stdout(&self.plaintext(path)?)
+            }
+            _ => self.run(program, args)?,
+        };
+        String::from_utf8_lossy(&output.stdout).lines().for_each(&mut *on_line);
+        Ok(CommandOutput { stdout: Vec::new(), ..output })
+    }
+}
+
+// The `--set` of `key`, a tree path like `["api_key"]`, to a JSON string `value`, applied to the
+// plaintext file at `path`
+fn apply_set(path: &Path, key: &str, value: &str) -> std::io::Result<()> {
+    let key = key.trim_start_matches("[\"").trim_end_matches("\"]");
+    let value: String = serde_json::from_str(value).expect("Should be a JSON string");
+    let env = path.extension().is_some_and(|extension| extension == "env");
+    let line = if env { format!("{}={}", key, value) } else { format!("{}: {}", key, value) };
+
+    let content = std::fs::read_to_string(path)?;
+    let mut lines: Vec<String> = content.lines().map(String::from).collect();
+    let defines = |existing: &String| existing.starts_with(&format!("{}:", key)) || existing.starts_with(&format!("{}=", key));
+    match lines.iter_mut().find(|existing| defines(existing)) {
+        Some(existing) => *existing = line,
+        None => lines.push(line),
+    }
+    std::fs::write(path, lines.join("\n") + "\n")
+}
+
+/// Builds the plaintext of a YAML file, a `# shell:` directive and its key at a time
+#[derive(Debug, Clone, Default)]
+pub struct Plaintext {
+    content: String,
+}
+
+impl Plaintext {
+    pub fn yaml() -> Self {
+        Plaintext::default()
+    }
+
+    /// `key` holding `value`, synced by `command`
+    pub fn secret(self, command: &str, key: &str, value: &str) -> Self {
+        self.line(&format!("# shell: {}", command)).key(key, value)
+    }
+
+    /// `key` holding `value`, without a directive
+    pub fn key(self, key: &str, value: &str) -> Self {
+        self.line(&format!("{}: {}", key, value))
+    }
+
+    /// Any other line, e.g. a directive with options
+    pub fn line(mut self, line: &str) -> Self {
+        self.content.push_str(line);
+        self.content.push('\n');
+        self
+    }
+
+    pub fn build(self) -> String {
+        self.content
+    }
+}
+
+/// A temporary file named `*<suffix>`, e.g. `.yaml` for sops to tell the format by, holding `content`
+pub fn secrets_file(content: &str, suffix: &str) -> tempfile::NamedTempFile {
+    let mut file = tempfile::Builder::new().suffix(suffix).tempfile().expect("Should create temp file");
+    file.write_all(content.as_bytes()).expect("Should write temp file");
+    file
+}
@@ -0,0 +1,148 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::parser::{parse_commands_with_includes, Capture, CommandMapping, ValueSource};
+use crate::sops::sops_decrypt;
+use crate::sync::{declined, ensure_not_empty, execute_command, execute_template, reads_keyring, resolved_command, SyncOptions};
+
+/// How a directive's command fared in `validate_file`
+#[derive(Debug)]
+pub enum CommandCheck {
+    Passed,
+    Failed(anyhow::Error),
+    /// Not run, as its `when=` condition doesn't hold or it was declined at the confirmation prompt
+    Skipped,
+}
+
+/// Runs the command of every directive in `filepath` and reports whether it succeeded. The
+/// output is discarded, so nothing is compared against the stored values or written
+pub fn validate_file(filepath: &Path, options: &SyncOptions) -> Result<Vec<(String, CommandCheck)>> {
+    println!("\nValidating commands in {}...", filepath.display());
+
+    let options = options.for_file(filepath);
+    let decrypted = sops_decrypt(&*options.runner, filepath, &options.sops)?;
+    if options.abort_on_empty_file {
+        ensure_not_empty(filepath, &decrypted)?;
+    }
+    let parsed = parse_commands_with_includes(&decrypted, filepath, &options.parse)?;
+    if parsed.mappings.is_empty() {
+        println!("  No secret(s) with 'shell:' commands found");
+        return Ok(Vec::new());
+    }
+
+    // A `shell-map` command is run once for all of its keys
+    let mut shared_checks: HashMap<usize, Result<(), String>> = HashMap::new();
+    let mut checks = Vec::new();
+    for mapping in &parsed.mappings {
+        let ran = mapping.shared && shared_checks.contains_key(&mapping.line);
+        let check = if mapping.when.as_ref().is_some_and(|condition| !condition.holds())
+            || (!ran && declined(mapping, &options).is_some())
+        {
+            CommandCheck::Skipped
+        } else {
+            let result = match shared_checks.get(&mapping.line).filter(|_| mapping.shared) {
+                Some(cached) => cached.clone().map_err(|e| anyhow!(e)),
+                None => {
+                    let result = run_mapping(mapping, filepath, &options);
+                    if mapping.shared {
+                        shared_checks.insert(mapping.line, result.as_ref().map_err(|e| format!("{:#}", e)).copied());
+                    }
+                    result
+                }
+            };
+            match result {
+                Ok(()) => CommandCheck::Passed,
+                Err(e) => CommandCheck::Failed(e),
+            }
+        };
+
+        match &check {
+            CommandCheck::Passed => println!("  ok      {}", mapping.key),
+            CommandCheck::Failed(e) => println!("  FAILED  {}: {:#}", mapping.key, e),
+            CommandCheck::Skipped => println!("  skipped {}", mapping.key),
+        }
+        checks.push((mapping.key.clone(), check));
+    }
+
+    Ok(checks)
+}
+
+fn run_mapping(mapping: &CommandMapping, filepath: &Path, options: &SyncOptions) -> Result<()> {
+    mapping
+        .commands()
+        .into_iter()
+        .filter(|command| !reads_keyring(command))
+        .try_for_each(|command| options.policy.check(command))?;
+
+    let (user, env, timeout) = (mapping.user.as_deref(), &options.command_env, mapping.settings.timeout);
+    let run = || {
+        if let Some(setup) = &mapping.setup {
+            execute_command(&*options.runner, setup, user, env, ValueSource::Stdout, Capture::Stdout, timeout)
+                .context("Setup command failed")?;
+        }
+        if let Some(template) = &mapping.template {
+            return execute_template(&*options.runner, template, user, env, timeout);
+        }
+        #[cfg(feature = "keyring")]
+        if let Some(value) = crate::keyring::read_reference(options.keyring.as_ref(), &mapping.command) {
+            return value;
+        }
+        let command = resolved_command(mapping, filepath)?;
+        execute_command(&*options.runner, &command, user, env, mapping.value_from, mapping.capture, timeout)
+    };
+
+    let mut result = run();
+    for _ in 0..mapping.settings.retries.unwrap_or(0) {
+        if result.is_ok() {
+            break;
+        }
+        result = run();
+    }
+    result.map(|_| ())
+}
+
+/// Validates the commands of every file, failing if any command or file failed
+pub fn validate_files(files: &[impl AsRef<Path>], options: &SyncOptions) -> Result<()> {
+    let (mut passed, mut failed, mut skipped, mut file_errors) = (0, 0, 0, 0);
+
+    for file in files {
+        let file = file.as_ref();
+        match validate_file(file, options) {
+            Ok(checks) => {
+                for (_, check) in checks {
+                    match check {
+                        CommandCheck::Passed => passed += 1,
+                        CommandCheck::Failed(_) => failed += 1,
+                        CommandCheck::Skipped => skipped += 1,
+                    }
+                }
+            }
+            Err(e) => {
+                println!("  Error: Failed to read directives of {}: {:#}", file.display(), e);
+                file_errors += 1;
+            }
+        }
+    }
+
+    println!("\n{}", "=".repeat(60));
+    println!("Summary:");
+    println!("  Files validated: {}", files.len());
+    println!("  Commands passed: {}", passed);
+    println!("  Commands failed: {}", failed);
+    if skipped > 0 {
+        println!("  Commands skipped: {}", skipped);
+    }
+    if file_errors > 0 {
+        println!("  File errors: {}", file_errors);
+    }
+
+    if file_errors > 0 {
+        return Err(anyhow!("{} command(s) failed and {} file(s) couldn't be read", failed, file_errors));
+    }
+    if failed > 0 {
+        return Err(anyhow!("{} command(s) failed", failed));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+
+/// Characters a value may be made of, for the `charset=` directive option
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Charset {
+    /// `0-9`, `a-f` and `A-F`
+    Hex,
+    /// `A-Z`, `a-z` and `0-9`
+    Alphanumeric,
+    /// The standard and URL-safe base64 alphabets, with `=` padding
+    Base64,
+    /// Printable ASCII, i.e. no whitespace or control characters
+    Printable,
+}
+
+impl Charset {
+    pub fn parse(raw: &str) -> Result<Self> {
+        match raw.trim() {
+            "hex" => Ok(Charset::Hex),
+            "alphanumeric" => Ok(Charset::Alphanumeric),
+            "base64" => Ok(Charset::Base64),
+            "printable" => Ok(Charset::Printable),
+            other => Err(anyhow!(
+                "Unknown charset '{}', expected 'hex', 'alphanumeric', 'base64' or 'printable'",
+                other
+            )),
+        }
+    }
+
+    fn allows(self, ch: char) -> bool {
+        match self {
+            Charset::Hex => ch.is_ascii_hexdigit(),
+            Charset::Alphanumeric => ch.is_ascii_alphanumeric(),
+            Charset::Base64 => ch.is_ascii_alphanumeric() || matches!(ch, '+' | '/' | '-' | '_' | '='),
+            Charset::Printable => ch.is_ascii_graphic(),
+        }
+    }
+}
+
+impl fmt::Display for Charset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Charset::Hex => "hex",
+            Charset::Alphanumeric => "alphanumeric",
+            Charset::Base64 => "base64",
+            Charset::Printable => "printable",
+        })
+    }
+}
+
+/// Constraints a command's value has to meet before it's written, from the `min-len`, `max-len`
+/// and `charset` directive options. Lengths count characters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ValueCheck {
+    pub min_len: Option<usize>,
+    pub max_len: Option<usize>,
+    pub charset: Option<Charset>,
+}
+
+impl ValueCheck {
+    /// Applies the directive option `name`. Returns false for other option names
+    pub fn apply(&mut self, name: &str, value: &str) -> Result<bool> {
+        let length = |value: &str| {
+            value.trim().parse::<usize>().map_err(|_| anyhow!("Invalid {} '{}', expected a number", name, value.trim()))
+        };
+        match name {
+            "min-len" => self.min_len = Some(length(value)?),
+            "max-len" => self.max_len = Some(length(value)?),
+            "charset" => self.charset = Some(Charset::parse(value)?),
+            _ => return Ok(false),
+        }
+        if let (Some(min_len), Some(max_len)) = (self.min_len, self.max_len) {
+            if min_len > max_len {
+                return Err(anyhow!("min-len={} is greater than max-len={}", min_len, max_len));
+            }
+        }
+        Ok(true)
+    }
+
+    /// Fails with the first constraint `value` breaks. The value itself is never part of the error
+    pub fn check(&self, value: &str) -> Result<()> {
+        let length = value.chars().count();
+        if let Some(min_len) = self.min_len.filter(|min_len| length < *min_len) {
+            return Err(anyhow!("Value is {} character(s) long, shorter than min-len={}", length, min_len));
+        }
+        if let Some(max_len) = self.max_len.filter(|max_len| length > *max_len) {
+            return Err(anyhow!("Value is {} character(s) long, longer than max-len={}", length, max_len));
+        }
+        if let Some(charset) = self.charset {
+            if let Some(position) = value.chars().position(|ch| !charset.allows(ch)) {
+                return Err(anyhow!("Value has a character outside charset={} at position {}", charset, position + 1));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+fn check(dir: &Path, content: &str, extra_args: &[&str]) -> i32 {
+    let secrets = dir.join("secrets.yaml");
+    fs::write(&secrets, content).expect("Failed to write secrets file");
+
+    common::sops_shell(dir)
+        .arg("check")
+        .args(extra_args)
+        .arg(&secrets)
+        .output()
+        .expect("Failed to run sops-shell")
+        .status
+        .code()
+        .expect("sops-shell was terminated by a signal")
+}
+
+fn check_with_exit_code(content: &str) -> i32 {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    check(dir.path(), content, &["--exit-code"])
+}
+
+#[test]
+fn test_in_sync_exits_zero() {
+    assert_eq!(check_with_exit_code("# shell: echo same\napi_key: same\n"), 0);
+}
+
+#[test]
+fn test_out_of_sync_exits_one() {
+    assert_eq!(check_with_exit_code("# shell: echo new\napi_key: old\n"), 1);
+}
+
+#[test]
+fn test_failed_command_exits_two() {
+    assert_eq!(check_with_exit_code("# shell: exit 3\napi_key: old\n# shell: echo new\nother: old\n"), 2);
+}
+
+#[test]
+fn test_missing_file_exits_two() {
+    let output = Command::new(env!("CARGO_BIN_EXE_sops-shell"))
+        .args(["check", "--exit-code", "does-not-exist.yaml"])
+        .output()
+        .expect("Failed to run sops-shell");
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_out_of_sync_exits_zero_by_default() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    assert_eq!(check(dir.path(), "# shell: echo new\napi_key: old\n", &[]), 0);
+}
+
+#[test]
+fn test_invalid_name_regex_is_rejected() {
+    let output = Command::new(env!("CARGO_BIN_EXE_sops-shell"))
+        .args(["check", "--name-regex", "db_(", "secrets.yaml"])
+        .output()
+        .expect("Failed to run sops-shell");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--name-regex"));
+}
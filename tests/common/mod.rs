@@ -0,0 +1,32 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+
+// Stands in for sops with files kept in plaintext, logging the arguments of every call to `sops.log`
+// and the tree path and value of every write to `sets.log`
+const FAKE_SOPS: &str = r#"#!/bin/sh
+echo "$@" >> sops.log
+for last; do :; done
+case " $* " in
+    *" --version "*) echo "sops 3.9.0" ;;
+    *" --decrypt "*) cat "$last" ;;
+    *" --encrypt "*) ;;
+    *" --set "*)
+        while [ "$1" != "--set" ]; do shift; done
+        echo "$2" >> sets.log ;;
+    *) exit 1 ;;
+esac
+"#;
+
+/// Puts the fake sops in `dir` and returns sops-shell to run from there, finding it first on PATH
+pub fn sops_shell(dir: &Path) -> Command {
+    let sops = dir.join("sops");
+    fs::write(&sops, FAKE_SOPS).expect("Failed to write fake sops");
+    fs::set_permissions(&sops, fs::Permissions::from_mode(0o755)).expect("Failed to make fake sops executable");
+
+    let path = format!("{}:{}", dir.display(), std::env::var("PATH").unwrap_or_default());
+    let mut command = Command::new(env!("CARGO_BIN_EXE_sops-shell"));
+    command.current_dir(dir).env("PATH", path).env_remove("SOPS_SHELL_DRY_RUN");
+    command
+}
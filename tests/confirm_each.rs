@@ -0,0 +1,54 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Output, Stdio};
+
+const CONTENT: &str = "# shell: echo one\nfirst: one\n# shell: echo two\nsecond: two\n# shell: echo three\nthird: three\n";
+
+fn check(dir: &Path, args: &[&str], answers: &str) -> Output {
+    fs::write(dir.join("secrets.yaml"), CONTENT).expect("Failed to write secrets file");
+
+    let mut child = common::sops_shell(dir)
+        .arg("check")
+        .args(args)
+        .arg("secrets.yaml")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to run sops-shell");
+    child.stdin.take().expect("stdin is piped").write_all(answers.as_bytes()).expect("Failed to write answers");
+    child.wait_with_output().expect("Failed to wait for sops-shell")
+}
+
+#[test]
+fn test_piped_answers() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let output = check(dir.path(), &["--paranoid"], "y\nn\nall\n");
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Command for first: echo one"), "{}", stderr);
+    assert_eq!(stderr.matches("Run it? [y/N/all]").count(), 3, "{}", stderr);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Command refused: declined at the confirmation prompt"), "{}", stdout);
+    assert_eq!(stdout.matches("Status: IN SYNC").count(), 2, "{}", stdout);
+}
+
+#[test]
+fn test_yes_approves_all() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let output = check(dir.path(), &["--confirm-each", "--yes"], "");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("Run it?"));
+}
+
+#[test]
+fn test_yes_requires_paranoid() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    assert_eq!(check(dir.path(), &["--yes"], "").status.code(), Some(2));
+}
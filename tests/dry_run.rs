@@ -0,0 +1,66 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::process::Output;
+
+const CONTENT: &str = "# shell: echo new\napi_key: old\n";
+
+fn sops_shell(dir: &Path, args: &[&str], dry_run_env: Option<&str>) -> Output {
+    fs::write(dir.join("secrets.yaml"), CONTENT).expect("Failed to write secrets file");
+
+    let mut command = common::sops_shell(dir);
+    command.args(args);
+    if let Some(value) = dry_run_env {
+        command.env("SOPS_SHELL_DRY_RUN", value);
+    }
+    command.output().expect("Failed to run sops-shell")
+}
+
+fn writes(dir: &Path) -> String {
+    fs::read_to_string(dir.join("sets.log")).unwrap_or_default()
+}
+
+#[test]
+fn test_env_var_forces_no_writes() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let output = sops_shell(dir.path(), &["sync", "secrets.yaml"], Some("1"));
+    assert!(output.status.success());
+    assert_eq!(writes(dir.path()), "");
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Would update 1 secrets (dry run)"));
+}
+
+#[test]
+fn test_flag_forces_no_writes() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let output = sops_shell(dir.path(), &["sync", "--dry-run", "secrets.yaml"], None);
+    assert!(output.status.success());
+    assert_eq!(writes(dir.path()), "");
+}
+
+#[test]
+fn test_false_env_var_leaves_sync_writing() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let output = sops_shell(dir.path(), &["sync", "secrets.yaml"], Some("0"));
+    assert!(output.status.success());
+    assert_eq!(writes(dir.path()), "[\"api_key\"] \"new\"\n");
+}
+
+#[test]
+fn test_config_shows_where_it_came_from() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let setting = |args: &[&str], env: Option<&str>| {
+        let output = sops_shell(dir.path(), args, env);
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        stdout.lines().find(|line| line.contains("dry run")).map(str::to_string).expect("dry run listed")
+    };
+
+    let from_env = setting(&["config"], Some("true"));
+    assert!(from_env.contains("true") && from_env.contains("env"), "{}", from_env);
+    let from_flag = setting(&["config", "--dry-run"], Some("1"));
+    assert!(from_flag.contains("true") && from_flag.contains("flag"), "{}", from_flag);
+    let default = setting(&["config"], None);
+    assert!(default.contains("false") && default.contains("default"), "{}", default);
+}
@@ -0,0 +1,83 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::process::Output;
+
+const CONTENT: &str = "# shell: echo same\napi_key: same\n";
+
+fn check(dir: &Path, args: &[&str]) -> Output {
+    common::sops_shell(dir).arg("check").args(args).output().expect("Failed to run sops-shell")
+}
+
+#[test]
+fn test_duplicate_and_equivalent_paths_processed_once() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    fs::write(dir.path().join("secrets.yaml"), CONTENT).expect("Failed to write secrets file");
+    fs::create_dir(dir.path().join("nested")).expect("Failed to create nested dir");
+
+    let output = check(dir.path(), &["secrets.yaml", "./secrets.yaml", "nested/../secrets.yaml", "secrets.yaml"]);
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.matches("Processing").count(), 1, "{}", stdout);
+    assert!(stdout.contains("Processing secrets.yaml..."), "{}", stdout);
+    assert!(stdout.contains("Files checked: 1"), "{}", stdout);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Skipping 3 duplicate file argument(s)"));
+}
+
+#[test]
+fn test_sort_files() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    for name in ["a.yaml", "b.yaml"] {
+        fs::write(dir.path().join(name), CONTENT).expect("Failed to write secrets file");
+    }
+
+    let order = |args: &[&str]| {
+        let stdout = String::from_utf8_lossy(&check(dir.path(), args).stdout).into_owned();
+        stdout.find("Processing a.yaml").expect("a.yaml processed") < stdout.find("Processing b.yaml").expect("b.yaml processed")
+    };
+    assert!(!order(&["b.yaml", "a.yaml"]), "Files are processed in the order given by default");
+    assert!(order(&["--sort-files", "b.yaml", "a.yaml"]));
+}
+
+#[test]
+fn test_variables_and_tilde_in_arguments_are_expanded() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    fs::create_dir(dir.path().join("home")).expect("Failed to create home dir");
+    fs::write(dir.path().join("home").join("secrets.yaml"), CONTENT).expect("Failed to write secrets file");
+
+    for argument in ["$SECRETS_HOME/secrets.yaml", "${SECRETS_HOME}/secrets.yaml", "~/secrets.yaml"] {
+        let output = common::sops_shell(dir.path())
+            .args(["check", argument])
+            .env("HOME", dir.path().join("home"))
+            .env("SECRETS_HOME", dir.path().join("home"))
+            .output()
+            .expect("Failed to run sops-shell");
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{}: {}", argument, String::from_utf8_lossy(&output.stderr));
+        assert!(stdout.contains("Files checked: 1"), "{}: {}", argument, stdout);
+    }
+}
+
+#[test]
+fn test_variables_and_tilde_in_created_files_are_expanded() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let home = dir.path().join("home");
+    fs::create_dir(&home).expect("Failed to create home dir");
+    fs::write(home.join("template.yaml"), "# shell: echo new\napi_key: placeholder\n").expect("Failed to write template");
+
+    for (argument, name) in [("$SECRETS_HOME/created.yaml", "created.yaml"), ("~/other.yaml", "other.yaml")] {
+        let output = common::sops_shell(dir.path())
+            .args(["sync", "--create", "--template", "~/template.yaml", argument])
+            .env("HOME", &home)
+            .env("SECRETS_HOME", &home)
+            .output()
+            .expect("Failed to run sops-shell");
+        assert!(output.status.success(), "{}: {}", argument, String::from_utf8_lossy(&output.stderr));
+        assert!(home.join(name).exists(), "{} wasn't created", argument);
+    }
+    assert!(!dir.path().join("$SECRETS_HOME").exists() && !dir.path().join("~").exists());
+}
@@ -0,0 +1,61 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::process::Output;
+
+const CONTENT: &str = "# shell: echo same\napi_key: same\n# shell: echo same\ntoken: same\n";
+
+fn check(dir: &Path, args: &[&str]) -> Output {
+    fs::write(dir.join("secrets.yaml"), CONTENT).expect("Failed to write secrets file");
+
+    common::sops_shell(dir).arg("check").args(args).arg("secrets.yaml").output().expect("Failed to run sops-shell")
+}
+
+#[test]
+fn test_recorded_and_missing_times() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let state = r#"{"files": {}, "synced": {"secrets.yaml": {"api_key": 1714555800}}}"#;
+    fs::write(dir.path().join(".sops-shell-state.json"), state).expect("Failed to write state file");
+
+    let output = check(dir.path(), &["--show-last-synced"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let api_key = stdout.find("  api_key").expect("api_key listed");
+    let token = stdout.find("  token").expect("token listed");
+    assert!(stdout[api_key..token].contains("Last synced: 2024-05-01T09:30:00Z"), "{}", stdout);
+    assert!(stdout[token..].contains("Last synced: never"), "{}", stdout);
+}
+
+#[test]
+fn test_without_state_file_everything_is_never() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let output = check(dir.path(), &["--show-last-synced"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout).matches("Last synced: never").count(), 2);
+}
+
+#[test]
+fn test_hidden_by_default() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let output = check(dir.path(), &[]);
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("Last synced"));
+}
+
+#[test]
+fn test_json_output() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let state = r#"{"files": {}, "synced": {"secrets.yaml": {"api_key": 1714555800}}}"#;
+    fs::write(dir.path().join(".sops-shell-state.json"), state).expect("Failed to write state file");
+
+    let output = check(dir.path(), &["--show-last-synced", "--format", "json"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let keys: Vec<serde_json::Value> = stdout
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter(|event| event["event"] == "key")
+        .collect();
+    assert_eq!(keys[0]["last_synced"], "2024-05-01T09:30:00Z");
+    assert!(keys[1]["last_synced"].is_null());
+}
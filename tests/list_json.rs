@@ -0,0 +1,48 @@
+#![cfg(unix)]
+
+mod common;
+
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+fn list_json(dir: &Path, files: &[&Path]) -> Value {
+    let output =
+        common::sops_shell(dir).args(["list", "--format", "json"]).args(files).output().expect("Failed to run sops-shell");
+    assert!(output.status.success(), "list failed: {}", String::from_utf8_lossy(&output.stderr));
+    serde_json::from_slice(&output.stdout).expect("Should print JSON")
+}
+
+#[test]
+fn test_directives_are_listed_as_json_without_running_commands() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let marker = dir.path().join("ran");
+    let secrets = dir.path().join("secrets.yaml");
+    let content = format!("# shell: touch {} && echo new\napi_key: old\n\n# shell: echo orphan\n", marker.display());
+    fs::write(&secrets, content).expect("Failed to write secrets file");
+    let plain = dir.path().join("plain.yaml");
+    fs::write(&plain, "other: value\n").expect("Failed to write plain file");
+
+    let listed = list_json(dir.path(), &[&secrets, &plain]);
+    assert!(!marker.exists(), "A directive's command was run");
+
+    let files = listed.as_array().expect("Should be an array of files");
+    assert_eq!(files.len(), 2);
+    assert_eq!(files[0]["file"], secrets.display().to_string());
+
+    let directives = files[0]["directives"].as_array().expect("Should list directives");
+    assert_eq!(directives.len(), 2);
+    assert_eq!(directives[0]["line"], 1);
+    assert_eq!(directives[0]["key"], "api_key");
+    assert_eq!(directives[0]["command"], format!("touch {} && echo new", marker.display()));
+    assert_eq!(directives[0]["status"], "resolved");
+    assert!(directives[0].get("reason").is_none());
+
+    assert_eq!(directives[1]["line"], 4);
+    assert!(directives[1]["key"].is_null());
+    assert_eq!(directives[1]["command"], "# shell: echo orphan");
+    assert_eq!(directives[1]["status"], "unresolved");
+    assert!(directives[1]["reason"].is_string());
+
+    assert_eq!(files[1]["directives"], Value::Array(Vec::new()));
+}
@@ -0,0 +1,91 @@
+#![cfg(unix)]
+
+mod common;
+
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use std::process::Output;
+
+const CONFIG: &str = r#"{
+  "profiles": {
+    "prod": {
+      "env": {"VAULT_ADDR": "https://vault.prod"},
+      "sops_config": "deploy/.sops.yaml",
+      "env_profile": "prod",
+      "sops_args": ["--verbose"]
+    },
+    "staging": {"env_profile": "staging"}
+  }
+}"#;
+
+fn sops_shell(dir: &Path, args: &[&str]) -> Output {
+    fs::write(dir.join(".sops-shell.json"), CONFIG).expect("Failed to write config file");
+    common::sops_shell(dir).args(args).output().expect("Failed to run sops-shell")
+}
+
+fn settings(dir: &Path, args: &[&str]) -> Value {
+    let output = sops_shell(dir, &[&["config", "--format", "json"], args].concat());
+    assert!(output.status.success(), "config failed: {}", String::from_utf8_lossy(&output.stderr));
+    let config: Value = serde_json::from_slice(&output.stdout).expect("Should print JSON");
+    config["settings"].clone()
+}
+
+#[test]
+fn test_profile_sets_effective_config() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let settings = settings(dir.path(), &["--profile", "prod"]);
+
+    assert_eq!(settings["profile"]["value"], "prod");
+    assert_eq!(settings["env profile"]["value"], "prod");
+    assert_eq!(settings["env profile"]["source"], "profile");
+    assert_eq!(settings["sops args"]["value"], "--config, deploy/.sops.yaml, --verbose");
+    assert_eq!(settings["sops args"]["source"], "profile");
+    assert_eq!(settings["command env"]["value"], "VAULT_ADDR");
+    assert_eq!(settings["command env"]["source"], "profile");
+    assert_eq!(settings["compare"]["source"], "default");
+}
+
+#[test]
+fn test_flags_win_over_profile() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let args = ["--profile", "prod", "--env-profile", "canary", "--sops-arg=--indent=2", "--env", "TEAM=ops"];
+    let settings = settings(dir.path(), &args);
+
+    assert_eq!(settings["env profile"]["value"], "canary");
+    assert_eq!(settings["env profile"]["source"], "flag");
+    assert_eq!(settings["sops args"]["value"], "--config, deploy/.sops.yaml, --verbose, --indent=2");
+    assert_eq!(settings["command env"]["value"], "VAULT_ADDR, TEAM");
+}
+
+#[test]
+fn test_without_profile_nothing_is_read() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let settings = settings(dir.path(), &["--config-file", "missing.json"]);
+
+    assert_eq!(settings["profile"]["value"], "(none)");
+    assert_eq!(settings["env profile"]["source"], "default");
+}
+
+#[test]
+fn test_unknown_profile_fails() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let output = sops_shell(dir.path(), &["config", "--profile", "qa"]);
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("No profile 'qa', known profiles are: prod, staging"), "{}", stderr);
+}
+
+#[test]
+fn test_profile_applies_to_sops_and_commands() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let content = "# shell[prod]: echo $VAULT_ADDR\nvault: https://vault.prod\n# shell[staging]: echo other\nother: x\n";
+    fs::write(dir.path().join("secrets.yaml"), content).expect("Failed to write secrets file");
+
+    let output = sops_shell(dir.path(), &["check", "--exit-code", "--profile", "prod", "secrets.yaml"]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stdout));
+
+    let log = fs::read_to_string(dir.path().join("sops.log")).expect("sops should have been run");
+    assert!(log.contains("--config deploy/.sops.yaml --verbose --decrypt"), "{}", log);
+}
@@ -0,0 +1,35 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+const CONTENT: &str = "# shell: echo same\napi_key: same\n# shell: echo new\ntoken: old\n";
+
+fn check(dir: &Path, args: &[&str]) -> String {
+    fs::write(dir.join("secrets.yaml"), CONTENT).expect("Failed to write secrets file");
+
+    let output =
+        common::sops_shell(dir).arg("check").args(args).arg("secrets.yaml").output().expect("Failed to run sops-shell");
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn test_piped_output_keeps_lines() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let stdout = check(dir.path(), &["--table"]);
+    assert!(stdout.contains("Status: OUT OF SYNC"), "{}", stdout);
+    assert!(!stdout.contains("| Status"), "{}", stdout);
+}
+
+#[test]
+fn test_forced_table() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let stdout = check(dir.path(), &["--table=always"]);
+    assert!(stdout.contains("  Key     | Status      | Command       | Duration"), "{}", stdout);
+    // The value is in the command itself
+    assert!(stdout.contains("  api_key | in_sync     | echo ******** |"), "{}", stdout);
+    assert!(stdout.contains("  token   | out_of_sync | echo new      |"), "{}", stdout);
+    assert!(!stdout.contains("Status: OUT OF SYNC"), "{}", stdout);
+}